@@ -7,13 +7,89 @@ const y0 = 50;
 const scale = 50;
 let cells = {};
 let all_stacked_trees = [];
+let failover = { broken_ports: {}, trees: {}, reparents: [], events: [] };
 let canvas;
+let refresh_timer = null;
 window.onload = function() {
     canvas = document.getElementById("viz-canvas");
     visualize();
+    listen();
+}
+// The server sends each trace record as it arrives, and the failover state after each failover
+// record; apply them to what's drawn
+function listen() {
+    const events = new EventSource('http://127.0.0.1:8088/events');
+    const handlers = {"border_cell_start": (body) => add_cell(body, true),
+                      "interior_cell_start": (body) => add_cell(body, false),
+                      "ca_process_hello_msg": add_neighbor,
+                      "ca_process_discoverd_msg": add_black_tree_link,
+                      "ca_process_stack_treed_msg": add_stacked_tree_link};
+    for (format in handlers) {
+        let handler = handlers[format];
+        events.addEventListener(format, (e) => {
+            if (handler(JSON.parse(e.data).body)) { schedule_draw(); }
+        });
+    }
+    events.addEventListener("failover", (e) => { setup_failover(e.data); schedule_draw(); });
+}
+// Redraw at most five times a second
+function schedule_draw() {
+    if (refresh_timer) { return; }
+    refresh_timer = setTimeout(() => { refresh_timer = null; draw(); }, 200);
+}
+function add_cell(body, is_border) {
+    let cellID = body.cell_id.name;
+    if (cells[cellID]) { return false; }
+    cells[cellID] = { row: body.location[0], col: body.location[1], is_border: is_border,
+                      neighbors: {}, black_trees: {}, stacked_trees: { trees: {} } };
+    create_node_at(cellID);
+    return true;
+}
+// Links go under the cells so the cells stay clickable
+function add_neighbor(body) {
+    let cellID = body.cell_id.name;
+    let neighbor = { cell_name: body.msg.payload.cell_id.name, port: body.msg.payload.port_no };
+    if (!cells[cellID]) { return false; }
+    cells[cellID].neighbors[body.port_no] = neighbor;
+    if (!cells[neighbor.cell_name]) { return false; }
+    let id = make_link_id(cellID, body.port_no, neighbor.cell_name, neighbor.port);
+    if (document.getElementById(id)) { return false; }
+    let line = create_line_at(id, cellID, neighbor.cell_name);
+    canvas.insertBefore(line, canvas.firstChild);
+    return true;
+}
+// The receiving cell is the sender's parent on the tree
+function add_tree_link(trees_of, cellID, port, tree_id, join) {
+    let cell = cells[cellID];
+    if (!cell || !cell.neighbors[port]) { return; }
+    let trees = trees_of(cell);
+    if (!trees[tree_id]) { trees[tree_id] = { tree: {} }; }
+    trees[tree_id].tree[port] = "Child";
+    let neighbor = cell.neighbors[port];
+    let neighbor_cell = cells[neighbor.cell_name];
+    if (join && neighbor_cell) {
+        let neighbor_trees = trees_of(neighbor_cell);
+        if (!neighbor_trees[tree_id]) { neighbor_trees[tree_id] = { tree: {} }; }
+        neighbor_trees[tree_id].tree[neighbor.port] = "Parent";
+    }
+}
+// Trees are only drawn when asked for, so these never need a redraw
+function add_black_tree_link(body) {
+    if (body.msg.payload.discover_type == "Parent") {
+        add_tree_link((cell) => cell.black_trees, body.cell_id.name, body.port_no,
+                      body.msg.payload.port_tree_id.name, true);
+    }
+    return false;
+}
+function add_stacked_tree_link(body) {
+    if (body.port_no == 0) { return false; }
+    let tree_id = body.msg.payload.port_tree_id.name;
+    add_tree_link((cell) => cell.stacked_trees.trees, body.cell_id.name, body.port_no, tree_id, body.msg.payload.join);
+    add_stacked_tree_button(tree_id);
+    return false;
 }
 function clear_dispay() {
-    let buttons = document.querySelectorAll(".stackedtreebutton");
+    let buttons = document.querySelectorAll(".stackedtreebutton, .repairedtreebutton, .losttreebutton");
     if (buttons.length > 0 ) {
         for (button of buttons) {
             button.parentNode.removeChild(button);
@@ -24,6 +100,7 @@ function clear_dispay() {
     }
 }
 function visualize() {
+    cells = {};
     canvas.innerHTML = "";
     const Http = new XMLHttpRequest();
     clear_dispay();
@@ -49,6 +126,13 @@ function visualize() {
                             Http.onreadystatechange = (e) => {
                                 if ( Http.readyState == 4 && Http.status == 200 ) {
                                     setup_stacked_trees(Http.responseText);
+                                    Http.open("GET", url + "failover");
+                                    Http.send();
+                                    Http.onreadystatechange = (e) => {
+                                        if ( Http.readyState == 4 && Http.status == 200 ) {
+                                            setup_failover(Http.responseText);
+                                        }
+                                    }
                                 }
                             }
                         }
@@ -58,6 +142,36 @@ function visualize() {
         }
     }
 }
+// A replay session moves through the trace on the server; redraw after each move
+function session_post(path, body) {
+    const Http = new XMLHttpRequest();
+    Http.open("POST", 'http://127.0.0.1:8088/session/' + path);
+    Http.setRequestHeader("Content-Type", "application/x-www-form-urlencoded");
+    Http.onreadystatechange = (e) => {
+        if ( Http.readyState == 4 ) {
+            if ( Http.status == 200 ) {
+                let session = JSON.parse(Http.responseText);
+                document.getElementById("session_position").innerText =
+                    session.position + "/" + session.no_records + " records" +
+                    (session.time == null ? "" : ", " + session.time + "µs") +
+                    (session.record == null ? "" : ", " + session.record.header.format);
+                if ( session.position > 0 ) { visualize(); } else { clear_dispay(); }
+            } else {
+                alert(Http.responseText);
+            }
+        }
+    }
+    Http.send(body);
+}
+function session_load() {
+    let filename = document.querySelector("input[name=filename]").value;
+    session_post("load", "filename=" + encodeURIComponent(filename));
+}
+function session_step(query) { session_post("step?" + query, null); }
+function session_seek() {
+    let time = document.getElementById("session_time").value;
+    session_post("seek?time=" + encodeURIComponent(time), null);
+}
 function setup_geometry(geometry_text) {
     let geometry = JSON.parse(geometry_text);
     let rowcol = geometry.geometry.rowcol;
@@ -105,22 +219,70 @@ function setup_black_trees(black_trees_text) {
 function setup_stacked_trees(stacked_trees_text) {
     let trees = JSON.parse(stacked_trees_text);
     let appcells = trees.appcells;
-    let buttons = document.getElementById("buttons");
     for (cellID in appcells) {
         let cell = cells[cellID];
         cell.stacked_trees = appcells[cellID].stacked_trees;
         let stacked_trees = cell.stacked_trees;
         for (stacked_tree in stacked_trees.trees) {
-            if (!document.getElementById(stacked_tree)) {
-                all_stacked_trees.push(stacked_tree);
-                let button = document.createElement("button");
-                button.id = stacked_tree;
-                button.innerText = stacked_tree;
-                button.onclick = stacked_tree_button_click;
-                buttons.appendChild(button);
-                let this_button = document.getElementById(stacked_tree);
-                this_button.setAttribute("class", "stackedtreebutton");
-            }
+            add_stacked_tree_button(stacked_tree);
+        }
+    }
+}
+function add_stacked_tree_button(stacked_tree) {
+    if (document.getElementById(stacked_tree)) { return; }
+    all_stacked_trees.push(stacked_tree);
+    let button = document.createElement("button");
+    button.id = stacked_tree;
+    button.innerText = stacked_tree;
+    button.onclick = stacked_tree_button_click;
+    button.setAttribute("class", "stackedtreebutton");
+    document.getElementById("buttons").appendChild(button);
+}
+// Broken links stay red; each tree a link break hit gets a button, green if failover repaired it
+function setup_failover(failover_text) {
+    failover = JSON.parse(failover_text).failover;
+    for (cellID in failover.broken_ports) {
+        for (port in failover.broken_ports[cellID]) {
+            let element = broken_link(cellID, port);
+            if (element) { element.setAttribute("class", "linkbroken"); }
+        }
+    }
+    let buttons = document.getElementById("buttons");
+    for (tree_id in failover.trees) {
+        let button_id = "failover" + tree_id;
+        if (!document.getElementById(button_id)) {
+            let status = failover.trees[tree_id].status;
+            let button = document.createElement("button");
+            button.id = button_id;
+            button.innerText = tree_id + " " + status;
+            button.onclick = failover_tree_button_click;
+            button.setAttribute("class", status == "Repaired" ? "repairedtreebutton" : "losttreebutton");
+            button.setAttribute("data-tree", tree_id);
+            buttons.appendChild(button);
+        }
+    }
+}
+function broken_link(cellID, port) {
+    let cell = cells[cellID];
+    if (!cell || !cell.neighbors[port]) { return null; }
+    let neighbor = cell.neighbors[port];
+    return document.getElementById(make_link_id(cellID, port, neighbor.cell_name, neighbor.port));
+}
+// The tree as discovery built it, plus the links failover moved it to
+function failover_tree_button_click(evt) {
+    let tree_id = evt.target.getAttribute("data-tree");
+    reset_view();
+    draw_black_tree(tree_id);
+    for (reparent of failover.reparents) {
+        if (reparent.trees.includes(tree_id)) {
+            let element = broken_link(reparent.cell_name, reparent.port);
+            if (element) { element.setAttribute("class", "linkrepaired"); }
+        }
+    }
+    for (cellID in failover.broken_ports) {
+        for (port in failover.broken_ports[cellID]) {
+            let element = broken_link(cellID, port);
+            if (element) { element.setAttribute("class", "linkbroken"); }
         }
     }
 }
@@ -177,6 +339,7 @@ function draw() { canvas.innerHTML = canvas.innerHTML; }
 function reset_view() {
     let swap = {".linktree": "link",
                 ".linkstackedtree": "link",
+                ".linkrepaired": "link",
                 ".noderoot": "node",
                 ".noderootborder": "nodeborder",
                 ".nodestacked": "node",
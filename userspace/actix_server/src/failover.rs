@@ -0,0 +1,249 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Link breaks and what failover did about them.  A link break shows up as a Disconnected status
+// on the ports at both ends.  The trees that used the link are broken until a FailoverD message
+// says whether a new path was found, and the cell that asked reroutes the broken port's
+// packets to its new parent port.
+use std::{
+    collections::{BTreeMap},
+    sync::{Mutex}
+};
+
+use actix_web::{web, Error, HttpResponse, Responder, Scope};
+use ec_trace_reader::TraceRecord;
+use serde::{Deserialize, Serialize};
+
+use crate::hello::{AppCells, LinkType};
+
+type Size = usize;
+
+async fn show_failover(appfailover: web::Data<AppFailover>) -> Result<HttpResponse, actix_web::Error> {
+    let failover = appfailover.get_ref();
+    let string = serde_json::to_string(failover)?;
+    Ok(HttpResponse::Ok().body(string))
+}
+async fn show_links(appfailover: web::Data<AppFailover>) -> Result<HttpResponse, actix_web::Error> {
+    let failover = appfailover.get_ref().failover.lock().unwrap();
+    let string = serde_json::to_string(&failover.broken_ports)?;
+    Ok(HttpResponse::Ok().body(string))
+}
+async fn process_failover(appcells: web::Data<AppCells>, appfailover: web::Data<AppFailover>,
+                          record: web::Json<TraceRecord>) -> Result<impl Responder, Error> {
+    process_failover_record(appcells, appfailover, &record)
+}
+pub fn process_failover_record(appcells: web::Data<AppCells>, appfailover: web::Data<AppFailover>,
+                               record: &TraceRecord) -> Result<impl Responder, Error> {
+    let header = record.header();
+    let time = header.epoch().saturating_sub(header.starting_epoch());
+    let mut failover = appfailover
+        .get_ref()
+        .failover.lock().unwrap();
+    match record.format() {
+        "ca_from_cm_status" => {
+            let body: StatusBody = serde_json::from_value(record.body().clone())?;
+            failover.port_status(time, body.cell_id.name, body.port, &body.status);
+        },
+        "ca_process_failover_msg" => {
+            let body: FailoverBody = serde_json::from_value(record.body().clone())?;
+            failover.trees_broken(time, &body.msg.payload);
+            failover.add_event(time, body.cell_id.name, EventKind::FailoverRequest, body.msg.payload.rw_port_tree_id.name);
+        },
+        "ca_process_failover_d_msg" => {
+            let body: FailoverDBody = serde_json::from_value(record.body().clone())?;
+            let payload = body.msg.payload;
+            failover.trees_broken(time, &payload.failover_payload);
+            failover.failover_done(time, body.cell_id.name, &payload.response, &payload.failover_payload);
+        },
+        "ca_to_cm_reroute" => {
+            let body: RerouteBody = serde_json::from_value(record.body().clone())?;
+            let cells = appcells
+                .get_ref()
+                .appcells.lock().unwrap();
+            // The trees whose parent was on the other end of the broken link
+            let trees = cells
+                .get(&body.cell_id.name)
+                .map(|appcell| appcell
+                    .black_trees()
+                    .iter()
+                    .filter(|(_, tree)| tree.tree().get(&body.broken_port_no) == Some(&LinkType::Parent))
+                    .map(|(tree_name, _)| tree_name.clone())
+                    .collect())
+                .unwrap_or_default();
+            failover.reroute(time, body.cell_id.name, body.broken_port_no, body.port_no, trees);
+        },
+        _ => ()
+    }
+    Ok(HttpResponse::Ok().body(format!("process_failover {}", record.format())))
+}
+
+// Trace record bodies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StatusBody {
+    cell_id: CellID,
+    port: Size,
+    status: String // Connected or Disconnected
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverBody {
+    cell_id: CellID,
+    port_no: Size,
+    msg: FailoverMsg
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverMsg {
+    payload: FailoverPayload
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverPayload {
+    rw_port_tree_id: TreeID, // The tree being repaired
+    broken_tree_ids: Vec<TreeID>
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverDBody {
+    cell_id: CellID,
+    port_no: Size,
+    msg: FailoverDMsg
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverDMsg {
+    payload: FailoverDPayload
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailoverDPayload {
+    response: String, // Success or Failure
+    failover_payload: FailoverPayload
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RerouteBody {
+    cell_id: CellID,
+    broken_port_no: Size,
+    port_no: Size
+}
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct CellID {
+    name: String
+}
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct TreeID {
+    name: String
+}
+// Server data
+#[derive(Debug, Default, Serialize)]
+pub struct AppFailover {
+    pub failover: Mutex<Failover>
+}
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TreeStatus {
+    Broken, Repaired, Lost
+}
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum EventKind {
+    LinkBroken, LinkRestored, FailoverRequest, FailoverSuccess, FailoverFailure, Reroute
+}
+// Times are microseconds from the start of the run
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TreeRepair {
+    pub status: TreeStatus,
+    pub broken_at: u64,
+    pub done_at: Option<u64> // When it was repaired or given up on
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Reparent {
+    pub time: u64,
+    pub cell_name: String,
+    pub broken_port: Size,
+    pub port: Size, // The new parent port
+    pub trees: Vec<String>
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FailoverEvent {
+    pub time: u64,
+    pub cell_name: String,
+    pub kind: EventKind,
+    pub detail: String // Port or tree
+}
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Failover {
+    pub broken_ports: BTreeMap<String, BTreeMap<Size, u64>>, // When each port went down, by cell
+    pub trees: BTreeMap<String, TreeRepair>,
+    pub reparents: Vec<Reparent>,
+    pub events: Vec<FailoverEvent>
+}
+impl Failover {
+    fn port_status(&mut self, time: u64, cell_name: String, port: Size, status: &str) {
+        let ports = self.broken_ports.entry(cell_name.clone()).or_insert(Default::default());
+        if status == "Disconnected" {
+            ports.insert(port, time);
+            self.add_event(time, cell_name, EventKind::LinkBroken, format!("port {}", port));
+        } else if ports.remove(&port).is_some() {
+            if ports.is_empty() { self.broken_ports.remove(&cell_name); }
+            self.add_event(time, cell_name, EventKind::LinkRestored, format!("port {}", port));
+        }
+    }
+    fn trees_broken(&mut self, time: u64, payload: &FailoverPayload) {
+        let broken = payload.broken_tree_ids.iter().chain(Some(&payload.rw_port_tree_id));
+        for tree_id in broken {
+            let repair = self.trees
+                .entry(tree_id.name.clone())
+                .or_insert(TreeRepair { status: TreeStatus::Broken, broken_at: time, done_at: None });
+            // A tree that was repaired or lost can break again
+            if repair.status != TreeStatus::Broken {
+                *repair = TreeRepair { status: TreeStatus::Broken, broken_at: time, done_at: None };
+            }
+        }
+    }
+    // Every cell on the path back sees the response; the first one sets the time
+    fn failover_done(&mut self, time: u64, cell_name: String, response: &str, payload: &FailoverPayload) {
+        let (status, kind) = if response == "Success" {
+            (TreeStatus::Repaired, EventKind::FailoverSuccess)
+        } else {
+            (TreeStatus::Lost, EventKind::FailoverFailure)
+        };
+        let broken = payload.broken_tree_ids.iter().chain(Some(&payload.rw_port_tree_id));
+        for tree_id in broken {
+            let repair = self.trees.get_mut(&tree_id.name).expect("Failover: tree must be broken");
+            if repair.status == TreeStatus::Broken {
+                repair.status = status;
+                repair.done_at = Some(time);
+            }
+        }
+        self.add_event(time, cell_name, kind, payload.rw_port_tree_id.name.clone());
+    }
+    fn reroute(&mut self, time: u64, cell_name: String, broken_port: Size, port: Size, trees: Vec<String>) {
+        self.add_event(time, cell_name.clone(), EventKind::Reroute, format!("port {} to port {}", broken_port, port));
+        self.reparents.push(Reparent { time, cell_name, broken_port, port, trees });
+    }
+    fn add_event(&mut self, time: u64, cell_name: String, kind: EventKind, detail: String) {
+        self.events.push(FailoverEvent { time, cell_name, kind, detail });
+    }
+}
+
+pub fn get() -> Scope {
+    web::scope("/failover")
+        .route("", web::get().to(show_failover))
+}
+pub fn get_links() -> Scope {
+    web::scope("/broken_links")
+        .route("", web::get().to(show_links))
+}
+pub fn post_status() -> Scope {
+    web::scope("/ca_from_cm_status")
+        .route("", web::post().to(process_failover))
+}
+pub fn post_failover() -> Scope {
+    web::scope("/ca_process_failover_msg")
+        .route("", web::post().to(process_failover))
+}
+pub fn post_failover_d() -> Scope {
+    web::scope("/ca_process_failover_d_msg")
+        .route("", web::post().to(process_failover))
+}
+pub fn post_reroute() -> Scope {
+    web::scope("/ca_to_cm_reroute")
+        .route("", web::post().to(process_failover))
+}
+pub fn data() -> web::Data<AppFailover> {
+    web::Data::new(AppFailover::default())
+}
@@ -109,6 +109,7 @@ pub struct Tree {
     tree: HashMap<usize, LinkType>
 }
 impl Tree {
+    pub fn tree(&self) -> &HashMap<usize, LinkType> { &self.tree }
     pub fn tree_mut(&mut self) -> &mut HashMap<usize, LinkType> { &mut self.tree }
 }
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
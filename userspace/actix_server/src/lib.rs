@@ -10,9 +10,11 @@
 //#![warn(rust_2018_idioms)]
 
 pub mod discoverd;
+pub mod failover;
 pub mod geometry;
 pub mod hello;
 pub mod index;
 pub mod replay;
+pub mod routing;
 pub mod stacktreed;
-pub mod trace_record;
+pub mod stream;
@@ -8,7 +8,7 @@ use std::{env,
 
 use actix_web::{web, App, HttpServer, Responder, HttpResponse};
 
-use ec_trace_analyzer::{discoverd, geometry, hello, index, replay, stacktreed};
+use ec_trace_analyzer::{discoverd, failover, geometry, hello, index, replay, routing, stacktreed, stream};
 
 #[actix_rt::main]
 async fn main() {
@@ -19,6 +19,13 @@ async fn main() {
     let index_data = web::Data::new(html_file_name); // Location of index.html
     let geo_data = geometry::data();
     let hello_data = hello::data();
+    let failover_data = failover::data();
+    let routing_data = routing::data();
+    let replay_data = replay::data();
+    let stream_data = stream::data();
+    let trace_stream = env::var("TRACE_STREAM").unwrap_or(stream::DEFAULT_TRACE_STREAM.to_owned());
+    stream::listen(&trace_stream, hello_data.clone(), geo_data.clone(), failover_data.clone(), routing_data.clone(), stream_data.clone())
+        .expect("Cannot listen for trace streams");
     HttpServer::new(move || {
         App::new()
             .route("/visualizer.css", web::get().to(get_css))
@@ -43,8 +50,26 @@ async fn main() {
         
             .service(stacktreed::get())
             .service(stacktreed::post())
+
+            .app_data(failover_data.clone())
+            .service(failover::get())
+            .service(failover::get_links())
+            .service(failover::post_status())
+            .service(failover::post_failover())
+            .service(failover::post_failover_d())
+            .service(failover::post_reroute())
+
+            .app_data(routing_data.clone())
+            .service(routing::get())
+            .service(routing::post())
         
             .service(replay::post())
+
+            .app_data(replay_data.clone())
+            .service(replay::session())
+
+            .app_data(stream_data.clone())
+            .service(stream::get())
         })
         .keep_alive(100usize)
         .bind(server_url)
@@ -2,74 +2,49 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std;
-use std::{
-          fs::{File},
-          io::{prelude::*, BufReader},
-};
+use std::{sync::{Mutex}};
 
-use actix_web::{http, web, Error, Responder, HttpResponse, Scope};
+use actix_web::{error, http, web, Error, Responder, HttpResponse, Scope};
+use ec_trace_reader::{TraceReader, TraceRecord};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::{discoverd, geometry, hello, stacktreed};
+use crate::stream;
+use crate::failover::{AppFailover, Failover};
 use crate::geometry::{AppGeometry, RowCol};
 use crate::hello::{AppCells};
-use crate::trace_record::TraceRecord;
+use crate::routing::{AppRouting};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileNameParams { filename: String }
 
 async fn replay_from_file(appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+                    appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>,
                     form_data: web::Form<FileNameParams>)
                     -> Result<impl Responder, Error> {
-    reset(appcells.clone(), appgeometry.clone()).await;
+    reset(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone()).await;
     let filename = if form_data.filename == "" {
         "../cellagent/trace/trace.json"
     } else {
         &form_data.filename
     };
-    let replay_file = File::open(filename)?;
-    let reader = BufReader::new(replay_file);
-    for line in reader.lines() {
-        let mut trace_line = line?;
-        trace_line.pop(); // Remove LF
-        let trace_record: TraceRecord = serde_json::from_str(&trace_line)?;
-        let header = trace_record.header();
-        let body = trace_record.body().clone();
-        match header.format() {
-            "ca_process_discoverd_msg" => {
-                let body: discoverd::Body = serde_json::from_value(body)?;
-                discoverd::process_discoverd_body(appcells.clone(), body)?;
-            },
-            "border_cell_start" => {
-                let body: geometry::Body = serde_json::from_value(body)?;
-                let path = "border_cell_start";
-                let is_border = true;
-                geometry::cell_geometry_body(path, is_border, appgeometry.clone(), body)?;
-            },
-            "interior_cell_start" => {
-                let body: geometry::Body = serde_json::from_value(body)?;
-                let path = "interior_cell_start";
-                let is_border = false;
-                geometry::cell_geometry_body(path, is_border, appgeometry.clone(), body)?;
-            },
-            "ca_process_stack_treed_msg" => {
-                let body: stacktreed::Body = serde_json::from_value(body)?;
-                stacktreed::process_stack_treed_body(appcells.clone(), body)?;
-            },
-            "ca_process_hello_msg" => {
-                let body: hello::Body = serde_json::from_value(body)?;
-                hello::process_hello_body(appcells.clone(), body)?;
-            }
-            _ => ()
-        }
+    let trace_reader = TraceReader::open(filename).map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    for trace_record in trace_reader {
+        let trace_record = trace_record.map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+        stream::process_record(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone(), &trace_record)?;
     }
     Ok(HttpResponse::Found()
         .header(http::header::LOCATION, "/")
         .content_type("text/plain")
         .body(format!("Replay from file {}", filename)))
 }
-pub async fn reset(appcells: web::Data<AppCells>, geometry: web::Data<AppGeometry>) -> impl Responder {
+pub async fn reset(appcells: web::Data<AppCells>, geometry: web::Data<AppGeometry>,
+                   failover: web::Data<AppFailover>, routing: web::Data<AppRouting>) -> impl Responder {
+    clear(appcells, geometry, failover, routing);
+    HttpResponse::Ok().body("reset")
+}
+fn clear(appcells: web::Data<AppCells>, geometry: web::Data<AppGeometry>, failover: web::Data<AppFailover>,
+         routing: web::Data<AppRouting>) {
     let mut cells = appcells
         .get_ref()
         .appcells.lock().unwrap();
@@ -80,9 +55,220 @@ pub async fn reset(appcells: web::Data<AppCells>, geometry: web::Data<AppGeometr
     geometry.maxcol = 0;
     geometry.maxrow = 0;
     geometry.rowcol = RowCol::default();
-    HttpResponse::Ok().body("reset")
+    let mut failover = failover
+        .get_ref()
+        .failover.lock().unwrap();
+    *failover = Failover::default();
+    let mut routing = routing
+        .get_ref()
+        .routing.lock().unwrap();
+    routing.clear();
 }
 pub fn post() -> Scope {
     web::scope("/replay")
         .route("", web::post().to(replay_from_file))
 }
+
+// A replay session holds a whole trace and applies it up to a point that can move either way.
+// The fabric views can't be undone record by record, so going back starts over from the top.
+#[derive(Debug, Default)]
+pub struct AppReplay {
+    pub session: Mutex<Session>
+}
+#[derive(Debug, Default)]
+pub struct Session {
+    filename: String,
+    records: Vec<TraceRecord>,
+    position: usize // Number of records applied
+}
+impl Session {
+    pub fn new(filename: &str, records: Vec<TraceRecord>) -> Session {
+        Session { filename: filename.to_owned(), records, position: 0 }
+    }
+    // Microseconds from the start of the run to the last record applied
+    fn time(&self) -> Option<u64> {
+        self.position.checked_sub(1).map(|index| time(&self.records[index]))
+    }
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            filename: self.filename.clone(),
+            position: self.position,
+            no_records: self.records.len(),
+            time: self.time(),
+            record: self.position.checked_sub(1).map(|index| self.records[index].clone())
+        }
+    }
+    // Counts only the records the analyzer follows, so each step changes what is shown
+    fn event_target(&self, events: i64) -> usize {
+        let is_event = |index: &usize| stream::follows(self.records[*index].format());
+        let count = events.abs() as usize;
+        if count == 0 { return self.position; }
+        if events > 0 {
+            (self.position..self.records.len())
+                .filter(is_event)
+                .nth(count - 1)
+                .map_or(self.records.len(), |index| index + 1)
+        } else {
+            (0..self.position)
+                .rev()
+                .filter(is_event)
+                .nth(count - 1)
+                .unwrap_or(0)
+        }
+    }
+    fn time_target(&self, time_us: u64) -> usize {
+        self.records
+            .iter()
+            .position(|record| time(record) > time_us)
+            .unwrap_or(self.records.len())
+    }
+    fn event_id_target(&self, event_id: &[u64]) -> Option<usize> {
+        self.records
+            .iter()
+            .position(|record| record.header().event_id().as_slice() == event_id)
+            .map(|index| index + 1)
+    }
+    // A number of records, one by default, or of events if given; negative to go back
+    pub fn step_target(&self, records: Option<i64>, events: Option<i64>) -> usize {
+        match (records, events) {
+            (_, Some(events)) => self.event_target(events),
+            (records, None) => {
+                let records = records.unwrap_or(1);
+                if records < 0 {
+                    self.position.saturating_sub(records.abs() as usize)
+                } else {
+                    self.position + records as usize
+                }
+            }
+        }
+    }
+    // Through a record number, a time, or the record with an event id, e.g., 3.1.0, in that order
+    pub fn seek_target(&self, record: Option<usize>, time_us: Option<u64>, event_id: Option<&str>) -> Result<usize, Error> {
+        if let Some(record) = record {
+            Ok(record)
+        } else if let Some(time_us) = time_us {
+            Ok(self.time_target(time_us))
+        } else if let Some(event_id) = event_id {
+            let event_id = event_id
+                .split('.')
+                .map(|n| n.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+            self.event_id_target(&event_id)
+                .ok_or_else(|| error::ErrorNotFound(format!("No record with event id {:?}", event_id)))
+        } else {
+            Err(error::ErrorBadRequest("Seek needs record, time, or event_id"))
+        }
+    }
+}
+fn time(record: &TraceRecord) -> u64 {
+    record.header().epoch().saturating_sub(record.header().starting_epoch())
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub filename: String,
+    pub position: usize,
+    pub no_records: usize,
+    pub time: Option<u64>,
+    pub record: Option<TraceRecord> // The last one applied
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StepParams {
+    records: Option<i64>, // Negative to go back
+    events: Option<i64>
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SeekParams {
+    record: Option<usize>,
+    time: Option<u64>,
+    event_id: Option<String> // E.g., 3.1.0, applies through the record with this event id
+}
+// Moves the session to target, starting over if that means going back
+pub fn goto(session: &mut Session, target: usize, appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+        appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>) -> Result<(), Error> {
+    let target = target.min(session.records.len());
+    if target < session.position {
+        clear(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone());
+        session.position = 0;
+    }
+    for trace_record in &session.records[session.position..target] {
+        stream::process_record(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone(), trace_record)?;
+    }
+    session.position = target;
+    Ok(())
+}
+async fn load_session(appreplay: web::Data<AppReplay>, appcells: web::Data<AppCells>,
+                      appgeometry: web::Data<AppGeometry>, appfailover: web::Data<AppFailover>,
+                      approuting: web::Data<AppRouting>, form_data: web::Form<FileNameParams>) -> Result<HttpResponse, Error> {
+    let filename = if form_data.filename == "" {
+        "../cellagent/trace/trace.json"
+    } else {
+        &form_data.filename
+    };
+    let trace_reader = TraceReader::open(filename).map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    let records = trace_reader
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    clear(appcells, appgeometry, appfailover, approuting);
+    let mut session = appreplay
+        .get_ref()
+        .session.lock().unwrap();
+    *session = Session::new(filename, records);
+    Ok(HttpResponse::Ok().json(session.info()))
+}
+async fn step_session(appreplay: web::Data<AppReplay>, appcells: web::Data<AppCells>,
+                      appgeometry: web::Data<AppGeometry>, appfailover: web::Data<AppFailover>,
+                      approuting: web::Data<AppRouting>, params: web::Query<StepParams>) -> Result<HttpResponse, Error> {
+    let mut session = appreplay
+        .get_ref()
+        .session.lock().unwrap();
+    let target = session.step_target(params.records, params.events);
+    goto(&mut session, target, appcells, appgeometry, appfailover, approuting)?;
+    Ok(HttpResponse::Ok().json(session.info()))
+}
+async fn seek_session(appreplay: web::Data<AppReplay>, appcells: web::Data<AppCells>,
+                      appgeometry: web::Data<AppGeometry>, appfailover: web::Data<AppFailover>,
+                      approuting: web::Data<AppRouting>, params: web::Query<SeekParams>) -> Result<HttpResponse, Error> {
+    let mut session = appreplay
+        .get_ref()
+        .session.lock().unwrap();
+    let target = session.seek_target(params.record, params.time, params.event_id.as_deref())?;
+    goto(&mut session, target, appcells, appgeometry, appfailover, approuting)?;
+    Ok(HttpResponse::Ok().json(session.info()))
+}
+async fn show_session(appreplay: web::Data<AppReplay>) -> Result<HttpResponse, Error> {
+    let session = appreplay
+        .get_ref()
+        .session.lock().unwrap();
+    Ok(HttpResponse::Ok().json(session.info()))
+}
+// Everything the analyzer knows at the session's position
+async fn show_session_state(appreplay: web::Data<AppReplay>, appcells: web::Data<AppCells>,
+                            appgeometry: web::Data<AppGeometry>, appfailover: web::Data<AppFailover>,
+                            approuting: web::Data<AppRouting>) -> Result<HttpResponse, Error> {
+    let session = appreplay
+        .get_ref()
+        .session.lock().unwrap();
+    Ok(HttpResponse::Ok().json(session_state(&session, appcells, appgeometry, appfailover, approuting)))
+}
+pub fn session_state(session: &Session, appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+                     appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>) -> Value {
+    json!({
+        "session": session.info(),
+        "geometry": appgeometry.get_ref(),
+        "cells": appcells.get_ref(),
+        "failover": appfailover.get_ref(),
+        "routing": approuting.get_ref().routing.lock().unwrap().tables()
+    })
+}
+pub fn session() -> Scope {
+    web::scope("/session")
+        .route("", web::get().to(show_session))
+        .route("/state", web::get().to(show_session_state))
+        .route("/load", web::post().to(load_session))
+        .route("/step", web::post().to(step_session))
+        .route("/seek", web::post().to(seek_session))
+}
+pub fn data() -> web::Data<AppReplay> {
+    web::Data::new(AppReplay::default())
+}
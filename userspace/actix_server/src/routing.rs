@@ -0,0 +1,216 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Each cell's routing table, rebuilt from the entries the cell agents send their cmodels.
+// Entries only carry the tree UUID, so names are looked up when asked for, from every ID
+// the server has seen by then.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex}
+};
+
+use actix_web::{web, Error, HttpResponse, Responder, Scope};
+use ec_trace_reader::TraceRecord;
+use ec_trace_reader::tree_names::TreeNames;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::hello::{AppCells};
+
+type Size = usize;
+
+async fn show_routing(approuting: web::Data<AppRouting>) -> Result<HttpResponse, actix_web::Error> {
+    let routing = approuting.get_ref().routing.lock().unwrap();
+    Ok(HttpResponse::Ok().json(routing.tables()))
+}
+async fn show_cell(approuting: web::Data<AppRouting>, cell_name: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
+    let routing = approuting.get_ref().routing.lock().unwrap();
+    let tables = routing.tables();
+    let table = tables.get(cell_name.as_str()).cloned().unwrap_or_default();
+    Ok(HttpResponse::Ok().json(table))
+}
+async fn show_tree(approuting: web::Data<AppRouting>, tree_uuid: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
+    let routing = approuting.get_ref().routing.lock().unwrap();
+    Ok(HttpResponse::Ok().json(routing.tree(&tree_uuid)))
+}
+async fn check_routing(approuting: web::Data<AppRouting>, appcells: web::Data<AppCells>) -> Result<HttpResponse, actix_web::Error> {
+    let routing = approuting.get_ref().routing.lock().unwrap();
+    let cells = appcells.get_ref().appcells.lock().unwrap();
+    // Where each port leads, from the hello messages
+    let links = cells
+        .iter()
+        .flat_map(|(cell_name, appcell)| appcell
+            .neighbors()
+            .iter()
+            .map(move |(port, neighbor)| ((cell_name.clone(), *port), neighbor.cell_name().clone())))
+        .collect::<HashMap<_, _>>();
+    Ok(HttpResponse::Ok().json(routing.check(&links)))
+}
+async fn process_entry(approuting: web::Data<AppRouting>, record: web::Json<TraceRecord>)
+                       -> Result<impl Responder, Error> {
+    process_routing_record(approuting, &record)
+}
+// Every record may name trees; only ca_to_cm_entry changes a table
+pub fn process_routing_record(approuting: web::Data<AppRouting>, record: &TraceRecord)
+                              -> Result<impl Responder, Error> {
+    let mut routing = approuting
+        .get_ref()
+        .routing.lock().unwrap();
+    routing.tree_names.learn(record.body());
+    if record.format() == "ca_to_cm_entry" {
+        let body: EntryBody = serde_json::from_value(record.body().clone())?;
+        routing.entries
+            .entry(body.cell_id.name)
+            .or_insert(Default::default())
+            .insert(uuid_string(&body.entry.tree_uuid), body.entry);
+    }
+    Ok(HttpResponse::Ok().body("process_routing".to_owned()))
+}
+fn uuid_string(uuid: &Value) -> String { uuid["uuid"].as_str().unwrap_or("").to_owned() }
+
+// Trace record bodies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EntryBody {
+    cell_id: CellID,
+    entry: Entry
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    tree_uuid: Value,
+    may_send: bool,
+    inuse: bool,
+    parent: Size,
+    mask: Mask
+}
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+struct Mask {
+    mask: u16
+}
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct CellID {
+    name: String
+}
+// Server data
+#[derive(Debug, Default)]
+pub struct AppRouting {
+    pub routing: Mutex<Routing>
+}
+#[derive(Debug, Clone, Default)]
+pub struct Routing {
+    tree_names: TreeNames,
+    entries: BTreeMap<String, HashMap<String, Entry>> // By cell, then by tree UUID
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RoutingEntry {
+    pub tree: String, // The tree's name, only for display since names needn't be unique
+    pub parent: Size, // 0 at the root
+    pub ports: Vec<Size>, // Where packets on the tree go, from the mask
+    pub may_send: bool,
+    pub inuse: bool
+}
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ProblemKind {
+    Loop, // Following parents comes back around
+    Orphan, // The parent port leads nowhere, or to a cell with no entry for the tree
+    NoRoot,
+    ManyRoots
+}
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub tree: String,
+    pub cells: Vec<String>
+}
+impl Routing {
+    pub fn clear(&mut self) { *self = Routing::default(); }
+    // By cell, then by tree UUID
+    pub fn tables(&self) -> BTreeMap<String, BTreeMap<String, RoutingEntry>> {
+        self.entries
+            .iter()
+            .map(|(cell_name, entries)| (cell_name.clone(), entries
+                .iter()
+                .map(|(uuid, entry)| (uuid.clone(), self.routing_entry(entry)))
+                .collect()))
+            .collect()
+    }
+    // By cell, for the tree with this UUID
+    pub fn tree(&self, tree_uuid: &str) -> BTreeMap<String, RoutingEntry> {
+        self.entries
+            .iter()
+            .filter_map(|(cell_name, entries)| entries
+                .get(tree_uuid)
+                .map(|entry| (cell_name.clone(), self.routing_entry(entry))))
+            .collect()
+    }
+    // Looks at the entries in use; links maps (cell, port) to the cell on the other end.  Trees
+    // are told apart by UUID, so two with the same name, or none, aren't mixed up.
+    pub fn check(&self, links: &HashMap<(String, Size), String>) -> Vec<Problem> {
+        let mut trees: BTreeMap<&str, (&Value, BTreeMap<String, RoutingEntry>)> = BTreeMap::new();
+        for (cell_name, entries) in &self.entries {
+            for (uuid, entry) in entries.iter().filter(|(_, entry)| entry.inuse) {
+                trees.entry(uuid.as_str())
+                    .or_insert((&entry.tree_uuid, Default::default()))
+                    .1.insert(cell_name.clone(), self.routing_entry(entry));
+            }
+        }
+        let mut problems = Vec::new();
+        for (tree_uuid, cells) in trees.values() {
+            let tree_name = self.tree_names.name(tree_uuid);
+            let mut problem = |kind, cells: Vec<String>| problems.push(Problem { kind, tree: tree_name.clone(), cells });
+            let roots = cells.iter().filter(|(_, entry)| entry.parent == 0).map(|(cell_name, _)| cell_name.clone()).collect::<Vec<_>>();
+            match roots.len() {
+                0 => problem(ProblemKind::NoRoot, vec![]),
+                1 => (),
+                _ => problem(ProblemKind::ManyRoots, roots)
+            }
+            let mut in_loop = BTreeSet::new();
+            for (cell_name, entry) in cells.iter().filter(|(_, entry)| entry.parent != 0) {
+                match links.get(&(cell_name.clone(), entry.parent)) {
+                    Some(parent_name) if cells.contains_key(parent_name) => (),
+                    _ => problem(ProblemKind::Orphan, vec![cell_name.clone()])
+                }
+                // Walk up at most once around every cell
+                let mut path = vec![cell_name.clone()];
+                let mut current = cell_name.clone();
+                while let Some(parent_name) = cells.get(&current)
+                        .filter(|entry| entry.parent != 0)
+                        .and_then(|entry| links.get(&(current.clone(), entry.parent))) {
+                    if let Some(start) = path.iter().position(|name| name == parent_name) {
+                        let cycle = path[start..].to_vec();
+                        let key = cycle.iter().cloned().collect::<BTreeSet<_>>();
+                        if in_loop.is_disjoint(&key) {
+                            in_loop.extend(key);
+                            problem(ProblemKind::Loop, cycle);
+                        }
+                        break;
+                    }
+                    path.push(parent_name.clone());
+                    current = parent_name.clone();
+                }
+            }
+        }
+        problems.sort_by(|a, b| a.tree.cmp(&b.tree)); // Keeps each tree's problems in order
+        problems
+    }
+    fn routing_entry(&self, entry: &Entry) -> RoutingEntry {
+        let ports = (0..16).filter(|port| entry.mask.mask & (1 << port) != 0).collect();
+        RoutingEntry { tree: self.tree_names.name(&entry.tree_uuid), parent: entry.parent, ports,
+                       may_send: entry.may_send, inuse: entry.inuse }
+    }
+}
+
+pub fn get() -> Scope {
+    web::scope("/routing")
+        .route("", web::get().to(show_routing))
+        .route("/check", web::get().to(check_routing))
+        .route("/cell/{cell_name}", web::get().to(show_cell))
+        .route("/tree/{tree_uuid}", web::get().to(show_tree))
+}
+pub fn post() -> Scope {
+    web::scope("/ca_to_cm_entry")
+        .route("", web::post().to(process_entry))
+}
+pub fn data() -> web::Data<AppRouting> {
+    web::Data::new(AppRouting::default())
+}
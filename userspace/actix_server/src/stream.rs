@@ -0,0 +1,195 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Cells stream their trace records over one TCP connection each, the same JSON Lines the Tcp
+// trace sink sends.  Each record updates the server's view of the fabric and goes out to every
+// browser listening on /events as a Server-Sent Event.
+use std::{env, fmt,
+          io::BufReader,
+          net::{SocketAddr, TcpListener, TcpStream},
+          sync::{Arc, Mutex, OnceLock, atomic::{AtomicUsize, Ordering}},
+          thread};
+
+use actix_web::{web, web::Bytes, Error, HttpResponse, Scope};
+use ec_trace_reader::{TraceReader, TraceRecord};
+use futures::{channel::mpsc, StreamExt};
+use serde::Serialize;
+
+use crate::{discoverd, failover, geometry, hello, routing, stacktreed};
+use crate::failover::{AppFailover};
+use crate::geometry::{AppGeometry};
+use crate::hello::{AppCells};
+use crate::routing::{AppRouting};
+
+pub const DEFAULT_TRACE_STREAM: &str = "127.0.0.1:9899"; // The cells' default Tcp trace sink
+pub const CLIENT_BACKLOG: usize = 1000; // Events a browser can fall behind before it's dropped
+const MAX_STREAMS: usize = 128; // One per cell; more are turned away
+
+// The browsers listening on /events
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    clients: Mutex<Vec<mpsc::Sender<Bytes>>>
+}
+impl Broadcaster {
+    pub fn new_client(&self) -> mpsc::Receiver<Bytes> {
+        let (mut tx, rx) = mpsc::channel(CLIENT_BACKLOG);
+        let _ = tx.try_send(Bytes::from("retry: 1000\n\n"));
+        self.clients.lock().unwrap().push(tx);
+        rx
+    }
+    // Browsers that have gone away are dropped here, and so are those that fall CLIENT_BACKLOG
+    // events behind, so they don't hold records without end; the browser connects again and
+    // picks up from there
+    pub fn send(&self, record: &TraceRecord) {
+        if !follows(record.format()) { return; }
+        self.send_event(record.format(), record);
+    }
+    // The fabric's failover state after a failover record, so browsers needn't work it out again
+    pub fn send_failover(&self, appfailover: &AppFailover) {
+        self.send_event(FAILOVER_EVENT, appfailover);
+    }
+    fn send_event<T: Serialize>(&self, name: &str, value: &T) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() { return; }
+        let data = match serde_json::to_string(value) {
+            Ok(data) => data,
+            Err(e) => { trace(Level::Error, format_args!("Can't send {}: {}", name, e)); return; }
+        };
+        let event = Bytes::from(format!("event: {}\ndata: {}\n\n", name, data));
+        clients.retain_mut(|client| client.try_send(event.clone()).is_ok());
+    }
+}
+pub const FAILOVER_EVENT: &str = "failover";
+// The formats process_record does something with
+const FOLLOWED_FORMATS: &[&str] = &["border_cell_start", "interior_cell_start", "ca_process_hello_msg",
+                                    "ca_process_discoverd_msg", "ca_process_stack_treed_msg", "ca_from_cm_status",
+                                    "ca_process_failover_msg", "ca_process_failover_d_msg", "ca_to_cm_reroute",
+                                    "ca_to_cm_entry"];
+
+pub fn follows(format: &str) -> bool { FOLLOWED_FORMATS.contains(&format) }
+fn is_failover(format: &str) -> bool {
+    matches!(format, "ca_from_cm_status" | "ca_process_failover_msg" | "ca_process_failover_d_msg" | "ca_to_cm_reroute")
+}
+// Update the fabric from one record, from a stream or a replay
+pub fn process_record(appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+                      appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>,
+                      trace_record: &TraceRecord) -> Result<(), Error> {
+    routing::process_routing_record(approuting, trace_record)?; // Learns tree names from every record
+    let body = trace_record.body().clone();
+    match trace_record.format() {
+        "ca_process_discoverd_msg" => {
+            let body: discoverd::Body = serde_json::from_value(body)?;
+            discoverd::process_discoverd_body(appcells, body)?;
+        },
+        "border_cell_start" => {
+            let body: geometry::Body = serde_json::from_value(body)?;
+            let path = "border_cell_start";
+            let is_border = true;
+            geometry::cell_geometry_body(path, is_border, appgeometry, body)?;
+        },
+        "interior_cell_start" => {
+            let body: geometry::Body = serde_json::from_value(body)?;
+            let path = "interior_cell_start";
+            let is_border = false;
+            geometry::cell_geometry_body(path, is_border, appgeometry, body)?;
+        },
+        "ca_process_stack_treed_msg" => {
+            let body: stacktreed::Body = serde_json::from_value(body)?;
+            stacktreed::process_stack_treed_body(appcells, body)?;
+        },
+        "ca_process_hello_msg" => {
+            let body: hello::Body = serde_json::from_value(body)?;
+            hello::process_hello_body(appcells, body)?;
+        },
+        format if is_failover(format) => {
+            failover::process_failover_record(appcells, appfailover, trace_record)?;
+        }
+        _ => ()
+    }
+    Ok(())
+}
+// Accepts trace streams on its own thread so a slow browser never backs up a cell; returns the
+// address it listens on, which tells the port when the address gives 0
+pub fn listen(address: &str, appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+              appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>, broadcaster: web::Data<Broadcaster>) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let local_addr = listener.local_addr()?;
+    trace(Level::Info, format_args!("Listening at {}", local_addr));
+    let streams = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new().name("TraceStream".to_owned()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if streams.fetch_add(1, Ordering::SeqCst) >= MAX_STREAMS {
+                        streams.fetch_sub(1, Ordering::SeqCst);
+                        trace(Level::Warn, format_args!("Already reading {} streams, closing {:?}", MAX_STREAMS, stream.peer_addr()));
+                        continue;
+                    }
+                    let appcells = appcells.clone();
+                    let appgeometry = appgeometry.clone();
+                    let appfailover = appfailover.clone();
+                    let approuting = approuting.clone();
+                    let broadcaster = broadcaster.clone();
+                    let reading = streams.clone();
+                    let spawned = thread::Builder::new().name("TraceStreamReader".to_owned()).spawn(move || {
+                        read_stream(stream, appcells, appgeometry, appfailover, approuting, broadcaster);
+                        reading.fetch_sub(1, Ordering::SeqCst);
+                    });
+                    if let Err(e) = spawned {
+                        streams.fetch_sub(1, Ordering::SeqCst);
+                        trace(Level::Error, format_args!("{}", e));
+                    }
+                },
+                Err(e) => trace(Level::Error, format_args!("{}", e))
+            }
+        }
+    })?;
+    Ok(local_addr)
+}
+fn read_stream(stream: TcpStream, appcells: web::Data<AppCells>, appgeometry: web::Data<AppGeometry>,
+               appfailover: web::Data<AppFailover>, approuting: web::Data<AppRouting>, broadcaster: web::Data<Broadcaster>) {
+    let trace_reader = match TraceReader::new(BufReader::new(stream)) {
+        Ok(trace_reader) => trace_reader,
+        Err(e) => { trace(Level::Error, format_args!("{}", e)); return; }
+    };
+    for trace_record in trace_reader {
+        match trace_record {
+            Ok(trace_record) => {
+                if let Err(e) = process_record(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone(), &trace_record) {
+                    trace(Level::Warn, format_args!("{}: {}", trace_record.format(), e));
+                }
+                broadcaster.send(&trace_record);
+                if is_failover(trace_record.format()) { broadcaster.send_failover(appfailover.get_ref()); }
+            },
+            Err(e) => { trace(Level::Error, format_args!("{}", e)); return; }
+        }
+    }
+}
+async fn events(broadcaster: web::Data<Broadcaster>) -> HttpResponse {
+    let rx = broadcaster.new_client();
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(rx.map(Ok::<_, Error>))
+}
+pub fn get() -> Scope {
+    web::scope("/events")
+        .route("", web::get().to(events))
+}
+pub fn data() -> web::Data<Broadcaster> {
+    web::Data::new(Broadcaster::default())
+}
+// The server has no tracing crate, so stream events go to stderr, as much as TRACE_STREAM_LOG
+// (error, warn or info) lets through
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+enum Level { Error, Warn, Info }
+fn trace(level: Level, args: fmt::Arguments) {
+    static MAX_LEVEL: OnceLock<Level> = OnceLock::new();
+    let max_level = *MAX_LEVEL.get_or_init(|| match env::var("TRACE_STREAM_LOG").as_deref() {
+        Ok("error") => Level::Error,
+        Ok("warn") => Level::Warn,
+        _ => Level::Info
+    });
+    if level <= max_level { eprintln!("{:?} TraceStream: {}", level, args); }
+}
@@ -0,0 +1,280 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+#[macro_use] extern crate serde_json;
+
+use std::{collections::{BTreeSet, HashMap}, io::Write, net::TcpStream};
+
+use actix_web::web::Bytes;
+use ec_trace_reader::{test_result, TraceFileHeader, TraceReader, TraceRecord};
+use ec_trace_reader::testing::Test;
+use futures::{executor::block_on, FutureExt, StreamExt};
+
+use ec_trace_analyzer::{failover, geometry, hello, replay, routing, stream};
+use ec_trace_analyzer::failover::{EventKind, TreeStatus};
+use ec_trace_analyzer::routing::{Problem, ProblemKind};
+
+fn file_header() -> String {
+    serde_json::to_string(&TraceFileHeader::new("0123456789abcdef", 100)).unwrap()
+}
+fn record(epoch: u64, format: &str, body: serde_json::Value) -> String {
+    json!({ "header": { "starting_epoch": 100, "epoch": epoch, "spawning_thread_id": 1, "thread_id": 1,
+                        "event_id": [epoch], "trace_type": "Trace", "module": "test.rs", "line_no": 0, "function": "test",
+                        "format": format, "repo": "CellAgent" },
+            "body": body }).to_string()
+}
+
+// The header line, then the records
+fn trace_records(lines: &[String]) -> Vec<TraceRecord> {
+    let lines = Some(file_header()).into_iter().chain(lines.iter().cloned()).collect::<Vec<_>>();
+    TraceReader::new(lines.join("\n").as_bytes()).unwrap().collect::<Result<Vec<_>, _>>().unwrap()
+}
+
+struct StreamEvents {}
+
+impl Test for StreamEvents {
+    // A record a cell streams to the server goes to each browser as an event named for its format,
+    // unless the visualizer doesn't follow that format; a failover record also sends the new
+    // failover state
+    fn test(&mut self) {
+        let broadcaster = stream::data();
+        let mut events = broadcaster.new_client();
+        let address = stream::listen("127.0.0.1:0", hello::data(), geometry::data(), failover::data(),
+                                     routing::data(), broadcaster.clone()).unwrap();
+        let mut cell = TcpStream::connect(address).unwrap();
+        writeln!(cell, "{}", file_header()).unwrap();
+        writeln!(cell, "{}", record(110, "ca_new", json!({ "cell_id": { "name": "C:0" } }))).unwrap();
+        writeln!(cell, "{}", record(120, "border_cell_start", json!({ "cell_id": { "name": "C:0" }, "location": [0, 0] }))).unwrap();
+        writeln!(cell, "{}", record(130, "ca_from_cm_status", json!({ "cell_id": { "name": "C:0" }, "port": 1, "status": "Disconnected" }))).unwrap();
+        assert_eq!(block_on(events.next()), Some(Bytes::from("retry: 1000\n\n")));
+        let event = block_on(events.next()).unwrap();
+        assert!(event.starts_with(b"event: border_cell_start\ndata: {"));
+        assert!(event.ends_with(b"}\n\n"));
+        assert!(block_on(events.next()).unwrap().starts_with(b"event: ca_from_cm_status\n"));
+        let event = block_on(events.next()).unwrap();
+        assert!(event.starts_with(b"event: failover\ndata: {\"failover\":{\"broken_ports\":{\"C:0\":{\"1\":30}}"));
+    }
+}
+
+test_result!(test_stream_events, StreamEvents {});
+
+struct SlowBrowser {}
+
+impl Test for SlowBrowser {
+    // A browser that falls too far behind is dropped instead of holding records without end,
+    // and the others keep getting them
+    fn test(&mut self) {
+        let trace_record = trace_records(&[record(110, "border_cell_start", json!({ "cell_id": { "name": "C:0" }, "location": [0, 0] }))]).remove(0);
+        let broadcaster = stream::data();
+        let mut slow = broadcaster.new_client();
+        for _ in 0..stream::CLIENT_BACKLOG + 10 { broadcaster.send(&trace_record); }
+        let mut queued = 0;
+        loop {
+            match slow.next().now_or_never() {
+                Some(Some(_)) => queued += 1,
+                Some(None) => break, // Dropped
+                None => panic!("Slow browser still connected after {} events", queued)
+            }
+        }
+        assert!(queued <= stream::CLIENT_BACKLOG + 1);
+        let mut fast = broadcaster.new_client();
+        broadcaster.send(&trace_record);
+        assert_eq!(block_on(fast.next()), Some(Bytes::from("retry: 1000\n\n")));
+        assert!(block_on(fast.next()).unwrap().starts_with(b"event: border_cell_start\n"));
+    }
+}
+
+test_result!(test_slow_browser, SlowBrowser {});
+
+struct FailoverTracking {}
+
+impl Test for FailoverTracking {
+    // C:1's link to its parent C:0 on port 1 breaks; failover repairs tree C:0 and C:1 reroutes
+    // to port 2, but tree C:2 is lost.  Then the link comes back.
+    fn test(&mut self) {
+        let (appcells, appgeometry, appfailover, approuting) = (hello::data(), geometry::data(), failover::data(), routing::data());
+        let hello = |cell: &str, port: usize, other: &str| {
+            json!({ "cell_id": { "name": cell }, "port_no": port, "msg": { "payload": { "cell_id": { "name": other }, "port_no": port } } })
+        };
+        let status = |cell: &str, status: &str| json!({ "cell_id": { "name": cell }, "port": 1, "status": status });
+        let failover_payload = |tree: &str| json!({ "rw_port_tree_id": { "name": tree }, "broken_tree_ids": [{ "name": tree }] });
+        let failover_d = |tree: &str, response: &str| {
+            json!({ "cell_id": { "name": "C:1" }, "port_no": 2, "msg": { "payload": { "response": response, "failover_payload": failover_payload(tree) } } })
+        };
+        let records = trace_records(&[
+            record(110, "ca_process_hello_msg", hello("C:0", 1, "C:1")),
+            record(120, "ca_process_hello_msg", hello("C:1", 1, "C:0")),
+            record(130, "ca_process_discoverd_msg", json!({ "cell_id": { "name": "C:0" }, "port_no": 1,
+                                                            "msg": { "payload": { "discover_type": "Parent", "port_tree_id": { "name": "C:0" } } } })),
+            record(200, "ca_from_cm_status", status("C:1", "Disconnected")),
+            record(210, "ca_process_failover_msg", json!({ "cell_id": { "name": "C:2" }, "port_no": 3, "msg": { "payload": failover_payload("C:0") } })),
+            record(220, "ca_process_failover_d_msg", failover_d("C:0", "Success")),
+            record(230, "ca_to_cm_reroute", json!({ "cell_id": { "name": "C:1" }, "broken_port_no": 1, "port_no": 2 })),
+            record(240, "ca_process_failover_d_msg", failover_d("C:2", "Failure")),
+        ]);
+        for trace_record in &records {
+            stream::process_record(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone(), trace_record).unwrap();
+        }
+        {
+            let failover = appfailover.get_ref().failover.lock().unwrap();
+            assert_eq!(failover.broken_ports["C:1"][&1], 100);
+            assert_eq!((failover.trees["C:0"].status, failover.trees["C:0"].broken_at, failover.trees["C:0"].done_at),
+                       (TreeStatus::Repaired, 110, Some(120)));
+            assert_eq!((failover.trees["C:2"].status, failover.trees["C:2"].done_at), (TreeStatus::Lost, Some(140)));
+            assert_eq!(failover.reparents.len(), 1);
+            assert_eq!((failover.reparents[0].broken_port, failover.reparents[0].port), (1, 2));
+            assert_eq!(failover.reparents[0].trees, vec!["C:0".to_owned()]);
+            let kinds = failover.events.iter().map(|event| event.kind).collect::<Vec<_>>();
+            assert_eq!(kinds, vec![EventKind::LinkBroken, EventKind::FailoverRequest, EventKind::FailoverSuccess,
+                                   EventKind::Reroute, EventKind::FailoverFailure]);
+        }
+        let restored = trace_records(&[record(300, "ca_from_cm_status", status("C:1", "Connected"))]);
+        stream::process_record(appcells, appgeometry, appfailover.clone(), approuting, &restored[0]).unwrap();
+        let failover = appfailover.get_ref().failover.lock().unwrap();
+        assert!(failover.broken_ports.is_empty());
+        assert_eq!(failover.events.last().map(|event| event.kind), Some(EventKind::LinkRestored));
+    }
+}
+
+test_result!(test_failover_tracking, FailoverTracking {});
+
+struct FailoverTwice {}
+
+impl Test for FailoverTwice {
+    // Tree C:0 breaks and is repaired, then breaks again; the second break starts a new repair
+    fn test(&mut self) {
+        let (appcells, appgeometry, appfailover, approuting) = (hello::data(), geometry::data(), failover::data(), routing::data());
+        let failover = json!({ "cell_id": { "name": "C:2" }, "port_no": 3,
+                               "msg": { "payload": { "rw_port_tree_id": { "name": "C:0" }, "broken_tree_ids": [{ "name": "C:0" }] } } });
+        let failover_d = json!({ "cell_id": { "name": "C:1" }, "port_no": 2,
+                                 "msg": { "payload": { "response": "Success",
+                                                       "failover_payload": { "rw_port_tree_id": { "name": "C:0" }, "broken_tree_ids": [{ "name": "C:0" }] } } } });
+        let process = |records: Vec<TraceRecord>| {
+            for trace_record in &records {
+                stream::process_record(appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone(), trace_record).unwrap();
+            }
+        };
+        process(trace_records(&[
+            record(100, "ca_process_failover_msg", failover.clone()),
+            record(110, "ca_process_failover_d_msg", failover_d.clone()),
+            record(200, "ca_process_failover_msg", failover.clone()),
+        ]));
+        {
+            let failover = appfailover.get_ref().failover.lock().unwrap();
+            assert_eq!((failover.trees["C:0"].status, failover.trees["C:0"].broken_at, failover.trees["C:0"].done_at),
+                       (TreeStatus::Broken, 100, None));
+        }
+        process(trace_records(&[record(230, "ca_process_failover_d_msg", failover_d)]));
+        let failover = appfailover.get_ref().failover.lock().unwrap();
+        assert_eq!((failover.trees["C:0"].status, failover.trees["C:0"].broken_at, failover.trees["C:0"].done_at),
+                   (TreeStatus::Repaired, 100, Some(130)));
+    }
+}
+
+test_result!(test_failover_twice, FailoverTwice {});
+
+struct ReplaySession {}
+
+impl Test for ReplaySession {
+    // A session steps by records or by the events the analyzer follows, seeks by record, time,
+    // or event id, and going back shows the fabric as it was
+    fn test(&mut self) {
+        let (appcells, appgeometry, appfailover, approuting) = (hello::data(), geometry::data(), failover::data(), routing::data());
+        let hello = |cell: &str, other: &str| {
+            json!({ "cell_id": { "name": cell }, "port_no": 1, "msg": { "payload": { "cell_id": { "name": other }, "port_no": 1 } } })
+        };
+        let mut session = replay::Session::new("test", trace_records(&[
+            record(110, "ca_new", json!({ "cell_id": { "name": "C:0" } })),
+            record(120, "ca_process_hello_msg", hello("C:0", "C:1")),
+            record(130, "ca_new", json!({ "cell_id": { "name": "C:1" } })),
+            record(140, "ca_process_hello_msg", hello("C:1", "C:0")),
+            record(150, "ca_from_cm_status", json!({ "cell_id": { "name": "C:1" }, "port": 1, "status": "Disconnected" })),
+        ]));
+        let goto = |session: &mut replay::Session, target: usize| {
+            replay::goto(session, target, appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone()).unwrap();
+            let info = session.info();
+            let cells = appcells.get_ref().appcells.lock().unwrap().keys().cloned().collect::<BTreeSet<_>>();
+            let broken = !appfailover.get_ref().failover.lock().unwrap().broken_ports.is_empty();
+            (info.position, info.time, cells.into_iter().collect::<Vec<_>>(), broken)
+        };
+        let info = session.info();
+        assert_eq!((info.position, info.no_records, info.time), (0, 5, None));
+        let target = session.step_target(None, None);
+        assert_eq!(goto(&mut session, target), (1, Some(10), vec![], false));
+        assert_eq!(session.info().record.map(|record| record.format().to_owned()), Some("ca_new".to_owned()));
+        let target = session.step_target(None, Some(1));
+        assert_eq!(goto(&mut session, target), (2, Some(20), vec!["C:0".to_owned()], false));
+        let target = session.step_target(None, Some(2));
+        assert_eq!(goto(&mut session, target), (5, Some(50), vec!["C:0".to_owned(), "C:1".to_owned()], true));
+        let target = session.step_target(None, Some(-1));
+        assert_eq!(goto(&mut session, target), (4, Some(40), vec!["C:0".to_owned(), "C:1".to_owned()], false));
+        let target = session.step_target(Some(-10), None);
+        assert_eq!(goto(&mut session, target), (0, None, vec![], false));
+        let target = session.seek_target(None, Some(30), None).unwrap();
+        assert_eq!(goto(&mut session, target), (3, Some(30), vec!["C:0".to_owned()], false));
+        let target = session.seek_target(None, None, Some("140")).unwrap();
+        assert_eq!(goto(&mut session, target), (4, Some(40), vec!["C:0".to_owned(), "C:1".to_owned()], false));
+        assert_eq!(session.seek_target(Some(2), Some(30), None).unwrap(), 2);
+        assert!(session.seek_target(None, None, Some("999")).is_err());
+        assert!(session.seek_target(None, None, Some("1.x")).is_err());
+        assert!(session.seek_target(None, None, None).is_err());
+        let state = replay::session_state(&session, appcells.clone(), appgeometry.clone(), appfailover.clone(), approuting.clone());
+        assert_eq!(state["session"]["position"], 4);
+        assert!(state["cells"]["appcells"]["C:1"].is_object());
+    }
+}
+
+test_result!(test_replay_session, ReplaySession {});
+
+struct RoutingCheck {}
+
+impl Test for RoutingCheck {
+    // C:0 and C:1 are linked on port 1.  Trees are told apart by UUID, so the two trees named
+    // Twin, each with its own root, aren't taken for one tree with two roots, and the tables
+    // show both.
+    fn test(&mut self) {
+        let approuting = routing::data();
+        let uuid_string = |key: &str| format!("0000{}-0000-4000-8000-000000000000", key);
+        let uuid = |key: &str| json!({ "uuid": uuid_string(key) });
+        let tree = |name: &str, key: &str| record(110, "ca_new", json!({ "cell_id": { "name": "C:0" }, "my_tree_id": { "name": name, "uuid": uuid(key) } }));
+        let entry = |cell: &str, key: &str, parent: usize, inuse: bool| {
+            record(120, "ca_to_cm_entry", json!({ "cell_id": { "name": cell },
+                                                  "entry": { "tree_uuid": uuid(key), "may_send": true, "inuse": inuse, "parent": parent, "mask": { "mask": 3 } } }))
+        };
+        let records = trace_records(&[
+            tree("Good", "000a"), tree("Loop", "000b"), tree("Many", "000c"), tree("Orphan", "000d"),
+            tree("Twin", "000e"), tree("Twin", "000f"), tree("Old", "0010"),
+            entry("C:0", "000a", 0, true), entry("C:1", "000a", 1, true),
+            entry("C:0", "000b", 1, true), entry("C:1", "000b", 1, true),
+            entry("C:0", "000c", 0, true), entry("C:1", "000c", 0, true),
+            entry("C:0", "000d", 0, true), entry("C:1", "000d", 2, true),
+            entry("C:0", "000e", 0, true), entry("C:1", "000f", 0, true),
+            entry("C:0", "0010", 2, false), entry("C:1", "0010", 2, false),
+        ]);
+        for trace_record in &records { routing::process_routing_record(approuting.clone(), trace_record).unwrap(); }
+        let routing = approuting.get_ref().routing.lock().unwrap();
+        let good = routing.tree(&uuid_string("000a"));
+        assert_eq!((good["C:0"].parent, good["C:1"].parent, good["C:1"].ports.clone()), (0, 1, vec![0, 1]));
+        let tables = routing.tables();
+        let orphan = &tables["C:1"][&uuid_string("000d")];
+        assert_eq!((orphan.tree.as_str(), orphan.parent), ("Orphan", 2));
+        assert_eq!(tables["C:0"][&uuid_string("000e")].tree, "Twin");
+        assert_eq!(tables["C:1"][&uuid_string("000f")].tree, "Twin");
+        assert_eq!(routing.tree(&uuid_string("000e")).keys().collect::<Vec<_>>(), vec!["C:0"]);
+        let links = vec![(("C:0".to_owned(), 1), "C:1".to_owned()), (("C:1".to_owned(), 1), "C:0".to_owned())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let problem = |kind, tree: &str, cells: &[&str]| {
+            Problem { kind, tree: tree.to_owned(), cells: cells.iter().map(|cell| cell.to_string()).collect() }
+        };
+        assert_eq!(routing.check(&links), vec![
+            problem(ProblemKind::NoRoot, "Loop", &[]),
+            problem(ProblemKind::Loop, "Loop", &["C:0", "C:1"]),
+            problem(ProblemKind::ManyRoots, "Many", &["C:0", "C:1"]),
+            problem(ProblemKind::Orphan, "Orphan", &["C:1"]),
+        ]);
+    }
+}
+
+test_result!(test_routing_check, RoutingCheck {});
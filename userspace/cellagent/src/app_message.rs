@@ -10,11 +10,15 @@ use std::{fmt,
 use serde_json;
 
 use crate::cellagent::CellAgent;
+use crate::config::{BASE_TREE_NAME, CellQty};
+use crate::container::ContainerStatus;
 use crate::gvm_equation::{GvmEquation};
-use crate::name::{OriginatorID};
-use crate::noc::{NocToPort, Noc};
-use crate::uptree_spec::{AllowedTree, Manifest};
-use crate::utility::{ByteArray, S};
+use crate::name::{CellID, OriginatorID};
+use crate::noc::{NOC_CONTROL_TREE_NAME, NocToPort, Noc};
+use crate::tree_key::{TreeKey, TreeKeys};
+use crate::uptree_spec::{AllowedTree, DeployMode, Manifest};
+use crate::utility::{ByteArray, PlacementReport, S};
+use crate::wire;
 
 // This is currently at the cell level, but could be placed at the up-tree level.
 #[derive(Debug, Copy, Clone, Default, Hash, Eq, PartialEq, Serialize, Deserialize)]
@@ -27,16 +31,20 @@ pub fn get_next_count() -> SenderMsgSeqNo { SenderMsgSeqNo(MESSAGE_COUNT.fetch_a
 pub enum AppMsgType { // Make sure these match the struct names
     AppInterapplicationMsg,
     AppDeleteTreeMsg,
+    AppHealthMsg,
     AppManifestMsg,
+    AppPlacementMsg,
     AppQueryMsg,
+    AppRedeployMsg,
     AppStackTreeMsg,
+    AppTenantMsg,
     AppTreeNameMsg,
+    AppUndeployMsg,
 }
 impl AppMsgType {
     pub fn app_msg_from_bytes(bytes: &ByteArray) -> Result<Box<dyn AppMessage>, Error> {
         let _f = "app_msg_from_bytes";
-        let serialized = bytes.stringify()?;
-        let msg = serde_json::from_str(&serialized)?;
+        let msg = wire::decode(bytes).context(AppMessageError::Chain { func_name: _f, comment: S("") })?;
         Ok(msg)
     }
 }
@@ -45,10 +53,15 @@ impl fmt::Display for AppMsgType {
         let s = match *self {
             AppMsgType::AppInterapplicationMsg => "AppInterapplication",
             AppMsgType::AppDeleteTreeMsg       => "AppDeleteTree",
+            AppMsgType::AppHealthMsg           => "AppHealth",
             AppMsgType::AppManifestMsg         => "AppManifest",
+            AppMsgType::AppPlacementMsg        => "AppPlacement",
             AppMsgType::AppQueryMsg            => "AppQuery",
+            AppMsgType::AppRedeployMsg         => "AppRedeploy",
             AppMsgType::AppStackTreeMsg        => "AppStackTree",
+            AppMsgType::AppTenantMsg           => "AppTenant",
             AppMsgType::AppTreeNameMsg         => "AppTreeName",
+            AppMsgType::AppUndeployMsg         => "AppUndeploy",
         };
         write!(f, "{}", s)
     }
@@ -87,6 +100,9 @@ pub trait AppMessage: fmt::Display {
     fn get_sender_name(&self) -> &str { &self.get_header().get_sender_name() }
     fn get_direction(&self) -> AppMsgDirection { self.get_header().get_direction() }
     fn get_allowed_trees(&self) -> &Vec<AllowedTree> { &self.get_header().get_allowed_trees() }
+    fn get_tenant(&self) -> Option<&str> { self.get_header().get_tenant() }
+    // What a container sees of the payload, decrypted if it was sealed with one of its tree keys
+    fn open(&self, _tree_keys: &TreeKeys) -> Result<String, Error> { Ok(format!("{}", self.get_payload())) }
     fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error>;
     fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error>;
 }
@@ -102,6 +118,8 @@ pub struct AppMsgHeader {
     is_snake: bool,
     direction: AppMsgDirection,
     allowed_trees: Vec<AllowedTree>, // Trees named in message body
+    #[serde(default)]
+    tenant: Option<String>, // Charged for the request, or the root tenant if none
 }
 impl AppMsgHeader {
     pub fn new(sender_name: &str, target_tree: &AllowedTree, is_ait: bool, is_snake: bool,
@@ -109,7 +127,8 @@ impl AppMsgHeader {
             -> AppMsgHeader {
         let msg_count = get_next_count();
         AppMsgHeader { sender_msg_seq_no: msg_count, target_tree: target_tree.clone(),
-            sender_name: S(sender_name), msg_type, is_ait, is_snake, direction, allowed_trees: allowed_trees.clone() }
+            sender_name: S(sender_name), msg_type, is_ait, is_snake, direction, allowed_trees: allowed_trees.clone(),
+            tenant: None }
     }
     fn get_sender_msg_seq_no(&self) -> SenderMsgSeqNo { self.sender_msg_seq_no }
     fn get_target_tree_name(&self) -> &AllowedTree { &self.target_tree }
@@ -119,6 +138,8 @@ impl AppMsgHeader {
     fn is_snake(&self) -> bool { self.is_snake }
     fn get_direction(&self) -> AppMsgDirection { self.direction }
     fn get_allowed_trees(&self) -> &Vec<AllowedTree> { &self.allowed_trees }
+    fn get_tenant(&self) -> Option<&str> { self.tenant.as_ref().map(|tenant| tenant.as_str()) }
+    fn set_tenant(&mut self, tenant: &str) { self.tenant = Some(S(tenant)); }
 }
 impl fmt::Display for AppMsgHeader { 
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { 
@@ -142,6 +163,18 @@ impl AppInterapplicationMsg {
         let payload = AppInterapplicationMsgPayload::new(body);
         AppInterapplicationMsg { header, payload }
     }
+    // Only containers holding the key of the target tree can read the body
+    pub fn new_sealed(sender_name: &str, is_ait: bool, is_snake: bool, target_tree: &AllowedTree, direction: AppMsgDirection,
+                      allowed_trees: &Vec<AllowedTree>, body: &str, tree_key: &TreeKey) -> Result<AppInterapplicationMsg, Error> {
+        let _f = "new_sealed";
+        let msg_type = AppMsgType::AppInterapplicationMsg;
+        let header = AppMsgHeader::new(sender_name, target_tree, is_ait, is_snake,
+                                       msg_type, direction, allowed_trees);
+        let (body, nonce) = tree_key.seal(body).context(AppMessageError::Chain { func_name: _f, comment: S(target_tree.get_name()) })?;
+        let payload = AppInterapplicationMsgPayload { body, nonce: Some(nonce) };
+        Ok(AppInterapplicationMsg { header, payload })
+    }
+    pub fn is_sealed(&self) -> bool { self.payload.nonce.is_some() }
 }
 #[typetag::serde]
 impl AppMessage for AppInterapplicationMsg {
@@ -151,6 +184,20 @@ impl AppMessage for AppInterapplicationMsg {
     fn value(&self) -> serde_json::Value {
         serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
     }
+    fn open(&self, tree_keys: &TreeKeys) -> Result<String, Error> {
+        let _f = "open";
+        let tree_name = self.get_target_tree_name().get_name();
+        // Once a tree has a key, anything sent on it in the clear could have come from anyone
+        let nonce = match &self.payload.nonce {
+            Some(nonce) => nonce,
+            None if tree_keys.contains_key(tree_name) => return Err(AppMessageError::Unsealed { func_name: _f, tree_name: S(tree_name) }.into()),
+            None => return Ok(format!("{}", self.payload))
+        };
+        let tree_key = tree_keys
+            .get(tree_name)
+            .ok_or(AppMessageError::NoTreeKey { func_name: _f, tree_name: S(tree_name) })?;
+        tree_key.open(&self.payload.body, nonce)
+    }
     fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
         let _f = "process_ca";
         cell_agent.app_interapplication(self, sender_id).context(AppMessageError::Chain { func_name: _f, comment: S("") })?;
@@ -170,10 +217,12 @@ impl fmt::Display for AppInterapplicationMsg {
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub struct AppInterapplicationMsgPayload {
     body: ByteArray,
+    #[serde(default)]
+    nonce: Option<Vec<u8>>, // Only for sealed bodies
 }
 impl AppInterapplicationMsgPayload {
     fn new(body: &str) -> AppInterapplicationMsgPayload {
-        AppInterapplicationMsgPayload { body: ByteArray::new(body) }
+        AppInterapplicationMsgPayload { body: ByteArray::new(body), nonce: None }
     }
     pub fn get_body(&self) -> &ByteArray { &self.body }
 }
@@ -181,6 +230,7 @@ impl AppInterapplicationMsgPayload {
 impl AppMsgPayload for AppInterapplicationMsgPayload {}
 impl fmt::Display for AppInterapplicationMsgPayload {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.nonce.is_some() { return write!(f, "Sealed {} bytes", self.body.len()); }
         let body = self.body.stringify().expect("Error converting bytes to string in AppInterapplicationMsg fmt::Display");
         write!(f, "{}", body)
     }
@@ -240,6 +290,96 @@ impl fmt::Display for AppDeleteTreeMsgPayload {
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppHealthMsg {
+    header: AppMsgHeader,
+    payload: AppHealthMsgPayload
+}
+impl AppHealthMsg {
+    pub fn new(sender_name: &str, source: &str, status: ContainerStatus, restarts: usize, reason: &str) -> AppHealthMsg {
+        // Note that direction is rootward on the NOC master's control tree so failures reach the master
+        let msg_type = AppMsgType::AppHealthMsg;
+        let header = AppMsgHeader::new(sender_name, &AllowedTree::new(NOC_CONTROL_TREE_NAME),
+                                       false, false, msg_type,
+                                       AppMsgDirection::Rootward, &vec![]);
+        let payload = AppHealthMsgPayload::new(source, status, restarts, reason);
+        AppHealthMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &AppHealthMsgPayload { &self.payload }
+}
+#[typetag::serde]
+impl AppMessage for AppHealthMsg {
+    fn get_header(&self) -> &AppMsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn AppMsgPayload { &self.payload }
+    fn get_msg_type(&self) -> AppMsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
+        cell_agent.app_health(self, sender_id)?;
+        Ok(())
+    }
+    fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error> {
+        noc.app_process_health(self, noc_to_port)?;
+        Ok(())
+    }
+}
+impl fmt::Display for AppHealthMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{}: {}", self.get_header(), self.get_payload());
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct AppHealthMsgPayload {
+    source: String, // Container or VM reporting
+    status: ContainerStatus,
+    restarts: usize,
+    reason: String
+}
+impl AppHealthMsgPayload {
+    fn new(source: &str, status: ContainerStatus, restarts: usize, reason: &str) -> AppHealthMsgPayload {
+        AppHealthMsgPayload { source: S(source), status, restarts, reason: S(reason) }
+    }
+    pub fn get_source(&self) -> &str { &self.source }
+    pub fn get_status(&self) -> ContainerStatus { self.status }
+    pub fn get_restarts(&self) -> usize { self.restarts }
+    pub fn get_reason(&self) -> &str { &self.reason }
+}
+#[typetag::serde]
+impl AppMsgPayload for AppHealthMsgPayload {}
+impl fmt::Display for AppHealthMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("{} {} after {} restarts", self.source, self.status, self.restarts);
+        if !self.reason.is_empty() { s = s + ": " + &self.reason; }
+        write!(f, "{}", s)
+    }
+}
+// What a cell agent tells the NOC master about a failed container, sent as the body of an
+// interapplication message on the NOC control tree
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    cell_id: CellID,
+    report: AppHealthMsgPayload
+}
+impl HealthReport {
+    pub fn new(cell_id: CellID, report: &AppHealthMsgPayload) -> HealthReport {
+        HealthReport { cell_id, report: report.clone() }
+    }
+    pub fn get_cell_id(&self) -> CellID { self.cell_id }
+    pub fn get_report(&self) -> &AppHealthMsgPayload { &self.report }
+    pub fn to_body(&self) -> Result<String, Error> {
+        let _f = "to_body";
+        Ok(serde_json::to_string(self).context(AppMessageError::Chain { func_name: _f, comment: S(self.cell_id) })?)
+    }
+    // None for a body that isn't a health report
+    pub fn from_body(body: &str) -> Option<HealthReport> { serde_json::from_str(body).ok() }
+}
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cell {}: {}", self.cell_id, self.report)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppManifestMsg {
     header: AppMsgHeader,
     payload: AppManifestMsgPayload
@@ -257,6 +397,7 @@ impl AppManifestMsg {
     }
     pub fn get_payload(&self) -> &AppManifestMsgPayload { &self.payload }
     pub fn get_deploy_tree_name(&self) -> &AllowedTree { self.header.get_target_tree_name() }
+    pub fn with_tenant(mut self, tenant: &str) -> AppManifestMsg { self.header.set_tenant(tenant); self }
 }
 #[typetag::serde]
 impl AppMessage for AppManifestMsg {
@@ -300,6 +441,65 @@ impl fmt::Display for AppManifestMsgPayload {
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppPlacementMsg {
+    header: AppMsgHeader,
+    payload: AppPlacementMsgPayload
+}
+impl AppPlacementMsg {
+    pub fn new(sender_name: &str, deploy_tree_name: &AllowedTree, report: &PlacementReport) -> AppPlacementMsg {
+        // Note that direction is rootward so the NOC will get the message
+        let msg_type = AppMsgType::AppPlacementMsg;
+        let header = AppMsgHeader::new(sender_name, deploy_tree_name,
+                                       false, false, msg_type,
+                                       AppMsgDirection::Rootward, &vec![]);
+        let payload = AppPlacementMsgPayload::new(report);
+        AppPlacementMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &AppPlacementMsgPayload { &self.payload }
+    pub fn get_report(&self) -> &PlacementReport { self.payload.get_report() }
+}
+#[typetag::serde]
+impl AppMessage for AppPlacementMsg {
+    fn get_header(&self) -> &AppMsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn AppMsgPayload { &self.payload }
+    fn get_msg_type(&self) -> AppMsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
+        cell_agent.app_placement(self, sender_id)?;
+        Ok(())
+    }
+    fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error> {
+        noc.app_process_placement(self, noc_to_port)?;
+        Ok(())
+    }
+}
+impl fmt::Display for AppPlacementMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{}: {}", self.get_header(), self.get_payload());
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppPlacementMsgPayload {
+    report: PlacementReport
+}
+impl AppPlacementMsgPayload {
+    fn new(report: &PlacementReport) -> AppPlacementMsgPayload {
+        AppPlacementMsgPayload { report: report.clone() }
+    }
+    fn get_report(&self) -> &PlacementReport { &self.report }
+}
+#[typetag::serde]
+impl AppMsgPayload for AppPlacementMsgPayload {}
+impl fmt::Display for AppPlacementMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("Placement: {}", self.report);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppQueryMsg {
     header: AppMsgHeader,
     payload: AppQueryMsgPayload
@@ -358,6 +558,68 @@ impl fmt::Display for AppQueryMsgPayload {
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppRedeployMsg {
+    header: AppMsgHeader,
+    payload: AppRedeployMsgPayload
+}
+impl AppRedeployMsg {
+    pub fn new(sender_name: &str, deploy_tree_name: &AllowedTree, manifest: &Manifest, mode: DeployMode,
+               allowed_trees: &Vec<AllowedTree>) -> AppRedeployMsg {
+        // Note that direction is leafward so cell agent will get the message
+        let msg_type = AppMsgType::AppRedeployMsg;
+        let header = AppMsgHeader::new(sender_name, deploy_tree_name,
+                                       false, false, msg_type,
+                                       AppMsgDirection::Leafward, allowed_trees);
+        let payload = AppRedeployMsgPayload::new(manifest, mode);
+        AppRedeployMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &AppRedeployMsgPayload { &self.payload }
+    pub fn get_deploy_tree_name(&self) -> &AllowedTree { self.header.get_target_tree_name() }
+}
+#[typetag::serde]
+impl AppMessage for AppRedeployMsg {
+    fn get_header(&self) -> &AppMsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn AppMsgPayload { &self.payload }
+    fn get_msg_type(&self) -> AppMsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
+        cell_agent.app_redeploy(self, sender_id)?;
+        Ok(())
+    }
+    fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error> {
+        noc.app_process_redeploy(self, noc_to_port)?;
+        Ok(())
+    }
+}
+impl fmt::Display for AppRedeployMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{}: {}", self.get_header(), self.get_payload());
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct AppRedeployMsgPayload {
+    manifest: Manifest,
+    mode: DeployMode
+}
+impl AppRedeployMsgPayload {
+    fn new(manifest: &Manifest, mode: DeployMode) -> AppRedeployMsgPayload {
+        AppRedeployMsgPayload { manifest: manifest.clone(), mode }
+    }
+    pub fn get_manifest(&self) -> &Manifest { &self.manifest }
+    pub fn get_mode(&self) -> DeployMode { self.mode }
+}
+#[typetag::serde]
+impl AppMsgPayload for AppRedeployMsgPayload {}
+impl fmt::Display for AppRedeployMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{} redeploy of manifest: {}", self.mode, self.manifest);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppStackTreeMsg {
     header: AppMsgHeader,
     payload: AppStackTreeMsgPayload
@@ -377,6 +639,7 @@ impl AppStackTreeMsg {
     pub fn get_new_tree_name(&self) -> &AllowedTree { &self.payload.get_new_tree_name() }
     pub fn get_parent_tree_name(&self) -> &AllowedTree { &self.header.get_target_tree_name() }
     pub fn get_gvm(&self) -> &GvmEquation { &self.payload.get_gvm_eqn() }
+    pub fn with_tenant(mut self, tenant: &str) -> AppStackTreeMsg { self.header.set_tenant(tenant); self }
 }
 #[typetag::serde]
 impl AppMessage for AppStackTreeMsg {
@@ -421,6 +684,68 @@ impl fmt::Display for AppStackTreeMsgPayload {
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppTenantMsg {
+    header: AppMsgHeader,
+    payload: AppTenantMsgPayload
+}
+impl AppTenantMsg {
+    pub fn new(sender_name: &str, parent_tenant: &str, tenant_name: &str, ncells: CellQty) -> AppTenantMsg {
+        // Tenants are kept by the cell agent the NOC talks to, so the target tree doesn't matter
+        let msg_type = AppMsgType::AppTenantMsg;
+        let header = AppMsgHeader::new(sender_name, &AllowedTree::new(BASE_TREE_NAME),
+                                       false, false, msg_type,
+                                       AppMsgDirection::Leafward, &vec![]);
+        let payload = AppTenantMsgPayload::new(parent_tenant, tenant_name, ncells);
+        AppTenantMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &AppTenantMsgPayload { &self.payload }
+}
+#[typetag::serde]
+impl AppMessage for AppTenantMsg {
+    fn get_header(&self) -> &AppMsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn AppMsgPayload { &self.payload }
+    fn get_msg_type(&self) -> AppMsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
+        cell_agent.app_tenant(self, sender_id)?;
+        Ok(())
+    }
+    fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error> {
+        noc.app_process_tenant(self, noc_to_port)?;
+        Ok(())
+    }
+}
+impl fmt::Display for AppTenantMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{}: {}", self.get_header(), self.get_payload());
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct AppTenantMsgPayload {
+    parent_tenant: String,
+    tenant_name: String,
+    ncells: CellQty
+}
+impl AppTenantMsgPayload {
+    fn new(parent_tenant: &str, tenant_name: &str, ncells: CellQty) -> AppTenantMsgPayload {
+        AppTenantMsgPayload { parent_tenant: S(parent_tenant), tenant_name: S(tenant_name), ncells }
+    }
+    pub fn get_parent_tenant(&self) -> &str { &self.parent_tenant }
+    pub fn get_tenant_name(&self) -> &str { &self.tenant_name }
+    pub fn get_ncells(&self) -> CellQty { self.ncells }
+}
+#[typetag::serde]
+impl AppMsgPayload for AppTenantMsgPayload {}
+impl fmt::Display for AppTenantMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("Sub-tenant {} of {} with {} cells", self.tenant_name, self.parent_tenant, self.ncells);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppTreeNameMsg {
     header: AppMsgHeader,
     payload: AppTreeNameMsgPayload
@@ -482,6 +807,66 @@ impl fmt::Display for AppTreeNameMsgPayload {
         write!(f, "{}", s)
     }
 }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppUndeployMsg {
+    header: AppMsgHeader,
+    payload: AppUndeployMsgPayload
+}
+impl AppUndeployMsg {
+    pub fn new(sender_name: &str, deploy_tree_name: &AllowedTree, manifest_id: &str) -> AppUndeployMsg {
+        // Note that direction is leafward so cell agent will get the message
+        let msg_type = AppMsgType::AppUndeployMsg;
+        let header = AppMsgHeader::new(sender_name, deploy_tree_name,
+                                       false, false, msg_type,
+                                       AppMsgDirection::Leafward, &vec![]);
+        let payload = AppUndeployMsgPayload::new(manifest_id);
+        AppUndeployMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &AppUndeployMsgPayload { &self.payload }
+    pub fn get_deploy_tree_name(&self) -> &AllowedTree { self.header.get_target_tree_name() }
+    pub fn get_manifest_id(&self) -> &str { self.payload.get_manifest_id() }
+}
+#[typetag::serde]
+impl AppMessage for AppUndeployMsg {
+    fn get_header(&self) -> &AppMsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn AppMsgPayload { &self.payload }
+    fn get_msg_type(&self) -> AppMsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&self, cell_agent: &mut CellAgent, sender_id: OriginatorID) -> Result<(), Error> {
+        cell_agent.app_undeploy(self, sender_id)?;
+        Ok(())
+    }
+    fn process_noc(&self, noc: &mut Noc, noc_to_port: &NocToPort) -> Result<(), Error> {
+        noc.app_process_undeploy(self, noc_to_port)?;
+        Ok(())
+    }
+}
+impl fmt::Display for AppUndeployMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{}: {}", self.get_header(), self.get_payload());
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct AppUndeployMsgPayload {
+    manifest_id: String
+}
+impl AppUndeployMsgPayload {
+    fn new(manifest_id: &str) -> AppUndeployMsgPayload {
+        AppUndeployMsgPayload { manifest_id: S(manifest_id) }
+    }
+    fn get_manifest_id(&self) -> &str { &self.manifest_id }
+}
+#[typetag::serde]
+impl AppMsgPayload for AppUndeployMsgPayload {}
+impl fmt::Display for AppUndeployMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("Undeploy manifest {}", self.manifest_id);
+        write!(f, "{}", s)
+    }
+}
 
 // Errors
 use failure::{Error, ResultExt};
@@ -490,6 +875,8 @@ use failure::{Error, ResultExt};
 pub enum AppMessageError {
     #[fail(display = "AppMessageError::Chain {} {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
+    #[fail(display = "AppMessageError::NoTreeKey {}: No key to open a payload sent on tree {}", func_name, tree_name)]
+    NoTreeKey { func_name: &'static str, tree_name: String },
 //    #[fail(display = "AppMessageError::Gvm {}: No GVM for this message type {}", func_name, msg_type)]
 //    Gvm { func_name: &'static str, msg_type: AppMsgType },
 //    #[fail(display = "AppMessageError::InvalidAppMsgType {}: Invalid message type {} from packet assembler", func_name, msg_type)]
@@ -502,6 +889,8 @@ pub enum AppMessageError {
 //    Payload { func_name: &'static str, msg_type: AppMsgType },
     #[fail(display = "AppMessageError::Process {}: Wrong message process function called", func_name)]
     Process { func_name: &'static str },
+    #[fail(display = "AppMessageError::Unsealed {}: Payload sent in the clear on tree {}, which has a key", func_name, tree_name)]
+    Unsealed { func_name: &'static str, tree_name: String },
 //    #[fail(display = "AppMessageError::TreeID {}: No TreeID ", func_name, msg_type: AppMsgType)]
 //    TreeID { func_name: &'static str, msg_type: AppMsgType },
 //    #[fail(display = "AppMessageError::TreeMapEntry {}: No tree named {} in map", func_name, tree_name)]
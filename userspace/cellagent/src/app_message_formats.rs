@@ -5,7 +5,7 @@
 //use std::sync::mpsc;
 use crossbeam::crossbeam_channel as mpsc;
 
-use crate::port::PortStatusOld;
+use crate::link::LinkStatus;
 use crate::utility::{ByteArray, PortNo};
 pub type SNAKE = bool;
 pub type ISAIT = bool;
@@ -23,7 +23,7 @@ pub type NocToApplicationMsg = String;
 //pub type NocApplicationError = mpsc::SendError<NocToApplicationMsg>;
 // Boundary Port to Ca
 #[derive(Debug, Clone, Serialize)]
-pub enum PortToCaMsg { Status(PortNo, PortStatusOld), AppMsg(PortNo, APP) }
+pub enum PortToCaMsg { Status(PortNo, LinkStatus), AppMsg(PortNo, APP) }
 pub type PortToCa = mpsc::Sender<PortToCaMsg>;
 pub type CaFromPort = mpsc::Receiver<PortToCaMsg>;
 //pub type PortCaError = mpsc::SendError<PortToCaMsg>;
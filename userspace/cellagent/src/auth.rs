@@ -0,0 +1,219 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{collections::{BTreeSet, HashMap},
+          sync::{Arc, atomic::{AtomicU64, Ordering}},
+          time::{SystemTime, UNIX_EPOCH}};
+
+use curve25519_dalek::{edwards::CompressedEdwardsY, scalar::Scalar};
+use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde;
+use serde_json::{self, Map, Value};
+use sha2::{Digest, Sha512};
+
+use crate::config::CONFIG;
+use crate::ec_message::{Message, MsgHeader};
+use crate::name::CellID;
+use crate::tree_key::{TreeKey, TreeKeys, WrappedTreeKeys};
+use crate::utility::{ByteArray, CellNo, S, from_hex, to_hex};
+use crate::wire;
+
+// Signed sequence numbers remembered per sending cell.  Each hop signs with its own increasing
+// sequence number, so anything this far below the highest one I've seen is a replay.
+const REPLAY_WINDOW: usize = 1024;
+
+// One entry of the auth_keys section of the config.  Every cell needs every cell's public key,
+// but only its own secret key, so a cell's config needs no other cell's secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthKey {
+    pub public: String, // Hex of the cell's ed25519 public key
+    #[serde(default)]
+    pub secret: Option<String>, // Hex of the cell's ed25519 secret key
+}
+impl AuthKey {
+    // For making configs and tests; the secret key is 32 bytes of hex
+    pub fn from_secret(secret: &str) -> Result<AuthKey, Error> {
+        let _f = "from_secret";
+        let secret_key = from_hex(secret)
+            .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+            .ok_or(AuthError::BadKey { func_name: _f, key: S(secret) })?;
+        let public_key: PublicKey = (&secret_key).into();
+        Ok(AuthKey { public: to_hex(public_key.as_bytes()), secret: Some(S(secret)) })
+    }
+    pub fn without_secret(&self) -> AuthKey { AuthKey { public: self.public.clone(), secret: None } }
+}
+
+// Each cell signs the messages it sends with its own secret key and checks the messages it
+// receives with the public key of the sending cell, so no cell can sign for another.  Keys come
+// from the auth_keys section of the config, which turns authentication on; each cell's name is
+// its cell number, e.g., C:3.
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    cell_id: CellID,
+    keypair: Arc<Keypair>,
+    public_keys: HashMap<String, PublicKey>,
+    wrapping_keys: HashMap<String, TreeKey>, // For tree keys I send to or get from each cell
+    signed_seq_no: Arc<AtomicU64>, // Shared by the clones of my cell agent
+    seen: HashMap<CellID, ReplayWindow>,
+}
+impl Authenticator {
+    pub fn new(cell_id: CellID) -> Result<Option<Authenticator>, Error> {
+        match &CONFIG.auth_keys {
+            Some(auth_keys) => Ok(Some(Authenticator::from_keys(cell_id, auth_keys)?)),
+            None => Ok(None)
+        }
+    }
+    pub fn from_keys(cell_id: CellID, auth_keys: &HashMap<CellNo, AuthKey>) -> Result<Authenticator, Error> {
+        let _f = "from_keys";
+        let mut public_keys = HashMap::new();
+        for (cell_no, auth_key) in auth_keys {
+            let public_key = from_hex(&auth_key.public)
+                .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+                .ok_or(AuthError::BadKey { func_name: _f, key: auth_key.public.clone() })?;
+            public_keys.insert(format!("{}", cell_no), public_key);
+        }
+        let secret = auth_keys
+            .iter()
+            .find(|(cell_no, _)| format!("{}", cell_no) == cell_id.get_name())
+            .and_then(|(_, auth_key)| auth_key.secret.as_ref())
+            .ok_or(AuthError::NoKey { func_name: _f, cell_id })?;
+        let secret_key = from_hex(secret)
+            .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+            .ok_or(AuthError::BadKey { func_name: _f, key: S(cell_id) })?;
+        let public_key: PublicKey = (&secret_key).into();
+        if public_keys.get(&cell_id.get_name()) != Some(&public_key) {
+            return Err(AuthError::BadKey { func_name: _f, key: S(cell_id) }.into());
+        }
+        let mut wrapping_keys = HashMap::new();
+        for (cell_name, public_key) in &public_keys {
+            wrapping_keys.insert(cell_name.clone(), wrapping_key(&secret_key, public_key)?);
+        }
+        // Start from the time, so a cell that restarts doesn't reuse numbers the others have seen
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_micros() as u64).unwrap_or(0);
+        Ok(Authenticator { cell_id, keypair: Arc::new(Keypair { secret: secret_key, public: public_key }),
+                           public_keys, wrapping_keys, signed_seq_no: Arc::new(AtomicU64::new(start)), seen: HashMap::new() })
+    }
+    // The signed sequence number is mine, even when I forward a message someone else made
+    pub fn sign<M>(&self, msg: &M) -> Result<ByteArray, Error>
+            where M: Message + serde::Serialize {
+        let _f = "sign";
+        let mut value = serde_json::to_value(msg as &dyn Message).context(AuthError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        value["header"]["signed_seq_no"] = Value::from(self.signed_seq_no.fetch_add(1, Ordering::SeqCst));
+        value["header"]["signature"] = Value::Null;
+        let signature = self.keypair.sign(&signed_bytes(self.cell_id, &value)?);
+        value["header"]["signature"] = Value::String(to_hex(&signature.to_bytes()));
+        let bytes = wire::encode(&value).context(AuthError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        Ok(bytes)
+    }
+    // Errors mean the message must be dropped
+    pub fn verify(&mut self, header: &MsgHeader, bytes: &ByteArray) -> Result<(), Error> {
+        let _f = "verify";
+        let sending_cell_id = header.get_sending_cell_id();
+        let (signature, seq_no) = match (header.get_signature(), header.get_signed_seq_no()) {
+            (Some(signature), Some(seq_no)) => (signature, seq_no),
+            _ => return Err(AuthError::Unsigned { func_name: _f, cell_id: sending_cell_id }.into())
+        };
+        let signature = from_hex(signature)
+            .and_then(|bytes| Signature::from_bytes(&bytes).ok())
+            .ok_or(AuthError::Forged { func_name: _f, cell_id: sending_cell_id, seq_no })?;
+        let public_key = self.public_keys
+            .get(&sending_cell_id.get_name())
+            .ok_or(AuthError::NoKey { func_name: _f, cell_id: sending_cell_id })?;
+        let mut value: Value = wire::decode(bytes).context(AuthError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        value["header"]["signature"] = Value::Null;
+        public_key
+            .verify(&signed_bytes(sending_cell_id, &value)?, &signature)
+            .map_err(|_| AuthError::Forged { func_name: _f, cell_id: sending_cell_id, seq_no })?;
+        // Only check for replay after verifying, so a forger can't use up my window
+        if !self.seen.entry(sending_cell_id).or_default().insert(seq_no) {
+            return Err(AuthError::Replayed { func_name: _f, cell_id: sending_cell_id, seq_no }.into());
+        }
+        Ok(())
+    }
+    // Every cell I have a key for gets a copy, since I don't know which cells the manifest will reach
+    pub fn wrap_tree_keys(&self, tree_keys: &TreeKeys) -> Result<WrappedTreeKeys, Error> {
+        let _f = "wrap_tree_keys";
+        let wrapped = WrappedTreeKeys::wrap(self.cell_id, tree_keys, &self.wrapping_keys)
+            .context(AuthError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        Ok(wrapped)
+    }
+    pub fn open_tree_keys(&self, wrapped: &WrappedTreeKeys) -> Result<TreeKeys, Error> {
+        let _f = "open_tree_keys";
+        let wrapped_by = wrapped.get_wrapped_by();
+        let wrapping_key = self.wrapping_keys
+            .get(&wrapped_by.get_name())
+            .ok_or(AuthError::NoKey { func_name: _f, cell_id: wrapped_by })?;
+        wrapped.open(&self.cell_id.get_name(), wrapping_key)
+    }
+}
+#[derive(Debug, Clone, Default)]
+struct ReplayWindow {
+    seq_nos: BTreeSet<u64>,
+    low_water: Option<u64>, // Highest sequence number seen less the window
+}
+impl ReplayWindow {
+    // Returns false if I've already seen this sequence number or it's fallen out of the window
+    fn insert(&mut self, seq_no: u64) -> bool {
+        if self.low_water.map_or(false, |low_water| seq_no <= low_water) { return false; }
+        if !self.seq_nos.insert(seq_no) { return false; }
+        let highest = *self.seq_nos.iter().next_back().unwrap_or(&seq_no);
+        self.low_water = highest.checked_sub(REPLAY_WINDOW as u64);
+        // Everything at or below the low-water mark is rejected without looking it up
+        if let Some(low_water) = self.low_water {
+            self.seq_nos = self.seq_nos.split_off(&(low_water + 1));
+        }
+        true
+    }
+}
+// Diffie-Hellman on the signing keys; my secret scalar times your public point is the same as
+// your secret scalar times mine
+fn wrapping_key(secret_key: &SecretKey, public_key: &PublicKey) -> Result<TreeKey, Error> {
+    let _f = "wrapping_key";
+    let point = CompressedEdwardsY(public_key.to_bytes())
+        .decompress()
+        .ok_or(AuthError::BadKey { func_name: _f, key: to_hex(public_key.as_bytes()) })?;
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&ExpandedSecretKey::from(secret_key).to_bytes()[..32]);
+    let shared = (Scalar::from_bits(scalar) * point).compress();
+    let digest = Sha512::new().chain(b"tree keys").chain(shared.as_bytes()).finalize();
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&digest[..32]);
+    Ok(TreeKey::from_bytes(&key))
+}
+fn signed_bytes(cell_id: CellID, value: &Value) -> Result<Vec<u8>, Error> {
+    let _f = "signed_bytes";
+    let canonical = serde_json::to_string(&canonical(value)).context(AuthError::Chain { func_name: _f, comment: S(cell_id) })?;
+    Ok(canonical.into_bytes())
+}
+// The receiver rebuilds HashMaps in its own order, so sort the keys of every object before signing
+fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys = map.keys().collect::<Vec<_>>();
+            keys.sort();
+            let mut sorted = Map::new();
+            for key in keys { sorted.insert(key.clone(), canonical(&map[key])); }
+            Value::Object(sorted)
+        },
+        Value::Array(values) => Value::Array(values.iter().map(canonical).collect()),
+        _ => value.clone()
+    }
+}
+// Errors
+use failure::{Error, ResultExt};
+#[derive(Debug, Fail)]
+pub enum AuthError {
+    #[fail(display = "AuthError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "AuthError::BadKey {}: Key {} is not a valid ed25519 key", func_name, key)]
+    BadKey { func_name: &'static str, key: String },
+    #[fail(display = "AuthError::Forged {}: Message {} claiming to be from cell {} has a bad signature", func_name, seq_no, cell_id)]
+    Forged { func_name: &'static str, cell_id: CellID, seq_no: u64 },
+    #[fail(display = "AuthError::NoKey {}: No key for cell {}", func_name, cell_id)]
+    NoKey { func_name: &'static str, cell_id: CellID },
+    #[fail(display = "AuthError::Replayed {}: Already saw message {} from cell {}", func_name, seq_no, cell_id)]
+    Replayed { func_name: &'static str, cell_id: CellID, seq_no: u64 },
+    #[fail(display = "AuthError::Unsigned {}: Message from cell {} is not signed", func_name, cell_id)]
+    Unsigned { func_name: &'static str, cell_id: CellID },
+}
@@ -18,16 +18,21 @@ use std::{collections::{HashMap, HashSet},
 
 use ec_fabrix::blueprint::{Blueprint};
 use ec_fabrix::config::{CONFIG, PortQty, CellQty};
+use ec_fabrix::dal;
 use ec_fabrix::ecnl::{ECNL_Session};
 use ec_fabrix::ecnl_port::{ECNL_Port};
+use ec_fabrix::metrics;
 use ec_fabrix::nalcell::{NalCell};
 use ec_fabrix::noc::{DuplexNocPortChannel, Noc, NocToPort, NocFromPort};
 use ec_fabrix::port::{PortSeed};
 use ec_fabrix::simulated_border_port::{SimulatedBorderPortFactory, SimulatedBorderPort, PortFromNoc, PortToNoc, DuplexPortNocChannel};
+use ec_fabrix::tenant::{Tenant};
+use ec_fabrix::tree_key::SharedTreeKeys;
 use ec_fabrix::utility::{CellConfig, CellNo, PortNo, S};
 
 fn main() -> Result<(), Error> {
     let _f = "main";
+    let _trace_guard = dal::TraceGuard::new(); // Flushes the trace however main ends
     println!("Multicell Routing: Output to file {} (set in config.rs)", CONFIG.output_file_name);
     println!("{:?} Quenching of Discover messages", CONFIG.quench);
     let _ = OpenOptions::new()
@@ -101,6 +106,8 @@ fn main() -> Result<(), Error> {
         num_phys_ports,
         &HashSet::from_iter(border_port_list),
         CellConfig::Large,
+        Tenant::new_tenants(CellQty(1))?, // The only cell in this process
+        SharedTreeKeys::default(),
         PortSeed::new(),
         Some(
             SimulatedBorderPortFactory::new(
@@ -111,10 +118,16 @@ fn main() -> Result<(), Error> {
             )
         ),
     )?;
+    if let Some(metrics_address) = &CONFIG.metrics_address {
+        metrics::serve(metrics_address, vec![nal_cell.get_metrics_source()]).context(MainError::Chain { func_name: _f, comment: S("metrics") })?;
+        println!("Metrics at http://{}/metrics", metrics_address);
+    }
     let mut noc = Noc::new(duplex_noc_port_channel_cell_port_map).context(MainError::Chain { func_name: _f, comment: S("Noc::new")})?;
     noc.initialize(&blueprint).context(MainError::Chain { func_name: "initialize", comment: S("")})?;
     ecnl_session.listen_link_and_pe_loops(&mut nal_cell)?;
-    match ca_join_handle.join() {
+    let joined = ca_join_handle.join();
+    dal::flush_trace();
+    match joined {
         Ok(()) => Ok(()),
         Err(e) => Err(MainError::Chain { func_name: _f, comment: format!("{:?}", e) }.into())
     }
@@ -0,0 +1,34 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// replay_cell config_file_name cell_name, e.g., replay_cell configs/10cell_config.json C:3
+// Replays one cell from the trace of a run of the simulator with the same config file
+#[macro_use] extern crate failure;
+
+use std::{env, process};
+
+use ec_fabrix::cell_replay::{CellReplay};
+use ec_fabrix::dal;
+use ec_fabrix::utility::{S};
+
+fn main() -> Result<(), Error> {
+    let _f = "main";
+    let _trace_guard = dal::TraceGuard::new(); // Flushes the trace however main ends
+    let cell_name = env::args().nth(2).ok_or::<Error>(MainError::Usage { func_name: _f }.into())?;
+    let cell_replay = CellReplay::run(&cell_name).context(MainError::Chain { func_name: _f, comment: S(&cell_name) })?;
+    println!("{}", cell_replay);
+    dal::flush_trace();
+    if !cell_replay.is_match() { process::exit(1); }
+    Ok(())
+}
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum MainError {
+    #[fail(display = "MainError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "MainError::Usage {}: replay_cell config_file_name cell_name", func_name)]
+    Usage { func_name: &'static str },
+}
@@ -5,22 +5,25 @@
 #[macro_use] extern crate failure;
 
 use std::{convert::TryFrom,
-          io::{stdin, stdout, Read, Write},
-          fs::{File,},
+          io::{stdin, stdout, Write},
           collections::{HashSet},
 };
 
 use ec_fabrix::blueprint::{Blueprint};
-use ec_fabrix::config::{CONFIG};
-use ec_fabrix::datacenter::{Datacenter, ApplicationToNoc};
+use ec_fabrix::config::{CONFIG, CellQty};
+use ec_fabrix::dal;
+use ec_fabrix::datacenter::{Datacenter};
 use ec_fabrix::gvm_equation::{GvmEqn};
 use ec_fabrix::link::Link;
+use ec_fabrix::metrics;
 use ec_fabrix::rack::{EdgeConnection, CellInteriorConnection};
-use ec_fabrix::uptree_spec::{AllowedTree, ContainerSpec, Manifest, UpTreeSpec, VmSpec};
+use ec_fabrix::trace_filter::{self, TraceFilter};
+use ec_fabrix::uptree_spec::{AllowedTree, ContainerSpec, DeployMode, Manifest, UpTreeSpec, VmSpec};
 use ec_fabrix::utility::{CellConfig, CellNo, PortNo, S, print_hash_map, sleep};
 
 fn main() -> Result<(), Error> {
     let _f = "main";
+    let _trace_guard = dal::TraceGuard::new(); // Flushes the trace however main ends
     let output_file_name = format!("{}{}.json", CONFIG.output_dir_name, CONFIG.output_file_name);
     println!("\nMulticell trace and debug output to file {}", output_file_name);
     println!("{} Quenching of Discover messages", CONFIG.quench);
@@ -44,6 +47,11 @@ fn main() -> Result<(), Error> {
             Ok(dc) => dc,
             Err(err) => panic!("Datacenter construction failure: {}", err)
         };
+    if let Some(metrics_address) = &CONFIG.metrics_address {
+        let sources = dc.get_rack().get_cells().values().map(|cell| cell.get_metrics_source()).collect();
+        metrics::serve(metrics_address, sources).context(MainError::Chain { func_name: _f, comment: S("metrics") })?;
+        println!("Metrics at http://{}/metrics", metrics_address);
+    }
     if false { deployment_demo()?; }    // Demonstrate features of deployment spec
     if CONFIG.auto_break.is_some() { break_link(&mut dc)?; }
     loop {
@@ -52,6 +60,11 @@ fn main() -> Result<(), Error> {
             c to print cells
             l to print links
             p to print forwarding table
+            s to print packet counters
+            t to change trace filters
+            u to undeploy a manifest
+            r to redeploy a manifest
+            n to create a tenant
             x to exit program\n\n").context(MainError::Chain { func_name: "run", comment: S("") })?;
         let mut print_opt = String::new();
         stdin().read_line(&mut print_opt).context(MainError::Chain { func_name: _f, comment: S("") })?;
@@ -64,7 +77,16 @@ fn main() -> Result<(), Error> {
                 "c" => show_ca(&dc),
                 "l" => break_link(&mut dc),
                 "p" => show_pe(&dc),
-                "x" => std::process::exit(0),
+                "s" => show_counters(&dc),
+                "t" => change_trace_filters(),
+                "u" => undeploy(&dc),
+                "r" => redeploy(&dc),
+                "n" => create_tenant(&dc),
+                "x" => {
+                    dump_counters(&dc)?;
+                    dal::flush_trace();
+                    std::process::exit(0)
+                },
                 _   => {
                     println!("Invalid input {}", print_opt);
                     Ok(())
@@ -73,6 +95,24 @@ fn main() -> Result<(), Error> {
         }
     }
 }
+// One filter per line, e.g., "debug module=pe cell=C:3", ending with an empty line
+fn change_trace_filters() -> Result<(), Error> {
+    println!("Trace filters");
+    for filter in trace_filter::get_trace_filters() { println!("  {}", filter); }
+    let _ = stdout().write(b"Enter new filters, one per line, or an empty line to keep these\n")?;
+    let mut filters = Vec::new();
+    loop {
+        let mut line = String::new();
+        stdin().read_line(&mut line)?;
+        if line.trim().is_empty() { break; }
+        match line.parse::<TraceFilter>() {
+            Ok(filter) => filters.push(filter),
+            Err(e) => println!("{}", e)
+        }
+    }
+    if !filters.is_empty() { trace_filter::set_trace_filters(filters); }
+    Ok(())
+}
 fn show_ca(dc: &Datacenter) -> Result<(), Error> {
     let rack = dc.get_rack();
     let cells = rack.get_cells();
@@ -99,6 +139,31 @@ fn show_pe(dc: &Datacenter) -> Result<(), Error> {
                      });
     Ok(())
 }
+fn show_counters(dc: &Datacenter) -> Result<(), Error> {
+    let rack = dc.get_rack();
+    let cells = rack.get_cells();
+    print_hash_map(&rack.get_cell_ids());
+    let _ = stdout().write(b"Enter cell to display packet counters\n")?;
+    let cell_no = read_int()?;
+    cells.get(&CellNo(cell_no))
+        .map_or_else(|| println!("{} is not a valid input", cell_no),
+                     |cell| {
+                         println!("Cell {}{}", cell.get_id(), cell.get_counters());
+                     });
+    Ok(())
+}
+// Keep the counters of a run for comparing with other runs
+fn dump_counters(dc: &Datacenter) -> Result<(), Error> {
+    let _f = "dump_counters";
+    let counters = dc.get_rack().get_cells()
+        .values()
+        .map(|cell| (cell.get_id().get_name(), cell.get_counters().to_json()))
+        .collect::<serde_json::Map<_, _>>();
+    let file_name = format!("{}counters.json", CONFIG.output_dir_name);
+    std::fs::write(&file_name, serde_json::to_string_pretty(&counters)?).context(MainError::Chain { func_name: _f, comment: file_name.clone() })?;
+    println!("Packet counters written to {}", file_name);
+    Ok(())
+}
 fn break_link(dc: &mut Datacenter) -> Result<(), Error> {
     let rack = dc.get_rack_mut();
     // Changed this (for the time being, at least) to use an edge_connection (with ports) instead of an edge.  We may want to look up and break all connections for an edge
@@ -134,6 +199,42 @@ fn break_link(dc: &mut Datacenter) -> Result<(), Error> {
         )?;
     Ok(())
 }
+fn undeploy(dc: &Datacenter) -> Result<(), Error> {
+    let _ = stdout().write(b"Enter the id of the manifest to undeploy\n")?;
+    let manifest_id = read_line()?;
+    let _ = stdout().write(b"Enter the name of the tree it was deployed on\n")?;
+    let deploy_tree = AllowedTree::new(&read_line()?);
+    dc.undeploy(&manifest_id, &deploy_tree)
+}
+// The new version goes on the tree named in its manifest
+fn redeploy(dc: &Datacenter) -> Result<(), Error> {
+    let _f = "redeploy";
+    let _ = stdout().write(b"Enter the name of a file containing the new manifest\n")?;
+    let filename = read_line()?;
+    let manifest = match Manifest::from_file(&filename) {
+        Ok(manifest) => manifest,
+        Err(e) => { println!("{}", e); return Ok(()); }
+    };
+    let _ = stdout().write(b"Enter r for a rolling upgrade, anything else to replace every copy at once\n")?;
+    let mode = if read_line()? == "r" { DeployMode::Rolling } else { DeployMode::Replace };
+    dc.redeploy(&manifest, mode).context(MainError::Chain { func_name: _f, comment: filename })?;
+    Ok(())
+}
+// Quotas are in cells and come out of the parent's
+fn create_tenant(dc: &Datacenter) -> Result<(), Error> {
+    let _ = stdout().write(b"Enter the name of the parent tenant, e.g., Root\n")?;
+    let parent_tenant = read_line()?;
+    let _ = stdout().write(b"Enter the name of the new tenant\n")?;
+    let tenant_name = read_line()?;
+    let _ = stdout().write(b"Enter the number of cells for the new tenant\n")?;
+    let ncells = CellQty(read_int()?);
+    dc.create_tenant(&parent_tenant, &tenant_name, ncells)
+}
+fn read_line() -> Result<String, Error> {
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    Ok(S(line.trim()))
+}
 fn read_int() -> Result<usize, Error> {
     let _f = "read_int";
     let mut char = String::new();
@@ -146,17 +247,6 @@ fn read_int() -> Result<usize, Error> {
         }
     }
 }
-fn _deploy(application_to_noc: &ApplicationToNoc) -> Result<(), Error> {
-    let _f = "deploy";
-    stdout().write(b"Enter the name of a file containing a manifest\n").context(MainError::Chain { func_name: "run", comment: S("") })?;
-    let mut filename = String::new();
-    stdin().read_line(&mut filename).context(MainError::Chain { func_name: "run", comment: S("") })?;
-    let mut f = File::open(filename.trim()).context(MainError::Chain { func_name: "run", comment: S("") })?;
-    let mut manifest = String::new();
-    f.read_to_string(&mut manifest).context(MainError::Chain { func_name: "run", comment: S("") })?;
-    application_to_noc.send(manifest)?;
-    Ok(())
-}
 fn deployment_demo() -> Result<(), Error> {
     let mut eqns = HashSet::new();
     eqns.insert(GvmEqn::Recv("true"));
@@ -185,6 +275,7 @@ fn deployment_demo() -> Result<(), Error> {
                                     &[allowed_tree1, allowed_tree2],
                                     vec![&vm_spec1, &vm_spec2], vec![&up_tree3]).context(MainError::Chain { func_name: "deployment_demo", comment: S("")})?;
     println!("{}", up_tree_def);
+    for error in up_tree_def.validate() { println!("  {}", error); }
     Ok(())
 }
 // Errors
@@ -170,7 +170,7 @@ pub struct BorderCell {
 impl Cell for BorderCell {
     fn get_cell_no(&self) -> CellNo { self.cell_no }
     fn get_cell_type(&self) -> CellType { self.cell_type }
-    fn get_num_phys_ports(&self) -> PortQty { PortQty((self.border_ports.len() + self.interior_ports.len()) as u8) }
+    fn get_num_phys_ports(&self) -> PortQty { PortQty((self.border_ports.len() + self.interior_ports.len() + 1) as u8) } // Port 0 too
     fn get_interior_ports(&self) -> &Vec<PortNo> { &self.interior_ports }
 }
 impl BorderCell {
@@ -198,7 +198,7 @@ pub struct InteriorCell {
 impl Cell for InteriorCell {
     fn get_cell_no(&self) -> CellNo { self.cell_no }
     fn get_cell_type(&self) -> CellType { self.cell_type }
-    fn get_num_phys_ports(&self) -> PortQty { PortQty(self.interior_ports.len() as u8) }
+    fn get_num_phys_ports(&self) -> PortQty { PortQty((self.interior_ports.len() + 1) as u8) } // Port 0 too
     fn get_interior_ports(&self) -> &Vec<PortNo> { &self.interior_ports }
 }
 impl InteriorCell {
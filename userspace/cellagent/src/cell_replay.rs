@@ -0,0 +1,237 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Replays one cell agent from its trace file, with no other cells, cmodel traffic, or NOC.
+// The cell agent gets the inputs it recorded from the cmodel, in the order it recorded them, and
+// what it sends to the cmodel and the NOC is compared with what it recorded sending.  The trace
+// needs the records of the "replay" module.
+use std::{collections::{HashMap, HashSet},
+          fmt,
+          thread,
+          time::{Duration, Instant}};
+
+use crossbeam::crossbeam_channel::unbounded as channel;
+use ec_trace_reader::{TraceReader, TraceRecord};
+use serde_json::Value;
+
+use crate::app_message_formats::{PortToCaMsg};
+use crate::cellagent::{CellAgent};
+use crate::config::{CONFIG, PortQty};
+use crate::dal::{get_cell_replay_file_name};
+use crate::ec_message_formats::{CaToCmBytes, CmToCaBytes};
+use crate::name::{CellID, TreeID};
+use crate::replay::{TraceFormat, process_trace_record};
+use crate::tenant::{Tenant};
+use crate::tree_key::SharedTreeKeys;
+use crate::utility::{CellConfig, CellType, PortNo, S};
+use crate::wire;
+
+const QUIET_TIME: Duration = Duration::from_secs(2); // The cell agent is done if it sends nothing for this long
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The ca_new record
+#[derive(Debug, Clone, Deserialize)]
+struct CellSetup {
+    cell_id: CellID,
+    my_tree_id: TreeID,
+    control_tree_id: TreeID,
+    connected_tree_id: TreeID,
+    cell_config: CellConfig,
+    cell_type: Option<CellType>, // Not in traces made before single cell replay
+    no_ports: Option<PortQty>,
+    border_port_nos: Option<HashSet<PortNo>>,
+}
+#[derive(Debug, Clone)]
+pub enum CellOutput {
+    Entry(Value), // Routing table entry
+    Msg(Value), // Message to send
+    Noc(PortNo, Value), // Application message to the NOC
+}
+impl CellOutput {
+    // The output a record shows the cell agent sending, if any
+    pub fn from_record(record: &TraceRecord) -> Result<Option<CellOutput>, Error> {
+        let body = record.body();
+        Ok(match record.format() {
+            "ca_to_cm_entry" => Some(CellOutput::Entry(body["entry"].clone())),
+            "ca_to_cm_bytes" => Some(CellOutput::Msg(body["msg"].clone())),
+            "ca_to_noc_tree_name" | "ca_to_noc_placement" => match process_trace_record(record.clone())? {
+                TraceFormat::CaToNoc(port_no, bytes) => Some(CellOutput::Noc(port_no, wire::decode(&bytes)?)),
+                _ => None
+            },
+            _ => None
+        })
+    }
+    fn from_ca_to_cm(msg: &CaToCmBytes) -> Result<Option<CellOutput>, Error> {
+        Ok(match msg {
+            CaToCmBytes::Entry(entry) => Some(CellOutput::Entry(serde_json::to_value(entry)?)),
+            CaToCmBytes::Bytes((_, _, _, _, _, _, bytes)) => Some(CellOutput::Msg(wire::decode(bytes)?)),
+            _ => None // Not recorded
+        })
+    }
+    // Sequence numbers, MACs, and the UUIDs of trees the cell agent makes up change from one
+    // run to the next, so they aren't compared
+    fn key(&self) -> String {
+        let (kind, value) = match self {
+            CellOutput::Entry(value) => (S("Entry"), value),
+            CellOutput::Msg(value) => (S("Msg"), value),
+            CellOutput::Noc(port_no, value) => (format!("Noc {}", port_no), value),
+        };
+        format!("{} {}", kind, without_run_fields(value))
+    }
+}
+impl fmt::Display for CellOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellOutput::Entry(entry) => write!(f, "Entry {}", entry),
+            CellOutput::Msg(msg) => write!(f, "Msg {} {}", msg["header"]["msg_type"], msg["payload"]),
+            CellOutput::Noc(port_no, app_msg) => write!(f, "Noc port {} {}", port_no, app_msg),
+        }
+    }
+}
+fn without_run_fields(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(fields
+            .iter()
+            .filter(|(key, _)| !(*key == "sender_msg_seq_no" || *key == "signed_seq_no" || *key == "signature" || key.ends_with("uuid")))
+            .map(|(key, value)| (key.clone(), without_run_fields(value)))
+            .collect()),
+        Value::Array(values) => Value::Array(values.iter().map(without_run_fields).collect()),
+        _ => value.clone()
+    }
+}
+// The outputs that have no match in others; order isn't compared because the cell agent walks
+// hash maps
+pub fn unmatched<'a>(outputs: &'a [CellOutput], others: &[CellOutput]) -> Vec<&'a CellOutput> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for other in others { *counts.entry(other.key()).or_insert(0) += 1; }
+    outputs
+        .iter()
+        .filter(|output| match counts.get_mut(&output.key()) {
+            Some(count) if *count > 0 => { *count -= 1; false },
+            _ => true
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct CellReplay {
+    cell_id: CellID,
+    inputs: usize,
+    recorded: Vec<CellOutput>,
+    replayed: Vec<CellOutput>,
+}
+impl CellReplay {
+    // Reads the cell's trace from the replay directory, see get_cell_replay_file_name()
+    pub fn run(cell_name: &str) -> Result<CellReplay, Error> {
+        let _f = "run";
+        let file_name = get_cell_replay_file_name(cell_name);
+        let trace_records = TraceReader::open(&file_name).context(CellReplayError::Chain { func_name: _f, comment: file_name.clone() })?;
+        if let Some(file_header) = trace_records.get_file_header() {
+            if file_header.config_hash != CONFIG.config_hash {
+                return Err(CellReplayError::ConfigHash { func_name: _f, file_name }.into());
+            }
+        }
+        let mut setup = None;
+        let mut inputs = Vec::new();
+        let mut recorded = Vec::new();
+        for record in trace_records {
+            let record = record?;
+            if record.format() == "ca_new" { setup = Some(record.body_as::<CellSetup>()?); continue; }
+            if let Some(output) = CellOutput::from_record(&record)? { recorded.push(output); continue; }
+            // What the cell agent gets from its border ports it only tunnels through the cmodel,
+            // which hands it back as the ca_from_cm_bytes_port and ca_from_cm_status records, so
+            // every input comes in on one channel in the order it was recorded
+            match process_trace_record(record)? {
+                TraceFormat::CaFromCmBytesMsg(port_no, is_ait, uuid, bytes) =>
+                    inputs.push(CmToCaBytes::Bytes((port_no, is_ait, uuid, bytes))),
+                TraceFormat::CaFromCmBytesStatus(port_no, is_border, number_of_packets, status) =>
+                    inputs.push(CmToCaBytes::Status((port_no, is_border, number_of_packets, status))),
+                TraceFormat::CaFromCmBytesPort(port_no, bytes) =>
+                    inputs.push(CmToCaBytes::TunnelPort((port_no, bytes))),
+                TraceFormat::CaFromCmBytesUp(originator_id, bytes) =>
+                    inputs.push(CmToCaBytes::TunnelUp((originator_id, bytes))),
+                _ => ()
+            }
+        }
+        let setup = setup.ok_or::<Error>(CellReplayError::Setup { func_name: _f, file_name: file_name.clone() }.into())?;
+        let (cell_type, no_ports, border_port_nos) = match (setup.cell_type, setup.no_ports, setup.border_port_nos) {
+            (Some(cell_type), Some(no_ports), Some(border_port_nos)) => (cell_type, no_ports, border_port_nos),
+            _ => return Err(CellReplayError::Setup { func_name: _f, file_name }.into())
+        };
+        // The cmodel and packet engine are made, but nothing is connected to them
+        let (cm_to_ca, ca_from_cm) = channel();
+        let (idle_port_to_ca, ca_from_ports) = channel::<PortToCaMsg>();
+        let (ca_to_cm, replay_from_ca) = channel();
+        let (idle_ca_to_cm, cm_from_ca) = channel();
+        let (idle_port_to_pe, pe_from_ports) = channel();
+        let (pe_to_cm, cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let mut ca_to_ports = HashMap::new();
+        let mut noc_from_ca = Vec::new();
+        for &port_no in &border_port_nos {
+            let (ca_to_port, port_from_ca) = channel();
+            ca_to_ports.insert(port_no, ca_to_port);
+            noc_from_ca.push((port_no, port_from_ca));
+        }
+        let (cell_agent, _cm_join_handle) = CellAgent::new(setup.cell_id,
+                Some((setup.my_tree_id, setup.control_tree_id, setup.connected_tree_id)),
+                cell_type, setup.cell_config, no_ports, ca_to_ports, cm_to_ca.clone(),
+                pe_from_ports, HashMap::new(), &border_port_nos,
+                ca_to_cm, cm_from_ca, pe_to_cm, cm_from_pe, cm_to_pe, pe_from_cm,
+                Tenant::new_tenants(CONFIG.num_cells)?, SharedTreeKeys::default())
+            .context(CellReplayError::Chain { func_name: _f, comment: S(setup.cell_id) })?;
+        let _ca_join_handle = cell_agent.start(ca_from_cm, ca_from_ports);
+        let number_of_inputs = inputs.len();
+        for msg in inputs { cm_to_ca.send(msg)?; }
+        let mut replayed = Vec::new();
+        let mut last_output = Instant::now();
+        while last_output.elapsed() < QUIET_TIME {
+            let mut outputs = Vec::new();
+            while let Ok(msg) = replay_from_ca.try_recv() {
+                if let Some(output) = CellOutput::from_ca_to_cm(&msg)? { outputs.push(output); }
+            }
+            for (port_no, port_from_ca) in &noc_from_ca {
+                while let Ok(bytes) = port_from_ca.try_recv() { outputs.push(CellOutput::Noc(*port_no, wire::decode(&bytes)?)); }
+            }
+            if outputs.is_empty() {
+                thread::sleep(POLL_INTERVAL);
+            } else {
+                last_output = Instant::now();
+                replayed.append(&mut outputs);
+            }
+        }
+        drop((idle_ca_to_cm, idle_port_to_pe, idle_port_to_ca)); // Kept until now so no idle thread sees a closed channel
+        Ok(CellReplay { cell_id: setup.cell_id, inputs: number_of_inputs, recorded, replayed })
+    }
+    pub fn get_recorded(&self) -> &Vec<CellOutput> { &self.recorded }
+    pub fn get_replayed(&self) -> &Vec<CellOutput> { &self.replayed }
+    // Recorded but not sent in the replay
+    pub fn missing(&self) -> Vec<&CellOutput> { unmatched(&self.recorded, &self.replayed) }
+    // Sent in the replay but not recorded
+    pub fn extra(&self) -> Vec<&CellOutput> { unmatched(&self.replayed, &self.recorded) }
+    pub fn is_match(&self) -> bool { self.missing().is_empty() && self.extra().is_empty() }
+}
+impl fmt::Display for CellReplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("Replay of {}: {} inputs, {} outputs recorded, {} replayed",
+                            self.cell_id, self.inputs, self.recorded.len(), self.replayed.len());
+        for output in self.missing() { s = s + &format!("\n  Missing {}", output); }
+        for output in self.extra() { s = s + &format!("\n  Extra   {}", output); }
+        if self.is_match() { s = s + "\n  Outputs match"; }
+        write!(f, "{}", s)
+    }
+}
+
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum CellReplayError {
+    #[fail(display = "CellReplayError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "CellReplayError::ConfigHash {}: {} was made with a different config file", func_name, file_name)]
+    ConfigHash { func_name: &'static str, file_name: String },
+    #[fail(display = "CellReplayError::Setup {}: {} has no ca_new record with the cell type and ports", func_name, file_name)]
+    Setup { func_name: &'static str, file_name: String },
+}
@@ -11,16 +11,17 @@ use std::{fmt, fmt::Write,
 use bimap::BiMap;
 use crossbeam::crossbeam_channel::unbounded as channel;
 use serde;
-use serde_json;
 
-use crate::app_message::{AppMessage, AppMsgType,
-                         AppDeleteTreeMsg, AppInterapplicationMsg, AppManifestMsg,
-                         AppQueryMsg, AppStackTreeMsg, AppTreeNameMsg,
-                         SenderMsgSeqNo};
+use crate::app_message::{AppMessage, AppMsgDirection, AppMsgType,
+                         AppDeleteTreeMsg, AppHealthMsg, AppInterapplicationMsg, AppManifestMsg,
+                         AppPlacementMsg, AppQueryMsg, AppRedeployMsg, AppStackTreeMsg, AppTenantMsg, AppTreeNameMsg,
+                         AppUndeployMsg, HealthReport, SenderMsgSeqNo};
+use crate::auth::Authenticator;
 use crate::app_message_formats::{CaToPort, PortToCaMsg,
                                  CaToVm, VmFromCa, VmToCa, CaFromVm};
 use crate::cmodel::{Cmodel};
-use crate::config::{CONFIG, BASE_TREE_NAME, CONNECTED_PORTS_TREE_NAME, CONTROL_TREE_NAME,
+use crate::container::ContainerStatus;
+use crate::config::{CONFIG, BASE_TREE_NAME, CONNECTED_PORTS_TREE_NAME, CONTROL_TREE_NAME, PACKET_DEFAULT,
                     CellQty, PathLength, PortQty};
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
 use crate::ec_message::{Message, MsgHeader, MsgTreeMap, MsgType,
@@ -29,31 +30,37 @@ use crate::ec_message::{Message, MsgHeader, MsgTreeMap, MsgType,
                         DiscoverMsg, DiscoverDMsg, DiscoverDType,
                         FailoverMsg, FailoverDMsg, FailoverMsgPayload, FailoverResponse,
                         HelloMsg,
-                        ManifestMsg,
+                        ManifestMsg, PlacementMsg, RedeployMsg, UndeployMsg,
                         DiscoverAckDMsg, DiscoverAckMsg,
                         StackTreeMsg, StackTreeDMsg,
                         TreeNameMsg};
 use crate::ec_message_formats::{CaToCm, CaFromCm, CmToCa, CmFromCa, PeToCm, CmFromPe,
                                 CmToPe, PeFromCm,
-                                CaToCmBytes, CmToCaBytesOld, 
-                                PeToPort, PeFromPort,
-                                PeToPortOld, PeFromPortOld};
+                                CaToCmBytes, CmToCaBytes, 
+                                PeToPort, PeFromPort};
 use crate::gvm_equation::{GvmEquation, GvmEqn};
+use crate::metrics::{CellMetrics, MetricsSource, count_thread};
+use crate::noc::NOC_CONTROL_TREE_NAME;
 use crate::name::{Name, CellID, OriginatorID, PortTreeID, TreeID, UptreeID, VmID};
 use crate::packet_engine::NumberOfPackets;
-use crate::port::{PortStatusOld};
+use crate::link::{LinkStatus};
 use crate::port_tree::PortTree;
+use crate::tenant::{ROOT_TENANT_NAME, Tenants, is_within};
 use crate::routing_table_entry::{RoutingTableEntry};
+use crate::trace_filter::{Level, tracing};
 use crate::traph::{PortState, Traph};
 use crate::tree::Tree;
-use crate::uptree_spec::{AllowedTree, Manifest};
+use crate::tree_key::{SharedTreeKeys, TreeKey, TreeKeys, WrappedTreeKeys};
+use crate::uptree::UpTree;
+use crate::uptree_spec::{AllowedTree, ContainerSpec, DeployMode, Manifest, VmSpec};
 use crate::utility::{BASE_TENANT_MASK, DEFAULT_USER_MASK,
-                     ByteArray, CellConfig, CellInfo, CellType, Mask, Path, PortNo,
+                     ByteArray, CellConfig, CellInfo, CellType, Mask, Path, Placement, PlacementReport, PortNo,
                      Quench, PortNumber, S,
-                     TraceHeader, TraceHeaderParams, TraceType, UtilityError,
+                     TraceHeader, TraceHeaderParams, UtilityError,
                      write_err, new_hashset};
 use crate::uuid_ec::Uuid;
 use crate::vm::VirtualMachine;
+use crate::wire::{self, WireFormat};
 
 use failure::{Error, ResultExt, Fail};
 use crate::app_message_formats::{CaFromPort};
@@ -64,7 +71,32 @@ pub type PortTreeIDMap = HashMap<Uuid, PortTreeID>;
 pub type Traphs = HashMap<Uuid, Traph>;
 pub type TreeMap = HashMap<Uuid, Uuid>;
 pub type TreeNameMap = HashMap<OriginatorID, TreeIDNameMap>;
-pub type TreeVmMap = HashMap<TreeID, Vec<CaToVm>>;
+pub type TreeVmMap = HashMap<TreeID, HashMap<VmID, CaToVm>>;
+type VmListener = (OriginatorID, VmID, HashSet<AllowedTree>, CaFromVm);
+
+// A VM that fits this cell's configuration but is waiting for slots to free up
+#[derive(Debug, Clone)]
+struct DeferredVm {
+    originator_id: OriginatorID,
+    deployment_port_tree_id: PortTreeID,
+    deployment_tree: AllowedTree,
+    manifest_id: String,
+    manifest_config: CellConfig,
+    vm_spec: VmSpec,
+}
+// What undeploy needs to stop a VM and give back its slots
+#[derive(Debug, Clone)]
+struct DeployedVm {
+    vm_id: VmID,
+    spec_id: String,
+    slots: usize,
+    containers: Vec<ContainerSpec>, // Images with their versions, to compare with a redeploy
+}
+impl DeployedVm {
+    fn is_running(&self, vm_spec: &VmSpec) -> bool {
+        self.spec_id == *vm_spec.get_id() && self.containers == *vm_spec.get_containers()
+    }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct CellAgent {
@@ -86,17 +118,28 @@ pub struct CellAgent {
     tree_name_map: Arc<Mutex<TreeNameMap>>,
     traphs: Traphs,
     traphs_mutex: Arc<Mutex<Traphs>>, // Needed so I can print from main() because I have to clone to get self.traphs into the thread
+    metrics: Arc<Mutex<CellMetrics>>, // Shared with the metrics endpoint
     tree_map: TreeMap, // Base tree for given stacked tree
     border_port_tree_id_map: BorderSenderIDMap, // Find the tree id associated with a border port
     base_tree_map: HashMap<PortTreeID, TreeID>, // Find the black tree associated with any tree, needed for stacking
     tree_id_map: PortTreeIDMap,
     tenant_masks: Vec<Mask>,
+    authenticator: Option<Authenticator>, // None unless the config has keys
+    tenants: Tenants, // Shared by every cell, so each charges only for itself
+    manifest_tenants: HashMap<String, String>, // Tenant that deployed each manifest on this cell
+    originator_tenants: HashMap<OriginatorID, String>, // VMs belong to their manifest's tenant, everyone else to the root
+    tree_keys: SharedTreeKeys, // Shared by every cell, so a tree has one key
+    manifest_keys: HashMap<String, TreeKeys>, // Keys deployed with each manifest on this cell
     tree_vm_map: TreeVmMap,
     ca_to_vms: HashMap<VmID, CaToVm>,
     ca_to_cm: Vec<CaToCm>,
     ca_to_ports: HashMap<PortNo, CaToPort>,
     vm_id_no: usize,
     up_tree_senders: HashMap<UptreeID, HashMap<String,TreeID>>,
+    vm_up_trees: HashMap<(String, AllowedTree), UpTree<VmID>>, // Up trees connecting the VMs deployed on this cell, by manifest ID and tree name
+    slots_used: usize, // One slot per container deployed on this cell
+    deferred_vms: Vec<DeferredVm>,
+    deployments: HashMap<String, Vec<DeployedVm>>, // VMs running on this cell by manifest ID
     up_traphs_clist: HashMap<TreeID, TreeID>,
     neighbors: HashMap<PortNo, (CellID, PortNo)>,
     discover_ack_d: HashMap<TreeID, usize>,
@@ -119,13 +162,13 @@ impl CellAgent {
                config: CellConfig, no_ports: PortQty,
                ca_to_ports: HashMap<PortNo, CaToPort>, cm_to_ca: CmToCa, 
                pe_from_ports: PeFromPort, pe_to_ports: HashMap<PortNo, PeToPort>,
-               pe_from_ports_old: PeFromPortOld, pe_to_ports_old: HashMap<PortNo, PeToPortOld>,
                border_port_nos: &HashSet<PortNo>,
                ca_to_cm: CaToCm, cm_from_ca: CmFromCa, pe_to_cm: PeToCm, cm_from_pe: CmFromPe,
-               cm_to_pe: CmToPe, pe_from_cm: PeFromCm)
+               cm_to_pe: CmToPe, pe_from_cm: PeFromCm, tenants: Tenants, tree_keys: SharedTreeKeys)
                -> Result<(CellAgent, JoinHandle<()>), Error> {
         let _f = "new";
         let tenant_masks = vec![BASE_TENANT_MASK];
+        let authenticator = Authenticator::new(cell_id).context(CellagentError::Chain { func_name: _f, comment: S(cell_id) + " authenticator" })?;
         let (my_tree_id, control_tree_id, connected_tree_id) = match tree_ids {
             Some(tree_ids) => tree_ids,
             None => {
@@ -138,11 +181,13 @@ impl CellAgent {
             }
         };
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.replay {
+            if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_new" };
                 let trace = json!({ "cell_id": cell_id, "my_tree_id": my_tree_id,
-                     "control_tree_id": control_tree_id, "connected_tree_id": connected_tree_id});
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                     "control_tree_id": control_tree_id, "connected_tree_id": connected_tree_id,
+                     "cell_config": config, "slots": config.get_slots(),
+                     "cell_type": cell_type, "no_ports": no_ports, "border_port_nos": border_port_nos });
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let mut base_tree_map = HashMap::new();
@@ -153,14 +198,13 @@ impl CellAgent {
         let my_entry = RoutingTableEntry::default().add_child(PortNumber::default());
         let (cmodel, _pe_join_handle) = Cmodel::new(cell_id, connected_tree_id, pe_to_cm, cm_to_ca,
                                                     pe_from_ports, pe_to_ports, 
-                                                    pe_from_ports_old, pe_to_ports_old, 
                                                     border_port_nos, cm_to_pe, pe_from_cm);
         let cm_join_handle = cmodel.start(cm_from_ca, cm_from_pe);
         Ok((CellAgent {
             cell_id, my_tree_id, cell_type, config, no_ports,
             control_tree_id, connected_tree_id,
-            cmodel: vec![cmodel], ca_to_cm: vec![ca_to_cm], ca_to_ports, my_entry, base_tree_map, no_packets, tenant_masks,
-            ..Default::default()
+            cmodel: vec![cmodel], ca_to_cm: vec![ca_to_cm], ca_to_ports, my_entry, base_tree_map, no_packets, tenant_masks, tenants,
+            tree_keys, authenticator, ..Default::default()
         }, cm_join_handle))
     }
 
@@ -168,10 +212,10 @@ impl CellAgent {
     pub fn start(&self, ca_from_cm: CaFromCm, ca_from_ports: CaFromPort) -> JoinHandle<()> {
         let _f = "start_cell";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.nal {
+            if let Some(event) = tracing(Level::Info, &["nal"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "nalcell_start_ca" };
                 let trace = json!({ "cell_id": self.get_cell_id() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let mut ca = self.clone();
@@ -179,6 +223,7 @@ impl CellAgent {
         let thread_name = format!("CellAgent {}", self.get_cell_id());
         thread::Builder::new().name(thread_name).spawn(move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(ca.get_cell_id());
             let _ = ca.initialize(ca_from_cm.clone(), ca_from_ports.clone()).map_err(|e| write_err("cellagent", &e));
             if CONFIG.continue_on_error { ca.start(ca_from_cm, ca_from_ports); }
         }).expect("cellagent thread failed")
@@ -188,10 +233,10 @@ impl CellAgent {
     pub fn initialize(&mut self, ca_from_cm: CaFromCm, ca_from_ports: CaFromPort) -> Result<&mut Self, Error> {
         let _f = "initialize";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": &self.cell_id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         // Set up predefined trees - Must be first two in this order
@@ -245,6 +290,9 @@ impl CellAgent {
         }
     }
     pub fn get_cmodel(&self) -> &Cmodel { &self.cmodel[0] } // Needed in main() to print forwarding tables
+    pub fn get_metrics_source(&self) -> MetricsSource {
+        MetricsSource::new(self.cell_id, self.metrics.clone(), self.get_cmodel().get_packet_engine().get_counters_mutex())
+    }
     fn get_cell_id(&self) -> CellID { self.cell_id }
     fn get_connected_tree_id(&self) -> TreeID { self.connected_tree_id }
     fn get_no_ports(&self) -> PortQty { self.no_ports }
@@ -257,7 +305,7 @@ impl CellAgent {
         let _f = "get_vm_senders";
         self.tree_vm_map
             .get(&tree_id)
-            .cloned()
+            .map(|senders| senders.values().cloned().collect())
             .ok_or(CellagentError::TreeVmMap { func_name: _f, cell_id: self.cell_id, tree_id }.into())
     }
     fn get_mask(&self, port_tree_id: PortTreeID) -> Result<Mask, Error> {
@@ -286,12 +334,12 @@ impl CellAgent {
             no_seen_ports >= self.neighbors.len() && self.tree_count >= CONFIG.min_trees
         };
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.enough_ports {
+            if let Some(event) = tracing(Level::Debug, &["enough_ports"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_enough_ports" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": tree_id, "is_done": is_done,
                     "no_neighbors": self.neighbors.len(), "kind": kind,
                     "seen_ports": no_seen_ports, "seen_trees": self.tree_count });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         is_done
@@ -304,7 +352,7 @@ impl CellAgent {
             .or_insert(Default::default())
             .len();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.enough_ports {
+            if let Some(event) = tracing(Level::Debug, &["enough_ports"]) {
                 let default = HashSet::default();
                 let seen_neighbors: Vec<PortNo> = self.discover_ack_seen_on_tree.get(&tree_id)
                     .or(Some(&default))
@@ -315,7 +363,7 @@ impl CellAgent {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_discover_ack_done" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": tree_id, "kind": "DiscoverAck",
                     "neighbors_seen_on_tree": self.neighbor_names_from_port_nos(&seen_neighbors) });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.enough_ports(tree_id, no_seen_ports, "DiscoverAckD")
@@ -328,7 +376,7 @@ impl CellAgent {
             .or_insert(Default::default())
             .len();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.enough_ports {
+            if let Some(event) = tracing(Level::Debug, &["enough_ports"]) {
                 let default = HashSet::default();
                 let seen_neighbors: Vec<PortNo> = self.discover_ack_seen_on_tree.get(&tree_id)
                     .or(Some(&default))
@@ -339,7 +387,7 @@ impl CellAgent {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_discover_ack_done" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": tree_id, "kind": kind,
                     "neighbors_seen_on_tree": self.neighbor_names_from_port_nos(&seen_neighbors) });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.enough_ports(tree_id, no_seen_ports, kind)
@@ -351,10 +399,10 @@ impl CellAgent {
         let _f = "add_saved_discover";
         let port_tree_id = discover_msg.get_port_tree_id();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.saved_msgs {
+            if let Some(event) = tracing(Level::Debug, &["saved_msgs"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_save_discover_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": port_tree_id, "msg": discover_msg });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.saved_discover.insert(port_tree_id.to_tree_id(), discover_msg.clone());
@@ -363,10 +411,10 @@ impl CellAgent {
         let _f = "add_saved_discoverd";
         let port_tree_id = discoverd_msg.get_port_tree_id();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.saved_msgs {
+            if let Some(event) = tracing(Level::Debug, &["saved_msgs"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_save_discoverd_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": port_tree_id, "msg": discoverd_msg });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.saved_discoverd.insert(port_tree_id.to_tree_id(), discoverd_msg.clone());
@@ -375,10 +423,10 @@ impl CellAgent {
         let _f = "add_saved_discover_ack_d";
         let port_tree_id = discover_ack_d_msg.get_port_tree_id();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.saved_msgs {
+            if let Some(event) = tracing(Level::Debug, &["saved_msgs"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_save_discoverd_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": port_tree_id, "msg": discover_ack_d_msg });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.saved_discover_ack_d.insert(port_tree_id.to_tree_id(), discover_ack_d_msg.clone());
@@ -453,10 +501,10 @@ impl CellAgent {
     fn update_base_tree_map(&mut self, stacked_tree_id: PortTreeID, base_tree_id: TreeID) {
         let _f = "update_base_tree_map";
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.traph_entry {
+            if let Some(event) = tracing(Level::Debug, &["traph_entry"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_update_base_tree_map" };
                 let trace = json!({ "cell_id": &self.cell_id, "stacked_tree_id": stacked_tree_id, "base_tree_id": base_tree_id, });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.base_tree_map.insert(stacked_tree_id, base_tree_id);
@@ -484,10 +532,10 @@ impl CellAgent {
     fn get_base_tree_id(&self, port_tree_id: PortTreeID) -> Result<TreeID, Error> {
         let _f = "get_base_tree_id";
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.traph_entry {   // Debug print
+            if let Some(event) = tracing(Level::Debug, &["traph_entry"]) {   // Debug print
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_get_base_tree_id" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_tree_id": port_tree_id });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.base_tree_map
@@ -536,13 +584,13 @@ impl CellAgent {
         let neighbor = self.neighbors.get(&port_number.get_port_no());
         self.tree_id_map.insert(base_tree_id.get_uuid(), base_port_tree_id);
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.traph_entry {
+            if let Some(event) = tracing(Level::Debug, &["traph_entry"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_update_traph" };
                 let trace = json!({ "cell_id": &self.cell_id,
                 "base_tree_id": base_port_tree_id, "neighbor": neighbor, "hops": &hops,
                 "port_state": &port_state,
                 "children": children, "gvm": &gvm_eqn });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let mut traph = self.traphs
@@ -589,11 +637,11 @@ impl CellAgent {
         if gvm_send { entry.enable_send() } else { entry.disable_send() }
         {
             let traph_state = traph.get_port_status(port_number);
-            if CONFIG.debug_options.all || CONFIG.debug_options.traph_entry {
+            if let Some(event) = tracing(Level::Debug, &["traph_entry"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_updated_traph_entry" };
                 let trace = json!({ "cell_id": &self.cell_id, "base_tree_id": base_tree_id,
                     "neighbor": neighbor, "traph_state": traph_state, "hops": hops, "updated hops": updated_hops, "entry": &entry });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         // Need traph even if cell only forwards on this tree
@@ -658,57 +706,254 @@ impl CellAgent {
             .get(&uuid)
             .ok_or(CellagentError::NoTraph { cell_id: self.cell_id, func_name: "stack_tree", tree_id: base_tree_id }.into())
     }
+    // A redeploy passes the VMs it kept running, which stay members of the manifest's up trees
     fn deploy(&mut self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID, _msg_tree_id: PortTreeID,
-              _msg_tree_map: &MsgTreeMap, manifest: &Manifest) -> Result<(), Error> {
+              _msg_tree_map: &MsgTreeMap, manifest: &Manifest, unchanged_vms: Vec<DeployedVm>) -> Result<(), Error> {
         let _f = "deploy";
+        let deployment_tree = manifest.get_deployment_tree();
+        let mut report = PlacementReport::new(self.cell_id, manifest.get_id(), self.config, self.free_slots());
+        let mut vm_ids = Vec::new();
+        let mut vm_listeners = Vec::new();
+        for vm_spec in manifest.get_vms() {
+            if let Some(vm) = unchanged_vms.iter().find(|vm| vm.is_running(vm_spec)) {
+                vm_ids.push(vm.vm_id);
+                self.deployments.entry(S(manifest.get_id())).or_insert_with(Vec::new).push(vm.clone());
+                report.add_vm(vm_spec.get_id(), Placement::Unchanged);
+                continue;
+            }
+            let placement = self.placement(manifest.get_cell_config(), vm_spec);
+            match placement {
+                Placement::Placed => {
+                    let vm_listener = self.deploy_vm(originator_id, deployment_port_tree_id, manifest.get_id(), vm_spec).context(CellagentError::Chain { func_name: _f, comment: S(manifest.get_id()) })?;
+                    vm_ids.push(vm_listener.1);
+                    vm_listeners.push(vm_listener);
+                },
+                Placement::Deferred => {
+                    self.deferred_vms.push(DeferredVm { originator_id, deployment_port_tree_id,
+                        deployment_tree: deployment_tree.clone(), manifest_id: S(manifest.get_id()),
+                        manifest_config: manifest.get_cell_config(), vm_spec: vm_spec.clone() });
+                },
+                Placement::Refused | Placement::Undeployed | Placement::Unchanged => () // placement() never undeploys
+            }
+            report.add_vm(vm_spec.get_id(), placement);
+        }
+        // VM i in the manifest is member i of each of the manifest's up trees, so they need all the VMs
+        if vm_ids.len() == manifest.get_vms().len() {
+            for up_tree_spec in manifest.get_trees() {
+                let up_tree_id = UptreeID::new(up_tree_spec.get_id()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " up tree id" })?;
+                let up_tree = UpTree::new(up_tree_id, up_tree_spec, &vm_ids).context(CellagentError::Chain { func_name: _f, comment: S(manifest.get_id()) })?;
+                {
+                    if let Some(event) = tracing(Level::Debug, &["deploy"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_deploy_up_tree" };
+                        let trace = json!({ "cell_id": &self.cell_id, "up_tree_id": up_tree_id,
+                            "parent_list": up_tree_spec.get_parent_list(), "members": up_tree.get_members() });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.vm_up_trees.insert((S(manifest.get_id()), up_tree.get_tree_name().clone()), up_tree);
+            }
+        }
+        // Start listening only after all VMs exist so each listener can route on the up trees
+        for (vm_originator_id, vm_id, allowed_trees, ca_from_vm) in vm_listeners {
+            self.listen_uptree(vm_originator_id, vm_id, S(manifest.get_id()), allowed_trees, ca_from_vm);
+        }
+        report.set_free_slots(self.free_slots());
+        self.report_placement(originator_id, deployment_port_tree_id, deployment_tree, &report)?;
+        Ok(())
+    }
+    fn deploy_vm(&mut self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID, manifest_id: &str,
+                 vm_spec: &VmSpec) -> Result<VmListener, Error> {
+        let _f = "deploy_vm";
         let tree_name_map = self.tree_name_map.lock().unwrap()
             .get(&originator_id)
             .cloned()
             .ok_or::<Error>(CellagentError::TreeNameMap { func_name: _f, cell_id: self.cell_id, originator_id }.into())?;
-        for vm_spec in manifest.get_vms() {
-            let (vm_to_ca, ca_from_vm): (VmToCa, CaFromVm) = channel();
-            let (ca_to_vm, vm_from_ca): (CaToVm, VmFromCa) = channel();
-            let container_specs = vm_spec.get_containers();
-            let vm_id = VmID::new(self.cell_id, &vm_spec.get_id())?;
-            let vm_allowed_trees = vm_spec.get_allowed_trees();
-            let vm_originator_id = OriginatorID::new(self.cell_id, &vm_id.get_name())?;
-            let up_tree_name = vm_spec.get_id();
-            let mut allowed_trees = HashSet::new();
-            allowed_trees.insert(AllowedTree::new(CONTROL_TREE_NAME));
-            let mut vm = VirtualMachine::new(self.cell_id, vm_id, vm_to_ca, vm_allowed_trees);
-            for vm_allowed_tree in vm_allowed_trees {
-                tree_name_map
-                    .get_by_right(vm_allowed_tree)
-                    .ok_or::<Error>(CellagentError::NameMap { cell_id: self.cell_id, func_name: "deploy(vm)", originator_id, tree_name: vm_allowed_tree.clone() }.into())
-                    .map(|allowed_tree_id| {
-                        allowed_trees.insert(vm_allowed_tree.clone());
-                        self.add_tree_name_map_item(originator_id, vm_allowed_tree, allowed_tree_id.clone());
-                        self.add_tree_name_map_item(vm_originator_id, vm_allowed_tree, allowed_tree_id.clone());
-                        // Functional style runs into a borrow problem
-                        match self.tree_vm_map.get_mut(allowed_tree_id) {
-                            Some(senders) => senders.push(ca_to_vm.clone()),
-                            None => { self.tree_vm_map.insert(allowed_tree_id.clone(), vec![ca_to_vm.clone()]); }
+        let (vm_to_ca, ca_from_vm): (VmToCa, CaFromVm) = channel();
+        let (ca_to_vm, vm_from_ca): (CaToVm, VmFromCa) = channel();
+        let container_specs = vm_spec.get_containers();
+        let vm_id = VmID::new(self.cell_id, &vm_spec.get_id())?;
+        let vm_allowed_trees = vm_spec.get_allowed_trees();
+        let vm_originator_id = OriginatorID::new(self.cell_id, &vm_id.get_name())?;
+        let up_tree_name = vm_spec.get_id();
+        let up_tree_specs = vm_spec.get_trees();
+        let mut allowed_trees = HashSet::new();
+        allowed_trees.insert(AllowedTree::new(CONTROL_TREE_NAME));
+        let mut vm = VirtualMachine::new(self.cell_id, vm_id, vm_to_ca, vm_allowed_trees);
+        for vm_allowed_tree in vm_allowed_trees {
+            tree_name_map
+                .get_by_right(vm_allowed_tree)
+                .ok_or::<Error>(CellagentError::NameMap { cell_id: self.cell_id, func_name: "deploy(vm)", originator_id, tree_name: vm_allowed_tree.clone() }.into())
+                .map(|allowed_tree_id| {
+                    allowed_trees.insert(vm_allowed_tree.clone());
+                    self.add_tree_name_map_item(originator_id, vm_allowed_tree, allowed_tree_id.clone());
+                    self.add_tree_name_map_item(vm_originator_id, vm_allowed_tree, allowed_tree_id.clone());
+                    // Functional style runs into a borrow problem
+                    match self.tree_vm_map.get_mut(allowed_tree_id) {
+                        Some(senders) => { senders.insert(vm_id, ca_to_vm.clone()); },
+                        None => {
+                            let mut senders = HashMap::new();
+                            senders.insert(vm_id, ca_to_vm.clone());
+                            self.tree_vm_map.insert(allowed_tree_id.clone(), senders);
                         }
-                    })?;
+                    }
+                })?;
+        }
+        let tree_keys = self.manifest_keys.get(manifest_id).cloned().unwrap_or_default();
+        vm.initialize(up_tree_name, vm_from_ca, &allowed_trees, container_specs, up_tree_specs, &tree_keys)?;
+        self.slots_used = self.slots_used + container_specs.len();
+        {
+            if let Some(event) = tracing(Level::Debug, &["deploy"]) {
+                let keys = self.tree_vm_map.keys().collect::<Vec<_>>();
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_deploy" };
+                let trace = json!({ "cell_id": &self.cell_id, "vm_id": vm_id,
+                    "deployment_port_tree_id": deployment_port_tree_id, "tree_vm_map_keys":  &keys,
+                    "up_tree_name": up_tree_name, "slots_used": self.slots_used });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.ca_to_vms.insert(vm_id, ca_to_vm, );
+        if let Some(tenant) = self.manifest_tenants.get(manifest_id).cloned() {
+            self.originator_tenants.insert(vm_originator_id, tenant);
+        }
+        self.deployments
+            .entry(S(manifest_id))
+            .or_insert_with(Vec::new)
+            .push(DeployedVm { vm_id, spec_id: S(vm_spec.get_id()), slots: container_specs.len(),
+                               containers: container_specs.clone() });
+        Ok((vm_originator_id, vm_id, allowed_trees, ca_from_vm))
+    }
+    // Try again to place VMs that were waiting for slots.  They are not members of their manifest's up trees.
+    fn deploy_deferred(&mut self) -> Result<(), Error> {
+        let _f = "deploy_deferred";
+        let deferred_vms = std::mem::replace(&mut self.deferred_vms, Vec::new());
+        for deferred in deferred_vms {
+            match self.placement(deferred.manifest_config, &deferred.vm_spec) {
+                Placement::Placed => {
+                    let (vm_originator_id, vm_id, allowed_trees, ca_from_vm) = self.deploy_vm(deferred.originator_id, deferred.deployment_port_tree_id, &deferred.manifest_id, &deferred.vm_spec).context(CellagentError::Chain { func_name: _f, comment: S(&deferred.manifest_id) })?;
+                    self.listen_uptree(vm_originator_id, vm_id, deferred.manifest_id.clone(), allowed_trees, ca_from_vm);
+                    let mut report = PlacementReport::new(self.cell_id, &deferred.manifest_id, self.config, self.free_slots());
+                    report.add_vm(deferred.vm_spec.get_id(), Placement::Placed);
+                    self.report_placement(deferred.originator_id, deferred.deployment_port_tree_id, &deferred.deployment_tree, &report)?;
+                },
+                _ => self.deferred_vms.push(deferred)
             }
-            vm.initialize(up_tree_name, vm_from_ca, &allowed_trees, container_specs)?;
+        }
+        Ok(())
+    }
+    // A VM needs a cell at least as large as both it and its manifest ask for, and a free slot per container
+    fn placement(&self, manifest_config: CellConfig, vm_spec: &VmSpec) -> Placement {
+        let required_config = std::cmp::max(manifest_config, vm_spec.get_required_config());
+        let needed_slots = vm_spec.get_containers().len();
+        if !self.config.can_host(required_config) || needed_slots > self.config.get_slots() {
+            Placement::Refused
+        } else if needed_slots > self.free_slots() {
+            Placement::Deferred
+        } else {
+            Placement::Placed
+        }
+    }
+    fn free_slots(&self) -> usize { self.config.get_slots().saturating_sub(self.slots_used) }
+    fn undeploy(&mut self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID,
+                deployment_tree: &AllowedTree, manifest_id: &str) -> Result<(), Error> {
+        let _f = "undeploy";
+        let vms = self.deployments.remove(manifest_id).unwrap_or_default();
+        self.deferred_vms.retain(|deferred| deferred.manifest_id != manifest_id);
+        self.stop_vms(originator_id, deployment_port_tree_id, deployment_tree, manifest_id, vms).context(CellagentError::Chain { func_name: _f, comment: S(manifest_id) })?;
+        Ok(())
+    }
+    // Replace stops the old VMs before starting the new ones; Rolling starts the new ones first
+    // so the old ones keep serving, which can leave new VMs deferred until the old slots are freed.
+    // VMs already running the images the manifest names keep running either way.
+    fn redeploy(&mut self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID, msg_tree_id: PortTreeID,
+                msg_tree_map: &MsgTreeMap, manifest: &Manifest, mode: DeployMode) -> Result<(), Error> {
+        let _f = "redeploy";
+        let manifest_id: &str = manifest.get_id();
+        let deployment_tree = manifest.get_deployment_tree();
+        let (unchanged_vms, old_vms): (Vec<DeployedVm>, Vec<DeployedVm>) = self.deployments
+            .remove(manifest_id)
+            .unwrap_or_default()
+            .into_iter()
+            .partition(|vm| manifest.get_vms().iter().any(|vm_spec| vm.is_running(vm_spec)));
+        self.deferred_vms.retain(|deferred| deferred.manifest_id != manifest_id);
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_redeploy" };
+                let old_vm_ids = old_vms.iter().map(|vm| vm.vm_id).collect::<Vec<_>>();
+                let unchanged_vm_ids = unchanged_vms.iter().map(|vm| vm.vm_id).collect::<Vec<_>>();
+                let trace = json!({ "cell_id": &self.cell_id, "manifest_id": manifest_id, "mode": mode,
+                    "old_vms": old_vm_ids, "unchanged_vms": unchanged_vm_ids });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        let is_changed = !old_vms.is_empty();
+        match mode {
+            DeployMode::Replace => {
+                if is_changed {
+                    self.stop_vms(originator_id, deployment_port_tree_id, deployment_tree, manifest_id, old_vms).context(CellagentError::Chain { func_name: _f, comment: S(manifest_id) })?;
+                }
+                self.deploy(originator_id, deployment_port_tree_id, msg_tree_id, msg_tree_map, manifest, unchanged_vms).context(CellagentError::Chain { func_name: _f, comment: S(manifest_id) })?;
+            },
+            DeployMode::Rolling => {
+                self.deploy(originator_id, deployment_port_tree_id, msg_tree_id, msg_tree_map, manifest, unchanged_vms).context(CellagentError::Chain { func_name: _f, comment: S(manifest_id) })?;
+                if is_changed {
+                    self.stop_vms(originator_id, deployment_port_tree_id, deployment_tree, manifest_id, old_vms).context(CellagentError::Chain { func_name: _f, comment: S(manifest_id) })?;
+                }
+            }
+        }
+        Ok(())
+    }
+    // Tell each VM to shut down its containers, then forget it.  This is the only place slots are
+    // freed, so VMs waiting for them get their chance here.
+    fn stop_vms(&mut self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID,
+                deployment_tree: &AllowedTree, manifest_id: &str, vms: Vec<DeployedVm>) -> Result<(), Error> {
+        let _f = "stop_vms";
+        let undeploy_msg = AppUndeployMsg::new("cell_agent", deployment_tree, manifest_id);
+        let bytes = wire::encode(&undeploy_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        let mut report = PlacementReport::new(self.cell_id, manifest_id, self.config, self.free_slots());
+        for vm in vms {
+            if let Some(ca_to_vm) = self.ca_to_vms.remove(&vm.vm_id) {
+                ca_to_vm.send(bytes.clone()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send undeploy to vm" })?;
+            }
+            for senders in self.tree_vm_map.values_mut() { senders.remove(&vm.vm_id); }
+            self.tree_vm_map.retain(|_, senders| !senders.is_empty());
+            self.vm_up_trees.retain(|_, up_tree| !up_tree.contains(vm.vm_id));
+            self.slots_used = self.slots_used.saturating_sub(vm.slots);
             {
-                if CONFIG.debug_options.all || CONFIG.debug_options.deploy {
-                    let keys = self.tree_vm_map.keys().collect::<Vec<_>>();
-                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_deploy" };
-                    let trace = json!({ "cell_id": &self.cell_id, "vm_id": vm_id,
-                        "deployment_port_tree_id": deployment_port_tree_id, "tree_vm_map_keys":  &keys,
-                        "up_tree_name": up_tree_name });
-                    add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                if let Some(event) = tracing(Level::Debug, &["deploy"]) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_undeploy" };
+                    let trace = json!({ "cell_id": &self.cell_id, "vm_id": vm.vm_id, "manifest_id": manifest_id, "slots_used": self.slots_used });
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
-            self.ca_to_vms.insert(vm_id, ca_to_vm, );
-            self.listen_uptree(vm_originator_id, vm_id, allowed_trees, ca_from_vm);
+            report.add_vm(&vm.spec_id, Placement::Undeployed);
         }
+        report.set_free_slots(self.free_slots());
+        self.report_placement(originator_id, deployment_port_tree_id, deployment_tree, &report)?;
+        self.deploy_deferred().context(CellagentError::Chain { func_name: _f, comment: S("deferred") })?;
         Ok(())
     }
+    // Reports go rootward on the deployment tree, and the cell at the root forwards them to the NOC
+    fn report_placement(&self, originator_id: OriginatorID, deployment_port_tree_id: PortTreeID,
+                        deployment_tree: &AllowedTree, report: &PlacementReport) -> Result<(), Error> {
+        let _f = "report_placement";
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_placement" };
+                let trace = json!({ "cell_id": &self.cell_id, "deployment_tree": deployment_tree, "report": report });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        let entry = self.get_tree_entry(deployment_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        if entry.get_parent() == PortNo(0) {
+            self.send_placement_to_noc(deployment_tree, report)
+        } else {
+            let deployment_tree_id = deployment_port_tree_id.to_tree_id();
+            let msg = PlacementMsg::new(self.cell_id, originator_id, deployment_tree_id, deployment_tree, report);
+            self.send_msg(line!(), deployment_tree_id, msg, DEFAULT_USER_MASK)
+        }
+    }
     // SPAWN THREAD (listen_uptree_loop)
-    fn listen_uptree(&self, originator_id: OriginatorID, vm_id: VmID, trees: HashSet<AllowedTree>,
+    fn listen_uptree(&self, originator_id: OriginatorID, vm_id: VmID, manifest_id: String, trees: HashSet<AllowedTree>,
                      ca_from_vm: CaFromVm) {
         let _f = "listen_uptree";
         let mut ca = self.clone();
@@ -716,32 +961,60 @@ impl CellAgent {
         let thread_name = format!("CellAgent {} listen_uptree_loop", self.cell_id);
         thread::Builder::new().name(thread_name).spawn(move || {
             update_trace_header(child_trace_header);
-            let _ = ca.listen_uptree_loop(originator_id, vm_id, &ca_from_vm).map_err(|e| write_err("cellagent", &e));
-            if CONFIG.continue_on_error { ca.listen_uptree(originator_id, vm_id, trees, ca_from_vm); }
+            let _thread = count_thread(ca.get_cell_id());
+            // Ok means the VM was undeployed
+            if let Err(e) = ca.listen_uptree_loop(originator_id, vm_id, &manifest_id, &ca_from_vm) {
+                write_err("cellagent", &e);
+                if CONFIG.continue_on_error { ca.listen_uptree(originator_id, vm_id, manifest_id, trees, ca_from_vm); }
+            }
         }).expect("listen uptree thread failed");
     }
 
     // WORKER (CaFromVm)
-    fn listen_uptree_loop(&mut self, originator_id: OriginatorID, _vm_id: VmID, ca_from_vm: &CaFromVm)
+    fn listen_uptree_loop(&mut self, originator_id: OriginatorID, vm_id: VmID, manifest_id: &str, ca_from_vm: &CaFromVm)
                           -> Result<(), Error> {
         let _f = "listen_uptree_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": &self.cell_id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
-            let bytes = ca_from_vm.recv().context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+            // The channel closes when the VM stops
+            let bytes = match ca_from_vm.recv() {
+                Ok(bytes) => bytes,
+                Err(_) => return Ok(())
+            };
+            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("uptree") })?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.ca {
-                    let serialized = bytes.stringify()?;
-                    let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(CellagentError::Chain { func_name: _f, comment: S("uptree") })?;
+                if let Some(event) = tracing(Level::Info, &["ca"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_vm_app" };
                     let trace = json!({ "cell_id": &self.cell_id, "app_msg": app_msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            // Messages on one of its manifest's up trees go straight to the other VMs.  Up trees only
+            // connect VMs on this cell; a manifest that spreads its VMs over several cells gets an up
+            // tree on each, and messages never cross from one to another.
+            let up_tree_key = (S(manifest_id), app_msg.get_target_tree_name().clone());
+            if let Some(up_tree) = self.vm_up_trees.get(&up_tree_key) {
+                let next_hops = up_tree.next_hops(vm_id, app_msg.get_direction()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+                {
+                    if let Some(event) = tracing(Level::Info, &["ca"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_vm_up_tree" };
+                        let trace = json!({ "cell_id": &self.cell_id, "up_tree_id": up_tree.get_id(), "next_hops": next_hops, "app_msg": app_msg });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                for next_hop in next_hops {
+                    let ca_to_vm = self.ca_to_vms
+                        .get(&next_hop)
+                        .ok_or::<Error>(CellagentError::Vm { func_name: _f, cell_id: self.cell_id, vm_id: next_hop }.into())?;
+                    ca_to_vm.send(bytes.clone()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send to vm on up tree" })?;
                 }
+                continue;
             }
             self.ca_to_cm[0].send(CaToCmBytes::TunnelUp((originator_id, bytes)))?;
         }
@@ -801,6 +1074,7 @@ impl CellAgent {
         self.tree_id_map.insert(new_port_tree_id.get_uuid(), new_port_tree_id);
         // TODO: Make sure that stacked tree entries for port trees get created
         self.update_entry(&entry).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+        self.update_tree_weight(allowed_tree, new_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         // Next line avoids a mutability error; requires NLL
         let traph = self.get_traph_mut(parent_port_tree_id).context(CellagentError::Chain { func_name: "stack_tree", comment: S("own_traph") })?;
         // No new_port_tree for uptrees, denoted by new_port_tree_id = None
@@ -811,12 +1085,12 @@ impl CellAgent {
             self.update_entry(&entry)?;
         }
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.stack_tree { // Debug print
+            if let Some(event) = tracing(Level::Debug, &["stack_tree"]) { // Debug print
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_stack_tree" };
                 let trace = json!({ "cell_id": &self.cell_id,
                 "new_port_tree_id": &new_port_tree_id, "base_tree_id": &base_tree_id,
                 "params": params, "gvm_send": gvm_send, "gvm_recv": gvm_recv, "gvm_xtnd": gvm_xtnd });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         (*self.traphs_mutex.lock().unwrap()) = self.traphs.clone();
@@ -829,14 +1103,30 @@ impl CellAgent {
     }
     fn update_entry(&self, entry: &RoutingTableEntry) -> Result<(), Error> {
         let _f = "update_entry";
-        if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+        if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_cm_entry" };
             let trace = json!({ "cell_id": &self.cell_id, "entry": entry });
-            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+            add_to_trace(event, trace_params, &trace, _f);
         }
         self.ca_to_cm[0].send(CaToCmBytes::Entry(*entry)).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         Ok(())
     }
+    // Tree UUIDs are made up when the tree is, so the packet engine learns weights by UUID
+    fn update_tree_weight(&self, allowed_tree: &AllowedTree, port_tree_id: PortTreeID) -> Result<(), Error> {
+        let _f = "update_tree_weight";
+        if let Some(weight) = CONFIG.get_tree_weight(allowed_tree.get_name()) {
+            let uuid = port_tree_id.get_uuid();
+            {
+                if let Some(event) = tracing(Level::Info, &["ca"]) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_cm_tree_weight" };
+                    let trace = json!({ "cell_id": &self.cell_id, "tree_name": allowed_tree, "uuid": uuid, "weight": weight });
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            self.ca_to_cm[0].send(CaToCmBytes::TreeWeight((uuid, weight))).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+        }
+        Ok(())
+    }
 
     // SPAWN THREAD (listen_port_loop)
     fn listen_port(&mut self, ca_from_ports: CaFromPort) -> JoinHandle<()> {
@@ -846,6 +1136,7 @@ impl CellAgent {
         let thread_name = format!("CellAgent {} listen_port_loop", self.cell_id);
         thread::Builder::new().name(thread_name).spawn(move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(ca.get_cell_id());
             let _ = ca.listen_border_port_loop(&ca_from_ports).map_err(|e| write_err("cellagent", &e));
             if CONFIG.continue_on_error { ca.listen_port(ca_from_ports); }
         }).expect("cellagent listen port thread failed")
@@ -853,27 +1144,27 @@ impl CellAgent {
     fn listen_border_port_loop(&mut self, ca_from_port: &CaFromPort) -> Result<(), Error> {
         let _f = "listen_border_port_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": &self.cell_id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
             let msg = ca_from_port.recv().context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+                if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
                     match &msg {
                         PortToCaMsg::AppMsg(port_no, bytes) => {
-                            let ec_msg: Box<dyn AppMessage> = serde_json::from_str(&bytes.stringify()?).context(CellagentError::Chain { func_name: _f, comment: S("border_debug") })?;
+                            let ec_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("border_debug") })?;
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_port_bytes" };
-                            let trace = json!({ "cell_id": self.cell_id, "port": port_no, "ec_msg": ec_msg });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "cell_id": self.cell_id, "port": port_no, "ec_msg": ec_msg, "bytes": bytes });
+                            add_to_trace(event, trace_params, &trace, _f);
                         },
                         PortToCaMsg::Status(port_no, status) => {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_port_status" };
                             let trace = json!({ "cell_id": &self.cell_id, "port": port_no, "status": status });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         },
                     }
                 }
@@ -897,6 +1188,7 @@ impl CellAgent {
         let thread_name = format!("CellAgent {} listen_cm_loop", self.cell_id);
         thread::Builder::new().name(thread_name).spawn(move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(ca.get_cell_id());
             let _ = ca.listen_cm_loop(&ca_from_cm).map_err(|e| write_err("cellagent", &e));
             if CONFIG.continue_on_error { ca.listen_cm(ca_from_cm); }
         }).expect("cellagent listen cm thread failed")
@@ -906,64 +1198,66 @@ impl CellAgent {
     fn listen_cm_loop(&mut self, ca_from_cm: &CaFromCm) -> Result<(), Error> {
         let _f = "listen_cm_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": &self.cell_id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
             let msg = ca_from_cm.recv().context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
             {
                 match &msg {
-                    CmToCaBytesOld::Bytes((port_no, is_ait, uuid, bytes)) => {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.replay {
+                    CmToCaBytes::Bytes((port_no, is_ait, uuid, bytes)) => {
+                        if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_cm_bytes" };
                             let trace = json!({ "cell_id": self.cell_id, "port": port_no,
-                                "is_ait": is_ait, "tree_uuid": uuid, "ec_msg": bytes.stringify()?, "bytes": bytes }); // Need "msg" for readability of trace and "bytes" for replay
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                "is_ait": is_ait, "tree_uuid": uuid, "ec_msg": wire::to_json(bytes)?, "bytes": bytes }); // Need "msg" for readability of trace and "bytes" for replay
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     },
-                    CmToCaBytesOld::Status((port_no, is_border, number_of_packets, status)) => {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.replay {
+                    CmToCaBytes::Status((port_no, is_border, number_of_packets, status)) => {
+                        if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_cm_status" };
                             let trace = json!({ "cell_id": &self.cell_id, "port": port_no, "is_border": is_border, "no_packets": number_of_packets, "status": status });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     },
-                    CmToCaBytesOld::TunnelPort((port_no, bytes)) => {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.ca {
-                            let app_msg: Box<dyn AppMessage> = serde_json::from_str(&bytes.stringify()?).context(CellagentError::Chain { func_name: _f, comment: S("ca_loop_tunnel_port") })?;
+                    CmToCaBytes::TunnelPort((port_no, bytes)) => {
+                        if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
+                            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("ca_loop_tunnel_port") })?;
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_cm_bytes_port" };
-                            let trace = json!({ "cell_id": self.cell_id, "port": port_no, "app_msg": app_msg });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "cell_id": self.cell_id, "port": port_no, "app_msg": app_msg, "bytes": bytes });
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     },
-                    CmToCaBytesOld::TunnelUp((originator_id, bytes)) => {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.ca {
-                            let app_msg: Box<dyn AppMessage> = serde_json::from_str(&bytes.stringify()?).context(CellagentError::Chain { func_name: _f, comment: S("ca_loop_tunnel_up") })?;
+                    CmToCaBytes::TunnelUp((originator_id, bytes)) => {
+                        if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
+                            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("ca_loop_tunnel_up") })?;
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_from_cm_bytes_up" };
-                            let trace = json!({ "cell_id": self.cell_id, "originator_id": originator_id, "app_msg": app_msg });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "cell_id": self.cell_id, "originator_id": originator_id, "app_msg": app_msg, "bytes": bytes });
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
                 }
             }
             match msg {
-                CmToCaBytesOld::Status((port_no, is_border, number_of_packets, status)) => match status {
-                    PortStatusOld::Connected => self.port_connected(port_no, is_border).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " port_connected" })?,
-                    PortStatusOld::Disconnected => self.port_disconnected(port_no, number_of_packets).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " port_disconnected" })?
+                CmToCaBytes::Status((port_no, is_border, number_of_packets, status)) => match status {
+                    LinkStatus::Connected => self.port_connected(port_no, is_border).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " port_connected" })?,
+                    LinkStatus::Disconnected => self.port_disconnected(port_no, number_of_packets).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " port_disconnected" })?
                 },
-                CmToCaBytesOld::Bytes((port_no, is_ait, uuid, bytes)) => {
+                CmToCaBytes::Bytes((port_no, is_ait, uuid, bytes)) => {
                     // The index may be pointing to the control tree because the other cell didn't get the StackTree or StackTreeD message in time
                     let mut msg = MsgType::msg_from_bytes(&bytes).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
                     {
-                        if CONFIG.debug_options.all || CONFIG.debug_options.ca_msg_recv {
+                        if let Some(event) = tracing(Level::Debug, &["ca_msg_recv"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_msg" };
                             let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": &msg });
-                            add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
+                    if !self.authenticate(port_no, &bytes, msg.get_header()) { continue; }
+                    self.metrics.lock().unwrap().msg_recd(msg.get_msg_type());
                     let msg_tree_id = {  // Use control tree if uuid not found
                         self.tree_id_map
                             .get(&uuid)
@@ -971,8 +1265,9 @@ impl CellAgent {
                             .clone()
                     };
                     msg.process_ca(self, port_no, msg_tree_id, is_ait).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+                    self.metrics.lock().unwrap().set_trees(self.tree_count, self.tree_map.len());
                 },
-                CmToCaBytesOld::TunnelPort((port_no, bytes)) => {
+                CmToCaBytes::TunnelPort((port_no, bytes)) => {
                     if !CONFIG.replay {
                     let port_number = port_no.make_port_number(self.no_ports).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " PortNumber" })?;
                     let originator_id = self.border_port_tree_id_map
@@ -983,18 +1278,16 @@ impl CellAgent {
                     if !self.tree_name_map.lock().unwrap().contains_key(&originator_id) {
                         return Err(CellagentError::TreeNameMap { func_name: _f, cell_id: self.cell_id, originator_id }.into());
                     }
-                    let serialized = bytes.stringify()?;
-                    let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(CellagentError::Chain { func_name: _f, comment: S("cm_loop_tunnel_port") })?;
+                    let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("cm_loop_tunnel_port") })?;
                     app_msg.process_ca(self, originator_id)?;
                 }
                 }
-                CmToCaBytesOld::TunnelUp((originator_id, bytes)) => {
+                CmToCaBytes::TunnelUp((originator_id, bytes)) => {
                     if !CONFIG.replay {
                         if !self.tree_name_map.lock().unwrap().contains_key(&originator_id) {
                             return Err(CellagentError::TreeNameMap { func_name: _f, cell_id: self.cell_id, originator_id }.into());
                         }
-                        let serialized = bytes.stringify()?;
-                        let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(CellagentError::Chain { func_name: _f, comment: S("cm_loop_tunnel_up") })?;
+                        let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("cm_loop_tunnel_up") })?;
                         app_msg.process_ca(self, originator_id)?;
                     }
                 }
@@ -1009,10 +1302,10 @@ impl CellAgent {
     fn delete_tree(&mut self, delete_tree_id: &TreeID) -> Result<(), Error> {
         let _f = "delete_tree";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_cm_delete_tree" };
                 let trace = json!({ "cell_id": &self.cell_id, "delete_tree": delete_tree_id });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let uuid = delete_tree_id.get_uuid();
@@ -1028,10 +1321,10 @@ impl CellAgent {
             -> Result<(), Error> {
         let _f = "process_interapplication_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_interapplication_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": S(msg) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_tree_id = msg.get_port_tree_id();
@@ -1042,22 +1335,21 @@ impl CellAgent {
             self.update_sender_tree_map(originator_id, &allowed_trees, port_tree_id.to_tree_id());
         }
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.process_msg {
+            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_interapplication_msg_dbg" };
                 let trace = json!({ "cell_id": &self.cell_id,"port_tree_id": port_tree_id, "port_no": port_no, "msg": S(msg) });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_vm_app" };
                 let trace = json!({ "cell_id": &self.cell_id, "app_msg": S(app_msg) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let senders = self.get_vm_senders(port_tree_id.to_tree_id()).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
-        let serialized = serde_json::to_string(app_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: _f, comment: S("appmsg") })?;
-        let bytes = ByteArray::new(&serialized);
+        let bytes = wire::encode(app_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: _f, comment: S("appmsg") })?;
         for sender in senders {
             sender.send(bytes.clone()).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         }
@@ -1087,12 +1379,12 @@ impl CellAgent {
         let port_tree_seen = self.quench_root_port(new_port_tree_id);
         let my_port_seen = self.quench_my_port(new_tree_id, port_no);
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.discover {
+            if let Some(event) = tracing(Level::Debug, &["discover"]) {
                 let neighbors: Vec<_> = self.neighbors.keys().collect();
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discover_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value(),
                     "port_tree_id": new_port_tree_id, "seen_trees": tree_seen, "neighbors": neighbors });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let quench = match CONFIG.quench {
@@ -1102,7 +1394,7 @@ impl CellAgent {
         };
         let gvm_equation = Default::default();
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.discoverd {
+            if let Some(event) = tracing(Level::Debug, &["discoverd"]) {
                 let seen_ports = self.discoverd_seen_on_tree.get(&new_tree_id);
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discover_msg_dbg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() ,
@@ -1110,7 +1402,7 @@ impl CellAgent {
                             "seen ports": seen_ports,
                             "seen trees": self.traphs.len(), "discoverd parent msg": self.discoverd_parent_msg.get(&new_tree_id),
                             "neighbor count": self.neighbors.len()});
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.update_base_tree_map(new_port_tree_id, new_tree_id);
@@ -1120,6 +1412,14 @@ impl CellAgent {
         let in_reply_to = msg.get_sender_msg_seq_no();
         if new_tree_id != self.my_tree_id && !tree_seen {
             self.tree_count = self.tree_count + 1;
+            {
+                // The time of this record from the start of the run is my discovery time
+                if let Some(event) = tracing(Level::Debug, &["discover_done"]).filter(|_| self.tree_count + 1 == *CONFIG.num_cells) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_discover_done" };
+                    let trace = json!({ "cell_id": &self.cell_id, "tree_count": self.tree_count, "wire_format": WireFormat::configured() });
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
             let discoverd_parent_msg = DiscoverDMsg::new(in_reply_to, self.cell_id,
                                                          originator_id, new_port_tree_id, path,
                                                          DiscoverDType::Parent);
@@ -1160,10 +1460,10 @@ impl CellAgent {
                                   -> Result<(), Error> {
         let _f = "process_discoverd_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.visualize {
+            if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discoverd_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_tree_id = msg.get_port_tree_id();
@@ -1221,13 +1521,13 @@ impl CellAgent {
                     self.is_border() &&
                     self.discoverd_done(tree_id, "DiscoverD::Parent");
                 {
-                    if CONFIG.debug_options.all || CONFIG.debug_options.discoverd { 
+                    if let Some(event) = tracing(Level::Debug, &["discoverd"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discoverd_msg_noc" };
                         let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value(),
                             "tree_id": tree_id, "done_test": done_test, "border port connected": self.is_border_port_connected,
                             "sent to NOC": self.sent_to_noc, "is border": self.is_border(),
                             "parents seen on tree": parents_seen_on_tree, "neighbor count": self.neighbors.len()});
-                        add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 if done_test {
@@ -1240,10 +1540,10 @@ impl CellAgent {
     pub fn process_failover_msg(&mut self, msg: &FailoverMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_failover_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_failover_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let header = msg.get_header();
@@ -1280,10 +1580,10 @@ impl CellAgent {
     pub fn process_failover_d_msg(&mut self, msg: &FailoverDMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_failover_d_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca { // Needed for visualization
+            if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) { // Needed for visualization
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_failover_d_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let failover_reply_ports = &self.failover_reply_ports.clone();
@@ -1298,6 +1598,7 @@ impl CellAgent {
             println!("Cellagent {}: {} reached leafward node for port tree {}", self.cell_id, _f, rw_port_tree_id);
             match payload.get_response() {
                 FailoverResponse::Failure => {
+                    self.metrics.lock().unwrap().failover_failed();
                     let lw_tree_id = lw_port_tree_id.to_tree_id();
                     let rw_tree_id = rw_port_tree_id.to_tree_id();
                     return Err(CellagentError::Partition { func_name: _f, lw_tree_id, rw_tree_id }.into())
@@ -1310,13 +1611,14 @@ impl CellAgent {
                     };
                     let no_packets = payload.get_number_of_packets();
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+                        if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_cm_reroute" };
                             let trace = json!({ "cell_id": &self.cell_id, "broken_port_no": broken_port_no, "port_no": port_no, "no_packets": no_packets });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
                     self.ca_to_cm[0].send(CaToCmBytes::Reroute((broken_port_no, port_no, no_packets)))?;
+                    self.metrics.lock().unwrap().failover_succeeded();
                     // Following line is commented out because the packet engine does rerouting.
                     // Packets still go the out queue for the broken link, but the packet engine reroutes them
                     // to the failover port.  The traph will need to be repaired if another strategy is used.
@@ -1360,10 +1662,10 @@ impl CellAgent {
     pub fn process_hello_msg(&mut self, msg: &HelloMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_hello_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.visualize { // Needed for visualization
+            if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) { // Needed for visualization
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_hello_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "#neighbors": self.neighbors.len(), "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_number = PortNumber::new(port_no, self.no_ports)?;
@@ -1375,8 +1677,19 @@ impl CellAgent {
         let neighbor_cell_id = msg.get_cell_id();
         let neigbor_port_no = msg.get_port_no();
         self.neighbors.insert(port_no, (neighbor_cell_id, neigbor_port_no));
+        // The link carries the smaller of our packet sizes
+        let packet_size = std::cmp::min(CONFIG.get_packet_size(&self.cell_id.get_name()),
+                                        msg.get_packet_size().unwrap_or(PACKET_DEFAULT));
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.hello {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_packet_size" };
+                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "neighbor": neighbor_cell_id, "packet_size": packet_size });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.ca_to_cm[0].send(CaToCmBytes::PacketSize((port_no, packet_size)))?;
+        {
+            if let Some(event) = tracing(Level::Debug, &["hello"]) {
                 let neighbors: Vec<_> = self.neighbors.keys().collect();
                 let neighbor = self.neighbor_names_from_port_nos(&[port_no]);
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_hello_msg_dbg" };
@@ -1386,7 +1699,7 @@ impl CellAgent {
                     "discoverd_sent": self.saved_discoverd.len(),
                     "discover_ack_d_sent": self.discover_ack_d_sent,
                     "msg": msg.value() });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         // Send my DiscoverMsg and DiscoverDMsg
@@ -1431,10 +1744,10 @@ impl CellAgent {
             -> Result<(), Error> {
         let _f = "process_manifest_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_manifest_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let header = msg.get_header();
@@ -1443,17 +1756,83 @@ impl CellAgent {
         let msg_tree_map = header.get_tree_map();
         let deployment_tree_id = payload.get_deploy_port_tree_id();
         let originator_id = header.get_originator_id();
-        self.deploy(originator_id, deployment_tree_id, msg_port_tree_id, msg_tree_map, manifest).context(CellagentError::Chain { func_name: "process_ca", comment: S("ManifestMsg") })?;
+        if let Some(tenant) = payload.get_tenant() {
+            self.manifest_tenants.insert(S(manifest.get_id()), S(tenant));
+        }
+        let tree_keys = self.open_tree_keys(payload.get_tree_keys()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " open tree keys" })?;
+        self.manifest_keys.insert(S(manifest.get_id()), tree_keys);
+        self.tenants.lock().unwrap()
+            .charge_manifest(manifest.get_id(), self.cell_id)
+            .context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " charge manifest" })?;
+        // VMs already waiting get first claim on any slots that have freed up
+        self.deploy_deferred().context(CellagentError::Chain { func_name: _f, comment: S("deferred") })?;
+        self.deploy(originator_id, deployment_tree_id, msg_port_tree_id, msg_tree_map, manifest, Vec::new()).context(CellagentError::Chain { func_name: "process_ca", comment: S("ManifestMsg") })?;
         let tree_id = payload.get_deploy_port_tree_id();
         let traph = self.get_traph(tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         traph.get_tree_entry(&tree_id.get_uuid()).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.manifest {
+            if let Some(event) = tracing(Level::Debug, &["manifest"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_manifest_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": tree_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        Ok(())
+    }
+    pub fn process_placement_msg(&mut self, msg: &PlacementMsg, port_no: PortNo) -> Result<(), Error> {
+        let _f = "process_placement_msg";
+        let payload = msg.get_payload();
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_placement_msg" };
+                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.send_placement_to_noc(payload.get_tree_name(), payload.get_report())
+    }
+    pub fn process_redeploy_msg(&mut self, msg: &RedeployMsg, port_no: PortNo, msg_port_tree_id: PortTreeID)
+            -> Result<(), Error> {
+        let _f = "process_redeploy_msg";
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_redeploy_msg" };
+                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        let header = msg.get_header();
+        let payload = msg.get_payload();
+        let deployment_tree_id = payload.get_deploy_port_tree_id();
+        let mode = payload.get_mode();
+        self.redeploy(header.get_originator_id(), deployment_tree_id, msg_port_tree_id,
+                      header.get_tree_map(), payload.get_manifest(), mode).context(CellagentError::Chain { func_name: "process_ca", comment: S("RedeployMsg") })?;
+        if mode == DeployMode::Rolling { self.forward_rolling_redeploy(msg)?; }
+        Ok(())
+    }
+    // Children on the deployment tree start their upgrade only after this cell has finished
+    fn forward_rolling_redeploy(&self, msg: &RedeployMsg) -> Result<(), Error> {
+        let _f = "forward_rolling_redeploy";
+        let deployment_tree_id = msg.get_payload().get_deploy_port_tree_id();
+        let entry = self.get_tree_entry(deployment_tree_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        let child_mask = entry.get_mask().all_but_port(PortNumber::new0());
+        let updated_msg = msg.update_sender(self.cell_id);
+        self.send_msg(line!(), self.connected_tree_id, updated_msg, child_mask)?; // Send to children of deployment tree
+        Ok(())
+    }
+    pub fn process_undeploy_msg(&mut self, msg: &UndeployMsg, port_no: PortNo) -> Result<(), Error> {
+        let _f = "process_undeploy_msg";
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_undeploy_msg" };
+                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
+        let originator_id = msg.get_header().get_originator_id();
+        let payload = msg.get_payload();
+        self.undeploy(originator_id, payload.get_deploy_port_tree_id(), payload.get_tree_name(),
+                      payload.get_manifest_id()).context(CellagentError::Chain { func_name: "process_ca", comment: S("UndeployMsg") })?;
         Ok(())
     }
     pub fn _process_reroute_msg(&mut self) -> Result<(), Error> {
@@ -1463,10 +1842,10 @@ impl CellAgent {
     pub fn process_discover_ack_d_msg(&mut self, msg: &DiscoverAckDMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_discover_ack_d_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discover_ack_d_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_tree_id = msg.get_port_tree_id();
@@ -1485,10 +1864,10 @@ impl CellAgent {
     pub fn process_discover_ack_msg(&mut self, msg: &DiscoverAckMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_discover_ack_d_d_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca { // Needed for visualization
+            if let Some(event) = tracing(Level::Info, &["ca"]) { // Needed for visualization
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_discover_ack_d_d_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let tree_id = msg.get_port_tree_id().to_tree_id();
@@ -1512,10 +1891,10 @@ impl CellAgent {
             -> Result<(), Error> {
         let _f = "process_stack_tree_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca { // Needed for visualization
+            if let Some(event) = tracing(Level::Info, &["ca"]) { // Needed for visualization
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_stack_tree_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let header = msg.get_header();
@@ -1526,6 +1905,10 @@ impl CellAgent {
         let originator_id = header.get_originator_id();
         let gvm_eqn = payload.get_gvm_eqn();
         let port_number = port_no.make_port_number(self.get_no_ports())?;
+        // Over quota, I stay off the tree and don't pass it on
+        self.tenants.lock().unwrap()
+            .charge_tree(allowed_tree, self.cell_id)
+            .context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " charge tree" })?;
         let entry = self.stack_tree(originator_id, allowed_tree, new_port_tree_id, parent_port_tree_id,
                                     Some(new_port_tree_id), gvm_eqn)?;
         let traph = self.get_traph_mut(new_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
@@ -1537,12 +1920,12 @@ impl CellAgent {
         // Update StackTreeMsg and forward
         let parent_entry = self.get_tree_entry(parent_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.stack_tree {
+            if let Some(event) = tracing(Level::Debug, &["stack_tree"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_stack_tree_msg_dbg1" };
                 let trace = json!({ "cell_id": &self.cell_id, "new_port_tree_id": new_port_tree_id,
                     "parent_entry": parent_entry, "msg": msg.value(),
                     "gvm_send": gvm_send, "gvm_recv": gvm_recv, "gvm_eqn": gvm_eqn });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let parent_mask = parent_entry.get_mask().all_but_port(PortNumber::new0());
@@ -1569,11 +1952,11 @@ impl CellAgent {
         let base_tree_id = self.get_base_tree_id(parent_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         self.update_base_tree_map(new_port_tree_id, base_tree_id);
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.stack_tree {
+            if let Some(event) = tracing(Level::Debug, &["stack_tree"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_stack_tree_msg_dbg2" };
                 let trace = json!({ "cell_id": &self.cell_id, "new_port_tree_id": new_port_tree_id,
                 "port_no": port_no, "child_ports": self.child_ports.get(&new_port_tree_id.to_tree_id()), "msg": msg.value() });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         (*self.traphs_mutex.lock().unwrap()) = self.traphs.clone();
@@ -1582,10 +1965,10 @@ impl CellAgent {
     pub fn process_stack_tree_d_msg(&mut self, msg: &StackTreeDMsg, port_no: PortNo) -> Result<(), Error> {
         let _f = "process_stack_treed_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.visualize {
+            if let Some(event) = tracing(Level::Info, &["ca", "visualize"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_process_stack_treed_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg": msg.value() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let is_joining = msg.is_joining();
@@ -1608,10 +1991,10 @@ impl CellAgent {
             .get_mut(&port_tree_id.to_tree_id())
             .expect("Child ports must exist");
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.stack_tree {
+            if let Some(event) = tracing(Level::Debug, &["stack_tree"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "stack_treed_msg_dbg" };
                 let trace = json!({ "cell_id": &self.cell_id, "msg": msg, "join": is_joining, "port": port_no, "parent tree": parent_port_tree_id, "child_ports": child_ports });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let old_len = child_ports.len();
@@ -1656,19 +2039,38 @@ impl CellAgent {
         let port_no = port_number.get_port_no();
         let tree_name_msg = AppTreeNameMsg::new("cell_agent", false, false,
                                                 tree_name, tree_name);
-        let serialized = serde_json::to_string(&tree_name_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: "port_connected", comment: S(self.cell_id) })?;
-        let bytes = ByteArray::new(&serialized);
+        let bytes = wire::encode(&tree_name_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: "port_connected", comment: S(self.cell_id) })?;
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca || CONFIG.trace_options.replay {
+            if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_noc_tree_name" };
                 let trace = json!({ "cell_id": &self.cell_id, "noc_port": port_no, "app_msg": tree_name_msg, "bytes": bytes }); // Need "app_msg" for readability in trace and "bytes" for replay
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let ca_to_port = self.ca_to_ports.get(&port_no).expect("cellagent.rs send_tree_name_msg: send port must be set");
         ca_to_port.send(bytes)?;
         Ok(())
     }
+    fn send_placement_to_noc(&self, deployment_tree: &AllowedTree, report: &PlacementReport) -> Result<(), Error> {
+        let _f = "send_placement_to_noc";
+        let port_number = self.border_port_tree_id_map
+            .keys()
+            .next()
+            .ok_or::<Error>(CellagentError::NoBorder { func_name: _f, cell_id: self.cell_id }.into())?;
+        let port_no = port_number.get_port_no();
+        let placement_msg = AppPlacementMsg::new("cell_agent", deployment_tree, report);
+        let bytes = wire::encode(&placement_msg as &dyn AppMessage).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        {
+            if let Some(event) = tracing(Level::Info, &["ca", "replay"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_noc_placement" };
+                let trace = json!({ "cell_id": &self.cell_id, "noc_port": port_no, "app_msg": placement_msg, "bytes": bytes });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        let ca_to_port = self.ca_to_ports.get(&port_no).expect("cellagent.rs send_placement_to_noc: send port must be set");
+        ca_to_port.send(bytes)?;
+        Ok(())
+    }
     fn find_new_parent(&mut self, header: &MsgHeader, payload: &FailoverMsgPayload, port_no: PortNo)
             -> Result<(), Error> {
         let _f = "find_new_parent";
@@ -1734,6 +2136,7 @@ impl CellAgent {
     pub fn app_interapplication(&mut self, app_msg: &AppInterapplicationMsg, originator_id: OriginatorID)
             -> Result<(), Error> {
         let _f = "app_interapplication";
+        self.check_isolation(app_msg, originator_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
         let target_tree_name = app_msg.get_target_tree_name();
         let allowed_trees = app_msg.get_allowed_trees();
         let tree_map = self.make_tree_map(originator_id, allowed_trees)?;
@@ -1748,10 +2151,10 @@ impl CellAgent {
         let msg = InterapplicationMsg::new(self.cell_id, originator_id,
                                            is_ait, is_snake, tree_id, direction, &tree_map, app_msg);
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.process_msg {   // Debug
+            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {   // Debug
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_app_interapplication_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": tree_id, "msg": msg.value() });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.send_msg(line!(), tree_id, msg, DEFAULT_USER_MASK)?;
@@ -1772,14 +2175,15 @@ impl CellAgent {
                         let msg = DeleteTreeMsg::new(self.cell_id,
                                                      originator_id, false, false, delete_tree_id);
                         {
-                            if CONFIG.debug_options.all || CONFIG.debug_options.process_msg {   // Debug
+                            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {   // Debug
                                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_app_delete_tree_msg" };
                                 let trace = json!({ "cell_id": &self.cell_id, "delete_tree_id": delete_tree_id, "msg": msg.value() });
-                                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                                add_to_trace(event, trace_params, &trace, _f);
                            }
                         }
                         self.send_msg(line!(), parent_tree_id, msg, DEFAULT_USER_MASK)?;
                         self.delete_tree(&delete_tree_id)?;
+                        self.tenants.lock().unwrap().release_tree(delete_tree_name);
                     }
                 }
             }
@@ -1788,8 +2192,63 @@ impl CellAgent {
         }
         Ok(())
     }
+    // Failures go to the NOC master, which is at the root of its control tree
+    pub fn app_health(&mut self, app_msg: &AppHealthMsg, originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "app_health";
+        let report = app_msg.get_payload();
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_container_health" };
+                let trace = json!({ "cell_id": &self.cell_id, "originator_id": originator_id, "report": report });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        if report.get_status() != ContainerStatus::Failed { return Ok(()); }
+        let control_tree = AllowedTree::new(NOC_CONTROL_TREE_NAME);
+        // Any sender that knows the control tree will do, since it is the NOC's tree and not the container's
+        let control_tree_id = self.tree_name_map.lock().unwrap()
+            .values()
+            .find_map(|tree_names| tree_names.get_by_right(&control_tree).cloned());
+        let control_tree_id = match control_tree_id {
+            Some(tree_id) => tree_id,
+            None => {
+                {
+                    if let Some(event) = tracing(Level::Warn, &["ca"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_health_no_control_tree" };
+                        let trace = json!({ "cell_id": &self.cell_id, "originator_id": originator_id, "report": report });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                return Ok(());
+            }
+        };
+        let body = HealthReport::new(self.cell_id, report).to_body().context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        // The master won't take an unsealed payload on a tree with a key
+        let tree_key = self.tree_keys.lock().unwrap().get(NOC_CONTROL_TREE_NAME).cloned();
+        let event = match tree_key {
+            Some(tree_key) => AppInterapplicationMsg::new_sealed("cell_agent", false, false, &control_tree,
+                                                                 AppMsgDirection::Rootward, &vec![], &body, &tree_key)?,
+            None => AppInterapplicationMsg::new("cell_agent", false, false, &control_tree,
+                                                AppMsgDirection::Rootward, &vec![], &body)
+        };
+        let entry = self.get_tree_entry(control_tree_id.to_port_tree_id_0()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        if entry.get_parent() == PortNo(0) {
+            // The master is on this cell
+            let bytes = wire::encode(&event as &dyn AppMessage).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+            for sender in self.get_vm_senders(control_tree_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })? {
+                sender.send(bytes.clone()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send to vm" })?;
+            }
+        } else {
+            let msg = InterapplicationMsg::new(self.cell_id, originator_id, false, false, control_tree_id,
+                                               event.get_direction().into(), &HashMap::new(), &event);
+            self.send_msg(line!(), control_tree_id, msg, DEFAULT_USER_MASK)?;
+        }
+        Ok(())
+    }
     pub fn app_manifest(&mut self, app_msg: &AppManifestMsg, originator_id: OriginatorID) -> Result<(), Error> {
         let _f = "app_manifest";
+        self.check_isolation(app_msg, originator_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        let tenant = self.request_tenant(app_msg, originator_id);
         let allowed_trees = app_msg.get_allowed_trees();
         let mut tree_map = self.make_tree_map(originator_id, allowed_trees)?;
         let deploy_tree_name = app_msg.get_deploy_tree_name();
@@ -1800,20 +2259,112 @@ impl CellAgent {
             return Err(CellagentError::MayNotSend { func_name: _f, cell_id: self.cell_id, tree_id: deploy_tree_id }.into());
         }
         let manifest = app_msg.get_payload().get_manifest();
+        let is_charged = !self.is_noc_request(app_msg, originator_id);
+        self.tenants.lock().unwrap()
+            .get_mut(&tenant)
+            .and_then(|tenant| tenant.claim_manifest(manifest.get_id(), is_charged))
+            .context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " claim manifest" })?;
+        let tree_keys = self.wrap_tree_keys(manifest).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " wrap tree keys" })?;
         let msg = ManifestMsg::new(self.cell_id, originator_id,
-                                   false, false, deploy_tree_id.clone(), &tree_map, &manifest);
+                                   false, false, deploy_tree_id.clone(), &tree_map, &manifest)
+            .with_tenant(&tenant)
+            .with_tree_keys(tree_keys);
         let mask = self.get_mask(deploy_port_tree_id)?;
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.process_msg {   // Debug
+            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {   // Debug
                 let ports = mask.get_port_nos();
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_manifest_app_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "deploy_tree_id": deploy_tree_id, "ports": ports, "msg": msg.value() });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
          self.send_msg(line!(), deploy_tree_id, msg, mask.or(Mask::port0())).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send manifest" })?;
         Ok(())
     }
+    // Without auth keys there is nothing to wrap tree keys with, so payloads aren't sealed
+    fn wrap_tree_keys(&self, manifest: &Manifest) -> Result<Option<WrappedTreeKeys>, Error> {
+        match &self.authenticator {
+            Some(authenticator) => Ok(Some(authenticator.wrap_tree_keys(&self.make_tree_keys(manifest))?)),
+            None => Ok(None)
+        }
+    }
+    fn open_tree_keys(&self, wrapped: Option<&WrappedTreeKeys>) -> Result<TreeKeys, Error> {
+        match (&self.authenticator, wrapped) {
+            (Some(authenticator), Some(wrapped)) => authenticator.open_tree_keys(wrapped),
+            _ => Ok(TreeKeys::new())
+        }
+    }
+    // Every tree a container of the manifest may name gets a key, the same one for every
+    // manifest using that tree, so containers from different manifests can talk
+    fn make_tree_keys(&self, manifest: &Manifest) -> TreeKeys {
+        let mut tree_names: HashSet<String> = HashSet::new();
+        tree_names.extend(manifest.get_allowed_trees().iter().map(|tree| S(tree.get_name())));
+        tree_names.extend(manifest.get_trees().iter().map(|up_tree| S(up_tree.get_id())));
+        for vm_spec in manifest.get_vms() {
+            tree_names.extend(vm_spec.get_allowed_trees().iter().map(|tree| S(tree.get_name())));
+            tree_names.extend(vm_spec.get_trees().iter().map(|up_tree| S(up_tree.get_id())));
+            for container_spec in vm_spec.get_containers() {
+                tree_names.extend(container_spec.get_allowed_trees().iter().map(|tree| S(tree.get_name())));
+            }
+        }
+        let mut shared_tree_keys = self.tree_keys.lock().unwrap();
+        tree_names
+            .into_iter()
+            .map(|tree_name| {
+                let tree_key = shared_tree_keys.entry(tree_name.clone()).or_insert_with(TreeKey::new).clone();
+                (tree_name, tree_key)
+            })
+            .collect()
+    }
+    pub fn app_redeploy(&mut self, app_msg: &AppRedeployMsg, originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "app_redeploy";
+        let allowed_trees = app_msg.get_allowed_trees();
+        let mut tree_map = self.make_tree_map(originator_id, allowed_trees)?;
+        let deploy_tree_name = app_msg.get_deploy_tree_name();
+        let deploy_tree_id = self.tree_from_name(originator_id, deploy_tree_name).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+        tree_map.insert(S(deploy_tree_name.get_name()), deploy_tree_id);
+        let deploy_port_tree_id = deploy_tree_id.to_port_tree_id_0();
+        if !self.may_send(deploy_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })? {
+            return Err(CellagentError::MayNotSend { func_name: _f, cell_id: self.cell_id, tree_id: deploy_tree_id }.into());
+        }
+        let payload = app_msg.get_payload();
+        let mode = payload.get_mode();
+        let msg = RedeployMsg::new(self.cell_id, originator_id, deploy_tree_id, &tree_map,
+                                   payload.get_manifest(), mode);
+        {
+            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {   // Debug
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_redeploy_app_msg" };
+                let trace = json!({ "cell_id": &self.cell_id, "deploy_tree_id": deploy_tree_id, "mode": mode, "msg": msg.value() });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        match mode {
+            DeployMode::Replace => {
+                let mask = self.get_mask(deploy_port_tree_id)?;
+                self.send_msg(line!(), deploy_tree_id, msg, mask.or(Mask::port0())).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send redeploy" })?;
+            },
+            DeployMode::Rolling => {
+                // Like a deploy, the root doesn't run what it sends down the tree, so its children upgrade first
+                self.forward_rolling_redeploy(&msg).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " rolling redeploy" })?;
+            }
+        }
+        Ok(())
+    }
+    pub fn app_undeploy(&mut self, app_msg: &AppUndeployMsg, originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "app_undeploy";
+        let deploy_tree_name = app_msg.get_deploy_tree_name();
+        let deploy_tree_id = self.tree_from_name(originator_id, deploy_tree_name).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+        let deploy_port_tree_id = deploy_tree_id.to_port_tree_id_0();
+        if !self.may_send(deploy_port_tree_id).context(CellagentError::Chain { func_name: _f, comment: S("") })? {
+            return Err(CellagentError::MayNotSend { func_name: _f, cell_id: self.cell_id, tree_id: deploy_tree_id }.into());
+        }
+        let msg = UndeployMsg::new(self.cell_id, originator_id, deploy_tree_id, deploy_tree_name,
+                                   app_msg.get_manifest_id());
+        let mask = self.get_mask(deploy_port_tree_id)?;
+        self.send_msg(line!(), deploy_tree_id, msg, mask.or(Mask::port0())).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " send undeploy" })?;
+        self.tenants.lock().unwrap().release_manifest(app_msg.get_manifest_id());
+        Ok(())
+    }
     pub fn app_query(&self, _msg: &AppQueryMsg, _originator_id: OriginatorID) -> Result<MsgTreeMap, Error> {
         let _f = "app_query";
         // Needs may_send test
@@ -1821,12 +2372,20 @@ impl CellAgent {
     }
     pub fn app_stack_tree(&mut self, app_msg: &AppStackTreeMsg, originator_id: OriginatorID) -> Result<(), Error> {
         let _f = "app_stack_tree";
+        self.check_isolation(app_msg, originator_id).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
+        let tenant = self.request_tenant(app_msg, originator_id);
         let parent_tree_name = app_msg.get_target_tree_name();
         let new_tree_name = app_msg.get_new_tree_name();
         let gvm_eqn = app_msg.get_gvm();
         let app_msg_direction = app_msg.get_direction();
         let direction = app_msg_direction.into();
         let new_tree_id = self.my_tree_id.add_component(&new_tree_name.get_name()).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " new_tree_id" })?;
+        // Each cell charges for itself when it joins the tree
+        let is_charged = !self.is_noc_request(app_msg, originator_id);
+        self.tenants.lock().unwrap()
+            .get_mut(&tenant)
+            .and_then(|tenant| tenant.claim_tree(new_tree_name, is_charged))
+            .context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " claim tree" })?;
         self.add_tree_name_map_item(originator_id, new_tree_name, new_tree_id);
         let parent_tree_id = self.tree_from_name(originator_id, parent_tree_name).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
         let parent_entry = self.get_tree_entry(parent_tree_id.to_port_tree_id_0()).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
@@ -1834,10 +2393,10 @@ impl CellAgent {
         let child_ports = new_hashset(&parent_mask.get_port_nos());
         self.child_ports.insert(new_tree_id, child_ports);
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.process_msg {
+            if let Some(event) = tracing(Level::Debug, &["process_msg"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_got_stack_tree_app_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "new_tree_id": new_tree_id, "msg": app_msg });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let parent_port_tree_id = parent_tree_id.to_port_tree_id_0();
@@ -1850,6 +2409,83 @@ impl CellAgent {
         self.send_msg(line!(), self.control_tree_id, stack_tree_msg, Mask::port0())?;
         Ok(())
     }
+    pub fn app_tenant(&mut self, app_msg: &AppTenantMsg, _originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "app_tenant";
+        let payload = app_msg.get_payload();
+        let tenant_id = self.tenants.lock().unwrap()
+            .get_mut(payload.get_parent_tenant())
+            .and_then(|tenant| tenant.create_subtenant(payload.get_tenant_name(), payload.get_ncells()))
+            .context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " create sub-tenant" })?;
+        {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_create_tenant" };
+                let trace = json!({ "cell_id": &self.cell_id, "tenant_id": tenant_id, "ncells": payload.get_ncells() });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        Ok(())
+    }
+    // Requests that don't name a tenant are charged to the sender's
+    fn request_tenant(&self, app_msg: &dyn AppMessage, originator_id: OriginatorID) -> String {
+        app_msg.get_tenant()
+            .map(|tenant| S(tenant))
+            .unwrap_or_else(|| self.sender_tenant(originator_id))
+    }
+    fn sender_tenant(&self, originator_id: OriginatorID) -> String {
+        self.originator_tenants
+            .get(&originator_id)
+            .cloned()
+            .unwrap_or_else(|| S(ROOT_TENANT_NAME))
+    }
+    // The NOC's own trees and manifests, those it asks for without naming a tenant, aren't charged
+    fn is_noc_request(&self, app_msg: &dyn AppMessage, originator_id: OriginatorID) -> bool {
+        app_msg.get_tenant().is_none() &&
+            self.border_port_tree_id_map.values().any(|border_id| *border_id == originator_id)
+    }
+    // A sender may only name trees it was given and act for its own tenant or one of that tenant's
+    // sub-tenants.  A tree charged to a tenant is shared only along that tenant's line, so sibling
+    // tenants can't use each other's trees.
+    fn check_isolation(&self, app_msg: &dyn AppMessage, originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "check_isolation";
+        let sender_tenant = self.sender_tenant(originator_id);
+        let tenant = self.request_tenant(app_msg, originator_id);
+        if !is_within(&tenant, &sender_tenant) {
+            self.trace_security_violation(_f, app_msg, originator_id, "tenant");
+            return Err(CellagentError::TenantNotAllowed { func_name: _f, cell_id: self.cell_id, originator_id, tenant }.into());
+        }
+        let trees = std::iter::once(app_msg.get_target_tree_name()).chain(app_msg.get_allowed_trees().iter());
+        for tree in trees {
+            if self.tree_from_name(originator_id, tree).is_err() {
+                self.trace_security_violation(_f, app_msg, originator_id, "tree");
+                return Err(CellagentError::TreeNotAllowed { func_name: _f, cell_id: self.cell_id, originator_id, target_tree: tree.clone() }.into());
+            }
+            let owner = self.tenants.lock().unwrap().find_tree(tree);
+            if let Some((owner, _)) = owner {
+                let owner = owner.get_name();
+                if !is_within(&tenant, &owner) && !is_within(&owner, &tenant) {
+                    self.trace_security_violation(_f, app_msg, originator_id, "tree owner");
+                    return Err(CellagentError::TreeNotAllowed { func_name: _f, cell_id: self.cell_id, originator_id, target_tree: tree.clone() }.into());
+                }
+            }
+        }
+        Ok(())
+    }
+    // Traced as the function that found the violation
+    fn trace_security_violation(&self, _f: &'static str, app_msg: &dyn AppMessage, originator_id: OriginatorID, violation: &str) {
+        {
+            if let Some(event) = tracing(Level::Warn, &["security"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_security_violation" };
+                let trace = json!({ "cell_id": &self.cell_id, "originator_id": originator_id, "violation": violation,
+                    "sender_tenant": self.sender_tenant(originator_id), "msg_type": app_msg.get_msg_type(),
+                    "target_tree": app_msg.get_target_tree_name(), "allowed_trees": app_msg.get_allowed_trees() });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+    }
+    pub fn app_placement(&self, _msg: &AppPlacementMsg, _originator_id: OriginatorID) -> Result<(), Error> {
+        let _f = "app_placement";
+        Err(CellagentError::AppMessageType { func_name: _f, cell_id: self.cell_id, msg: AppMsgType::AppPlacementMsg }.into())
+    }
     pub fn app_tree_name(&self, _msg: &AppTreeNameMsg, _originator_id: OriginatorID) -> Result<(), Error> {
         let _f = "app_tree_name";
         Err(CellagentError::AppMessageType { func_name: _f, cell_id: self.cell_id, msg: AppMsgType::AppTreeNameMsg }.into())
@@ -1871,10 +2507,10 @@ impl CellAgent {
     fn port_connected(&mut self, port_no: PortNo, is_border: bool) -> Result<(), Error> {
         let _f = "port_connected";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_connected" };
                 let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "is_border": is_border });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_number = port_no.make_port_number(self.no_ports)?;
@@ -1961,6 +2597,7 @@ impl CellAgent {
                                                 rw_port_tree_id, lw_port_tree_id,
                                                 broken_path, &broken_port_tree_ids);
             println!("Cellagent {}: {} candidate parent for tree {} is port {}", self.cell_id, _f, rw_traph.get_base_tree_id(), *trial_parent_port);
+            self.metrics.lock().unwrap().failover_started();
             self.send_msg(line!(), self.connected_tree_id, failover_msg, mask).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) })?;
         } else {
             println!("Cellagent {}: {} no candidate parent found for tree {}", self.cell_id, _f, rw_traph.get_base_tree_id())
@@ -1969,14 +2606,40 @@ impl CellAgent {
         Ok(())
     }
     // Added line_no parameter for debugging purposes
+    // Forged and replayed messages are dropped without acting on them
+    fn authenticate(&mut self, port_no: PortNo, bytes: &ByteArray, header: &MsgHeader) -> bool {
+        let _f = "authenticate";
+        let cell_id = self.cell_id;
+        let authenticator = match self.authenticator.as_mut() {
+            Some(authenticator) => authenticator,
+            None => return true
+        };
+        match authenticator.verify(header, bytes) {
+            Ok(()) => true,
+            Err(e) => {
+                {
+                    if let Some(event) = tracing(Level::Warn, &["security"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_drop_unauthenticated" };
+                        let trace = json!({ "cell_id": &cell_id, "port_no": port_no, "sending_cell_id": header.get_sending_cell_id(),
+                            "originator_id": header.get_originator_id(), "msg_type": header.get_msg_type(), "error": S(e) });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                false
+            }
+        }
+    }
     fn send_msg<T: Message>(&self, line_no: u32, tree_id: TreeID, msg: T, user_mask: Mask) -> Result<(), Error>
         where T: Message + Sized + serde::Serialize
     {
         let _f = "send_msg";
         let seq_no = msg.get_sender_msg_seq_no();
-        let bytes = msg.to_bytes()?;
+        let bytes = match &self.authenticator {
+            Some(authenticator) => authenticator.sign(&msg).context(CellagentError::Chain { func_name: _f, comment: S(self.cell_id) + " sign" })?,
+            None => msg.to_bytes()?
+        };
         {
-            if CONFIG.debug_options.all || CONFIG.debug_options.ca_msg_send {
+            if let Some(event) = tracing(Level::Debug, &["ca_msg_send"]) {
                 let mask = self.get_mask(tree_id.to_port_tree_id_0())?;
                 let port_mask = user_mask.and(mask);
                 let port_nos = Mask::get_port_nos(port_mask);
@@ -1984,9 +2647,10 @@ impl CellAgent {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_send_msg" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": &tree_id,
                     "neighbors": neighbors, "length": bytes.len(), "msg": msg });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
+        self.metrics.lock().unwrap().msg_sent(msg.get_msg_type());
         self.send_bytes(line_no, tree_id, msg.is_control(), msg.is_ait(), msg.is_snake(), user_mask, seq_no, bytes).map_err(Error::from)
     }
     fn send_bytes(&self, line_no: u32, tree_id: TreeID, 
@@ -1999,19 +2663,18 @@ impl CellAgent {
             .get(&tree_uuid)
             .ok_or::<Error>(CellagentError::Tree { func_name: _f, cell_id: self.cell_id, tree_uuid }.into())?;
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca ||
-                    CONFIG.debug_options.all || CONFIG.debug_options.ca_msg_send {
+            if let Some(event) = tracing(Level::Info, &["ca", "ca_msg_send", "replay"]) {
                 let port_nos = user_mask.get_port_nos();
                 let neighbors = port_nos.iter()
                     .map(|port_no| self.neighbors.get(&port_no))
                     .filter(|neighbor| neighbor.is_some() )
                     .map(|neighbor| neighbor.unwrap().0.get_name())
                     .collect::<Vec<_>>();
-                let msg: Box<dyn Message> = serde_json::from_str(&bytes.stringify()?).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
+                let msg: Box<dyn Message> = wire::decode(&bytes).context(CellagentError::Chain { func_name: _f, comment: S("") })?;
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "ca_to_cm_bytes" };
                 let trace = json!({ "cell_id": &self.cell_id, "tree_id": &tree_id,
                 "neighbors": neighbors, "sending line": line_no, "msg": msg });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let msg = CaToCmBytes::Bytes((tree_id, is_control, is_ait, is_snake, user_mask, seq_no, bytes));
@@ -2069,6 +2732,8 @@ pub enum CellagentError {
 //    NoParentTraph { cell_id: CellID, func_name: &'static str, port_no: u8 },
     #[fail(display = "CellAgentError::NameMap {}: Sender {} on cell {} has no name to tree entry for {}", func_name, originator_id, cell_id, tree_name)]
     NameMap { func_name: &'static str, cell_id: CellID, tree_name: AllowedTree, originator_id: OriginatorID },
+    #[fail(display = "CellAgentError::NoBorder {}: Cell {} has no border port to reach the NOC", func_name, cell_id)]
+    NoBorder { func_name: &'static str, cell_id: CellID },
     #[fail(display = "CellAgentError::NoTraph {}: A Traph with TreeID {} does not exist on cell {}", func_name, tree_id, cell_id)]
     NoTraph { cell_id: CellID, func_name: &'static str, tree_id: TreeID },
 //    #[fail(display = "CellagentError::SavedMsgType {}: Message type {} does not support saving", func_name, msg_type)]
@@ -2081,6 +2746,8 @@ pub enum CellagentError {
     StackTree { func_name: &'static str, tree_id: PortTreeID, cell_id: CellID },
 //    #[fail(display = "CellAgentError::TenantMask {}: Cell {} has no tenant mask", func_name, cell_id)]
 //    TenantMask { func_name: &'static str, cell_id: CellID },
+    #[fail(display = "CellAgentError::TenantNotAllowed {}: Sender {} may not act for tenant {} on cell {}", func_name, originator_id, tenant, cell_id)]
+    TenantNotAllowed { func_name: &'static str, cell_id: CellID, originator_id: OriginatorID, tenant: String },
     #[fail(display = "CellAgentError::TreeNameMap {}: Cell {} has no tree name map entry for {}", func_name, cell_id, originator_id)]
     TreeNameMap { func_name: &'static str, cell_id: CellID, originator_id: OriginatorID },
     #[fail(display = "CellAgentError::TreeMap {}: Cell {} has no tree map entry for {} for sender {}", func_name, cell_id, tree_id, originator_id)]
@@ -2089,6 +2756,8 @@ pub enum CellagentError {
     TreeNotAllowed { func_name: &'static str, cell_id: CellID, originator_id: OriginatorID, target_tree: AllowedTree },
     #[fail(display = "CellAgentError::Tree {}: TreeID {} does not exist on cell {}", func_name, tree_uuid, cell_id)]
     Tree { func_name: &'static str, cell_id: CellID, tree_uuid: Uuid },
+    #[fail(display = "CellAgentError::Vm {}: No VM {} on cell {}", func_name, vm_id, cell_id)]
+    Vm { func_name: &'static str, cell_id: CellID, vm_id: VmID },
 //    #[fail(display = "CellAgentError::TreeUuid {}: No tree associated with uuid {:?} on cell {}", func_name, uuid, cell_id)]
 //    TreeUuid { func_name: &'static str, uuid: Uuid, cell_id: CellID },
     #[fail(display = "CellAgentError::TreeVmMap {} Cell {} has no tree map entry for {}", func_name, cell_id, tree_id)]
@@ -11,19 +11,21 @@ use std::{fmt, fmt::Write,
 
 use failure::{Error, ResultExt};
 
-use crate::config::{CONFIG};
+use crate::config::{CONFIG, PACKET_DEFAULT, PACKET_MAX};
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
 use crate::ec_message::MsgType;
 use crate::ec_message_formats::{CaToCmBytes, CmToCa, CmFromCa, CmToPe, CmFromPe, PeToCm, PeFromCm, 
                                 PeToPort, PeFromPort,
-                                PeToPortOld, PeFromPortOld, 
-                                PeToCmPacketOld, CmToPePacket, CmToCaBytesOld};
+                                PeToCmPacket, CmToPePacket, CmToCaBytes};
+use crate::metrics::count_thread;
 use crate::name::{Name, CellID, TreeID};
 use crate::packet_engine::{PacketEngine};
 use crate::packet::{Packet, PacketAssembler, PacketAssemblers, Packetizer, PacketUniquifier};
 use crate::snake::Snake;
-use crate::utility::{ByteArray, PortNo, S, TraceHeader, TraceHeaderParams, TraceType, write_err};
+use crate::trace_filter::{Level, tracing, is_tracing};
+use crate::utility::{ByteArray, PortNo, S, TraceHeader, TraceHeaderParams, write_err};
 use crate::uuid_ec::AitState;
+use crate::wire;
 
 #[derive(Debug, Clone)]
 pub struct Cmodel {
@@ -40,12 +42,11 @@ impl Cmodel {
     // NEW
     pub fn new(cell_id: CellID, connected_tree_id: TreeID, pe_to_cm: PeToCm, cm_to_ca: CmToCa,
                pe_from_ports: PeFromPort, pe_to_ports: HashMap<PortNo, PeToPort>,
-               pe_from_ports_old: PeFromPortOld, pe_to_ports_old: HashMap<PortNo, PeToPortOld>,
                border_port_nos: &HashSet<PortNo>, 
                cm_to_pe: CmToPe, pe_from_cm: PeFromCm) -> (Cmodel, JoinHandle<()>) {
         let packet_engine = PacketEngine::new(cell_id, connected_tree_id,
-                                              pe_to_cm, pe_to_ports, pe_to_ports_old, &border_port_nos);
-        let pe_join_handle = packet_engine.start(pe_from_cm, pe_from_ports, pe_from_ports_old);
+                                              pe_to_cm, pe_to_ports, &border_port_nos);
+        let pe_join_handle = packet_engine.start(pe_from_cm, pe_from_ports);
         (Cmodel { cell_id,
                   packet_engine,
                   packet_assemblers: PacketAssemblers::new(),
@@ -58,10 +59,10 @@ impl Cmodel {
     pub fn start(&self, cm_from_ca: CmFromCa, cm_from_pe: CmFromPe) -> JoinHandle<()> {
         let _f = "start";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.nal {
+            if let Some(event) = tracing(Level::Info, &["nal"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "nalcell_start_cmodel" };
                 let trace = json!({ "cell_id": self.get_cell_id() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let mut cm = self.clone();
@@ -69,6 +70,7 @@ impl Cmodel {
         let thread_name = format!("Cmodel {}", self.get_name());
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(*cm.get_cell_id());
             let _ = cm.initialize(cm_from_ca.clone(), cm_from_pe.clone()).map_err(|e| write_err("cmodel", &e));
             if CONFIG.continue_on_error { cm.start(cm_from_ca, cm_from_pe); } 
         }).expect("cmodel thread failed")
@@ -79,11 +81,11 @@ impl Cmodel {
     pub fn initialize(&mut self, cm_from_ca: CmFromCa, cm_from_pe: CmFromPe) -> Result<(), Error> {
         let _f = "initialize";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+            if let Some(event) = tracing(Level::Info, &["cm"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": &self.cell_id, "thread_name": thread::current().name(), 
                     "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.listen(cm_from_ca, cm_from_pe)?;
@@ -112,63 +114,83 @@ impl Cmodel {
             // just forward to PE
             CaToCmBytes::Reroute((broken_port, new_parent, number_of_packets)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm", "visualize"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_reroute" };
                         let trace = json!({ "cell_id": &self.cell_id, "broken_port": broken_port, "new_parent": new_parent, "no_packets": number_of_packets });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.cm_to_pe.send(CmToPePacket::Reroute((broken_port, new_parent, number_of_packets)))?;
             },
+            CaToCmBytes::PacketSize((port_no, packet_size)) => {
+                {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_packet_size" };
+                        let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "packet_size": packet_size });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.cm_to_pe.send(CmToPePacket::PacketSize((port_no, packet_size)))?;
+            },
+            CaToCmBytes::TreeWeight((uuid, weight)) => {
+                {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_tree_weight" };
+                        let trace = json!({ "cell_id": &self.cell_id, "uuid": uuid, "weight": weight });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.cm_to_pe.send(CmToPePacket::TreeWeight((uuid, weight)))?;
+            },
             CaToCmBytes::Delete(uuid) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_delete" };
                         let trace = json!({ "cell_id": &self.cell_id, "uuid": uuid });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.cm_to_pe.send(CmToPePacket::Delete(uuid))?;
             },
             CaToCmBytes::Entry(entry) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_entry" };
                         let trace = json!({ "cell_id": &self.cell_id, "entry": entry });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.cm_to_pe.send(CmToPePacket::Entry(entry))?;
             },
             CaToCmBytes::Status((port_no, is_border, no_packets, status)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_ca_status" };
                         let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "is_border": is_border, "status": status });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.cm_to_ca.send(CmToCaBytesOld::Status((port_no, is_border, no_packets, status)))?;
+                self.cm_to_ca.send(CmToCaBytes::Status((port_no, is_border, no_packets, status)))?;
             }
             CaToCmBytes::TunnelPort(tunnel_msg) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_ca_tunnel_port" };
-                        let trace = json!({ "cell_id": &self.cell_id, "tunnel_msg": tunnel_msg.1.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        let trace = json!({ "cell_id": &self.cell_id, "tunnel_msg": wire::to_json(&tunnel_msg.1)? });
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.cm_to_ca.send(CmToCaBytesOld::TunnelPort(tunnel_msg))?;
+                self.cm_to_ca.send(CmToCaBytes::TunnelPort(tunnel_msg))?;
             }
             CaToCmBytes::TunnelUp(tunnel_msg) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_ca_tunnel_up" };
-                        let trace = json!({ "cell_id": &self.cell_id, "tunnel_msg": tunnel_msg.1.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        let trace = json!({ "cell_id": &self.cell_id, "tunnel_msg": wire::to_json(&tunnel_msg.1)? });
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.cm_to_ca.send(CmToCaBytesOld::TunnelUp(tunnel_msg))?;
+                self.cm_to_ca.send(CmToCaBytes::TunnelUp(tunnel_msg))?;
             }
         
             // packetize
@@ -178,23 +200,31 @@ impl Cmodel {
                 if is_control { uuid.make_control(); }
                 if is_ait { uuid.make_ait(); }
                 if is_snake { uuid.make_snake(); }
-                let packets = Packetizer::packetize(&uuid, seq_no, &bytes).context(CmodelError::Chain { func_name: _f, comment: S("") })?;
+                let packets = Packetizer::packetize(&uuid, seq_no, &bytes, PACKET_MAX).context(CmodelError::Chain { func_name: _f, comment: S("") })?;
                 let first = packets.get(0).expect("No packets from packetizer");
                 let dpi_is_ait = first.is_ait();
                 let sender_msg_seq_no = first.get_unique_msg_id();
                 let packet_count = first.get_count();
                 {
-                    if CONFIG.debug_options.all || CONFIG.debug_options.cm_from_ca {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_packetize" };
+                        let trace = json!({ "cell_id": &self.cell_id, "wire_format": wire::format_of(&bytes)?,
+                            "msg_bytes": bytes.len(), "num_packets": Packetizer::num_packets(bytes.len(), PACKET_DEFAULT) }); // On a link of the default size, since the packet engine fragments to fit each link
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                {
+                    if is_tracing(Level::Debug, &["cm_from_ca"]) {
                         println!("Cmodel {}: {} packetize - is_ait {} sender_msg_seq_no {} count {}", self.cell_id, _f, dpi_is_ait, *sender_msg_seq_no, packet_count);
                     }
                 }
                 for packet in packets {
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                        if let Some(event) = tracing(Level::Info, &["cm"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_pe_packet" };
                             let trace = json!({ "cell_id": &self.cell_id, "user_mask": user_mask, "is_ait": is_ait, "is_snake": is_snake,
                                 "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
                     self.cm_to_pe.send(CmToPePacket::Packet((user_mask, packet)))?;
@@ -205,30 +235,30 @@ impl Cmodel {
     }
 
     // WORKER (CmFromPe)
-    fn listen_pe(&mut self, packet: PeToCmPacketOld) -> Result<(), Error> {
+    fn listen_pe(&mut self, packet: PeToCmPacket) -> Result<(), Error> {
         let _f = "listen_pe";
         match packet {
             // just forward to CA
-            PeToCmPacketOld::Status((port_no, is_border, number_of_packets, status)) => {
+            PeToCmPacket::Status((port_no, is_border, number_of_packets, status)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_ca_status" };
                         let trace = json!({ "cell_id": &self.cell_id, "port": port_no, "is_border": is_border, "no_packets": number_of_packets, "status": status});
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 if !CONFIG.replay {
-                    self.cm_to_ca.send(CmToCaBytesOld::Status((port_no, is_border, number_of_packets, status)))?;
+                    self.cm_to_ca.send(CmToCaBytes::Status((port_no, is_border, number_of_packets, status)))?;
                 }
             },
         
             // de-packetize
-            PeToCmPacketOld::Packet((port_no, packet)) => {
+            PeToCmPacket::Packet((port_no, packet)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                    if let Some(event) = tracing(Level::Info, &["cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_pe_packet" };
                         let trace = json!({ "cell_id": self.cell_id, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 if packet.get_ait_state() == AitState::SnakeD {
@@ -242,10 +272,10 @@ impl Cmodel {
                             let snake = snake_entry.get_mut();
                             let new_count = snake.decrement_count();
                             {
-                                if CONFIG.trace_options.all || CONFIG.trace_options.snake {
+                                if let Some(event) = tracing(Level::Info, &["snake"]) {
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_pe_snaked" };
                                     let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "new_count": new_count, "no_snakes": snakes_len, "snake": snake });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
                         if new_count == 0 {
@@ -262,7 +292,7 @@ impl Cmodel {
                     self.process_packet(port_no, packet)?;
                 }
             },
-            PeToCmPacketOld::Snake((ack_port_no, count, packet)) => {
+            PeToCmPacket::Snake((ack_port_no, count, packet)) => {
                 let uniquifier = packet.get_uniquifier();
                 if count > 0 {
                     let snake = Snake::new(ack_port_no, count, packet);
@@ -274,10 +304,10 @@ impl Cmodel {
                     self.cm_to_pe.send(CmToPePacket::SnakeD((ack_port_no, snaked_packet)))?;
                 }
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.snake {
+                    if let Some(event) = tracing(Level::Info, &["snake"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_from_pe_snake" };
                         let trace = json!({ "cell_id": &self.cell_id, "uniquifier": uniquifier, "count": count, "ack_port_no": ack_port_no, "no_snakes": self.snakes.len() });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
             }
@@ -308,25 +338,25 @@ packets: Vec<Packet>,
         let is_ait = packets[0].is_ait();
         let uuid = packet.get_tree_uuid();
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+            if let Some(event) = tracing(Level::Info, &["cm"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_packet_assembly" };
                 let trace = json!({ "cell_id": &self.cell_id, "port": port_no, 
                     "is_ait": is_ait, "tree_uuid": uuid, "last_packet": last_packet, "packet": packet.stringify()? });
-                add_to_trace(TraceType::Debug, trace_params, &trace, _f); // sender side, dup
+                add_to_trace(event, trace_params, &trace, _f); // sender side, dup
             }
         }
         if last_packet {
             let bytes = Packetizer::unpacketize(&packets).context(CmodelError::Chain { func_name: _f, comment: S("") })?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.cm {
+                if let Some(event) = tracing(Level::Info, &["cm"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "cm_to_ca_bytes" };
                     let trace = json!({ "cell_id": &self.cell_id, "port": port_no, 
-                        "is_ait": is_ait, "tree_uuid": uuid, "bytes": bytes.stringify()? });
-                    add_to_trace(TraceType::Debug, trace_params, &trace, _f); // sender side, dup
+                        "is_ait": is_ait, "tree_uuid": uuid, "bytes": wire::to_json(&bytes)? });
+                    add_to_trace(event, trace_params, &trace, _f); // sender side, dup
                 }
             }
             {
-                if CONFIG.debug_options.all || CONFIG.debug_options.cm_to_ca {
+                if is_tracing(Level::Debug, &["cm_to_ca"]) {
                     let packet_count = packets[0].get_count();
                     let dpi_msg = MsgType::msg_from_bytes(&bytes)?;
                     let dpi_msg_type = dpi_msg.get_msg_type();
@@ -338,7 +368,7 @@ packets: Vec<Packet>,
                     }
                 }
             }
-            let msg = CmToCaBytesOld::Bytes((port_no, is_ait, uuid, bytes));
+            let msg = CmToCaBytes::Bytes((port_no, is_ait, uuid, bytes));
             if !CONFIG.replay {
                 self.cm_to_ca.send(msg)?;
             }
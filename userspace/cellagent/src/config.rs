@@ -6,7 +6,7 @@ use std;
 use std::{fmt,
           collections::HashMap,
           env::args,
-          fs::{OpenOptions, create_dir, remove_dir_all},
+          fs::{create_dir, read_to_string, remove_dir_all},
           path::Path,
           ops::{Deref}};
 
@@ -14,8 +14,12 @@ use itertools::Itertools;
 use lazy_static::lazy_static;
 
 #[cfg(any(feature = "simulator"))]
+use crate::auth::AuthKey;
 use crate::rack::{EdgeConnection};
-use crate::utility::{CellConfig, CellNo, Edge, PortNo, Quench, S};
+use crate::trace_filter::TraceFilter;
+use crate::trace_sink::TraceSinkConfig;
+use crate::utility::{CellConfig, CellNo, Edge, OutbufType, PortNo, Quench, S};
+use crate::wire::WireFormat;
 
 pub type MaskType = u16;
 pub const MASK_MAX: u16 = MaskType::max_value();
@@ -28,9 +32,11 @@ pub const CONTROL_TREE_NAME: & str = "Control";
 pub const CONNECTED_PORTS_TREE_NAME: & str = "Connected";
 pub const BASE_TREE_NAME: & str = "Base";
 pub const PAYLOAD_DEFAULT_ELEMENT: u8 = 0;
-pub const PACKET_MIN: usize = 72;  // Must be at least NON_PAYLOAD_SIZE.  Can't be in Config because I use it as a const in packet.rs
-pub const PACKET_MAX: usize = 256; // Can't be in Config because I use it as a const in packet.rs
-pub const PACKET_PADDING: usize = 40; // Can't be in Config because I use it as a const in packet.rs
+// Each link negotiates its packet size in Hello; a Packet holds only the payload bytes in use
+pub const PACKET_MIN: usize = 72;  // Shortest frame on any link; must be at least NON_PAYLOAD_SIZE
+pub const PACKET_MAX: usize = 9000; // Largest packet any link can carry, e.g., a jumbo link
+pub const PACKET_DEFAULT: usize = 256; // Packet size of a link until Hello settles it
+pub const PACKET_PADDING: usize = 40; // Room for the packet fields not counted in NON_PAYLOAD_SIZE
 
 lazy_static!{
     pub static ref CONFIG: Config = Config::new().expect("Error in config file");
@@ -60,9 +66,17 @@ pub struct Config {
     pub edge_list: Vec<Edge>,
     pub geometry: Vec<(usize, usize)>,
     pub race_sleep: u64,
-    pub trace_options: TraceOptions,
-    pub debug_options: DebugOptions,
+    pub trace_filters: Vec<TraceFilter>, // Records at or above the level for the module, cell, tree, and message type
     pub replay: bool,
+    pub auth_keys: Option<HashMap<CellNo, AuthKey>>, // Cells sign the messages they send when present
+    pub wire_format: Option<WireFormat>, // Json when not present
+    pub packet_sizes: Option<HashMap<CellNo, usize>>, // Largest packet each cell's links take, PACKET_DEFAULT if not listed
+    pub traffic_classes: Option<Vec<OutbufType>>, // Order in which a port's outbufs drain, Control, Ait, Message if not present
+    pub tree_weights: Option<HashMap<String, usize>>, // Packets per turn for trees with these names, 1 if not listed
+    pub metrics_address: Option<String>, // Serve metrics at http://<address>/metrics when present
+    pub trace_sinks: Option<Vec<TraceSinkConfig>>, // Where trace records go, File (and Http with the webserver feature) if not present
+    #[serde(skip)]
+    pub config_hash: String, // Of the config file, for the header of each trace file
 }
 impl Config {
     pub fn new() -> Result<Config, Error> {
@@ -72,14 +86,15 @@ impl Config {
             .next()
             .unwrap_or(S("configs/10cell_config.json"));
         println!("\nReading configuratation from {}", config_file_name);
-        let config_file = match OpenOptions::new().read(true).open(config_file_name) {
-            Ok(f) => f,
+        let config_text = match read_to_string(config_file_name) {
+            Ok(text) => text,
             Err(e) => {
                 println!("Config: {} Error {}", _f, e);
                 return Err(e.into())
             }
         };
-        let mut config: Config = serde_json::from_reader(config_file)?;//.context(ConfigError::Chain { func_name: _f, comment: S("") })?;
+        let mut config: Config = serde_json::from_str(&config_text)?;//.context(ConfigError::Chain { func_name: _f, comment: S("") })?;
+        config.config_hash = Config::hash(&config_text);
         if *config.num_cells == 0 {
             let (nr, nc) = (config.nrows, config.ncols);
             config.num_cells = CellQty(nr*nc);
@@ -104,6 +119,37 @@ impl Config {
         create_dir(&config.output_dir_name)?;
         Ok(config)
     }
+    // FNV-1a, so the hash is the same from one build to the next
+    fn hash(config_text: &str) -> String {
+        let hash = config_text.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{:016x}", hash)
+    }
+    // The link between two cells uses the smaller of their packet sizes
+    pub fn get_packet_size(&self, cell_name: &str) -> usize {
+        let packet_size = self.packet_sizes
+            .iter()
+            .flat_map(|packet_sizes| packet_sizes.iter())
+            .find(|(cell_no, _)| format!("{}", cell_no) == cell_name)
+            .map(|(_, &packet_size)| packet_size)
+            .unwrap_or(PACKET_DEFAULT);
+        packet_size.max(PACKET_MIN).min(PACKET_MAX)
+    }
+    // Classes left out of the configured order drain last, so no packet is stranded
+    pub fn get_traffic_classes(&self) -> Vec<OutbufType> {
+        let mut traffic_classes = self.traffic_classes.clone().unwrap_or_default();
+        for outbuf_type in vec![OutbufType::Control, OutbufType::Ait, OutbufType::Message] {
+            if !traffic_classes.contains(&outbuf_type) { traffic_classes.push(outbuf_type); }
+        }
+        traffic_classes
+    }
+    pub fn get_tree_weight(&self, tree_name: &str) -> Option<usize> {
+        self.tree_weights
+            .as_ref()
+            .and_then(|tree_weights| tree_weights.get(tree_name))
+            .map(|&weight| weight.max(1))
+    }
     fn make_edges(nr: usize, nc: usize) -> Vec<Edge> {
         (0..nr).fold(vec![], |mut edges, r| {
             let along_row = ((r*nc)..((r+1)* nc-1))
@@ -128,55 +174,6 @@ impl Config {
         })
     }
 }
-// TODO: Use log crate for this
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TraceOptions {
-    pub all:       bool,
-    pub dc:        bool,
-    pub entl:      bool,
-    pub nal:       bool,
-    pub noc:       bool,
-    pub svc:       bool,
-    pub vm:        bool,
-    pub ca:        bool,
-    pub cm:        bool,
-    pub pe:        bool,
-    pub pe_cm:     bool,
-    pub pe_port:   bool,
-    pub port:      bool,
-    pub link:      bool,
-    pub replay:    bool,
-    pub snake:     bool,
-    pub visualize: bool,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DebugOptions {
-    pub all:            bool,
-    pub flow_control:   bool,
-    pub ca_msg_recv:    bool,
-    pub ca_msg_send:    bool,
-    pub cm_from_ca:     bool,
-    pub cm_to_ca:       bool,
-    pub cm_from_pe:     bool,
-    pub cm_to_pe:       bool,
-    pub application:    bool,
-    pub deploy:         bool,
-    pub discover:       bool,
-    pub discoverd:      bool,
-    pub discover_done:  bool,
-    pub enough_ports:   bool,
-    pub hello:          bool,
-    pub manifest:       bool,
-    pub pe_pkt_recv:    bool,
-    pub pe_pkt_send:    bool,
-    pub process_msg:    bool,
-    pub pe_process_pkt: bool,
-    pub port:           bool,
-    pub saved_msgs:     bool,
-    pub stack_tree:     bool,
-    pub traph_entry:    bool,
-}
 // Size of various fields
 #[derive(Debug, Copy, Clone, Default, Eq, Hash, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct CellQty(pub usize);
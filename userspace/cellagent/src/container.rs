@@ -9,28 +9,54 @@ use failure::Error;
 use crate::app_message_formats::{ContainerToVm, ContainerFromVm};
 use crate::name::{CellID, ContainerID, UptreeID};  // CellID for tracing purposes
 use crate::service::{Service};
-use crate::uptree_spec::AllowedTree;
+use crate::tree_key::TreeKeys;
+use crate::uptree_spec::{AllowedTree, RestartPolicy};
+
+// Reported by each container to its VM, which passes it on to the cell agent
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ContainerStatus {
+    Starting,
+    Running,
+    Failed,
+    Exited, // Stopped by an undeploy
+}
+impl fmt::Display for ContainerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            ContainerStatus::Starting => "Starting",
+            ContainerStatus::Running  => "Running",
+            ContainerStatus::Failed   => "Failed",
+            ContainerStatus::Exited   => "Exited",
+        };
+        write!(f, "{}", s)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Container {
     cell_id: CellID,
     id: ContainerID,
     allowed_trees: HashSet<AllowedTree>,
+    image_version: Option<String>, // None for an untagged image
     service: Service,
 }
 impl Container {
-    pub fn new(cell_id: CellID, id: ContainerID, service_name: &str, allowed_trees: &HashSet<AllowedTree>,
-               container_to_vm: ContainerToVm) -> Result<Container, Error> {
+    pub fn new(cell_id: CellID, id: ContainerID, service_name: &str, image_version: Option<&str>, allowed_trees: &HashSet<AllowedTree>,
+               tree_keys: &TreeKeys, restart_policy: Option<RestartPolicy>, container_to_vm: ContainerToVm) -> Result<Container, Error> {
         //println!("Create container {}", id);
-        let service = Service::new( cell_id, id, service_name, allowed_trees, container_to_vm)?;
-        Ok(Container { cell_id, id, allowed_trees: allowed_trees.to_owned(), service })
+        let service = Service::new( cell_id, id, service_name, allowed_trees, tree_keys, restart_policy, container_to_vm)?;
+        Ok(Container { cell_id, id, allowed_trees: allowed_trees.to_owned(), image_version: image_version.map(|v| v.to_owned()), service })
     }
+    pub fn get_image_version(&self) -> Option<&str> { self.image_version.as_deref() }
     pub fn initialize(&self, up_tree_id: UptreeID, container_from_vm: ContainerFromVm) -> Result<(), Error> {
         self.service.initialize(up_tree_id, container_from_vm,)
     }
 }
 impl fmt::Display for Container {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: Service {}", self.id, self.service)
+        match &self.image_version {
+            Some(version) => write!(f, "{}: Service {} version {}", self.id, self.service, version),
+            None => write!(f, "{}: Service {}", self.id, self.service)
+        }
     }
 }
@@ -0,0 +1,139 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{fmt, fmt::Write,
+          collections::HashMap};
+
+use serde_json::Value;
+
+use crate::packet::Packet;
+use crate::utility::PortNo;
+use crate::uuid_ec::Uuid;
+
+// What the packet engine has done on each port and for each tree, so hot links and
+// misbehaving trees show up without digging through the traces
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct PortCounters {
+    packets_in: usize,
+    bytes_in: usize,
+    packets_out: usize,
+    bytes_out: usize,
+    drops: usize, // Packets from the link that went nowhere
+    retransmits: usize, // Packets sent again here after another port's link broke
+    queue_depth: usize, // Packets waiting for credits now
+    max_queue_depth: usize, // Most packets waiting for credits at one time
+}
+impl PortCounters {
+    pub fn get_packets_in(&self) -> usize { self.packets_in }
+    pub fn get_bytes_in(&self) -> usize { self.bytes_in }
+    pub fn get_packets_out(&self) -> usize { self.packets_out }
+    pub fn get_bytes_out(&self) -> usize { self.bytes_out }
+    pub fn get_drops(&self) -> usize { self.drops }
+    pub fn get_retransmits(&self) -> usize { self.retransmits }
+    pub fn get_queue_depth(&self) -> usize { self.queue_depth }
+    pub fn get_max_queue_depth(&self) -> usize { self.max_queue_depth }
+}
+impl fmt::Display for PortCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {} ({} bytes), out {} ({} bytes), drops {}, retransmits {}, max queue {}",
+               self.packets_in, self.bytes_in, self.packets_out, self.bytes_out,
+               self.drops, self.retransmits, self.max_queue_depth)
+    }
+}
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct TreeCounters {
+    forwarded: usize, // Packets put on a port
+    to_cm: usize, // Packets delivered to the cell agent
+}
+impl TreeCounters {
+    pub fn get_forwarded(&self) -> usize { self.forwarded }
+    pub fn get_to_cm(&self) -> usize { self.to_cm }
+}
+impl fmt::Display for TreeCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "forwarded {}, to cm {}", self.forwarded, self.to_cm)
+    }
+}
+#[derive(Debug, Clone, Default)]
+pub struct PacketEngineCounters {
+    ports: Vec<PortCounters>, // Indexed by port number
+    trees: HashMap<Uuid, TreeCounters>,
+}
+impl PacketEngineCounters {
+    pub fn new(num_ports: usize) -> PacketEngineCounters {
+        PacketEngineCounters { ports: vec![Default::default(); num_ports], trees: HashMap::new() }
+    }
+    pub fn get_port(&self, port_no: PortNo) -> Option<&PortCounters> { self.ports.get(port_no.as_usize()) }
+    // Ports that have seen traffic
+    pub fn get_ports(&self) -> Vec<(PortNo, &PortCounters)> {
+        self.ports
+            .iter()
+            .enumerate()
+            .skip(1) // Port 0 is the cell agent
+            .filter(|(_, port)| port.packets_in + port.packets_out > 0)
+            .map(|(port_no, port)| (PortNo(port_no as u8), port))
+            .collect()
+    }
+    pub fn get_tree(&self, uuid: &Uuid) -> Option<&TreeCounters> { self.trees.get(uuid) }
+    pub fn get_trees(&self) -> &HashMap<Uuid, TreeCounters> { &self.trees }
+    fn port_mut(&mut self, port_no: PortNo) -> Option<&mut PortCounters> { self.ports.get_mut(port_no.as_usize()) }
+    fn tree_mut(&mut self, packet: &Packet) -> &mut TreeCounters {
+        self.trees.entry(packet.get_tree_uuid().for_lookup()).or_default()
+    }
+    pub fn packet_in(&mut self, port_no: PortNo, packet: &Packet) {
+        if let Some(port) = self.port_mut(port_no) {
+            port.packets_in += 1;
+            port.bytes_in += packet.get_frame_len();
+        }
+    }
+    pub fn packet_out(&mut self, port_no: PortNo, packet: &Packet) {
+        if let Some(port) = self.port_mut(port_no) {
+            port.packets_out += 1;
+            port.bytes_out += packet.get_frame_len();
+        }
+        self.tree_mut(packet).forwarded += 1;
+    }
+    pub fn packet_to_cm(&mut self, packet: &Packet) {
+        self.tree_mut(packet).to_cm += 1;
+    }
+    pub fn dropped(&mut self, port_no: PortNo) {
+        if let Some(port) = self.port_mut(port_no) { port.drops += 1; }
+    }
+    pub fn retransmitted(&mut self, port_no: PortNo, no_packets: usize) {
+        if let Some(port) = self.port_mut(port_no) { port.retransmits += no_packets; }
+    }
+    pub fn queue_depth(&mut self, port_no: PortNo, depth: usize) {
+        if let Some(port) = self.port_mut(port_no) {
+            port.queue_depth = depth;
+            port.max_queue_depth = port.max_queue_depth.max(depth);
+        }
+    }
+    // Tree UUIDs don't serialize as strings, so they can't be JSON keys
+    pub fn to_json(&self) -> Value {
+        let ports = self.ports
+            .iter()
+            .enumerate()
+            .skip(1) // Port 0 is the cell agent
+            .map(|(port_no, port)| (port_no.to_string(), json!(port)))
+            .collect::<serde_json::Map<_, _>>();
+        let trees = self.trees
+            .iter()
+            .map(|(uuid, tree)| (uuid.to_string(), json!(tree)))
+            .collect::<serde_json::Map<_, _>>();
+        json!({ "ports": ports, "trees": trees })
+    }
+}
+impl fmt::Display for PacketEngineCounters {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = String::from("\nPort counters");
+        for (port_no, port) in self.ports.iter().enumerate().skip(1) {
+            if port.packets_in + port.packets_out > 0 { write!(s, "\n  {:2} {}", port_no, port)?; }
+        }
+        s = s + "\nTree counters";
+        for (uuid, tree) in &self.trees {
+            write!(s, "\n  {} {}", uuid, tree)?;
+        }
+        write!(_f, "{}", s)
+    }
+}
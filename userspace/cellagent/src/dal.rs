@@ -5,39 +5,42 @@
 // This file contains hacks that represent functions of the DAL.
 // which will be replaced by actual distributed storage algorithms.
 use std::{cell::RefCell,
-          collections::{HashSet},
-          fs::{File, OpenOptions},
-          io::{BufReader, Lines, Write},
-          sync::atomic::{AtomicBool}
-};
-#[cfg(feature="webserver")]
-use {
-    actix_rt::{System, SystemRunner},
-    actix_web::client::{ClientBuilder},
-    futures::executor::block_on,
-    std::sync::atomic::Ordering
+          fs::{File},
+          io::{BufReader},
+          panic,
+          sync::atomic::{AtomicBool, Ordering},
+          thread,
+          time::Duration
 };
 
+use ec_trace_reader::{TraceFileHeader, TraceReader};
+
 //use futures::Future; // Needed for rdkafka
+use crossbeam::crossbeam_channel as mpsc;
 use lazy_static::lazy_static;
 //use rdkafka::{config::ClientConfig, producer::{FutureProducer, FutureRecord}};
 use serde_json;
 use serde_json::{Value};
 
 use crate::config::{CONFIG};
-use crate::utility::{S, TraceHeader, TraceHeaderParams, TraceType, write_err};
-
-const FOR_EVAL: bool = true;
-
-// TODO: Integrate with log crate
+use crate::trace_filter::TraceEvent;
+use crate::trace_sink::{TraceSink, TraceSinkConfig, get_ring_buffer, make_trace_sink, write_ring_buffer};
+use crate::utility::{S, TraceHeader, TraceHeaderParams, write_err};
 
 #[cfg(feature="webserver")]
 lazy_static! {
-    static ref SERVER_URL: String = ::std::env::var("SERVER_URL").expect("Environment variable SERVER_URL not set");
+    // Where the trace server listens for trace streams
+    static ref TRACE_STREAM: String = ::std::env::var("TRACE_STREAM").unwrap_or(S("127.0.0.1:9899"));
 }
 
+const TRACE_QUEUE: usize = 10_000; // Records waiting for the trace writer before tracing threads wait too
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1); // Longest the trace writer waits before flushing the sinks
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5); // Longest flush_trace waits for the trace writer
+
 lazy_static! {
-    static ref SERVER_ERROR: AtomicBool = AtomicBool::new(false);
+    // The sinks are opened with the first trace record and belong to the trace writer's thread after
+    // that, so threads tracing at the same time don't wait on each other for a lock
+    static ref TRACE_WRITER: mpsc::Sender<TraceLine> = start_trace_writer();
 /*
     static ref PRODUCER_RD: FutureProducer = ClientConfig::new()
                         .set("bootstrap.servers", &CONFIG.kafka_server)
@@ -47,17 +50,110 @@ lazy_static! {
 */
 }
 
-#[cfg(feature="webserver")]
-thread_local!{ static SYSTEM: RefCell<SystemRunner> = RefCell::new(System::new("Tracer")); }
-thread_local!{ static SKIP: RefCell<HashSet<String>> = RefCell::new(HashSet::new()); }
+static TRACE_STARTED: AtomicBool = AtomicBool::new(false); // So flushing doesn't start the trace writer
+
 thread_local!{ static TRACE_HEADER: RefCell<TraceHeader> = RefCell::new(TraceHeader::new()) }
 
+// The file sink unless the config says otherwise, plus a stream to the trace server when there is one
+fn default_trace_sinks() -> Vec<TraceSinkConfig> {
+    #[cfg(feature="webserver")]
+    return vec![TraceSinkConfig::File, TraceSinkConfig::Tcp { address: TRACE_STREAM.clone() }];
+    #[cfg(not(feature="webserver"))]
+    return vec![TraceSinkConfig::File];
+}
+fn trace_file_header() -> TraceFileHeader {
+    let start_time = TRACE_HEADER.with(|t| t.borrow().starting_epoch());
+    TraceFileHeader::new(&CONFIG.config_hash, start_time)
+}
+fn make_trace_sinks() -> Vec<Box<dyn TraceSink>> {
+    let file_header = trace_file_header();
+    CONFIG.trace_sinks
+        .clone()
+        .unwrap_or_else(default_trace_sinks)
+        .iter()
+        .filter_map(|config| make_trace_sink(config, &CONFIG.output_dir_name, &CONFIG.output_file_name, &file_header)
+            .map_err(|e| write_err("Dal", &e))
+            .ok())
+        .collect()
+}
+enum TraceLine {
+    Record { cell_name: String, format: String, line: String },
+    Flush(mpsc::Sender<()>),
+}
+fn start_trace_writer() -> mpsc::Sender<TraceLine> {
+    let sinks = make_trace_sinks();
+    let (trace_writer, lines) = mpsc::bounded(TRACE_QUEUE);
+    let _ = thread::Builder::new().name(S("TraceWriter")).spawn(move || write_trace(sinks, lines))
+        .map_err(|e| write_err("Dal: start_trace_writer", &e.into()));
+    TRACE_STARTED.store(true, Ordering::SeqCst);
+    trace_writer
+}
+// Flushes when it has been idle for a while, so no record waits long in a buffer
+fn write_trace(mut sinks: Vec<Box<dyn TraceSink>>, lines: mpsc::Receiver<TraceLine>) {
+    loop {
+        match lines.recv_timeout(FLUSH_INTERVAL) {
+            Ok(TraceLine::Record { cell_name, format, line }) => {
+                for sink in sinks.iter_mut() {
+                    let _ = sink.write_record(&cell_name, &format, &line).map_err(|e| write_err("Dal: write_trace", &e));
+                }
+            },
+            Ok(TraceLine::Flush(done)) => {
+                flush_sinks(&mut sinks);
+                let _ = done.send(());
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => flush_sinks(&mut sinks),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return flush_sinks(&mut sinks)
+        }
+    }
+}
+fn flush_sinks(sinks: &mut Vec<Box<dyn TraceSink>>) {
+    for sink in sinks.iter_mut() {
+        let _ = sink.flush().map_err(|e| write_err("Dal: flush_trace", &e));
+    }
+}
+// Buffered records are lost if the process exits without this; returns when the trace writer
+// has caught up
+pub fn flush_trace() {
+    if !TRACE_STARTED.load(Ordering::SeqCst) { return; }
+    let (done, flushed) = mpsc::bounded(1);
+    if TRACE_WRITER.send(TraceLine::Flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+// Flushes the trace when a thread panics, and when dropped, so main should hold it until it
+// returns, error or not
+pub struct TraceGuard {}
+impl TraceGuard {
+    pub fn new() -> TraceGuard {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |panic_info| {
+            flush_trace();
+            dump_ring_buffer();
+            default_hook(panic_info);
+        }));
+        TraceGuard {}
+    }
+}
+impl Drop for TraceGuard {
+    fn drop(&mut self) { flush_trace(); }
+}
+// The ring buffer sink holds the latest records for a post mortem, so write them out
+fn dump_ring_buffer() {
+    if get_ring_buffer().is_empty() { return; }
+    let file_name = format!("{}{}-ring_buffer.json", CONFIG.output_dir_name, CONFIG.output_file_name);
+    let _ = serde_json::to_string(&trace_file_header())
+        .map_err(Error::from)
+        .and_then(|file_header| write_ring_buffer(&file_name, &file_header))
+        .map(|num_records| eprintln!("Dal: Wrote the last {} trace records to {}", num_records, file_name))
+        .map_err(|e| write_err("Dal: dump_ring_buffer", &e));
+}
 pub fn fork_trace_header() -> TraceHeader { TRACE_HEADER.with(|t| t.borrow_mut().fork_trace()) }
 pub fn update_trace_header(child_trace_header: TraceHeader) { TRACE_HEADER.with(|t| *t.borrow_mut() = child_trace_header); }
 
-pub fn add_to_trace(trace_type: TraceType, trace_params: &TraceHeaderParams,
+pub fn add_to_trace(event: TraceEvent, trace_params: &TraceHeaderParams,
                     trace_body: &Value, caller: &str) {
     let _f = "add_to_trace";
+    if !event.accepts(trace_body) { return; }
     let other = json!({"name": "Other"});
     let cell_id = trace_body
         .get("cell_id")
@@ -66,33 +162,16 @@ pub fn add_to_trace(trace_type: TraceType, trace_params: &TraceHeaderParams,
         .unwrap()
         .as_str()
         .unwrap();
-    // Mac Finder replaces ":" with "/" which is obviously bad for filenames in the shell
-    let cell_file_name = format!("{}{}-{}.json", CONFIG.output_dir_name, CONFIG.output_file_name, str::replace(cell_id, ":", "-"));
-    let cell_file_handle = OpenOptions::new().append(true).open(cell_file_name.clone())
-        .or_else(|_| { File::create(cell_file_name) }).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()));
-    let output_file_name = format!("{}/{}.json", CONFIG.output_dir_name, CONFIG.output_file_name);
-    let file_handle = OpenOptions::new().append(true).open(output_file_name.clone())
-        .or_else(|_| { File::create(output_file_name) }).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()));
     TRACE_HEADER.with(|t| {
-        t.borrow_mut().next(trace_type);
+        t.borrow_mut().next(event.get_trace_type());
         t.borrow_mut().update(trace_params);
     });
     let trace_header = TRACE_HEADER.with(|t| t.borrow().clone());
     let trace_record = TraceRecord { header: &trace_header, body: trace_body };
-    #[cfg(feature="webserver")]
-    let _ = trace_it(&trace_record).map_err(|e| write_err(&format!("Dal: {}", caller), &e));
-    let line = if FOR_EVAL {
-        serde_json::to_string(&trace_record).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()))
-    } else {
-        Ok(format!("{:?}", &trace_record))
-    };
+    let line = serde_json::to_string(&trace_record).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()));
     if let Ok(line) = line {
-        if let Ok(mut c) = cell_file_handle {
-            let _ = c.write_all(&(line.clone() + ",\n").into_bytes()).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()));
-        };
-        if let Ok(mut f) = file_handle {
-            let _ = f.write_all(&(line.clone() + ",\n").into_bytes()).map_err(|e| write_err(&format!("Dal: {}", caller), &e.into()));
-        }
+        let record = TraceLine::Record { cell_name: S(cell_id), format: S(trace_header.format()), line };
+        let _ = TRACE_WRITER.send(record).map_err(|_| write_err(&format!("Dal: {}", caller), &DalError::Closed { func_name: _f }.into()));
     };
 /*
     let _ = PRODUCER_RD.send(FutureRecord::to(&CONFIG.kafka_topic)
@@ -108,53 +187,21 @@ pub fn add_to_trace(trace_type: TraceType, trace_params: &TraceHeaderParams,
         });
 */
 }
-pub fn get_cell_replay_lines(cell_name: &str) -> Result<Lines<BufReader<File>>, Error> {
-    let _f = "get_cell_replay_lines";
-    let dir_name = format!("{}-replay", &CONFIG.output_dir_name[..CONFIG.output_dir_name.len()-1]);
-    let file_name = if CONFIG.replay {
-        format!("{}/{}-{}.json", dir_name, CONFIG.output_file_name, cell_name)
+pub fn get_cell_replay_records(cell_name: &str) -> Result<TraceReader<BufReader<File>>, Error> {
+    let _f = "get_cell_replay_records";
+    let cell_file_name = if CONFIG.replay {
+        get_cell_replay_file_name(cell_name)
     } else {
         S("/dev/null")
     };
-    let cell_file_name = str::replace(&file_name, ":", "-");
-    let cell_file_handle = OpenOptions::new().read(true).open(cell_file_name.clone()).context(DalError::Replay { func_name: _f, file_name: cell_file_name, cell_name: S(cell_name) })?;
-    let reader = BufReader::new(cell_file_handle);
-    Ok(reader.lines())
-}
-#[cfg(feature = "webserver")]
-fn trace_it(trace_record: &TraceRecord<'_>) -> Result<(), Error> {
-    let _f = "trace_it";
-    if SERVER_ERROR.load(Ordering::SeqCst) { return Ok(()); }
-    let header = trace_record.header;
-    let format = header.format();
-    let server_url = format!("{}/{}", *SERVER_URL, format);
-    let server_url_clone = server_url.clone(); // So I can print as part of en error response
-    let value = serde_json::to_value(trace_record)?;
-    SKIP.with(|skip| {
-        block_on(
-            async {
-                let mut s = skip.borrow_mut();
-                if !s.contains(format) {
-                    let client_builder = ClientBuilder::new();
-                    let client = client_builder.disable_timeout().finish();
-                    if let Ok(response) = 
-                        client
-                            .post(server_url)
-                            .send_json(&value)
-                            .await
-                    {
-                        if !response.status().is_success() {
-                            if response.status() != 404 {
-                                println!("Error {}: {:?}", server_url_clone, response);
-                            }
-                                s.insert(format.to_owned());
-                            }
-                    };
-                }
-            }
-        );
-    });
-    Ok(())
+    let trace_reader = TraceReader::open(&cell_file_name).context(DalError::Replay { func_name: _f, file_name: cell_file_name.clone(), cell_name: S(cell_name) })?;
+    Ok(trace_reader)
+}
+// Copy the output directory of the run, e.g., trace/, to trace-replay/ to replay it
+pub fn get_cell_replay_file_name(cell_name: &str) -> String {
+    let dir_name = format!("{}-replay", &CONFIG.output_dir_name[..CONFIG.output_dir_name.len()-1]);
+    let file_name = format!("{}/{}-{}.json", dir_name, CONFIG.output_file_name, cell_name);
+    str::replace(&file_name, ":", "-")
 }
 #[derive(Debug, Clone, Serialize)]
 struct TraceRecord<'a> {
@@ -163,12 +210,13 @@ struct TraceRecord<'a> {
 }
 // Errors
 use failure::{Error, ResultExt};
-use std::io::BufRead;
 
 #[derive(Debug, Fail)]
 pub enum DalError {
     #[fail(display = "DalError::Chain {}: {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
+    #[fail(display = "DalError::Closed {}: The trace writer has stopped", func_name)]
+    Closed { func_name: &'static str },
     #[fail(display = "DalError::Kafka {}: Error {} producing trace record", func_name, kafka_error)]
     Kafka { func_name: &'static str, kafka_error: String },
     #[fail(display = "DalError::Replay {}: Error opening replay file {} on cell {}", func_name, file_name, cell_name)]
@@ -8,12 +8,14 @@ use std::{collections::{HashMap}, fmt};
 
 use crate::app_message_formats::{ApplicationNocMsg, NocToApplicationMsg};
 use crate::blueprint::{Blueprint, Cell};
-use crate::config::CONFIG;
+use crate::config::{CONFIG, CellQty};
 use crate::dal::{add_to_trace};
 use crate::noc::{DuplexNocPortChannel, Noc, NocToPort, NocFromPort};
 use crate::rack::{Rack};
 use crate::simulated_border_port::{PortFromNoc, PortToNoc, DuplexPortNocChannel};
-use crate::utility::{CellNo, PortNo, S, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, TraceEvent};
+use crate::uptree_spec::{AllowedTree, DeployMode, Manifest};
+use crate::utility::{CellNo, PortNo, S, TraceHeaderParams};
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct CellBorderConnection {
@@ -50,6 +52,8 @@ impl fmt::Display for DuplexApplicationNocChannel {
 #[derive(Debug)]
 pub struct Datacenter {
     rack: Rack,
+    noc: Noc,
+    noc_to_port: NocToPort, // To the border cell the NOC is connected to
 }
 impl Datacenter {
     pub fn construct(blueprint: Blueprint) -> Result<Datacenter, Error> {
@@ -59,7 +63,7 @@ impl Datacenter {
             { 
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "reset" };
                 let trace = json!({ "cell_id": {"name": "Datacenter"}, "blueprint": blueprint, "config": *CONFIG});
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(TraceEvent::unfiltered(Level::Info), trace_params, &trace, _f);
             }
         }
         let mut cell_border_connection_list = Vec::<CellBorderConnection>::new(); // This is not used, but analogous with edge case.
@@ -94,7 +98,7 @@ impl Datacenter {
         {
             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "border_cell" };
             let trace = json!({ "cell_id": {"name": "Rack"}, "cell_no": noc_border_cell_no});
-            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+            add_to_trace(TraceEvent::unfiltered(Level::Info), trace_params, &trace, _f);
         }
         let noc_border_port_no = noc_border_port_map[&noc_border_cell_no];
         if CONFIG.replay {
@@ -106,10 +110,32 @@ impl Datacenter {
         let mut noc = Noc::new(duplex_noc_port_channel_cell_port_map).context(DatacenterError::Chain { func_name: _f, comment: S("Noc::new")})?;
         noc.initialize(&blueprint).context(DatacenterError::Chain { func_name: "initialize", comment: S("")})?;
         println!("NOC created and initialized");
-        Ok(Datacenter { rack })
+        let noc_to_port = noc.get_noc_to_port(noc_border_cell_no, noc_border_port_no)
+            .expect("Datacenter: NOC must be connected to its border cell")
+            .clone();
+        Ok(Datacenter { rack, noc, noc_to_port })
     }
     pub fn get_rack(&self) -> &Rack { &self.rack }
     pub fn get_rack_mut(&mut self) -> &mut Rack { &mut self.rack }
+    pub fn get_noc(&self) -> &Noc { &self.noc }
+    pub fn deploy(&self, manifest: &Manifest) -> Result<(), Error> {
+        self.noc.deploy(manifest, &self.noc_to_port)
+    }
+    pub fn undeploy(&self, manifest_id: &str, deploy_tree: &AllowedTree) -> Result<(), Error> {
+        self.noc.undeploy(manifest_id, deploy_tree, &self.noc_to_port)
+    }
+    pub fn redeploy(&self, manifest: &Manifest, mode: DeployMode) -> Result<(), Error> {
+        self.noc.redeploy(manifest, mode, &self.noc_to_port)
+    }
+    pub fn create_tenant(&self, parent_tenant: &str, tenant_name: &str, ncells: CellQty) -> Result<(), Error> {
+        self.noc.create_tenant(parent_tenant, tenant_name, ncells, &self.noc_to_port)
+    }
+}
+// The cells keep running after the datacenter is gone, so cut their links to keep them off the CPU
+impl Drop for Datacenter {
+    fn drop(&mut self) {
+        for link in self.rack.get_links().values() { link.cut(); }
+    }
 }
 
 // Errors
@@ -11,13 +11,15 @@ use serde_json;
 
 use crate::app_message::{SenderMsgSeqNo, AppMsgDirection, AppInterapplicationMsg, get_next_count};
 use crate::cellagent::{CellAgent};
-use crate::config::{CellQty, PathLength};
+use crate::config::{CONFIG, CellQty, PathLength};
 use crate::gvm_equation::{GvmEquation};
 use crate::name::{CellID, PortTreeID, OriginatorID, TreeID};
 use crate::packet::{Packet, Packetizer, Serializer};
 use crate::packet_engine::NumberOfPackets;
-use crate::uptree_spec::{AllowedTree, Manifest};
-use crate::utility::{ByteArray, Path, PortNo, S};
+use crate::tree_key::WrappedTreeKeys;
+use crate::uptree_spec::{AllowedTree, DeployMode, Manifest};
+use crate::utility::{ByteArray, Path, PlacementReport, PortNo, S};
+use crate::wire;
 
 pub type MsgTreeMap = HashMap<String, TreeID>; // Must be String for serialization
 
@@ -34,9 +36,12 @@ pub enum MsgType {
     Hello,
     Interapplication,
     Manifest,
+    Placement,
+    Redeploy,
     StackTree,
     StackTreeD,
-    TreeName
+    TreeName,
+    Undeploy
 }
 impl MsgType {
     // Used for debug hack in packet_engine
@@ -44,14 +49,12 @@ impl MsgType {
         let _f = "get_msg";
         let bytes = Packetizer::unpacketize(packets).context(MessageError::Chain { func_name: _f, comment: S("unpacketize")})?;
         //println!("Message get_msg: serialized {}, packets {:?}", serialized, packets);
-        let serialized = bytes.stringify()?;
-        let msg = serde_json::from_str(&serialized)?;
+        let msg = wire::decode(&bytes).context(MessageError::Chain { func_name: _f, comment: S("decode")})?;
         Ok(msg)
     }
     pub fn msg_from_bytes(bytes: &ByteArray) -> Result<Box<dyn Message>, Error> {
         let _f = "msg_from_bytes";
-        let serialized = bytes.stringify()?;
-        let msg = serde_json::from_str(&serialized)?;
+        let msg = wire::decode(bytes).context(MessageError::Chain { func_name: _f, comment: S("")})?;
         Ok(msg)
     }
     // A hack for printing debug output only for a specific message type
@@ -70,9 +73,12 @@ impl MsgType {
         else if MsgType::is_type(packet, MsgType::FailoverD)   { MsgType::FailoverD }
         else if MsgType::is_type(packet, MsgType::Hello)       { MsgType::Hello }
         else if MsgType::is_type(packet, MsgType::Manifest)    { MsgType::Manifest }
+        else if MsgType::is_type(packet, MsgType::Placement)   { MsgType::Placement }
+        else if MsgType::is_type(packet, MsgType::Redeploy)    { MsgType::Redeploy }
         else if MsgType::is_type(packet, MsgType::StackTree)   { MsgType::StackTree }
         else if MsgType::is_type(packet, MsgType::StackTreeD)  { MsgType::StackTreeD }
         else if MsgType::is_type(packet, MsgType::TreeName)    { MsgType::TreeName }
+        else if MsgType::is_type(packet, MsgType::Undeploy)    { MsgType::Undeploy }
         else { MsgType::Entl }
     }
 }
@@ -90,9 +96,12 @@ impl fmt::Display for MsgType {
             MsgType::Hello             => "Hello",
             MsgType::Interapplication  => "Interapplication",
             MsgType::Manifest          => "Manifest",
+            MsgType::Placement         => "Placement",
+            MsgType::Redeploy          => "Redeploy",
             MsgType::StackTree         => "StackTree",
             MsgType::StackTreeD        => "StackTreeD",
             MsgType::TreeName          => "TreeName",
+            MsgType::Undeploy          => "Undeploy",
         };
         write!(f, "{}", s)
     }
@@ -140,8 +149,8 @@ pub trait Message {
     fn get_originator_id(&self) -> OriginatorID { self.get_header().get_originator_id() }
     fn to_bytes(&self) -> Result<ByteArray, Error> where Self: serde::Serialize + Sized {
         let _f = "to_bytes";
-        let serialized = Serializer::serialize(self).context(MessageError::Chain { func_name: _f, comment: S("")})?;
-        Ok(ByteArray::new(&serialized))
+        let bytes = Serializer::serialize(self).context(MessageError::Chain { func_name: _f, comment: S("")})?;
+        Ok(bytes)
     }
     fn process_ca(&mut self, _cell_agent: &mut CellAgent, _port_no: PortNo,
                   _msg_tree_id: PortTreeID, _is_ait: bool) -> Result<(), Error>;
@@ -165,6 +174,10 @@ pub struct MsgHeader {
     msg_type: MsgType,
     direction: MsgDirection,
     tree_map: MsgTreeMap,
+    #[serde(default)]
+    signed_seq_no: Option<u64>, // Set with the signature from the sending cell's own count
+    #[serde(default)]
+    signature: Option<String>, // Set by the sending cell when authentication is on
 }
 impl MsgHeader {
     pub fn new(sending_cell_id: CellID, originator_id: OriginatorID, 
@@ -173,7 +186,7 @@ impl MsgHeader {
         let msg_count = get_next_count();
         MsgHeader { sending_cell_id, originator_id, 
             is_control, is_ait, is_snake, msg_type, direction,
-            sender_msg_seq_no: msg_count, tree_map: tree_map.clone() }
+            sender_msg_seq_no: msg_count, tree_map: tree_map.clone(), signed_seq_no: None, signature: None }
     }
     pub fn get_msg_type(&self) -> MsgType { self.msg_type }
     pub fn get_sending_cell_id(&self) -> CellID { self.sending_cell_id }
@@ -185,6 +198,8 @@ impl MsgHeader {
     pub fn get_snake(&self) -> bool { self.is_snake }
     pub fn _get_direction(&self) -> MsgDirection { self.direction }
     pub fn get_tree_map(&self) -> &MsgTreeMap { &self.tree_map }
+    pub fn get_signed_seq_no(&self) -> Option<u64> { self.signed_seq_no }
+    pub fn get_signature(&self) -> Option<&str> { self.signature.as_ref().map(|signature| signature.as_str()) }
 }
 impl fmt::Display for MsgHeader { 
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { 
@@ -537,6 +552,7 @@ impl HelloMsg {
     pub fn get_payload(&self) -> &HelloMsgPayload { &self.payload }
     pub fn get_cell_id(&self) -> CellID { self.payload.get_cell_id() }
     pub fn get_port_no(&self) -> PortNo { self.payload.get_port_no() }
+    pub fn get_packet_size(&self) -> Option<usize> { self.payload.get_packet_size() }
 }
 #[typetag::serde]
 impl Message for HelloMsg {
@@ -555,14 +571,18 @@ impl Message for HelloMsg {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HelloMsgPayload {
     cell_id: CellID,
-    port_no: PortNo
+    port_no: PortNo,
+    #[serde(default)]
+    packet_size: Option<usize> // Largest packet I take on this link; None from cells that don't say
 }
 impl HelloMsgPayload {
     fn new(cell_id: CellID, port_no: PortNo) -> HelloMsgPayload {
-        HelloMsgPayload { cell_id, port_no }
+        let packet_size = Some(CONFIG.get_packet_size(&cell_id.get_name()));
+        HelloMsgPayload { cell_id, port_no, packet_size }
     }
     fn get_cell_id(&self) -> CellID { self.cell_id }
     fn get_port_no(&self) -> PortNo { self.port_no }
+    fn get_packet_size(&self) -> Option<usize> { self.packet_size }
 }
 #[typetag::serde]
 impl MsgPayload for HelloMsgPayload {}
@@ -807,33 +827,46 @@ impl ManifestMsg {
     }
     pub fn get_payload(&self) -> &ManifestMsgPayload { &self.payload }
     pub fn _get_port_tree_id(&self) -> PortTreeID { self.payload._get_port_tree_id() }
+    pub fn with_tenant(mut self, tenant: &str) -> ManifestMsg { self.payload.tenant = Some(S(tenant)); self }
+    pub fn with_tree_keys(mut self, tree_keys: Option<WrappedTreeKeys>) -> ManifestMsg { self.payload.tree_keys = tree_keys; self }
 }
 #[typetag::serde]
 impl Message for ManifestMsg {
     fn get_header(&self) -> &MsgHeader { &self.header }
     fn get_payload(&self) -> &dyn MsgPayload { &self.payload }
     fn get_msg_type(&self) -> MsgType { self.get_header().msg_type }
+    // Traces get the names of the cells the keys are wrapped for, since the keys are too big to be worth showing
     fn value(&self) -> serde_json::Value {
-        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+        let mut msg = self.clone();
+        let cell_names = msg.payload.tree_keys.take().map(|tree_keys| tree_keys.get_cell_names().into_iter().cloned().collect::<Vec<_>>());
+        let mut value = serde_json::to_value(msg).expect("I don't know how to handle errors in msg.value()");
+        value["payload"]["tree_keys"] = json!(cell_names);
+        value
     }
     fn process_ca(&mut self, cell_agent: &mut CellAgent, port_no: PortNo,
                   msg_port_tree_id: PortTreeID, _is_ait: bool) -> Result<(), Error> {
         cell_agent.process_manifest_msg(&self, port_no, msg_port_tree_id)
     }
 }
-#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ManifestMsgPayload {
     deploy_port_tree_id: PortTreeID,
     tree_name: AllowedTree,
-    manifest: Manifest 
+    manifest: Manifest,
+    #[serde(default)]
+    tenant: Option<String>, // Owner of the containers deployed from this manifest
+    #[serde(default)]
+    tree_keys: Option<WrappedTreeKeys>, // For sealing payloads sent on the manifest's trees
 }
 impl ManifestMsgPayload {
     fn new(deploy_port_tree_id: PortTreeID, manifest: &Manifest) -> ManifestMsgPayload {
         let tree_name = manifest.get_deployment_tree();
         ManifestMsgPayload { deploy_port_tree_id, tree_name: tree_name.clone(),
-            manifest: manifest.clone() }
+            manifest: manifest.clone(), tenant: None, tree_keys: None }
     }
     pub fn get_manifest(&self) -> &Manifest { &self.manifest }
+    pub fn get_tenant(&self) -> Option<&str> { self.tenant.as_ref().map(|tenant| tenant.as_str()) }
+    pub fn get_tree_keys(&self) -> Option<&WrappedTreeKeys> { self.tree_keys.as_ref() }
     pub fn get_deploy_port_tree_id(&self) -> PortTreeID { self.deploy_port_tree_id }
     pub fn _get_port_tree_id(&self) -> PortTreeID { self.deploy_port_tree_id }
 }
@@ -846,6 +879,168 @@ impl fmt::Display for ManifestMsgPayload {
     }
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementMsg {
+    header: MsgHeader,
+    payload: PlacementMsgPayload
+}
+impl PlacementMsg {
+    pub fn new(sending_cell_id: CellID, originator_id: OriginatorID, deploy_tree_id: TreeID,
+               deploy_tree_name: &AllowedTree, report: &PlacementReport) -> PlacementMsg {
+        // Note that direction is rootward so the cell agent at the root of the deployment tree gets the message
+        let header = MsgHeader::new(sending_cell_id, originator_id,
+                                    false, false, false, HashMap::new(),
+                                    MsgType::Placement, MsgDirection::Rootward);
+        let payload = PlacementMsgPayload::new(deploy_tree_id.to_port_tree_id_0(), deploy_tree_name, report);
+        PlacementMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &PlacementMsgPayload { &self.payload }
+}
+#[typetag::serde]
+impl Message for PlacementMsg {
+    fn get_header(&self) -> &MsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn MsgPayload { &self.payload }
+    fn get_msg_type(&self) -> MsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&mut self, cell_agent: &mut CellAgent, port_no: PortNo,
+                  _msg_port_tree_id: PortTreeID, _is_ait: bool) -> Result<(), Error> {
+        cell_agent.process_placement_msg(&self, port_no)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementMsgPayload {
+    deploy_port_tree_id: PortTreeID,
+    tree_name: AllowedTree,
+    report: PlacementReport
+}
+impl PlacementMsgPayload {
+    fn new(deploy_port_tree_id: PortTreeID, tree_name: &AllowedTree, report: &PlacementReport) -> PlacementMsgPayload {
+        PlacementMsgPayload { deploy_port_tree_id, tree_name: tree_name.clone(), report: report.clone() }
+    }
+    pub fn get_deploy_port_tree_id(&self) -> PortTreeID { self.deploy_port_tree_id }
+    pub fn get_tree_name(&self) -> &AllowedTree { &self.tree_name }
+    pub fn get_report(&self) -> &PlacementReport { &self.report }
+}
+#[typetag::serde]
+impl MsgPayload for PlacementMsgPayload {}
+impl fmt::Display for PlacementMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("Placement: {}", self.report);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedeployMsg {
+    header: MsgHeader,
+    payload: RedeployMsgPayload
+}
+impl RedeployMsg {
+    pub fn new(sending_cell_id: CellID, originator_id: OriginatorID, deploy_tree_id: TreeID,
+               tree_map: &MsgTreeMap, manifest: &Manifest, mode: DeployMode) -> RedeployMsg {
+        // A rolling upgrade goes hop by hop so each cell finishes before its children start
+        let is_control = mode == DeployMode::Rolling;
+        let header = MsgHeader::new(sending_cell_id, originator_id,
+                                    is_control, false, false, tree_map.clone(),
+                                    MsgType::Redeploy, MsgDirection::Leafward);
+        let payload = RedeployMsgPayload::new(deploy_tree_id.to_port_tree_id_0(), manifest, mode);
+        RedeployMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &RedeployMsgPayload { &self.payload }
+    pub fn update_sender(&self, sending_cell_id: CellID) -> RedeployMsg {
+        let mut msg = self.clone();
+        msg.header.sending_cell_id = sending_cell_id;
+        msg
+    }
+}
+#[typetag::serde]
+impl Message for RedeployMsg {
+    fn get_header(&self) -> &MsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn MsgPayload { &self.payload }
+    fn get_msg_type(&self) -> MsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&mut self, cell_agent: &mut CellAgent, port_no: PortNo,
+                  msg_port_tree_id: PortTreeID, _is_ait: bool) -> Result<(), Error> {
+        cell_agent.process_redeploy_msg(&self, port_no, msg_port_tree_id)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct RedeployMsgPayload {
+    deploy_port_tree_id: PortTreeID,
+    manifest: Manifest,
+    mode: DeployMode
+}
+impl RedeployMsgPayload {
+    fn new(deploy_port_tree_id: PortTreeID, manifest: &Manifest, mode: DeployMode) -> RedeployMsgPayload {
+        RedeployMsgPayload { deploy_port_tree_id, manifest: manifest.clone(), mode }
+    }
+    pub fn get_deploy_port_tree_id(&self) -> PortTreeID { self.deploy_port_tree_id }
+    pub fn get_manifest(&self) -> &Manifest { &self.manifest }
+    pub fn get_mode(&self) -> DeployMode { self.mode }
+}
+#[typetag::serde]
+impl MsgPayload for RedeployMsgPayload {}
+impl fmt::Display for RedeployMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{} redeploy of manifest: {}", self.mode, self.manifest);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UndeployMsg {
+    header: MsgHeader,
+    payload: UndeployMsgPayload
+}
+impl UndeployMsg {
+    pub fn new(sending_cell_id: CellID, originator_id: OriginatorID, deploy_tree_id: TreeID,
+               deploy_tree_name: &AllowedTree, manifest_id: &str) -> UndeployMsg {
+        // Note that direction is leafward so cell agent will get the message
+        let header = MsgHeader::new(sending_cell_id, originator_id,
+                                    false, false, false, HashMap::new(),
+                                    MsgType::Undeploy, MsgDirection::Leafward);
+        let payload = UndeployMsgPayload::new(deploy_tree_id.to_port_tree_id_0(), deploy_tree_name, manifest_id);
+        UndeployMsg { header, payload }
+    }
+    pub fn get_payload(&self) -> &UndeployMsgPayload { &self.payload }
+}
+#[typetag::serde]
+impl Message for UndeployMsg {
+    fn get_header(&self) -> &MsgHeader { &self.header }
+    fn get_payload(&self) -> &dyn MsgPayload { &self.payload }
+    fn get_msg_type(&self) -> MsgType { self.get_header().msg_type }
+    fn value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("I don't know how to handle errors in msg.value()")
+    }
+    fn process_ca(&mut self, cell_agent: &mut CellAgent, port_no: PortNo,
+                  _msg_port_tree_id: PortTreeID, _is_ait: bool) -> Result<(), Error> {
+        cell_agent.process_undeploy_msg(&self, port_no)
+    }
+}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct UndeployMsgPayload {
+    deploy_port_tree_id: PortTreeID,
+    tree_name: AllowedTree,
+    manifest_id: String
+}
+impl UndeployMsgPayload {
+    fn new(deploy_port_tree_id: PortTreeID, tree_name: &AllowedTree, manifest_id: &str) -> UndeployMsgPayload {
+        UndeployMsgPayload { deploy_port_tree_id, tree_name: tree_name.clone(), manifest_id: S(manifest_id) }
+    }
+    pub fn get_deploy_port_tree_id(&self) -> PortTreeID { self.deploy_port_tree_id }
+    pub fn get_tree_name(&self) -> &AllowedTree { &self.tree_name }
+    pub fn get_manifest_id(&self) -> &str { &self.manifest_id }
+}
+#[typetag::serde]
+impl MsgPayload for UndeployMsgPayload {}
+impl fmt::Display for UndeployMsgPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("Undeploy manifest {} on tree {}", self.manifest_id, self.tree_name);
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InterapplicationMsg {
     header: MsgHeader,
     payload: InterapplicationMsgPayload
@@ -10,15 +10,18 @@ use crate::app_message_formats::{ISCONTROL, ISAIT, SNAKE};
 use crate::name::{OriginatorID, TreeID};
 use crate::packet::{Packet};
 use crate::packet_engine::NumberOfPackets;
-use crate::port::{PortStatus, PortStatusOld};
+use crate::link::LinkStatus;
+use crate::port::PortStatus;
 use crate::routing_table_entry::{RoutingTableEntry};
 use crate::utility::{ActivityData, ByteArray, Mask, PortNo, OutbufType};
 use crate::uuid_ec::Uuid;
 
 type CATOCM = (TreeID, ISCONTROL, ISAIT, SNAKE, Mask, SenderMsgSeqNo, ByteArray);
+type PACKETSIZE = (PortNo, usize); // Settled in Hello
 type REROUTE = (PortNo, PortNo, NumberOfPackets);
 type STATUS = (PortNo, bool, PortStatus); // bool = is_border
-type STATUSOLD = (PortNo, bool, NumberOfPackets, PortStatusOld); // bool = is_border
+type LINKSTATUS = (PortNo, bool, NumberOfPackets, LinkStatus); // bool = is_border
+type TREEWEIGHT = (Uuid, usize); // Packets per turn for the tree when it shares a port
 type TUNNELPORT = (PortNo, ByteArray);
 type TUNNELUP = (OriginatorID, ByteArray);
 //pub type PePeError = mpsc::SendError<PeToPePacket>;
@@ -28,8 +31,10 @@ pub enum CaToCmBytes {
     Bytes(CATOCM),
     Delete(Uuid),
     Entry(RoutingTableEntry),
+    PacketSize(PACKETSIZE),
     Reroute(REROUTE),
-    Status(STATUSOLD),
+    Status(LINKSTATUS),
+    TreeWeight(TREEWEIGHT),
     TunnelPort(TUNNELPORT),
     TunnelUp(TUNNELUP),
 }
@@ -42,21 +47,21 @@ pub enum CmToPePacket {
     Delete(Uuid),
     Entry(RoutingTableEntry),
     Packet((Mask, Packet)),
+    PacketSize(PACKETSIZE),
     Reroute(REROUTE),
-    SnakeD((PortNo, Packet))
+    SnakeD((PortNo, Packet)),
+    TreeWeight(TREEWEIGHT),
 }
 pub type CmToPe = mpsc::Sender<CmToPePacket>;
 pub type PeFromCm = mpsc::Receiver<CmToPePacket>;
 //pub type CmPeError = mpsc::SendError<CmToPePacket>;
-// PacketEngine to Port
-pub type PeToPortPacketOld = Packet;
-pub type PeToPortOld = mpsc::Sender<PeToPortPacketOld>;
-pub type PortFromPeOld = mpsc::Receiver<PeToPortPacketOld>;
+// PacketEngine to Port; the port at the other end of the link returns a credit for each packet
+// once its packet engine has taken it, so a slow receiver holds back the sender
 #[derive(Debug, Clone, Serialize)]
 pub enum PeToPortPacket {
     Activity(ActivityData),
+    Credit, // I took the oldest packet this port gave me, so it can return the credit over the link
     Packet((OutbufType, Packet)),
-    Ready
 }
 pub type PeToPort = mpsc::Sender<PeToPortPacket>;
 pub type PortFromPe = mpsc::Receiver<PeToPortPacket>;
@@ -65,38 +70,31 @@ pub type PortFromPe = mpsc::Receiver<PeToPortPacket>;
 #[derive(Debug, Clone, Serialize)]
 pub enum PortToPePacket {
     Activity((PortNo, ActivityData)),
-    Increment((PortNo, OutbufType)),
+    Increment((PortNo, OutbufType)), // Credit from the other end of the link; the outbufs share it, so the type just says who used it
     Packet((PortNo, Packet)),
     Status(STATUS)
 }
 pub type PortToPe = mpsc::Sender<PortToPePacket>;
 pub type PeFromPort = mpsc::Receiver<PortToPePacket>;
-#[derive(Debug, Clone, Serialize)]
-pub enum PortToPePacketOld {
-    Status((PortNo, bool, PortStatusOld)), // bool = is_border
-    Packet((PortNo, Packet))
-}
-pub type PortToPeOld = mpsc::Sender<PortToPePacketOld>;
-pub type PeFromPortOld = mpsc::Receiver<PortToPePacketOld>;
 //pub type PortPeError = mpsc::SendError<PortToPePacket>;
 // PacketEngine to Cmodel
 #[derive(Debug, Clone, Serialize)]
-pub enum PeToCmPacketOld {
-    Status(STATUSOLD),
+pub enum PeToCmPacket {
+    Status(LINKSTATUS),
     Packet((PortNo, Packet)),
     Snake((PortNo, usize, Packet))
 }
-pub type PeToCm = mpsc::Sender<PeToCmPacketOld>;
-pub type CmFromPe = mpsc::Receiver<PeToCmPacketOld>;
+pub type PeToCm = mpsc::Sender<PeToCmPacket>;
+pub type CmFromPe = mpsc::Receiver<PeToCmPacket>;
 //pub type PeCmError = mpsc::SendError<PeToCmPacket>;
 // Cmodel to CellAgent
 #[derive(Debug, Clone, Serialize)]
-pub enum CmToCaBytesOld {
-    Status(STATUSOLD),
+pub enum CmToCaBytes {
+    Status(LINKSTATUS),
     Bytes((PortNo, bool, Uuid, ByteArray)),
     TunnelPort(TUNNELPORT),
     TunnelUp(TUNNELUP),
 }
-pub type CmToCa = mpsc::Sender<CmToCaBytesOld>;
-pub type CaFromCm = mpsc::Receiver<CmToCaBytesOld>;
+pub type CmToCa = mpsc::Sender<CmToCaBytes>;
+pub type CaFromCm = mpsc::Receiver<CmToCaBytes>;
 //pub type CmCaError = mpsc::SendError<CmToCaBytes>;
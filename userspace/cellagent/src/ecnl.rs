@@ -17,14 +17,15 @@ use std::{
     thread,
 };
 
-use crate::config::{CONFIG, PortQty};
+use crate::config::{PortQty};
 use crate::dal::{add_to_trace};
-use crate::ec_message_formats::{PortFromPeOld};
+use crate::ec_message_formats::{PortFromPe};
 use crate::ecnl_port::{ECNL_Port};
 use crate::nalcell::{NalCell};
 use crate::port::{PortSeed, InteriorPortLike};
 use crate::simulated_border_port::{SimulatedBorderPort, SimulatedBorderPortFactory};
-use crate::utility::{PortNo, TraceHeader, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, tracing};
+use crate::utility::{PortNo, TraceHeader, TraceHeaderParams};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -94,10 +95,10 @@ impl ECNL_Session {
     pub fn listen_link_and_pe_loops(&mut self, nalcell: &mut NalCell<PortSeed, ECNL_Port, SimulatedBorderPortFactory, SimulatedBorderPort>) -> Result<(), Error> {
         let _f = "link_ecnl_channels";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.ca {
+            if let Some(event) = tracing(Level::Info, &["ca"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                let _ = add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                let _ = add_to_trace(event, trace_params, &trace, _f);
             }
         }
         #[cfg(feature="cell")]
@@ -17,11 +17,11 @@ use std::{
 use crossbeam::crossbeam_channel as mpsc;
 
 use crate::app_message_formats::{PortToCa};
-use crate::ec_message_formats::{PortToPePacketOld, PortToPeOld};
+use crate::ec_message_formats::{PortToPePacket, PortToPe};
 use crate::name::{PortID, CellID};
-use crate::packet::{Packet};
-use crate::port::{CommonPortLike, InteriorPortLike, BasePort, InteriorPortFactoryLike, PortStatusOld, PortSeed, DuplexPortPeOrCaChannel, DuplexPortPeChannel};
-use crate::utility::{PortNo, PortNumber};
+use crate::packet::{Packet, PacketFrame};
+use crate::port::{CommonPortLike, InteriorPortLike, BasePort, FailoverInfo, InteriorPortFactoryLike, PortStatus, PortSeed, DuplexPortPeOrCaChannel, DuplexPortPeChannel};
+use crate::utility::{OutbufType, PortNo, PortNumber, S};
 
 #[repr(C)]
 enum NL_ECND_Commands {
@@ -51,14 +51,14 @@ enum NL_ECND_Commands {
 #[derive(Debug, Copy, Clone)]
 pub struct InBufferDesc {
     pub len: c_uint,
-    pub frame: *mut Packet,
+    pub frame: *mut PacketFrame,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct OutBufferDesc {
     pub len: c_uint,
-    pub frame: *const Packet,
+    pub frame: *const PacketFrame,
 }
 
 #[repr(C)]
@@ -82,13 +82,13 @@ pub struct ECNL_Port {
 #[link(name = ":ecnl_proto.o")]
 impl InBufferDesc {
      pub fn new() -> InBufferDesc {
-          const len : usize = size_of::<Packet>();
+          const len : usize = size_of::<PacketFrame>();
           let mut ary: Vec<u16> = vec![0; len/2];
 	  for i in 0..len/2 { ary[i] = i as u16; } // might want: i | 0x8080 ?
           let ary_FRAME : *mut u16 = ary.as_mut_ptr();
           const shortened : usize = len;// 1500 + 26; // MTU + ethernet header
 	  unsafe {
-	         let blob_FRAME = std::mem::transmute::<*mut u16, *mut Packet>(ary_FRAME); // magic 'cast'
+	         let blob_FRAME = std::mem::transmute::<*mut u16, *mut PacketFrame>(ary_FRAME); // magic 'cast'
 		 let blob_buf : InBufferDesc = InBufferDesc {
 		     len: shortened as c_uint, // u32
 		     frame: blob_FRAME
@@ -142,9 +142,9 @@ impl ECNL_Port {
              port_do_read_async(self, bdp);
 	     if ((*bdp).frame != null_mut() && (*bdp).len != 0) {
 	         sleep(Duration::from_millis(100));
-	         let packet: &Packet = &*((*bdp).frame);
+	         let packet = Packet::from(&*((*bdp).frame));
                  println!("Received Packet: {}", packet.to_string()); // Probably usually sufficient to print ec_msg_type.
-	         return Some(Ok(*packet));
+	         return Some(Ok(packet));
 	     } else {
 	         return None;
 	     }
@@ -183,18 +183,27 @@ impl CommonPortLike for ECNL_Port {
 
 #[cfg(feature = "cell")]
 impl InteriorPortLike for ECNL_Port {
-     fn send_to_link(self: &mut Self, packet: &mut Packet) -> Result<(), Error> {
+     fn send_to_link(self: &mut Self, outbuf_type: &OutbufType, packet: &mut Packet) -> Result<(), Error> {
+        let _f = "send_to_link";
+        let frame = Box::new(PacketFrame::from(&*packet));
         let bufferDesc: OutBufferDesc = OutBufferDesc {
-	    len: size_of::<Packet>() as c_uint, // Always send fixed-length frames
-	    frame: packet,
+	    len: packet.get_frame_len() as c_uint, // Only the bytes in use; the receive buffer holds the largest packet
+	    frame: &*frame,
 	};
 	println!("Sending Packet: {}", packet.to_string()); // Probably usually sufficient to print ec_msg_type.
         unsafe {
 	    port_do_xmit(self, &bufferDesc)
 	}
+	// The driver holds the receive queue on the other end and has no way to return credits,
+	// so the packet is off my hands as soon as it's sent
+	let port_to_pe = self.get_duplex_port_pe_channel().get_port_to_pe();
+	port_to_pe.send(PortToPePacket::Increment((self.get_port_no(), outbuf_type.clone()))).context(ECNL_PortError::Chain { func_name: _f, comment: S("increment") })?;
 	return Ok(())
     }
-     fn listen_link(self: &mut Self, port_to_pe: &PortToPeOld) -> Result<(), Error> {
+     fn return_credit(self: &mut Self) -> Result<(), Error> {
+         Ok(()) // Credits come back as soon as the driver sends a packet
+     }
+     fn listen_link(self: &mut Self, port_to_pe: &PortToPe) -> Result<(), Error> {
          let _f = "listen_and_forward_to";
          unsafe {
              let ecnl_port_sub = (*(self.ecnl_port_sub_ptr));
@@ -215,7 +224,7 @@ impl InteriorPortLike for ECNL_Port {
                              self.set_disconnected();
                          }
 			 println!("Port {} is {}", ecnl_port_sub.port_id, port_status_name);
-			port_to_pe.send(PortToPePacketOld::Status((PortNo(ecnl_port_sub.port_id), self.base_port.is_border(), if (event.event_up_down != 0) {PortStatusOld::Connected} else {PortStatusOld::Disconnected}))).unwrap();
+			port_to_pe.send(PortToPePacket::Status((PortNo(ecnl_port_sub.port_id), self.base_port.is_border(), if (event.event_up_down != 0) {PortStatus::Connected} else {PortStatus::Disconnected(FailoverInfo::new(self.base_port.get_id()))}))).unwrap();
 		    }
 		    cmd_id if (cmd_id == NL_ECND_Commands::NL_ECNL_CMD_SIGNAL_AIT_MESSAGE as c_int) => {
                         println!("AIT Message Signal Received...");
@@ -224,7 +233,7 @@ impl InteriorPortLike for ECNL_Port {
 			    let possible_packet_or_err: Option<Result<Packet, Error>> = self.retrieve(&mut bd);
 			    match possible_packet_or_err {
 		                Some(packet_or_err) => {
-				    port_to_pe.send(PortToPePacketOld::Packet((PortNo(ecnl_port_sub.port_id), packet_or_err?))).unwrap();
+				    port_to_pe.send(PortToPePacket::Packet((PortNo(ecnl_port_sub.port_id), packet_or_err?))).unwrap();
 				    first = false;
 				},
 				None => {
@@ -17,11 +17,14 @@
 
 pub mod app_message;
 pub mod app_message_formats;
+pub mod auth;
 pub mod blueprint;
+pub mod cell_replay;
 pub mod cellagent;
 pub mod cmodel;
 pub mod config;
 pub mod container;
+pub mod counters;
 pub mod dal;
 #[cfg(any(feature = "simulator"))]
 pub mod datacenter;
@@ -36,6 +39,7 @@ pub mod ec_message;
 pub mod ec_message_formats;
 #[cfg(feature = "cell")]
 pub mod ecnl_port;
+pub mod metrics;
 #[cfg(any(feature = "simulator", feature = "cell"))]
 pub mod nalcell;
 pub mod name;
@@ -55,11 +59,16 @@ pub mod simulated_border_port;
 pub mod simulated_interior_port;
 pub mod snake;
 pub mod tenant;
+pub mod trace_filter;
+pub mod trace_sink;
 pub mod traph;
 pub mod traph_element;
 pub mod tree;
+pub mod tree_key;
+pub mod uptree;
 pub mod uptree_spec;
 pub mod utility;
 pub mod uuid_ec;
 pub mod vm;
+pub mod wire;
 
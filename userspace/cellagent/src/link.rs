@@ -2,15 +2,16 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::{fmt};
+use std::{fmt,
+          sync::{Arc, atomic::{AtomicBool, Ordering}}};
 
-use crate::config::{CONFIG};
 use crate::dal::{add_to_trace};
 use crate::simulated_interior_port::{LinkFromPort, LinkToPort, LinkToPortPacket};
 use crate::name::{Name, LinkID, PortID};
-use crate::utility::{S, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, tracing};
+use crate::utility::{S, TraceHeaderParams};
 
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum LinkStatus {
     Connected,
     Disconnected
@@ -64,6 +65,7 @@ pub struct Link {
     id: LinkID,
     is_connected: bool,              //     Left Port        Link        Rite Port
     link_to_ports: LinkToPorts,
+    is_cut: Arc<AtomicBool>,         // Shared with the clone listening on the link
 }
 impl Link {
     pub fn new(left_id: PortID, rite_id: PortID,
@@ -71,10 +73,10 @@ impl Link {
         let _f = "new";
         let id = LinkID::new(left_id, rite_id)?;
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.link {
+            if let Some(event) = tracing(Level::Info, &["link"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_connected" };
                 let trace = json!({ "id": id });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         link_to_ports.left.send(LinkToPortPacket::Status(LinkStatus::Connected)).context(LinkError::Chain { func_name: _f, comment: S(id) + " send status to port"})?;
@@ -86,9 +88,12 @@ impl Link {
                 left: link_to_ports.left,
                 rite: link_to_ports.rite,
             },
+            is_cut: Arc::new(AtomicBool::new(false)),
         })
     }
     pub fn get_id(&self) -> LinkID { self.id }
+    // A cut link drops everything without telling the ports, so the cells on it go quiet
+    pub fn cut(&self) { self.is_cut.store(true, Ordering::SeqCst); }
     pub fn listen(&mut self, link_from_ports: LinkFromPorts)
                   -> Result<(), Error> {
         let _f = "listen";
@@ -96,32 +101,34 @@ impl Link {
             std::thread::sleep(std::time::Duration::from_millis(1));
             select! {
                 recv(link_from_ports.left) -> recvd => {
-                    let packet = recvd.context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " receive from left"})?;
+                    let msg = recvd.context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " receive from left"})?;
+                    if self.is_cut.load(Ordering::SeqCst) { continue; }
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.link {
+                        if let Some(event) = tracing(Level::Info, &["link"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_from_left_port" };
-                            let trace = json!({ "id": &self.get_id(), "packet":packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "id": &self.get_id(), "packet": msg.stringify()? });
+                            add_to_trace(event, trace_params, &trace, _f);
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_to_rite_port" };
-                            let trace = json!({ "id": &self.get_id(), "packet":packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "id": &self.get_id(), "packet": msg.stringify()? });
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
-                    self.link_to_ports.rite.send(LinkToPortPacket::Packet(packet)).context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " send to rite"})?;
+                    self.link_to_ports.rite.send(LinkToPortPacket::from(msg)).context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " send to rite"})?;
                 },
                 recv(link_from_ports.rite) -> recvd => {
-                    let packet = recvd.context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " receive from rite"})?;
+                    let msg = recvd.context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " receive from rite"})?;
+                    if self.is_cut.load(Ordering::SeqCst) { continue; }
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.link {
+                        if let Some(event) = tracing(Level::Info, &["link"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_from_rite_port" };
-                            let trace = json!({ "id": &self.get_id(), "packet":packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "id": &self.get_id(), "packet": msg.stringify()? });
+                            add_to_trace(event, trace_params, &trace, _f);
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_to_left_port" };
-                            let trace = json!({ "id": &self.get_id(), "packet":packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            let trace = json!({ "id": &self.get_id(), "packet": msg.stringify()? });
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
-                    self.link_to_ports.left.send(LinkToPortPacket::Packet(packet)).context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " send to left"})?;
+                    self.link_to_ports.left.send(LinkToPortPacket::from(msg)).context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " send to left"})?;
                 }
             }
         }
@@ -130,10 +137,10 @@ impl Link {
         let _f = "break_link";
         self.is_connected = false;
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.link {
+            if let Some(event) = tracing(Level::Info, &["link"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "link_to_port_disconnected" };
                 let trace = json!({ "id": &self.get_id(), "status": LinkToPortPacket::Status(LinkStatus::Disconnected) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.link_to_ports.left.send(LinkToPortPacket::Status(LinkStatus::Disconnected)).context(LinkError::Chain { func_name: _f, comment: S(self.id.clone()) + " left"})?;
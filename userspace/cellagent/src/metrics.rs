@@ -0,0 +1,259 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{fmt::Write,
+          collections::HashMap,
+          io::{BufRead, BufReader, Read, Write as IoWrite},
+          net::{TcpListener, TcpStream},
+          sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+          thread, thread::JoinHandle,
+          time::Duration};
+
+use lazy_static::lazy_static;
+
+use crate::config::CONFIG;
+use crate::counters::PacketEngineCounters;
+use crate::ec_message::MsgType;
+use crate::name::{CellID};
+use crate::trace_filter::{self, TraceFilter};
+use crate::utility::{S, write_err};
+
+lazy_static! {
+    // Each cell thread counts itself while it runs
+    static ref THREADS: Mutex<HashMap<CellID, usize>> = Mutex::new(HashMap::new());
+}
+pub struct ThreadCount {
+    cell_id: CellID,
+}
+// Hold the result for as long as the thread runs
+pub fn count_thread(cell_id: CellID) -> ThreadCount {
+    if let Ok(mut threads) = THREADS.lock() {
+        *threads.entry(cell_id).or_insert(0) += 1;
+    }
+    ThreadCount { cell_id }
+}
+impl Drop for ThreadCount {
+    fn drop(&mut self) {
+        if let Some(count) = THREADS.lock().ok().as_mut().and_then(|threads| threads.get_mut(&self.cell_id)) {
+            *count -= 1;
+        }
+    }
+}
+fn get_thread_count(cell_id: &CellID) -> usize {
+    THREADS.lock().map(|threads| threads.get(cell_id).cloned().unwrap_or(0)).unwrap_or(0)
+}
+// What the cell agent knows that the packet engine doesn't
+#[derive(Debug, Clone, Default)]
+pub struct CellMetrics {
+    msgs_sent: HashMap<MsgType, usize>,
+    msgs_recd: HashMap<MsgType, usize>,
+    trees_discovered: usize, // Black trees of other cells
+    trees: usize, // Including stacked trees
+    failovers_started: usize,
+    failovers_succeeded: usize,
+    failovers_failed: usize,
+}
+impl CellMetrics {
+    pub fn msg_sent(&mut self, msg_type: MsgType) { *self.msgs_sent.entry(msg_type).or_insert(0) += 1; }
+    pub fn msg_recd(&mut self, msg_type: MsgType) { *self.msgs_recd.entry(msg_type).or_insert(0) += 1; }
+    pub fn set_trees(&mut self, trees_discovered: usize, trees: usize) {
+        self.trees_discovered = trees_discovered;
+        self.trees = trees;
+    }
+    pub fn failover_started(&mut self) { self.failovers_started += 1; }
+    pub fn failover_succeeded(&mut self) { self.failovers_succeeded += 1; }
+    pub fn failover_failed(&mut self) { self.failovers_failed += 1; }
+}
+// Everything the endpoint reports about one cell; cheap to clone since the parts are shared
+#[derive(Debug, Clone)]
+pub struct MetricsSource {
+    cell_id: CellID,
+    cell_metrics: Arc<Mutex<CellMetrics>>,
+    counters: Arc<Mutex<PacketEngineCounters>>,
+}
+impl MetricsSource {
+    pub fn new(cell_id: CellID, cell_metrics: Arc<Mutex<CellMetrics>>, counters: Arc<Mutex<PacketEngineCounters>>) -> MetricsSource {
+        MetricsSource { cell_id, cell_metrics, counters }
+    }
+}
+// One metric with its samples from every cell, in the Prometheus text format
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, usize)>, // (labels, value)
+}
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Family {
+        Family { name, kind, help, samples: Vec::new() }
+    }
+    fn add(&mut self, labels: String, value: usize) { self.samples.push((labels, value)); }
+    fn write_to(&self, s: &mut String) -> Result<(), Error> {
+        writeln!(s, "# HELP {} {}", self.name, self.help)?;
+        writeln!(s, "# TYPE {} {}", self.name, self.kind)?;
+        for (labels, value) in &self.samples {
+            writeln!(s, "{}{{{}}} {}", self.name, labels, value)?;
+        }
+        Ok(())
+    }
+}
+pub fn render(sources: &[MetricsSource]) -> Result<String, Error> {
+    let mut msgs_sent = Family::new("ec_messages_sent_total", "counter", "Messages sent by the cell agent");
+    let mut msgs_recd = Family::new("ec_messages_received_total", "counter", "Messages received by the cell agent");
+    let mut trees_discovered = Family::new("ec_discover_trees", "gauge", "Black trees of other cells discovered");
+    let mut discover_done = Family::new("ec_discover_done", "gauge", "1 once the cell has discovered every other cell");
+    let mut trees = Family::new("ec_trees", "gauge", "Trees the cell is on, including stacked trees");
+    let mut failovers = Family::new("ec_failovers_total", "counter", "Failovers by outcome");
+    let mut packets = Family::new("ec_port_packets_total", "counter", "Packets by port and direction");
+    let mut bytes = Family::new("ec_port_bytes_total", "counter", "Bytes by port and direction");
+    let mut drops = Family::new("ec_port_drops_total", "counter", "Packets from the link that went nowhere");
+    let mut retransmits = Family::new("ec_port_retransmits_total", "counter", "Packets sent again after another port's link broke");
+    let mut queue_depth = Family::new("ec_port_queue_depth", "gauge", "Packets waiting for credits");
+    let mut max_queue_depth = Family::new("ec_port_queue_depth_max", "gauge", "Most packets waiting for credits at one time");
+    let mut tree_packets = Family::new("ec_tree_packets_total", "counter", "Packets by tree, put on a port or delivered to the cell agent");
+    let mut threads = Family::new("ec_threads", "gauge", "Threads running for the cell");
+    for source in sources {
+        let cell = source.cell_id.get_name();
+        let cell_metrics = source.cell_metrics.lock().unwrap().clone();
+        let counters = source.counters.lock().unwrap().clone();
+        for (msg_type, count) in &cell_metrics.msgs_sent {
+            msgs_sent.add(format!("cell=\"{}\",msg_type=\"{}\"", cell, msg_type), *count);
+        }
+        for (msg_type, count) in &cell_metrics.msgs_recd {
+            msgs_recd.add(format!("cell=\"{}\",msg_type=\"{}\"", cell, msg_type), *count);
+        }
+        trees_discovered.add(format!("cell=\"{}\"", cell), cell_metrics.trees_discovered);
+        discover_done.add(format!("cell=\"{}\"", cell), (cell_metrics.trees_discovered + 1 >= *CONFIG.num_cells) as usize);
+        trees.add(format!("cell=\"{}\"", cell), cell_metrics.trees);
+        failovers.add(format!("cell=\"{}\",outcome=\"started\"", cell), cell_metrics.failovers_started);
+        failovers.add(format!("cell=\"{}\",outcome=\"succeeded\"", cell), cell_metrics.failovers_succeeded);
+        failovers.add(format!("cell=\"{}\",outcome=\"failed\"", cell), cell_metrics.failovers_failed);
+        for (port_no, port) in counters.get_ports() {
+            let labels = format!("cell=\"{}\",port=\"{}\"", cell, *port_no);
+            packets.add(format!("{},direction=\"in\"", labels), port.get_packets_in());
+            packets.add(format!("{},direction=\"out\"", labels), port.get_packets_out());
+            bytes.add(format!("{},direction=\"in\"", labels), port.get_bytes_in());
+            bytes.add(format!("{},direction=\"out\"", labels), port.get_bytes_out());
+            drops.add(labels.clone(), port.get_drops());
+            retransmits.add(labels.clone(), port.get_retransmits());
+            queue_depth.add(labels.clone(), port.get_queue_depth());
+            max_queue_depth.add(labels, port.get_max_queue_depth());
+        }
+        for (uuid, tree) in counters.get_trees() {
+            let labels = format!("cell=\"{}\",tree=\"{}\"", cell, uuid);
+            tree_packets.add(format!("{},destination=\"port\"", labels), tree.get_forwarded());
+            tree_packets.add(format!("{},destination=\"cm\"", labels), tree.get_to_cm());
+        }
+        threads.add(format!("cell=\"{}\"", cell), get_thread_count(&source.cell_id));
+    }
+    let mut s = String::new();
+    for family in &[msgs_sent, msgs_recd, trees_discovered, discover_done, trees, failovers,
+                    packets, bytes, drops, retransmits, queue_depth, max_queue_depth, tree_packets, threads] {
+        family.write_to(&mut s)?;
+    }
+    Ok(s)
+}
+const MAX_CONNECTIONS: usize = 8; // Served at once; the rest get 503
+const MAX_REQUEST_BODY: usize = 64*1024; // Plenty for a list of trace filters
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5); // So a client that goes quiet doesn't hold a connection
+// SPAWN THREAD (listen_metrics)
+pub fn serve(address: &str, sources: Vec<MetricsSource>) -> Result<JoinHandle<()>, Error> {
+    let _f = "serve";
+    let listener = TcpListener::bind(address).context(MetricsError::Chain { func_name: _f, comment: S(address) })?;
+    let thread_name = format!("Metrics {}", address);
+    let sources = Arc::new(sources);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let join_handle = thread::Builder::new().name(thread_name).spawn( move || {
+        for stream in listener.incoming() {
+            let _ = stream
+                .map_err(Error::from)
+                .and_then(|stream| serve_connection(stream, &sources, &connections))
+                .map_err(|e| write_err("metrics", &e));
+        }
+    })?;
+    Ok(join_handle)
+}
+// Each connection gets its own thread, so a slow scrape doesn't hold up the others
+fn serve_connection(mut stream: TcpStream, sources: &Arc<Vec<MetricsSource>>, connections: &Arc<AtomicUsize>) -> Result<(), Error> {
+    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+        connections.fetch_sub(1, Ordering::SeqCst);
+        return write_response(&mut stream, "503 Service Unavailable", &[], "Too many connections\n");
+    }
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let is_local = stream.peer_addr().map(|address| address.ip().is_loopback()).unwrap_or(false);
+    let sources = sources.clone();
+    let connections = connections.clone();
+    thread::Builder::new().name(S("Metrics connection")).spawn(move || {
+        let _ = respond(stream, is_local, &sources).map_err(|e| write_err("metrics", &e));
+        connections.fetch_sub(1, Ordering::SeqCst);
+    })?;
+    Ok(())
+}
+// Answer one request on a connection; only a client on this machine can change the trace filters
+pub fn respond<T: Read + IoWrite>(mut stream: T, is_local: bool, sources: &[MetricsSource]) -> Result<(), Error> {
+    let _f = "respond";
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers so the client doesn't see a reset
+    let mut header = String::new();
+    let mut content_length = 0;
+    while reader.read_line(&mut header)? > 2 {
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.eq_ignore_ascii_case("content-length") { content_length = value.trim().parse().unwrap_or(0); }
+        }
+        header.clear();
+    }
+    if content_length > MAX_REQUEST_BODY {
+        drop(reader);
+        return write_response(&mut stream, "413 Payload Too Large", &[], &format!("Bodies are limited to {} bytes\n", MAX_REQUEST_BODY));
+    }
+    let mut request_body = vec![0; content_length];
+    reader.read_exact(&mut request_body)?;
+    drop(reader);
+    let mut words = request_line.split_whitespace();
+    let (status, allow, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", vec![], render(sources).context(MetricsError::Chain { func_name: _f, comment: S("render") })?),
+        (_, Some("/metrics")) => ("405 Method Not Allowed", vec!["GET"], S("Metrics only take GET\n")),
+        (Some("GET"), Some("/trace_filters")) => ("200 OK", vec![], show_trace_filters()),
+        (Some("PUT"), Some("/trace_filters")) if !is_local => ("403 Forbidden", vec![], S("Trace filters can only be changed from localhost\n")),
+        (Some("PUT"), Some("/trace_filters")) => match change_trace_filters(&String::from_utf8_lossy(&request_body)) {
+            Ok(()) => ("200 OK", vec![], show_trace_filters()),
+            Err(e) => ("400 Bad Request", vec![], format!("{}\n", e))
+        },
+        (_, Some("/trace_filters")) => ("405 Method Not Allowed", vec!["GET", "PUT"], S("Trace filters only take GET and PUT\n")),
+        _ => ("404 Not Found", vec![], S("Metrics are at /metrics, trace filters at /trace_filters\n"))
+    };
+    write_response(&mut stream, status, &allow, &body)
+}
+fn write_response<T: IoWrite>(stream: &mut T, status: &str, allow: &[&str], body: &str) -> Result<(), Error> {
+    let allow = if allow.is_empty() { S("") } else { format!("Allow: {}\r\n", allow.join(", ")) };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, allow, body.len(), body)?;
+    Ok(())
+}
+// One filter per line, e.g., "debug module=pe cell=C:3"
+fn show_trace_filters() -> String {
+    trace_filter::get_trace_filters().iter().map(|filter| format!("{}\n", filter)).collect()
+}
+fn change_trace_filters(body: &str) -> Result<(), Error> {
+    let filters = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.parse::<TraceFilter>())
+        .collect::<Result<Vec<_>, _>>()?;
+    trace_filter::set_trace_filters(filters);
+    Ok(())
+}
+
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum MetricsError {
+    #[fail(display = "MetricsError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+}
@@ -15,18 +15,22 @@ use either::Either;
 use crate::app_message_formats::{CaToPort, PortFromCa, PortToCa, CaFromPort};
 use crate::cellagent::{CellAgent};
 use crate::config::{CONFIG, PortQty};
-use crate::dal::{add_to_trace, get_cell_replay_lines};
+use crate::counters::PacketEngineCounters;
+use crate::dal::{add_to_trace, get_cell_replay_records};
 use crate::ec_message_formats::{PortToPe, PeFromPort, PeToPort, PortFromPe,
-                                PortToPeOld, PeFromPortOld, PeToPortOld, PortFromPeOld,
-                                CmToCa, CaFromCm, CaToCm, CmFromCa, CaToCmBytes, CmToCaBytesOld,
+                                CmToCa, CaFromCm, CaToCm, CmFromCa, CaToCmBytes, CmToCaBytes,
                                 PeToCm, CmFromPe, CmToPe, PeFromCm};
+use crate::metrics::MetricsSource;
 use crate::name::{CellID, PortID};
 use crate::port::{InteriorPortLike, BorderPortLike, 
                   InteriorPortFactoryLike, BorderPortFactoryLike, Port, 
                   DuplexPortPeOrCaChannel, DuplexPortPeChannel, DuplexPortCaChannel};
 use crate::replay::{TraceFormat, process_trace_record};
+use crate::tenant::Tenants;
+use crate::tree_key::SharedTreeKeys;
+use crate::trace_filter::{Level, tracing};
 use crate::utility::{CellConfig, CellType, PortNo, S,
-                     TraceHeaderParams, TraceType};
+                     TraceHeaderParams};
 
 #[derive(Debug, Clone)]
 pub struct NalCell<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>, 
@@ -48,7 +52,8 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
                  BorderPortType: 'static + Clone + BorderPortLike> 
         NalCell::<InteriorPortFactoryType, InteriorPortType, 
                   BorderPortFactoryType, BorderPortType> {
-    pub fn new(name: &str, num_phys_ports: PortQty, border_port_nos: &HashSet<PortNo>, config: CellConfig, 
+    pub fn new(name: &str, num_phys_ports: PortQty, border_port_nos: &HashSet<PortNo>, config: CellConfig,
+            tenants: Tenants, tree_keys: SharedTreeKeys,
             interior_port_factory: InteriorPortFactoryType, 
             border_port_factory: Option<BorderPortFactoryType>)
                 -> Result<(NalCell<InteriorPortFactoryType, InteriorPortType, BorderPortFactoryType, BorderPortType>, 
@@ -57,9 +62,9 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
         if *num_phys_ports > *CONFIG.max_num_phys_ports_per_cell {
             return Err(NalcellError::NumberPorts { num_phys_ports, func_name: "new", max_num_phys_ports: CONFIG.max_num_phys_ports_per_cell }.into())
         }
-        let mut trace_lines = get_cell_replay_lines(name).context(NalcellError::Chain { func_name: _f, comment: S(name) })?;
+        let mut trace_records = get_cell_replay_records(name).context(NalcellError::Chain { func_name: _f, comment: S(name) })?;
         let (cell_id, tree_ids) = if CONFIG.replay {
-            let record = trace_lines.next().transpose()?.expect(&format!("First record for cell {} must be there", name));
+            let record = trace_records.next().transpose()?.expect(&format!("First record for cell {} must be there", name));
             let trace_format = process_trace_record(record)?;
             match trace_format {
                 TraceFormat::CaNewFormat(cell_id, my_tree_id, control_tree_id, connected_tree_id) =>
@@ -73,7 +78,6 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
              None)
         };
         let (port_to_pe, pe_from_ports): (PortToPe, PeFromPort) = channel();
-        let (port_to_pe_old, pe_from_ports_old): (PortToPeOld, PeFromPortOld) = channel();
         let (port_to_ca, ca_from_ports): (PortToCa, CaFromPort) = channel();
         let port_list: Vec<PortNo> = (0..*num_phys_ports).map(|i| PortNo(i as u8)).collect();
         let all: HashSet<PortNo> = HashSet::from_iter(port_list);
@@ -84,15 +88,13 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
         interior_port_list.sort();
         let mut ports = Vec::new();
         let mut pe_to_ports = HashMap::new();
-        let mut pe_to_ports_old = HashMap::new();
         let mut ports_from_pe = HashMap::new();
-        let mut ports_from_pe_old = HashMap::new(); // So I can remove the item
         let mut ca_to_ports = HashMap::new();
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.nal {
+            if let Some(event) = tracing(Level::Info, &["nal"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "nalcell_port_setup" };
                 let trace = json!({ "cell_name": name });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let cell_type = if border_port_nos.is_empty() { CellType::Interior } else { CellType::Border };
@@ -108,16 +110,11 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
                 ))
             } else {
                 let (pe_to_port, port_from_pe): (PeToPort, PortFromPe) = channel();
-                let (pe_to_port_old, port_from_pe_old): (PeToPortOld, PortFromPeOld) = channel();
                 pe_to_ports.insert(PortNo(port_num), pe_to_port);
-                pe_to_ports_old.insert(PortNo(port_num), pe_to_port_old);
-                ports_from_pe.insert(PortNo(port_num), port_from_pe.clone()); // These two lines may not be needed.
-                ports_from_pe_old.insert(PortNo(port_num), port_from_pe_old.clone()); // I'm putting them here during a rebase.
+                ports_from_pe.insert(PortNo(port_num), port_from_pe.clone()); // May not be needed
                 DuplexPortPeOrCaChannel::Interior(DuplexPortPeChannel::new(
                     port_from_pe,
-                    port_from_pe_old,
                     port_to_pe.clone(),
-                    port_to_pe_old.clone(),
                 ))
             };
             let port_number = PortNo(port_num).make_port_number(num_phys_ports).context(NalcellError::Chain { func_name: "new", comment: S("port number") })?;
@@ -150,15 +147,14 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
         let (cell_agent, _cm_join_handle) = CellAgent::new(cell_id, tree_ids, cell_type, config,
                  num_phys_ports, ca_to_ports.clone(), cm_to_ca.clone(),
                   pe_from_ports, pe_to_ports,
-                  pe_from_ports_old, pe_to_ports_old,
                   border_port_nos,
                   ca_to_cm.clone(), cm_from_ca, pe_to_cm.clone(),
-                  cm_from_pe, cm_to_pe.clone(), pe_from_cm).context(NalcellError::Chain { func_name: "new", comment: S("cell agent create") })?;
+                  cm_from_pe, cm_to_pe.clone(), pe_from_cm, tenants, tree_keys).context(NalcellError::Chain { func_name: "new", comment: S("cell agent create") })?;
         let ca_join_handle = cell_agent.start(ca_from_cm, ca_from_ports);
         if CONFIG.replay {
             thread::spawn(move || -> Result<(), Error> {
                 loop {
-                    match trace_lines.next().transpose()? {
+                    match trace_records.next().transpose()? {
                         None => break,
                         Some(record) => {
                             let trace_format = process_trace_record(record)?;
@@ -170,11 +166,19 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
                                     ca_to_cm.send(CaToCmBytes::Entry(entry))?;
                                 }
                                 TraceFormat::CaFromCmBytesMsg(port_no, is_ait, uuid, msg) => {
-                                    cm_to_ca.send(CmToCaBytesOld::Bytes((port_no, is_ait, uuid, msg)))?;
+                                    cm_to_ca.send(CmToCaBytes::Bytes((port_no, is_ait, uuid, msg)))?;
                                 }
                                 TraceFormat::CaFromCmBytesStatus(port_no, is_border, number_of_packets, status) => {
-                                    cm_to_ca.send(CmToCaBytesOld::Status((port_no, is_border, number_of_packets, status)))?;
+                                    cm_to_ca.send(CmToCaBytes::Status((port_no, is_border, number_of_packets, status)))?;
                                 }
+                                TraceFormat::CaFromCmBytesPort(port_no, bytes) => {
+                                    cm_to_ca.send(CmToCaBytes::TunnelPort((port_no, bytes)))?;
+                                }
+                                TraceFormat::CaFromCmBytesUp(originator_id, bytes) => {
+                                    cm_to_ca.send(CmToCaBytes::TunnelUp((originator_id, bytes)))?;
+                                }
+                                // The cell agent passes these on to the cmodel, which sends them back as CaFromCmBytesPort
+                                TraceFormat::CaFromPortBytes(_, _) | TraceFormat::CaFromPortStatus(_, _) => (),
                                 TraceFormat::CaToNoc(noc_port, bytes) => {
                                     let ca_to_port = ca_to_ports.get(&noc_port).expect("cellagent.rs: border port sender must be set");
                                     ca_to_port.send(bytes)?;
@@ -202,9 +206,11 @@ impl<InteriorPortFactoryType: InteriorPortFactoryLike<InteriorPortType>,
     }
 
     pub fn get_id(&self) -> CellID { self.id }
-    fn _get_name(&self) -> String { self.id.get_name() }                     // Used only in tests
-    fn _get_num_ports(&self) -> PortQty { PortQty(self.ports.len() as u8) }  // Used only in tests
+    pub fn get_name(&self) -> String { self.id.get_name() }                     // Used only in tests
+    pub fn get_num_ports(&self) -> PortQty { PortQty(self.ports.len() as u8) }  // Used only in tests
     pub fn get_cell_agent(&self) -> &CellAgent { &self.cell_agent }
+    pub fn get_counters(&self) -> PacketEngineCounters { self.cell_agent.get_cmodel().get_packet_engine().get_counters() }
+    pub fn get_metrics_source(&self) -> MetricsSource { self.cell_agent.get_metrics_source() }
     pub fn listen_link_and_pe(&self, port_no: &PortNo) -> Result<InteriorPortType, Error> {
         let interior_port = self.get_interior_port(port_no)?;
         interior_port.clone().listen_link_and_pe();
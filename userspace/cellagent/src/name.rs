@@ -8,6 +8,7 @@ use std::string::String;
 use arrayvec::ArrayString;
 
 use crate::config::{MAX_CHARS, SEPARATOR};
+use crate::trace_filter;
 use crate::utility::{PortNo, PortNumber, S};
 use crate::uuid_ec::Uuid;
 
@@ -87,10 +88,16 @@ pub struct TreeID {
 impl TreeID {
     pub fn new(name: &str) -> Result<TreeID, Error> {
         match name.find(' ') {
-            None => Ok(TreeID { name: str_to_chars(&(S("Tree:") + name)), uuid: Uuid::new() }),
+            None => Ok(TreeID::named(&(S("Tree:") + name))),
             Some(_) => Err(NameError::Format { name: S(name), func_name: "TreeID::new" }.into())
         }
     }
+    // Trace filters know a tree by its name even in records that only have its UUID
+    fn named(name: &str) -> TreeID {
+        let tree_id = TreeID { name: str_to_chars(name), uuid: Uuid::new() };
+        trace_filter::learn_tree_name(name, &tree_id.uuid);
+        tree_id
+    }
     pub fn to_port_tree_id(&self, port_number: PortNumber) -> PortTreeID {
         let mut uuid = self.uuid;
         uuid.set_port_number(port_number);
@@ -101,7 +108,7 @@ impl TreeID {
 impl Name for TreeID {
     fn get_name(&self) -> String { str_from_chars(self.name) }
     fn get_uuid(&self) -> Uuid { self.uuid }
-    fn create_from_string(&self, name: &str) -> TreeID { TreeID { name: str_to_chars(name), uuid: Uuid::new() } }
+    fn create_from_string(&self, name: &str) -> TreeID { TreeID::named(name) }
 }
 impl Default for TreeID {
     fn default() -> Self {
@@ -170,21 +177,19 @@ impl Name for UptreeID {
     fn create_from_string(&self, name: &str) -> UptreeID { UptreeID { name: str_to_chars(name), uuid: Uuid::new() } }
 }
 impl fmt::Display for UptreeID { fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { str_from_chars(self.name).fmt(f) } }
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
 pub struct TenantID {
     name: NameType,
     uuid: Uuid
 }
 impl TenantID {
-    /*
     pub fn new(n: &str) -> Result<TenantID, Error> {
-        let name = S(n);
+        let name = str_to_chars(n);
         match n.find(' ') {
             None => Ok(TenantID { name, uuid: Uuid::new() }),
-            Some(_) => Err(NameError::Format { name, func_name: "TenantID::new" }.into())
+            Some(_) => Err(NameError::Format { name: S(n), func_name: "TenantID::new" }.into())
         }
     }
-    */
 }
 impl Name for TenantID {
     fn get_name(&self) -> String { str_from_chars(self.name) }
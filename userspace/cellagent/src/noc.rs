@@ -4,27 +4,31 @@
  *--------------------------------------------------------------------------------------------*/
 use std::{thread,
           thread::{JoinHandle},
+          sync::{Arc, Mutex},
           //sync::mpsc::channel,
           collections::{HashMap, HashSet}};
 use crossbeam::crossbeam_channel as mpsc;
 
 use crate::app_message::{AppMsgType, AppMessage, AppMsgDirection,
-                         AppDeleteTreeMsg, AppInterapplicationMsg, AppQueryMsg,
-                         AppManifestMsg, AppStackTreeMsg, AppTreeNameMsg};
+                         AppDeleteTreeMsg, AppHealthMsg, AppInterapplicationMsg, AppQueryMsg,
+                         AppManifestMsg, AppPlacementMsg, AppRedeployMsg, AppStackTreeMsg, AppTenantMsg, AppTreeNameMsg,
+                         AppUndeployMsg};
 use crate::app_message_formats::{PortToNocMsg, NocToPortMsg};
 use crate::blueprint::{Blueprint, Cell};
-use crate::config::{CONFIG, SCHEMA_VERSION};
+use crate::config::{CONFIG, SCHEMA_VERSION, CellQty};
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
 use crate::name::{CellID};  // CellID used for trace records
 use crate::gvm_equation::{GvmEquation, GvmEqn, GvmVariable, GvmVariableType};
-use crate::uptree_spec::{AllowedTree, ContainerSpec, Manifest, UpTreeSpec, VmSpec};
-use crate::utility::{ByteArray, CellNo, CellConfig, PortNo, S, TraceHeader, TraceHeaderParams, TraceType,
-                     get_geometry, vec_from_hashset, write_err};
+use crate::trace_filter::{Level, tracing};
+use crate::uptree_spec::{AllowedTree, ContainerSpec, DeployMode, Manifest, UpTreeSpec, VmSpec};
+use crate::utility::{CellNo, CellConfig, PlacementReport, PortNo, S, TraceHeader, TraceHeaderParams, get_geometry, vec_from_hashset, write_err};
+use crate::wire;
 
 const NOC_MASTER_DEPLOY_TREE_NAME: &str = "NocMasterDeploy";
-const NOC_AGENT_DEPLOY_TREE_NAME:  &str = "NocAgentDeploy";
+pub const NOC_AGENT_DEPLOY_TREE_NAME: &str = "NocAgentDeploy";
 pub const NOC_CONTROL_TREE_NAME:   &str = "NocMasterAgent";
 pub const NOC_LISTEN_TREE_NAME:    &str = "NocAgentMaster";
+pub const NOC_AGENT_MANIFEST:      &str = "manifests/noc_agent.json";
 
 pub type NocToPort = mpsc::Sender<NocToPortMsg>;
 pub type NocFromPort = mpsc::Receiver<PortToNocMsg>;
@@ -47,24 +51,25 @@ pub struct Noc {
     allowed_trees: HashSet<AllowedTree>,
     deploy_done: bool,
     duplex_noc_port_channel_cell_port_map: HashMap::<CellNo, HashMap<PortNo, DuplexNocPortChannel>>,
+    placements: Arc<Mutex<Vec<PlacementReport>>>, // Shared by the clones listening on each port
 }
 impl Noc {
     pub fn new(duplex_noc_port_channel_cell_port_map: HashMap::<CellNo, HashMap<PortNo, DuplexNocPortChannel>>) 
             -> Result<Noc, Error> {
         let cell_id = CellID::new("Noc")?;
         Ok(Noc { cell_id, base_tree: None, allowed_trees: HashSet::new(), deploy_done: false, 
-                 duplex_noc_port_channel_cell_port_map })
+                 duplex_noc_port_channel_cell_port_map, placements: Default::default() })
     }
     pub fn initialize(&mut self, blueprint: &Blueprint)
             -> Result<(), Error> {
         let _f = "initialize";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.noc {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
                 // For reasons I can't understand, the trace record doesn't show up when generated from main.
                 let (rows, cols) = get_geometry(blueprint.get_ncells());
                 let trace_params = &TraceHeaderParams { module: "src/main.rs", line_no: line!(), function: "MAIN", format: "trace_schema" };
                 let trace = json!({ "schema_version": SCHEMA_VERSION, "ncells": blueprint.get_ncells(), "rows": rows, "cols": cols });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         for border_cell in blueprint.get_border_cells() {
@@ -81,6 +86,14 @@ impl Noc {
 //		})
 //	}
     pub fn get_name(&self) -> &str { "NOC" }
+    pub fn get_noc_to_port(&self, cell_no: CellNo, port_no: PortNo) -> Option<&NocToPort> {
+        self.duplex_noc_port_channel_cell_port_map
+            .get(&cell_no)
+            .and_then(|channels| channels.get(&port_no))
+            .map(|channel| &channel.noc_to_port)
+    }
+    // Every placement report received so far, in the order they arrived
+    pub fn get_placements(&self) -> Vec<PlacementReport> { self.placements.lock().unwrap().clone() }
 
     // SPAWN THREAD (listen_port_loop)
     fn listen_port(&mut self, cell_no: CellNo, border_port_no: PortNo) -> JoinHandle<()> {
@@ -100,30 +113,41 @@ impl Noc {
             -> Result<(), Error> {
         let _f = "listen_port_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.noc {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.cell_id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let duplex_noc_port_channel = &self.duplex_noc_port_channel_cell_port_map[&cell_no][&border_port_no].clone();
         loop {
             let bytes = duplex_noc_port_channel.noc_from_port.recv().context(NocError::Chain { func_name: _f, comment: S("")})?;
-            let serialized = bytes.stringify()?;
-            let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(NocError::Chain { func_name: _f, comment: S("") })?;
+            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(NocError::Chain { func_name: _f, comment: S("") })?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.noc {
+                if let Some(event) = tracing(Level::Info, &["noc"]) {
                     let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_from_port" };
                     let trace = json!({ "cell_id": self.cell_id, "app_msg": app_msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
-            app_msg.process_noc(self, &duplex_noc_port_channel.noc_to_port)?;
+            // A message the NOC can't handle doesn't stop it from listening
+            let _ = app_msg.process_noc(self, &duplex_noc_port_channel.noc_to_port).map_err(|e| write_err("Noc: process", &e));
         }
     }
     pub fn app_process_delete_tree(&self, _msg: &AppDeleteTreeMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
         unimplemented!()
     }
+    pub fn app_process_health(&self, msg: &AppHealthMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "app_process_health";
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "app_process_health_msg" };
+                let trace = json!({ "cell_id": self.cell_id, "app_msg": msg });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        Ok(())
+    }
     pub fn app_process_interapplication(&self, _msg: &AppInterapplicationMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
         unimplemented!()
     }
@@ -133,17 +157,44 @@ impl Noc {
     pub fn app_process_manifest(&self, _msg: &AppManifestMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
         unimplemented!()
     }
+    pub fn app_process_placement(&self, msg: &AppPlacementMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "app_process_placement";
+        let report = msg.get_report();
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "app_process_placement_msg" };
+                let trace = json!({ "cell_id": self.cell_id, "app_msg": msg });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.placements.lock().unwrap().push(report.clone());
+        Ok(())
+    }
+    // The NOC sends redeploys; it never gets them
+    pub fn app_process_redeploy(&self, msg: &AppRedeployMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "app_process_redeploy";
+        Err(NocError::MsgType { func_name: _f, msg_type: msg.get_msg_type() }.into())
+    }
     pub fn app_process_stack_tree(&self, _msg: &AppStackTreeMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
         unimplemented!()
     }
+    pub fn app_process_undeploy(&self, msg: &AppUndeployMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "app_process_undeploy";
+        Err(NocError::MsgType { func_name: _f, msg_type: msg.get_msg_type() }.into())
+    }
+    // The NOC creates tenants; it never gets asked to
+    pub fn app_process_tenant(&self, msg: &AppTenantMsg, _noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "app_process_tenant";
+        Err(NocError::MsgType { func_name: _f, msg_type: msg.get_msg_type() }.into())
+    }
     pub fn app_process_tree_name(&mut self, msg: &AppTreeNameMsg, noc_to_port: &NocToPort) -> Result<(), Error> {
         let _f = "app_process_tree_name";
         let tree_name = msg.get_tree_name();
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.noc {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
                 let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "app_process_tree_name_msg" };
                 let trace = json!({ "cell_id": self.cell_id, "app_msg": msg });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
     // Handle duplicate notifications
@@ -172,7 +223,7 @@ impl Noc {
                 if self.has_allowed_trees(&deploy_trees) && !self.deploy_done {
                     self.deploy_done = true;
                     self.deploy_master(&master_deploy, noc_to_port)?;
-                    self.deploy_agent(&agent_deploy, noc_to_port)?;
+                    self.deploy_agent(noc_to_port)?;
                 }
             }
         }
@@ -198,25 +249,79 @@ impl Noc {
         let deploy_msg = AppManifestMsg::new("Noc", false, false,
                                              &master_deploy, &manifest,
                                              &allowed_trees);
-        println!("Noc: deploy {} on tree {}", manifest.get_id(), master_deploy);
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_deploy" };
+                let trace = json!({ "cell_id": self.cell_id, "manifest_id": manifest.get_id(), "deploy_tree": master_deploy });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
         self.send_msg(&deploy_msg, noc_to_port)?;
         Ok(())
     }
-    fn deploy_agent(&self, agent_deploy: &AllowedTree, noc_to_port: &NocToPort) -> Result<(), Error> {
+    fn deploy_agent(&self, noc_to_port: &NocToPort) -> Result<(), Error> {
         let _f = "deploy_agent";
-        let allowed_trees = vec![AllowedTree::new(NOC_CONTROL_TREE_NAME),
-                                                      AllowedTree::new(NOC_LISTEN_TREE_NAME)];
-        let up_tree = UpTreeSpec::new("NocAgent", vec![0]).context(NocError::Chain { func_name: _f, comment: S("NocAgent") })?;
-        let service = ContainerSpec::new("NocAgent", "NocAgent", vec![], &allowed_trees).context(NocError::Chain { func_name: "create_noc", comment: S("NocAgent") })?;
-        let vm_spec = VmSpec::new("vm1", "Ubuntu", CellConfig::Large,
-                                  &allowed_trees, vec![&service], vec![&up_tree]).context(NocError::Chain { func_name: _f, comment: S("NocAgent")})?;
-        let manifest = Manifest::new("NocAgent", CellConfig::Large, agent_deploy, &allowed_trees,
-                                     vec![&vm_spec], vec![&up_tree]).context(NocError::Chain { func_name: "create_noc", comment: S("NocAgent")})?;
+        let manifest = Manifest::from_file(NOC_AGENT_MANIFEST).context(NocError::Chain { func_name: _f, comment: S(NOC_AGENT_MANIFEST) })?;
+        self.deploy(&manifest, noc_to_port)
+    }
+    // Deploys on the manifest's deployment tree; cells report where the VMs went with AppPlacementMsg
+    pub fn deploy(&self, manifest: &Manifest, noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "deploy";
+        let deploy_tree = manifest.get_deployment_tree();
         let deploy_msg = AppManifestMsg::new("Noc", false, false,
-                                             &agent_deploy, &manifest,
-                                             &allowed_trees);
-        println!("Noc: deploy {} on tree {}", manifest.get_id(), agent_deploy);
-        self.send_msg(&deploy_msg, noc_to_port)?;
+                                             deploy_tree, manifest,
+                                             manifest.get_allowed_trees());
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_deploy" };
+                let trace = json!({ "cell_id": self.cell_id, "manifest_id": manifest.get_id(), "deploy_tree": deploy_tree });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.send_msg(&deploy_msg, noc_to_port).context(NocError::Chain { func_name: _f, comment: S(manifest.get_id()) })?;
+        Ok(())
+    }
+    // Cells report what they stopped and started with AppPlacementMsg, the same as for a deploy
+    pub fn undeploy(&self, manifest_id: &str, deploy_tree: &AllowedTree, noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "undeploy";
+        let undeploy_msg = AppUndeployMsg::new("Noc", deploy_tree, manifest_id);
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_undeploy" };
+                let trace = json!({ "cell_id": self.cell_id, "manifest_id": manifest_id, "deploy_tree": deploy_tree });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.send_msg(&undeploy_msg, noc_to_port).context(NocError::Chain { func_name: _f, comment: S(manifest_id) })?;
+        Ok(())
+    }
+    // The new tenant's cells come out of its parent's quota
+    pub fn create_tenant(&self, parent_tenant: &str, tenant_name: &str, ncells: CellQty, noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "create_tenant";
+        let tenant_msg = AppTenantMsg::new("Noc", parent_tenant, tenant_name, ncells);
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_create_tenant" };
+                let trace = json!({ "cell_id": self.cell_id, "tenant": tenant_name, "ncells": ncells, "parent_tenant": parent_tenant });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.send_msg(&tenant_msg, noc_to_port).context(NocError::Chain { func_name: _f, comment: S(tenant_name) })?;
+        Ok(())
+    }
+    pub fn redeploy(&self, manifest: &Manifest, mode: DeployMode, noc_to_port: &NocToPort) -> Result<(), Error> {
+        let _f = "redeploy";
+        let deploy_tree = manifest.get_deployment_tree();
+        let redeploy_msg = AppRedeployMsg::new("Noc", deploy_tree, manifest, mode,
+                                               manifest.get_allowed_trees());
+        {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
+                let trace_params = &TraceHeaderParams { module: "src/noc.rs", line_no: line!(), function: _f, format: "noc_redeploy" };
+                let trace = json!({ "cell_id": self.cell_id, "manifest_id": manifest.get_id(), "deploy_tree": deploy_tree, "mode": mode });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        self.send_msg(&redeploy_msg, noc_to_port).context(NocError::Chain { func_name: _f, comment: S(manifest.get_id()) })?;
         Ok(())
     }
     fn small_tree(&mut self, new_tree_name: &AllowedTree, parent_tree_name: &AllowedTree,
@@ -234,10 +339,10 @@ impl Noc {
                                                   &new_tree_name, &parent_tree_name,
                                                   AppMsgDirection::Leafward, &gvm_eqn);
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+            if let Some(event) = tracing(Level::Info, &["svc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "3hop_to_vm" };
                 let trace = json!({ "cell_id": self.cell_id, "NocMaster": self.get_name(), "app_msg": stack_tree_msg });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         println!("Noc: stack {} on tree {}", new_tree_name, parent_tree_name);
@@ -313,14 +418,13 @@ impl Noc {
     fn send_msg(&self, msg: &dyn AppMessage, noc_to_port: &NocToPort) -> Result<(), Error> {
         let _f = "send_msg";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.noc {
+            if let Some(event) = tracing(Level::Info, &["noc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "noc_to_port" };
                 let trace = json!({"cell_id": self.cell_id, "app_msg": msg });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
-        let serialized = serde_json::to_string(msg as &dyn AppMessage)?;
-        let bytes = ByteArray::new(&serialized);
+        let bytes = wire::encode(msg as &dyn AppMessage)?;
         noc_to_port.send(bytes).context(NocError::Chain { func_name: _f, comment: S("") })?;
         Ok(())
     }
@@ -5,7 +5,7 @@
 use std::{fmt,
           collections::HashMap,
           convert::TryFrom,
-          cmp::min,
+          cmp::{max, min},
 	  mem::{size_of},
           ops::Deref,
           sync::atomic::{AtomicUsize, Ordering},
@@ -22,10 +22,10 @@ use crate::ec_message::{Message};
 //use crate::name::{PortTreeID, Name};
 use crate::utility::{ByteArray, S};//, Stack};
 use crate::uuid_ec::{Uuid, AitState};
+use crate::wire;
  
 //const LARGEST_MSG: usize = std::u32::MAX as usize;
 const NON_PAYLOAD_SIZE: usize = size_of::<PacketHeader>() + size_of::<usize>() + size_of::<SenderMsgSeqNo>() + PACKET_PADDING;
-const PAYLOAD_MIN: usize = PACKET_MIN - NON_PAYLOAD_SIZE;
 const PAYLOAD_MAX: usize = PACKET_MAX - NON_PAYLOAD_SIZE;
 
 pub type PacketAssemblers = HashMap<UniqueMsgId, PacketAssembler>;
@@ -41,32 +41,31 @@ impl fmt::Display for UniqueMsgId {
     }
 }
 static PACKET_COUNT: AtomicUsize = AtomicUsize::new(0);
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct Packet {
-    // Changes here must be reflected in the calculations of PAYLOAD_MIN and PAYLOAD_MAX in packet.rs
+    // Changes here must be reflected in the calculation of NON_PAYLOAD_SIZE in packet.rs
     header: PacketHeader,
-    payload: Payload,
     packet_count: usize,
-    sender_msg_seq_no: SenderMsgSeqNo
+    sender_msg_seq_no: SenderMsgSeqNo,
+    payload: Payload, // Holds only the bytes in use, so a packet is as big as its link allows and no bigger
 }
 impl Packet {
-    pub fn new(unique_msg_id: UniqueMsgId, uuid: &Uuid, size: PacketNo,
+    pub fn new(unique_msg_id: UniqueMsgId, uuid: &Uuid, packet_no: PacketNo,
            is_last_packet: bool, seq_no: SenderMsgSeqNo, data_bytes: Vec<u8>) -> Packet {
         let header = PacketHeader::new(uuid);
-        let payload = Payload::new(unique_msg_id, size, is_last_packet, data_bytes);
-        Packet { header, payload, packet_count: Packet::get_next_count(), sender_msg_seq_no: seq_no }
+        let payload = Payload::new(unique_msg_id, packet_no, is_last_packet, data_bytes);
+        Packet { header, packet_count: Packet::get_next_count(), sender_msg_seq_no: seq_no, payload }
     }
     pub fn make_entl_packet() -> Packet {
         let mut uuid = Uuid::new();
         uuid.make_init();
-        Packet::new(UniqueMsgId::new(), &uuid, PacketNo(1),
+        Packet::new(UniqueMsgId::new(), &uuid, PacketNo(0),
                     false, SenderMsgSeqNo(0), vec![])
     }
     pub fn make_snaked_packet() -> Packet {
         let mut uuid = Uuid::new();
         uuid.make_snaked();
-        Packet::new(UniqueMsgId::new(), &uuid, PacketNo(1),
+        Packet::new(UniqueMsgId::new(), &uuid, PacketNo(0),
                         false, SenderMsgSeqNo(0), vec![])
     }
     pub fn make_snake_ack_packet(uniquifier: PacketUniquifier) -> Result<Packet, Error> {
@@ -88,14 +87,10 @@ impl Packet {
     pub fn stringify(&self) -> Result<String, Error> {
         let bytes = self.get_bytes();
         let is_last = self.payload.is_last;
-        let len = if is_last {
-            self.get_size()
-        } else {
-            PacketNo(u16::try_from(bytes.len())?)
-        };
-        let string = format!("is_last: {}, length: {}, unique_msg_id: {}, tree_id: {}, is_snake: {}, msg: {}", 
-            is_last, *len, self.payload.unique_msg_id, self.header.uuid, self.is_snake(), 
-            ByteArray::new_from_bytes(&bytes).stringify()?);
+        let len = self.get_size();
+        let string = format!("is_last: {}, length: {}, packet_no: {}, unique_msg_id: {}, tree_id: {}, is_snake: {}, msg: {}", 
+            is_last, *len, *self.get_packet_no(), self.payload.unique_msg_id, self.header.uuid, self.is_snake(), 
+            String::from_utf8_lossy(&bytes[..*len as usize])); // A packet may hold part of a binary message
         let default_as_char = PAYLOAD_DEFAULT_ELEMENT as char;
         Ok(string.replace(default_as_char, ""))
     }
@@ -110,7 +105,31 @@ impl Packet {
     pub fn is_last_packet(&self) -> bool { self.payload.is_last_packet() }
     pub fn get_unique_msg_id(&self) -> UniqueMsgId { self.payload.get_unique_msg_id() }
     pub fn get_size(&self) -> PacketNo { self.payload.get_size() }
-    pub fn get_bytes(&self) -> Vec<u8> { self.payload.bytes.iter().cloned().collect() }
+    pub fn get_packet_no(&self) -> PacketNo { self.payload.get_packet_no() }
+    // Only the used part of the payload goes on a link, but never less than the shortest frame
+    pub fn get_frame_len(&self) -> usize {
+        max(PACKET_MIN, NON_PAYLOAD_SIZE + *self.get_size() as usize)
+    }
+    // Split me into packets that fit a link with this packet size.  The pieces keep my packet_no,
+    // and only the last keeps my snake flag, so the cell downstream acks me once.
+    pub fn fragment(&self, packet_size: usize) -> Vec<Packet> {
+        let len = *self.get_size() as usize;
+        let mtu = Packetizer::payload_size(packet_size);
+        if len <= mtu { return vec![self.clone()]; }
+        let bytes = self.get_bytes();
+        let chunks = bytes[..len].chunks(mtu).collect::<Vec<_>>();
+        let num_chunks = chunks.len();
+        let mut packets = Vec::new();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let is_last_chunk = i == num_chunks - 1;
+            let mut uuid = self.get_uuid();
+            if !is_last_chunk { uuid.clear_snake(); }
+            packets.push(Packet::new(self.get_unique_msg_id(), &uuid, self.get_packet_no(),
+                                     is_last_chunk && self.is_last_packet(), self.sender_msg_seq_no, chunk.to_vec()));
+        }
+        packets
+    }
+    pub fn get_bytes(&self) -> Vec<u8> { self.payload.get_bytes() }
     // pub fn get_payload_bytes(&self) -> Vec<u8> { self.get_payload().get_bytes() }
     // pub fn get_payload_size(&self) -> usize { self.payload.get_no_bytes() }
 
@@ -152,11 +171,7 @@ impl Packet {
 impl fmt::Display for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let bytes = self.payload.get_bytes();
-        let len = if self.payload.is_last_packet() {
-            *self.payload.get_size() as usize
-        } else {
-            bytes.len()
-        };
+        let len = *self.payload.get_size() as usize;
         let is_snake = self.is_snake();
         let is_ait = self.is_ait();
         let is_snaked = self.is_snaked();
@@ -165,6 +180,38 @@ impl fmt::Display for Packet {
         write!(f, "{}", s)
     }
 }
+// The driver reads and writes flat frames, so only the buffers handed to it hold the largest payload
+#[cfg(feature = "cell")]
+#[repr(C)]
+pub struct PacketFrame {
+    header: PacketHeader,
+    packet_count: usize,
+    sender_msg_seq_no: SenderMsgSeqNo,
+    unique_msg_id: UniqueMsgId,
+    size: PacketNo,
+    packet_no: PacketNo,
+    is_last: bool,
+    bytes: [u8; PAYLOAD_MAX],
+}
+#[cfg(feature = "cell")]
+impl From<&Packet> for PacketFrame {
+    fn from(packet: &Packet) -> PacketFrame {
+        let payload = &packet.payload;
+        let mut bytes = [PAYLOAD_DEFAULT_ELEMENT; PAYLOAD_MAX];
+        bytes[..payload.bytes.len()].copy_from_slice(&payload.bytes);
+        PacketFrame { header: packet.header, packet_count: packet.packet_count, sender_msg_seq_no: packet.sender_msg_seq_no,
+                      unique_msg_id: payload.unique_msg_id, size: payload.size, packet_no: payload.packet_no,
+                      is_last: payload.is_last, bytes }
+    }
+}
+#[cfg(feature = "cell")]
+impl From<&PacketFrame> for Packet {
+    fn from(frame: &PacketFrame) -> Packet {
+        let len = min(*frame.size as usize, PAYLOAD_MAX);
+        let payload = Payload::new(frame.unique_msg_id, frame.packet_no, frame.is_last, frame.bytes[..len].to_vec());
+        Packet { header: frame.header, packet_count: frame.packet_count, sender_msg_seq_no: frame.sender_msg_seq_no, payload }
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize)]
@@ -190,39 +237,39 @@ impl fmt::Display for PacketHeader {
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct Payload {
     unique_msg_id: UniqueMsgId,  // Unique identifier of this message
-    size: PacketNo, // Number of packets remaining in message if not last packet
-                    // Number of bytes in last packet if last packet, 0 => Error
+    size: PacketNo, // Number of bytes in this packet, which depends on the links it crossed
+    packet_no: PacketNo, // Position in the message when it was packetized, kept by fragments
     is_last: bool,
-    bytes: [u8; PAYLOAD_MAX],
+    bytes: Box<[u8]>, // Sized to the data, at most PAYLOAD_MAX
     //wrapped_header: Stack<PacketHeader>,
 }
 impl Payload {
-    pub fn new(unique_msg_id: UniqueMsgId, size: PacketNo,
-               is_last: bool, data_bytes: Vec<u8>) -> Payload {
-        let mut bytes = [0 as u8; PAYLOAD_MAX];
-        // Next line recommended by clippy, but I think the loop is clearer
-        //bytes[..min(data_bytes.len(), PAYLOAD_MAX)].clone_from_slice(&data_bytes[..min(data_bytes.len(), PAYLOAD_MAX)]);
-        for i in 0..min(data_bytes.len(), PAYLOAD_MAX) { bytes[i] = data_bytes[i]; }
-        Payload { unique_msg_id, size, is_last, bytes } //, wrapped_header: Stack::new() }
-    }
-    fn get_bytes(&self) -> Vec<u8> { self.bytes.iter().cloned().collect() }
+    pub fn new(unique_msg_id: UniqueMsgId, packet_no: PacketNo,
+               is_last: bool, mut data_bytes: Vec<u8>) -> Payload {
+        data_bytes.truncate(PAYLOAD_MAX);
+        let size = PacketNo(data_bytes.len() as u16);
+        Payload { unique_msg_id, size, packet_no, is_last, bytes: data_bytes.into_boxed_slice() } //, wrapped_header: Stack::new() }
+    }
+    fn get_bytes(&self) -> Vec<u8> { self.bytes.to_vec() }
     fn set_bytes(&mut self, bytes: ByteArray) { 
-        self.bytes = [PAYLOAD_DEFAULT_ELEMENT; PAYLOAD_MAX];
-        for i in 0..bytes.len() { self.bytes[i] = bytes.get_bytes()[i] }
+        let mut bytes = bytes.get_bytes().to_vec();
+        bytes.truncate(PAYLOAD_MAX);
+        self.size = PacketNo(bytes.len() as u16);
+        self.bytes = bytes.into_boxed_slice();
     }
     fn get_unique_msg_id(&self) -> UniqueMsgId { self.unique_msg_id }
     fn get_size(&self) -> PacketNo { self.size }
+    fn get_packet_no(&self) -> PacketNo { self.packet_no }
     fn is_last_packet(&self) -> bool { self.is_last }
     //fn _get_wrapped_header(&self) -> &Stack<PacketHeader> { &self.wrapped_header }
 }
 impl Default for Payload {
     fn default() -> Self {
-        Payload { bytes: [0; PAYLOAD_MAX], is_last: false, size: Default::default(),
-                  unique_msg_id: Default::default() }
+        Payload { bytes: Box::new([]), is_last: false, size: Default::default(),
+                  packet_no: Default::default(), unique_msg_id: Default::default() }
     }
 }
 impl fmt::Display for Payload {
@@ -230,12 +277,12 @@ impl fmt::Display for Payload {
         let mut s = format!("Sender Unique MsgID {}", *self.unique_msg_id);
         if self.is_last_packet() { s = s + ", Last packet"; }
         else                     { s = s + ", Not last packet"; }
-        s = s + &format!(", Size {}", *self.size);
+        s = s + &format!(", Size {}, Packet {}", *self.size, *self.packet_no);
 //        s = s + &format!(", Wrapped headers: ");
 //        for w in self.wrapped_header.iter() {
 //            s = s + &format!("{}", w);
 //        }
-        s = s + &format!("{:?}", &self.bytes[0..10]);
+        s = s + &format!("{:?}", &self.bytes[..min(10, self.bytes.len())]);
         write!(f, "{}", s)
     }
 }
@@ -247,6 +294,7 @@ impl Serialize for Payload {
         let mut state = serializer.serialize_struct("Payload", 5)?;
         state.serialize_field("unique_msg_id", &self.unique_msg_id)?;
         state.serialize_field("size", &self.size)?;
+        state.serialize_field("packet_no", &self.packet_no)?;
         state.serialize_field("is_last", &self.is_last)?;
         state.serialize_field("bytes", &body)?;
         state.end()
@@ -254,88 +302,77 @@ impl Serialize for Payload {
 }
 impl fmt::Debug for Payload {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = &format!("{:?}", &self.bytes[0..10]);
+        let s = &format!("{:?}", &self.bytes[..min(10, self.bytes.len())]);
         write!(f, "{}", s)
     }
 }
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PacketUniquifier {
     unique_msg_id: UniqueMsgId,  // Unique identifier of this message
-    size: PacketNo, // Number of packets remaining in message if not last packet
-                    // Number of bytes in last packet if last packet, 0 => Error
+    packet_no: PacketNo, // Fragments keep the packet number, but only the last one is a snake
     is_last: bool,
 }
 impl PacketUniquifier {
     fn new(packet: &Packet) -> PacketUniquifier {
         PacketUniquifier {
             unique_msg_id: packet.get_unique_msg_id(),
-            size: packet.get_size(),
+            packet_no: packet.get_packet_no(),
             is_last: packet.is_last_packet()
         }
     }
 }
 impl fmt::Display for PacketUniquifier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({}, {}, {})", self.unique_msg_id.0, self.size.0, self.is_last)
+        write!(f, "({}, {}, {})", self.unique_msg_id.0, self.packet_no.0, self.is_last)
     }
 }
 pub struct Serializer {}
 impl Serializer {
-    pub fn serialize<M>(msg: &M) -> Result<String, Error>
+    // JSON or binary, depending on the wire format of this fabric
+    pub fn serialize<M>(msg: &M) -> Result<ByteArray, Error>
             where M: Message + serde::Serialize {
-        let serialized = serde_json::to_string(msg as &dyn Message).context(PacketError::Chain { func_name: "serialize", comment: S("msg")})?;
-        Ok(serialized)
+        let bytes = wire::encode(msg as &dyn Message).context(PacketError::Chain { func_name: "serialize", comment: S("msg")})?;
+        Ok(bytes)
     }
 }
 pub struct Packetizer {}
 impl Packetizer {
-    pub fn packetize(uuid: &Uuid, seq_no: SenderMsgSeqNo, msg: &ByteArray)
+    // The cell agent packetizes at PACKET_MAX, and the packet engine fragments to the size of each link
+    pub fn packetize(uuid: &Uuid, seq_no: SenderMsgSeqNo, msg: &ByteArray, packet_size: usize)
             -> Result<Vec<Packet>, Error> {
         let msg_bytes = msg.get_bytes();
-        let mtu = Packetizer::packet_payload_size(msg_bytes.len());
-        let num_packets = (msg_bytes.len() + mtu - 1)/ mtu; // Poor man's ceiling
-        let frag = msg_bytes.len() - (num_packets - 1) * mtu; // Remainder after filling num_packets-1 packets
+        let mtu = Packetizer::payload_size(packet_size);
+        let num_packets = Packetizer::num_packets(msg_bytes.len(), packet_size);
         let unique_msg_id = UniqueMsgId(rand::random()); // Can't use hash in case two cells send the same message
         let mut packets = Vec::new();
         for i in 0..num_packets {
-            let (size, is_last_packet) = if i == (num_packets-1) {
-                (frag, true)
-            } else {
-                (num_packets - i, false)
-            };
-            // Not a very Rusty way to put bytes into payload
-            let mut packet_bytes = vec![PAYLOAD_DEFAULT_ELEMENT; mtu];
-            for j in 0..mtu {
-                if i*mtu + j == msg_bytes.len() { break; }
-                packet_bytes[j] = msg_bytes[i*mtu + j];
-            }
-            let packet = Packet::new(unique_msg_id, uuid, PacketNo(u16::try_from(size)?),
+            let is_last_packet = i == (num_packets-1);
+            let packet_bytes = msg_bytes[min(i*mtu, msg_bytes.len())..min((i+1)*mtu, msg_bytes.len())].to_vec();
+            let packet = Packet::new(unique_msg_id, uuid, PacketNo(u16::try_from(i)?),
                                      is_last_packet, seq_no, packet_bytes);
             //println!("Packet: packet {} for msg {}", packet.get_packet_count(), msg.get_count());
             packets.push(packet);
         }
         Ok(packets)
     }
+    // Packets may have been fragmented on the way, so each one says how many bytes it holds
     pub fn unpacketize(packets: &Vec<Packet>) -> Result<ByteArray, Error> {
         let _f = "unpacketize";
         let mut msg_bytes = Vec::new();
         for packet in packets.iter() {
             let mut bytes = packet.get_bytes();
-            let frag = *packet.get_size() as usize;
-            let is_last_packet = packet.is_last_packet();
-            if is_last_packet { bytes.truncate(frag) };
+            bytes.truncate(*packet.get_size() as usize);
             msg_bytes.extend_from_slice(&bytes);
         }
-        let msg = std::str::from_utf8(&msg_bytes)?;
-        Ok(ByteArray::new(msg))
+        Ok(ByteArray::new_from_bytes(&msg_bytes))
         //Ok(str::from_utf8(&msg).context(PacketError::Chain { func_name: "unpacketize", comment: S("")})?.to_string())
     }
-    fn packet_payload_size(len: usize) -> usize {
-        match len-1 {
-            0..=PACKET_MIN            => PAYLOAD_MIN,
-            PAYLOAD_MIN..=PAYLOAD_MAX => len,
-            _                         => PAYLOAD_MAX
-        }
+    pub fn num_packets(msg_len: usize, packet_size: usize) -> usize {
+        let mtu = Packetizer::payload_size(packet_size);
+        max(1, (msg_len + mtu - 1)/ mtu) // Poor man's ceiling
+    }
+    pub fn payload_size(packet_size: usize) -> usize {
+        max(1, min(packet_size, PACKET_MAX).saturating_sub(NON_PAYLOAD_SIZE))
     }
 }
 #[derive(Debug, Clone)]
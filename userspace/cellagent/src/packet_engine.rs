@@ -2,33 +2,42 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::{collections::{HashMap, HashSet, VecDeque}, fmt, fmt::Write, str, sync::{Arc, Mutex}, thread, thread::JoinHandle};
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt, fmt::Write, str, sync::{Arc, Mutex, MutexGuard}, thread, thread::JoinHandle};
 
-use crate::{config::{CONFIG, PortQty}};
+use crate::trace_filter::{Level, tracing};
+use crate::{config::{CONFIG, PACKET_DEFAULT}};
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
+use crate::counters::PacketEngineCounters;
 use crate::ec_message::{MsgType};
 use crate::ec_message_formats::{PeFromCm, PeToCm,
                                 PeToPort, PeFromPort, PortToPePacket, PeToPortPacket,
-                                PeToPortOld, PeFromPortOld, PortToPePacketOld,
-                                PeToCmPacketOld,
+                                PeToCmPacket,
                                 CmToPePacket};
+use crate::metrics::count_thread;
 use crate::name::{Name, CellID, TreeID};
 use crate::packet::{Packet};
+use crate::link::LinkStatus;
+use crate::port::PortStatus;
 use crate::routing_table::RoutingTable;
 use crate::routing_table_entry::{RoutingTableEntry};
-use crate::utility::{ActivityData, Mask, OutbufType, PortNo, S, TraceHeaderParams, TraceType, write_err };
+use crate::utility::{ActivityData, Mask, OutbufType, PortNo, S, TraceHeaderParams, write_err };
 use crate::uuid_ec::{AitState, Uuid};
 
 // I need one slot per port, but ports use 1-based indexing.  I could subtract 1 all the time,
 // but it's safer to waste slot 0.
 // TODO: Use Config.max_num_phys_ports_per_cell
 const MAX_PORTS: u8 = 10; //MAX_NUM_PHYS_PORTS_PER_CELL.0 as usize + 1;
+// Credits a port starts with, shared by all its outbufs.  The packet engine at the other end of the
+// link returns one each time it takes a packet from its port, so neither the port nor the one
+// it sends to holds more than this many packets, and the rest wait here.  Credits take a round
+// trip to come back, so there are enough to keep the link busy in the meantime; as many as the
+// three traffic classes had between them before they shared, since fewer slow down discovery.
+pub const NUM_SLOTS: usize = 24;
 
 type UsizeArray = [usize; MAX_PORTS as usize];
-type InBuffer = (usize, Packet); // usize = # remaining to move to out port
-type OutBuffer = (usize, VecDeque<PortNo>);
-type BufferOld = VecDeque<(bool, PortNo, Packet)>; // bool = pong sent
-type PacketArray = Vec<BufferOld>;
+type Buffer = VecDeque<Packet>;
+type PacketArray = Vec<Buffer>;
+type TreeWeights = HashMap<Uuid, usize>;
 type Reroute = Vec<PortNo>;
 
 #[derive(Debug, Clone)]
@@ -38,60 +47,70 @@ pub struct PacketEngine {
     border_port_nos: HashSet<PortNo>,
     routing_table: RoutingTable,
     routing_table_mutex: Arc<Mutex<RoutingTable>>,  // So I can show the routing table on the console
+    counters: Arc<Mutex<PacketEngineCounters>>, // So the cell can report them
     no_seen_packets: UsizeArray, // Number of packets received since last packet sent
     no_sent_packets: UsizeArray, // Number of packets sent since last packet received
-    no_free_slots: Vec<HashMap<OutbufType, usize>>,  // Amount of room in port's out buffer
+    no_free_slots: UsizeArray,  // Credits for each port, taken by its outbufs in priority order
     sent_packets: PacketArray,  // Packets that may need to be resent
-    out_buffers: Vec<HashMap<OutbufType, OutBuffer>>,
-    out_buffers_old: PacketArray,   // Packets waiting to go on the out port
-    in_buffers: Vec<InBuffer>,
-    in_buffer_old: Vec<BufferOld>,    // Packets on the in port waiting to into out_buf on the out port
+    out_buffers: Vec<HashMap<OutbufType, OutBuffer>>, // Packets waiting for credits, never dropped
+    traffic_classes: Vec<OutbufType>, // Strict priority, highest first
+    tree_weights: TreeWeights, // Packets per turn for trees that don't get 1
     activity_data: Vec<ActivityData>,
     reroute: Reroute,
+    packet_sizes: Vec<usize>, // Settled for each link in Hello
     pe_to_cm: PeToCm,
     pe_to_ports: HashMap<PortNo, PeToPort>,
-    pe_to_ports_old: HashMap<PortNo, PeToPortOld>,
 }
 
 impl PacketEngine {
     // NEW
     pub fn new(cell_id: CellID, connected_tree_id: TreeID, pe_to_cm: PeToCm,
                pe_to_ports: HashMap<PortNo, PeToPort>,
-               pe_to_ports_old: HashMap<PortNo, PeToPortOld>,
                border_port_nos: &HashSet<PortNo>) -> PacketEngine {
         let routing_table = RoutingTable::new(cell_id);
         let routing_table_mutex = Arc::new(Mutex::new(routing_table.clone()));
         let count = [0; MAX_PORTS as usize];
+        let traffic_classes = CONFIG.get_traffic_classes();
         PacketEngine {
             cell_id,
             connected_tree_uuid: connected_tree_id.get_uuid(),
             routing_table,
             routing_table_mutex,  // Needed so I can print the routing table from main
+            counters: Arc::new(Mutex::new(PacketEngineCounters::new(MAX_PORTS as usize))),
             border_port_nos: border_port_nos.clone(),
-            no_free_slots: vec![Default::default(); MAX_PORTS as usize],
+            no_free_slots: [NUM_SLOTS; MAX_PORTS as usize],
             no_seen_packets: count,
             no_sent_packets: count,
             sent_packets: vec![Default::default(); MAX_PORTS as usize], // Slots need to be allocated ahead of time
-            out_buffers: vec![Default::default(); MAX_PORTS as usize],
-            out_buffers_old: vec![Default::default(); MAX_PORTS as usize],
-            in_buffers: vec![Default::default(); MAX_PORTS as usize],
-            in_buffer_old: vec![Default::default(); MAX_PORTS as usize],
+            out_buffers: vec![PacketEngine::per_outbuf_type(&traffic_classes, OutBuffer::new()); MAX_PORTS as usize],
+            traffic_classes,
+            tree_weights: HashMap::new(),
             activity_data: vec![Default::default(); MAX_PORTS as usize],
             reroute: vec![Default::default(); MAX_PORTS as usize],
+            packet_sizes: vec![PACKET_DEFAULT; MAX_PORTS as usize],
             pe_to_cm,
             pe_to_ports,
-            pe_to_ports_old,
         }
     }
+    fn per_outbuf_type<T: Clone>(traffic_classes: &[OutbufType], value: T) -> HashMap<OutbufType, T> {
+        traffic_classes.iter().map(|outbuf_type| (outbuf_type.clone(), value.clone())).collect()
+    }
+    // The outbuf a packet waits in; the order of traffic_classes says which one drains first
+    fn traffic_class(packet: &Packet) -> OutbufType {
+        let uuid = packet.get_tree_uuid();
+        if uuid.is_control()  { OutbufType::Control }
+        else if uuid.is_ait() { OutbufType::Ait }
+        else                  { OutbufType::Message }
+    }
     
     // SPAWN THREAD (pe.initialize)
-    pub fn start(&self, pe_from_cm: PeFromCm, pe_from_ports: PeFromPort, pe_from_ports_old: PeFromPortOld) -> JoinHandle<()> {
+    pub fn start(&self, pe_from_cm: PeFromCm, pe_from_ports: PeFromPort) -> JoinHandle<()> {
         let _f = "start_packet_engine";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.pe {
+            if let Some(event) = tracing(Level::Info, &["pe"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "start_pe" };
                 let trace = json!({ "cell_id": self.get_cell_id() });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let mut pe = self.clone();
@@ -99,14 +118,15 @@ impl PacketEngine {
         let thread_name = format!("PacketEngine {}", self.get_cell_id());
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
-            let _ = pe.initialize(pe_from_cm.clone(), pe_from_ports.clone(), pe_from_ports_old.clone()).map_err(|e| write_err("Called by nalcell", &e));
-            if CONFIG.continue_on_error { pe.start(pe_from_cm, pe_from_ports, pe_from_ports_old); } 
+            let _thread = count_thread(pe.get_cell_id());
+            let _ = pe.initialize(pe_from_cm.clone(), pe_from_ports.clone()).map_err(|e| write_err("Called by nalcell", &e));
+            if CONFIG.continue_on_error { pe.start(pe_from_cm, pe_from_ports); } 
         }).expect("thread failed")
     }
 
     // INIT (PeFromCm PeFromPort)
     // WORKER (PacketEngine)
-    pub fn initialize(&mut self, pe_from_cm: PeFromCm, pe_from_ports: PeFromPort, pe_from_ports_old: PeFromPortOld) -> Result<(), Error> {
+    pub fn initialize(&mut self, pe_from_cm: PeFromCm, pe_from_ports: PeFromPort) -> Result<(), Error> {
         let _f = "initialize";
         loop {
             select! {
@@ -118,84 +138,45 @@ impl PacketEngine {
                     let msg = recvd.context(PacketEngineError::Chain { func_name: _f, comment: S("pe from port") })?;
                     self.listen_port(msg).context(PacketEngineError::Chain { func_name: _f, comment: S("listen port") })?;
                 }
-                recv(pe_from_ports_old) -> recvd => {
-                    let msg = recvd.context(PacketEngineError::Chain { func_name: _f, comment: S("pe from port") })?;
-                    self.listen_port_old(msg).context(PacketEngineError::Chain { func_name: _f, comment: S("listen port") })?;
-                }
             }
         }
     }
     pub fn get_cell_id(&self) -> CellID { self.cell_id }
-
-    fn _get_inbuf(&self, port_no: PortNo) -> &InBuffer {
-        &self.in_buffers[port_no.as_usize()]
-    }
-    fn _get_inbuf_mut(&mut self, port_no: PortNo) -> &mut InBuffer {
-        &mut self.in_buffers[port_no.as_usize()]
-    }
-    fn _set_inbuf(&mut self, port_no: PortNo, new_count: usize, packet_ref: &Packet) {
-        self.in_buffers[port_no.as_usize()] = (new_count, packet_ref.clone());
-    }
-    fn _decrement_inbuf_count(&mut self, port_no: PortNo) -> Result<usize, Error> {
-        let _f = "decrement_inbuf_count";
-        let (count, _packet) = self._get_inbuf_mut(port_no);
-        if *count == 0 { return Err(PacketEngineError::InBuffer { func_name: _f, cell_id: self.cell_id, port_no: port_no }.into()); }
-        *count = *count - 1;
-        Ok(*count)
+    pub fn get_counters(&self) -> PacketEngineCounters { self.counters.lock().unwrap().clone() }
+    pub fn get_counters_mutex(&self) -> Arc<Mutex<PacketEngineCounters>> { self.counters.clone() }
+    fn count(&self) -> MutexGuard<'_, PacketEngineCounters> { self.counters.lock().unwrap() }
+    fn count_queue_depth(&self, port_no: PortNo) {
+        let queue_depth = self.out_buffers[port_no.as_usize()].values().map(|outbuf| outbuf.len()).sum();
+        self.count().queue_depth(port_no, queue_depth);
+    }
+    fn deliver_to_cm(&self, recv_port_no: PortNo, packet: Packet) -> Result<(), Error> {
+        self.count().packet_to_cm(&packet);
+        self.pe_to_cm.send(PeToCmPacket::Packet((recv_port_no, packet)))?;
+        Ok(())
     }
-    fn _get_outbuf(&self, outbuf_type: &OutbufType, port_no: PortNo) -> &OutBuffer {
+
+    fn get_outbuf(&self, outbuf_type: &OutbufType, port_no: PortNo) -> &OutBuffer {
         self.out_buffers[port_no.as_usize()]
             .get(&outbuf_type)
             .expect("PacketEngine: get_outbuf: OutbufType must be set")
     }
-    fn _get_outbuf_mut(&mut self, outbuf_type: &OutbufType, port_no: PortNo) -> &mut OutBuffer {
+    fn get_outbuf_mut(&mut self, outbuf_type: &OutbufType, port_no: PortNo) -> &mut OutBuffer {
         self.out_buffers[port_no.as_usize()]
             .get_mut(&outbuf_type)
             .expect("PacketEngine: get_outbuf: OutbufType must be set")
     }
-    fn _get_outbuf_size(&self, outbuf_type: &OutbufType, port_no: PortNo) -> usize {
-        self._get_outbuf(outbuf_type, port_no).1.len()
-    }
-    fn _get_outbuf_old(&self, port_no: PortNo) -> &BufferOld {
-        self.out_buffers_old.get(port_no.as_usize()).expect("PacketEngine: get_outbuf_old must succeed")
-    }
-    fn get_outbuf_mut_old(&mut self, port_no: PortNo) -> &mut BufferOld {
-        self.out_buffers_old.get_mut(port_no.as_usize()).expect("PacketEngine: get_outbuf_mut_old must succeed")
+    fn get_outbuf_size(&self, outbuf_type: &OutbufType, port_no: PortNo) -> usize {
+        self.get_outbuf(outbuf_type, port_no).len()
     }
-    fn get_size(array: &PacketArray, port_no: PortNo) -> usize {
-        array[port_no.as_usize()].len()
+    fn get_no_free_slots(&self, port_no: PortNo) -> usize {
+        self.no_free_slots[port_no.as_usize()]
     }
-    fn get_outbuf_size_old(&self, port_no: PortNo) -> usize {
-        PacketEngine::get_size(&self.out_buffers_old, port_no)
+    fn decr_no_free_slots(&mut self, port_no: PortNo) {
+        self.no_free_slots[port_no.as_usize()] -= 1;
     }
-    fn _get_outbuf_first_type_old(&self, port_no: PortNo) -> Option<MsgType> {
-        self._get_outbuf_old(port_no)
-            .get(0)
-            .map(|(_, _, packet)| MsgType::msg_type(packet))
-    }
-    fn _get_outbuf_first_ait_state_old(&self, port_no: PortNo) -> Option<AitState> {
-        self._get_outbuf_old(port_no)
-            .get(0)
-            .map(|(_, _, packet)| packet.get_ait_state())
-    }
-    fn _get_no_free_slots(&self, outbuf_type: &OutbufType, port_no: PortNo) -> usize {
-        *self.no_free_slots[port_no.as_usize()]
-            .get(&outbuf_type)
-            .expect("PacketEngine: Outbuf type must succeed")
-    }
-    fn _decr_no_free_slots(&mut self, outbuf_type: &OutbufType, port_no: PortNo) -> usize {
-        let no_free_slots = self.no_free_slots[port_no.as_usize()]
-            .get(&outbuf_type)
-            .expect("PacketEngine: Outbuf type must succeed")
-            - 1;
-        no_free_slots
-    }
-    fn _incr_no_free_slots(&mut self, outbuf_type: OutbufType, port_no: PortNo) -> usize {
-        let no_free_slots = self.no_free_slots[port_no.as_usize()]
-            .get(&outbuf_type)
-            .expect("PacketEngine: Outbuf type must succeed")
-            + 1;
-        no_free_slots
+    fn incr_no_free_slots(&mut self, port_no: PortNo) {
+        let no_free_slots = &mut self.no_free_slots[port_no.as_usize()];
+        *no_free_slots = std::cmp::min(*no_free_slots + 1, NUM_SLOTS); // A port never holds more than it was given
     }
     fn add_to_packet_count(packet_count: &mut UsizeArray, port_no: PortNo) {
          if packet_count.len() == 1 { // Replace 1 with PACKET_PIPELINE_SIZE when adding pipelining
@@ -221,27 +202,13 @@ impl PacketEngine {
     }
     fn add_sent_packet(&mut self, port_no: PortNo, packet: Packet) {
         let sent_packets = self.sent_packets.get_mut(port_no.as_usize()).expect("PacketEngine: sent_packets must be set");
-        sent_packets.push_back((true, port_no, packet));
+        sent_packets.push_back(packet);
         PacketEngine::add_to_packet_count(&mut self.no_sent_packets, port_no);
     }
     fn clear_sent_packets(&mut self, port_no: PortNo) {
         self.sent_packets.get_mut(*port_no as usize).expect("PacketEngine: sent_packets entry must be set").clear();
         self.clear_seen_packet_count(port_no);
     }
-    fn pop_first_outbuf(&mut self, port_no: PortNo) -> Option<(bool, PortNo, Packet)> {
-        self.get_outbuf_mut_old(port_no).pop_front()
-    }
-    fn add_to_out_buffer_back(&mut self, recv_port_no: PortNo, port_no: PortNo, packet: Packet) -> bool {
-        let _f = "add_to_out_buff_back";
-        // If the packet was put into the first half of the buffer, then the pong was sent.  We use true for
-        // pong_sent to denote this case.  If the packet was put into the second half of the buffer, the pong
-        // was not sent.  In that case, we remember the recv_port_no and use it to send the pong when the packet
-        // reaches the first half of the buffer.  We then set pong_sent to true.
-        let outbuf = self.get_outbuf_mut_old(port_no);
-        let pong_sent = outbuf.len() < MAX_PORTS as usize;
-        outbuf.push_back((pong_sent, recv_port_no, packet));
-        pong_sent
-    }
     // SPAWN THREAD (listen_cm_loop)
     fn listen_cm(&mut self, msg: CmToPePacket) -> Result<(), Error> {
         let _f = "listen_cm";
@@ -249,38 +216,49 @@ impl PacketEngine {
             // control plane from CellAgent
             CmToPePacket::Reroute((broken_port_no, new_parent, no_packets)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm", "visualize"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_reroute" };
                         let trace = json!({ "cell_id": &self.cell_id, "broken_port": broken_port_no, "new_parent": new_parent, "no_packets": no_packets });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.reroute_packets(broken_port_no, new_parent, no_packets);
+                self.reroute_packets(broken_port_no, new_parent, no_packets)?;
+            },
+            CmToPePacket::PacketSize((port_no, packet_size)) => {
+                {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_packet_size" };
+                        let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "packet_size": packet_size });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.packet_sizes[port_no.as_usize()] = packet_size;
             },
             CmToPePacket::Delete(uuid) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_entry" };
                         let trace = json!({ "cell_id": &self.cell_id, "uuid": uuid });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 {
-                    if CONFIG.debug_options.all || CONFIG.debug_options.pe_process_pkt {
+                    if let Some(event) = tracing(Level::Debug, &["pe_process_pkt"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_delete_entry_dbg" };
                         let trace = json!({ "cell_id": &self.cell_id, "uuid": uuid });
-                        add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.routing_table.delete_entry(uuid);
+                self.tree_weights.remove(&uuid);
                 (*self.routing_table_mutex.lock().unwrap()) = self.routing_table.clone();
             }
             CmToPePacket::Entry(entry) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_entry" };
                         let trace = json!({ "cell_id": &self.cell_id, "entry": entry });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.routing_table.set_entry(entry);
@@ -289,24 +267,34 @@ impl PacketEngine {
             // route packet, xmit to neighbor(s) or up to CModel
             CmToPePacket::Packet((user_mask, packet)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_packet" };
                         let trace = json!({ "cell_id": self.cell_id, "user_mask": user_mask, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 self.process_packet_from_cm(user_mask, packet)?;
             },
             CmToPePacket::SnakeD((ack_port_no, packet)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_snaked" };
                         let trace = json!({ "cell_id": &self.cell_id, "ack_port_no": ack_port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.send_pong_if_room(PortNo(0), ack_port_no, &packet)?;
-             }
+                self.add_to_outbuf(OutbufType::Control, ack_port_no, &packet)?;
+            },
+            CmToPePacket::TreeWeight((uuid, weight)) => {
+                {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_cm_tree_weight" };
+                        let trace = json!({ "cell_id": &self.cell_id, "uuid": uuid, "weight": weight });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.tree_weights.insert(uuid, weight);
+            }
         };
         Ok(())
     }
@@ -316,10 +304,10 @@ impl PacketEngine {
         match msg {
             PortToPePacket::Activity((port_no, data)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
+                    if let Some(event) = tracing(Level::Info, &["pe_port"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_activity" };
                         let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "activity data": data });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 let p = port_no.as_usize();
@@ -329,62 +317,111 @@ impl PacketEngine {
                 }
                 self.activity_data[p].update(&data);
             },
-            PortToPePacket::Increment((port_no, outbuf)) => {
+            // The other end of the link took a packet, so it has room for another
+            PortToPePacket::Increment((port_no, outbuf_type)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
+                    if let Some(event) = tracing(Level::Info, &["pe_port"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_increment" };
-                        let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "outbuf": outbuf });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "outbuf": outbuf_type,
+                            "outbuf_size": self.get_outbuf_size(&outbuf_type, port_no) });
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
+                self.incr_no_free_slots(port_no);
+                self.send_packet_flow_control(port_no)?;
             },
+            // recv from neighbor
             PortToPePacket::Packet((port_no, packet)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
+                    if let Some(event) = tracing(Level::Info, &["pe_port"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_packet" };
                         let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.process_packet_from_port(port_no, packet)?;
+                self.count().packet_in(port_no, &packet);
+                self.process_packet_from_port(port_no, packet).context(PacketEngineError::Chain { func_name: _f, comment: S("process_packet ") + &self.cell_id.get_name() })?;
+                // The packet is out of the port's queue, so the sender can send another
+                if let Some(pe_to_port) = self.pe_to_ports.get(&port_no) {
+                    pe_to_port.send(PeToPortPacket::Credit)?;
+                }
             },
+            // deliver to CModel
             PortToPePacket::Status((port_no, is_border, status)) => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
+                    if let Some(event) = tracing(Level::Info, &["pe_port"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_status" };
                         let trace = json!({ "cell_id": &self.cell_id,  "port": port_no, "is_border": is_border, "status": status});
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                let number_of_packets = NumberOfPackets {
+                    sent: self.get_no_sent_packets(port_no),
+                    recd: self.get_no_seen_packets(port_no)
+                };
+                let port_status = match status {
+                    PortStatus::Connected => {
+                        // Nothing is in flight on a new link, so the port starts with all its credits
+                        self.no_free_slots[port_no.as_usize()] = NUM_SLOTS;
+                        LinkStatus::Connected
+                    },
+                    PortStatus::Disconnected(_) => LinkStatus::Disconnected
+                };
+                {
+                    if let Some(event) = tracing(Level::Info, &["pe"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_status" };
+                        let trace = json!({ "cell_id": &self.cell_id, "port": port_no, "is_border": is_border, "no_packets": number_of_packets, "status": port_status });
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                //self.pe_to_cm.send(PeToCmPacket::Status((port_no, is_border, status, packet_opt))).context(PacketEngineError::Chain { func_name: "listen_port", comment: S("send status to ca ") + &self.cell_id.get_name() })?
+                self.pe_to_cm.send(PeToCmPacket::Status((port_no, is_border, number_of_packets, port_status))).context(PacketEngineError::Chain { func_name: _f, comment: S("send status to ca ") + &self.cell_id.get_name() })?
             }
         }
         Ok(())
     }
     fn process_packet_from_port(&mut self, recv_port_no: PortNo, packet: Packet) -> Result<(), Error> {
         let _f = "process_packet_from_port";
+        // Got a packet from the other side, so clear state
+        self.clear_sent_packets(recv_port_no);
+        {
+            if let Some(event) = tracing(Level::Debug, &["flow_control"]).filter(|_| packet.get_ait_state() == AitState::Normal) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_flow_control_recv" };
+                let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "msg_type": MsgType::msg_type(&packet),
+                    "outbuf_size": self.get_outbuf_size(&OutbufType::Message, recv_port_no) });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
         match packet.get_ait_state() {
-            AitState::Teck | // Forward packet if end-to-end AIT, but now we have hop by hop
-            AitState::Tack | // "
-            AitState::Tuck | // "
-            AitState::Tyck | // "
-            AitState::Tick | // "
-            AitState::Tock | // "
-            AitState::Init => {
+            AitState::Init |
+            AitState::Teck |
+            AitState::Tack |
+            AitState::Tuck |
+            AitState::Tyck |
+            AitState::Tock |
+            AitState::Tick => {
                 return Err(PacketEngineError::Ait { func_name: _f, ait_state: packet.get_ait_state() }.into())
             },
             AitState::SnakeD => {
                 {
-                    if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                    if let Some(event) = tracing(Level::Info, &["pe"]) {
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet_snaked" };
                         let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
-                self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet)))?;
+                self.deliver_to_cm(recv_port_no, packet)?;
             },
-            AitState::AitD => (), // TODO: Send to cm once cell agent knows how to handle it
-            AitState::Ait | // Implements hop-by-hop AIT
+            AitState::Ait  => { // Goes to cm until we have multi-hop AIT
+                {
+                    if let Some(event) = tracing(Level::Info, &["pe"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet" };
+                        let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "packet": packet.stringify()? });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.deliver_to_cm(recv_port_no, packet)?
+            },
+            AitState::AitD => self.count().dropped(recv_port_no), // TODO: Send to cm once cell agent knows how to handle it
             AitState::Normal => { // Forward packet
                 let uuid = packet.get_tree_uuid().for_lookup();
                 let entry = match self.routing_table.get_entry(uuid) {
@@ -392,24 +429,33 @@ impl PacketEngine {
                     Err(err) => {
                         // deliver to CellAgent when tree not recognized
                         {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                            if let Some(event) = tracing(Level::Info, &["pe"]) {
                                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet_err" };
                                 let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "err": err.to_string(), "packet": packet });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                add_to_trace(event, trace_params, &trace, _f);
                             }
                         }
-                        self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet))).context(PacketEngineError::Chain { func_name: "forward", comment: S("rootcast packet to ca ") + &self.cell_id.get_name() })?;
+                        self.deliver_to_cm(recv_port_no, packet).context(PacketEngineError::Chain { func_name: "forward", comment: S("rootcast packet to ca ") + &self.cell_id.get_name() })?;
                         return Ok(())
                     }
                 };
-                { 
+                { // Debug block
                     {
-                        let ait_state = packet.get_ait_state();
-                        if CONFIG.debug_options.all | CONFIG.debug_options.pe_process_pkt {
+                        if let Some(event) = tracing(Level::Debug, &["pe_pkt_send"]) {
+                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_entry" };
+                            let trace = json!({ "cell_id": &self.cell_id, "entry": entry, "packet": packet });
+                            add_to_trace(event, trace_params, &trace, _f);
+                        }
+                    }
+                    let msg_type = MsgType::msg_type(&packet);
+                    let uuid = packet.get_uuid();
+                    let ait_state = packet.get_ait_state();
+                    {
+                        if let Some(event) = tracing(Level::Debug, &["pe_process_pkt"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_process_packet" };
-                            let trace = json!({ "cell_id": self.cell_id, "ait_state": ait_state,
-                                "port_no": &recv_port_no, "entry": &entry, "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Debug, trace_params, &trace, _f);
+                            let trace = json!({ "cell_id": self.cell_id, "uuid": uuid, "ait_state": ait_state,
+                            "msg_type": &msg_type, "port_no": &recv_port_no, "entry": &entry });
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
                 }
@@ -417,65 +463,31 @@ impl PacketEngine {
                     // Put packets on the right port's queue
                     let mask = entry.get_mask();
                     self.forward(recv_port_no, entry, mask, &packet).context(PacketEngineError::Chain { func_name: "process_packet", comment: S("forward ") + &self.cell_id.get_name() })?;
+                } else {
+                    self.count().dropped(recv_port_no);
                 }
             }
         }
         Ok(())
     }
-    // TODO: One thread for all ports; should be a different thread for each port
-    fn listen_port_old(&mut self, msg_old: PortToPePacketOld) -> Result<(), Error> {
-        let _f = "listen_port_old";
-        match msg_old {
-            // deliver to CModel
-            PortToPePacketOld::Status((port_no, is_border, port_status)) => {
-                {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
-                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_status_old" };
-                        let trace = json!({ "cell_id": &self.cell_id,  "port": port_no, "is_border": is_border, "status": port_status});
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                    }
-                }
-                let number_of_packets = NumberOfPackets {
-                    sent: self.get_no_sent_packets(port_no),
-                    recd: self.get_no_seen_packets(port_no)
-                };
-                {
-                    if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_status_old" };
-                        let trace = json!({ "cell_id": &self.cell_id, "port": port_no, "is_border": is_border, "no_packets": number_of_packets, "status": port_status });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                    }
-                }
-                self.pe_to_cm.send(PeToCmPacketOld::Status((port_no, is_border, number_of_packets, port_status))).context(PacketEngineError::Chain { func_name: "listen_port", comment: S("send status to ca ") + &self.cell_id.get_name() })?
-            },
-            
-            // recv from neighbor
-            PortToPePacketOld::Packet((port_no, packet)) => {
-                {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
-                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_from_port_packet_old" };
-                        let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                    }
-                }
-                self.process_packet_from_port_old(port_no, packet).context(PacketEngineError::Chain { func_name: "listen_port", comment: S("process_packet ") + &self.cell_id.get_name() })?
-            }
-        };
-        Ok(())
-    }
-    fn reroute_packets(&mut self, broken_port_no: PortNo, new_parent: PortNo, no_packets: NumberOfPackets) {
+    fn reroute_packets(&mut self, broken_port_no: PortNo, new_parent: PortNo, no_packets: NumberOfPackets) -> Result<(), Error> {
         let _f = "reroute_packets";
         self.reroute[broken_port_no.as_usize()] = new_parent;
         let no_my_sent_packets = self.get_no_sent_packets(broken_port_no);
         let sent_buf = &mut self.sent_packets[broken_port_no.as_usize()];
         let no_her_seen_packets = no_packets.get_number_seen();
         let no_resend = no_my_sent_packets - no_her_seen_packets;
-        let mut remaining_sent = sent_buf.split_off(no_resend);
-        let broken_outbuf = &mut self.get_outbuf_mut_old(broken_port_no).clone();
-        let new_parent_outbuf = &mut self.get_outbuf_mut_old(new_parent);
-        new_parent_outbuf.append(&mut remaining_sent);
-        new_parent_outbuf.append(broken_outbuf); 
-        self.get_outbuf_mut_old(broken_port_no).clear(); // Because I had to clone the buffer
+        let remaining_sent = sent_buf.split_off(no_resend);
+        self.count().retransmitted(new_parent, remaining_sent.len());
+        for packet in remaining_sent {
+            self.get_outbuf_mut(&PacketEngine::traffic_class(&packet), new_parent).push_back(packet);
+        }
+        // Control packets go hop by hop, so they die with the link
+        for outbuf_type in self.traffic_classes.clone().iter().filter(|&outbuf_type| *outbuf_type != OutbufType::Control) {
+            let broken_outbuf = std::mem::replace(self.get_outbuf_mut(outbuf_type, broken_port_no), OutBuffer::new());
+            self.get_outbuf_mut(outbuf_type, new_parent).extend(broken_outbuf.into_packets());
+        }
+        self.send_packet_flow_control(new_parent)
     }
     fn process_packet_from_cm(&mut self, user_mask: Mask, packet: Packet) -> Result<(), Error> {
         let _f = "process_packet_from_cm";
@@ -494,176 +506,22 @@ impl PacketEngine {
             AitState::Normal |
             AitState::Ait => {
                 {
-                    if CONFIG.trace_options.all || CONFIG.trace_options.pe_cm {
+                    if let Some(event) = tracing(Level::Info, &["pe_cm"]) {
                         let uuid = packet.get_uuid();
                         let ait_state = packet.get_ait_state();
                         let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_packet_from_cm" };
                         let trace = json!({ "cell_id": self.cell_id, "uuid": uuid, "ait_state": ait_state, 
                             "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
-                    if CONFIG.debug_options.pe_pkt_recv {
-                        let msg_type = MsgType::msg_type(&packet);
-                        match msg_type {
-                            MsgType::Manifest => println!("PacketEngine {}: {} got from cm {} {}", self.cell_id, _f, msg_type, user_mask),
-                            _ => (),
-                        }
+                    if let Some(event) = tracing(Level::Debug, &["pe_pkt_recv"]).filter(|_| MsgType::msg_type(&packet) == MsgType::Manifest) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_manifest_from_cm" };
+                        let trace = json!({ "cell_id": &self.cell_id, "msg_type": MsgType::Manifest, "user_mask": user_mask });
+                        add_to_trace(event, trace_params, &trace, _f);
                     }
                 }
                 let port_no = PortNo(0);
-                self.forward_old(port_no, entry, user_mask, &packet).context(PacketEngineError::Chain { func_name: _f, comment: S(self.cell_id.get_name()) })?;
-            }
-        }
-        Ok(())
-    }
-    fn send_packet_to_port_or_cm(&mut self, recv_port_no: PortNo, packet: &Packet) -> Result<(), Error> {
-        let _f = "send_packet_to_outbuf";
-        let mut reroute_port_no = self.reroute[recv_port_no.as_usize()];
-        {
-            if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
-                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_port_or_cm_packet" };
-                let trace = json!({ "cell_id": self.cell_id, "recv_port_no": recv_port_no, "reroute_port_no": reroute_port_no, "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-            }
-        }
-        if reroute_port_no == PortNo(0) {
-            reroute_port_no = recv_port_no;
-        } else {
-            let broken_outbuf = &mut self.get_outbuf_mut_old(recv_port_no).clone();
-            if broken_outbuf.len() > 0 {
-                let reroute_outbuf = self.get_outbuf_mut_old(reroute_port_no);
-                reroute_outbuf.append(broken_outbuf);
-                self.get_outbuf_mut_old(recv_port_no).clear();
-            }
-        }
-        if recv_port_no == PortNo(0) {
-            self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone())))?;
-        } else {
-            self.add_seen_packet_count(recv_port_no);
-            self.pe_to_ports_old.get(&reroute_port_no)
-                .ok_or::<Error>(PacketEngineError::Sender { cell_id: self.cell_id, func_name: _f, port_no: reroute_port_no }.into())?
-                .send(packet.clone())?;
-        }
-        Ok(())
-    }
-    fn send_packet_flow_control(&mut self, port_no: PortNo) -> Result<(), Error> {
-        let _f = "send_packet_flow_control";
-        let first = self.pop_first_outbuf(port_no);
-        {
-            if CONFIG.trace_options.all || CONFIG.trace_options.pe_port {
-                let first_str = match first {
-                    Some((pong_sent, recv_port_no, packet)) => format!("pong_sent: {}, recv_port_no: {}, packet: {}", pong_sent, recv_port_no, packet.stringify()?),
-                    None => "None".to_owned()
-                };
-                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_first_in_buffer" };
-                let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "first": first_str });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-            }
-        }
-        if let Some((_, _, packet)) = first {
-            let outbuf = self.get_outbuf_mut_old(port_no);
-            let last_item = outbuf.get(MAX_PORTS as usize);
-            if let Some((pong_sent, recv_port_no, last_packet)) = last_item {
-                if !pong_sent {
-                    let recv_port_no = *recv_port_no; // Needed to avoid https://github.com/rust-lang/rust/issues/59159>
-                    outbuf[MAX_PORTS as usize] = (true, recv_port_no, last_packet.clone());
-                    self.send_packet_flow_control(recv_port_no)?;
-                }
-            }
-            self.send_packet_to_port_or_cm(port_no, &packet)?;
-            self.add_sent_packet(port_no, packet);
-        }
-        Ok(())
-    }
-    fn process_packet_from_port_old(&mut self, recv_port_no: PortNo, packet: Packet)
-                                -> Result<(), Error> {
-        let _f = "process_packet_from_port_old";
-        // Got a packet from the other side, so clear state
-        self.clear_sent_packets(recv_port_no);
-        {
-            if CONFIG.debug_options.all || CONFIG.debug_options.flow_control {
-                let msg_type = MsgType::msg_type(&packet);
-                match packet.get_ait_state() {
-                    AitState::Normal => println!("PacketEngine {}: recv port {} {} outbuf size {} msg type {} {}", self.cell_id, *recv_port_no, _f, self.get_outbuf_size_old(recv_port_no), msg_type, packet.get_ait_state()),
-                    _ => ()
-                }
-            }
-        }
-        match packet.get_ait_state() {
-            AitState::Init |
-            AitState::Teck |
-            AitState::Tack |
-            AitState::Tuck |
-            AitState::Tyck |
-            AitState::Tock |
-            AitState::Tick => {
-                return Err(PacketEngineError::Ait { func_name: _f, ait_state: packet.get_ait_state() }.into())
-            },
-            AitState::SnakeD => {
-                {
-                    if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet_snaked_old" };
-                        let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                    }
-                }
-                self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet)))?;
-            },
-            AitState::Ait  => { // Goes to cm until we have multi-hop AIT
-                {
-                    if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet_old" };
-                        let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "packet": packet.stringify()? });
-                        add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                    }
-                }
-                self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet)))?
-            },
-            AitState::AitD => (), // TODO: Send to cm once cell agent knows how to handle it
-            AitState::Normal => { // Forward packet
-                let uuid = packet.get_tree_uuid().for_lookup();
-                let entry = match self.routing_table.get_entry(uuid) {
-                    Ok(e) => e,
-                    Err(err) => {
-                        // deliver to CellAgent when tree not recognized
-                        {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_packet_err_old" };
-                                let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "err": err.to_string(), "packet": packet });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                            }
-                        }
-                        self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet))).context(PacketEngineError::Chain { func_name: "forward", comment: S("rootcast packet to ca ") + &self.cell_id.get_name() })?;
-                        return Ok(())
-                    }
-                };
-                { // Debug block
-                    {
-                        if CONFIG.debug_options.all | CONFIG.debug_options.pe_pkt_send {
-                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_entry_old" };
-                            let trace = json!({ "cell_id": &self.cell_id, "entry": entry, "packet": packet });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                        }
-                    }
-                    let msg_type = MsgType::msg_type(&packet);
-                    let uuid = packet.get_uuid();
-                    let ait_state = packet.get_ait_state();
-                    {
-                        if CONFIG.debug_options.all | CONFIG.debug_options.pe_process_pkt {
-                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_process_packet_old" };
-                            let trace = json!({ "cell_id": self.cell_id, "uuid": uuid, "ait_state": ait_state,
-                            "msg_type": &msg_type, "port_no": &recv_port_no, "entry": &entry });
-                            add_to_trace(TraceType::Debug, trace_params, &trace, _f);
-                        }
-                    }
-                }
-                if entry.is_in_use() {
-                    // Put packets on the right port's queue
-                    let mask = entry.get_mask();
-                    self.forward_old(recv_port_no, entry, mask, &packet).context(PacketEngineError::Chain { func_name: "process_packet", comment: S("forward ") + &self.cell_id.get_name() })?;
-                    // Send the packet at the head of this port's queue
-                    self.send_packet_flow_control(recv_port_no)?;
-                }
+                self.forward(port_no, entry, user_mask, &packet).context(PacketEngineError::Chain { func_name: _f, comment: S(self.cell_id.get_name()) })?;
             }
         }
         Ok(())
@@ -673,165 +531,32 @@ impl PacketEngine {
         let _f = "forward";
         let packet = packet_ref.clone();
         {
-            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_forward" };
-                let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "user_mask": user_mask, 
-                    "entry_mask": entry.get_mask(), "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-            }
-        }
-        let count = if packet.get_tree_uuid().is_control() {
-            0  // Control message, send with CA flow control (currently none)
-        } else if recv_port_no != entry.get_parent() { // Rootward
-            1  // Send to parent
-        } else { // Leafward
-            // Don't need following line once flow control for CA is implemented
-            let test_mask = entry.get_mask().and(Mask::all_but_zero(PortQty(MAX_PORTS)));
-            test_mask.get_no_ports() // Send leafward to potentially multiple ports
-        };
-        self._set_inbuf(recv_port_no, count, packet_ref);
-        if packet.get_tree_uuid().is_control() {
-            // No snake for hop-by-hop messages
-            // Send with CA flow control (currently none)
-            let mask = user_mask.and(entry.get_mask());
-            let port_nos = mask.get_port_nos();
-            {
-                if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_forward_connected_tree" };
-                    let trace = json!({ "cell_id": &self.cell_id, "port": recv_port_no,
-                        "user_mask": user_mask, "entry_mask": entry.get_mask(), "port_nos": port_nos,
-                        "packet": packet.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                }
-            }
-            for &port_no in port_nos.iter() {
-                if port_no == PortNo(0) { 
-                    self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone())))?;
-                } else {
-                    // Replace with OutbufType::Control
-                    self._move_to_outbuf(OutbufType::Control, recv_port_no, port_no)?;
-                }
-            }
-        } else {
-            if recv_port_no != entry.get_parent() {
-                // Send to root if recv port is not parent
-                let parent = entry.get_parent();
-                if *parent == 0 {
-                    // deliver to CModel
-                    {
-                        if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_as_parent" };
-                            let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "entry": entry, "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                        }
-                    }
-                    self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone())))?;
-                } else {
-                    // Forward rootward
-                    {
-                        if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_ports_rootward" };
-                            let trace = json!({ "cell_id": &self.cell_id, "port": recv_port_no, "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                        }
-                    }
-                    self._move_to_outbuf(OutbufType::Message, recv_port_no, parent)?;
-                }
-            } else {
-                // Send leafward if recv port is parent
-                let mask = user_mask.and(entry.get_mask());
-                let port_nos = mask.get_port_nos();
-                // Only side effects so use explicit loop instead of map
-                for port_no in port_nos.iter().cloned() {
-                    if *port_no == 0 {
-                        // deliver to CModel
-                        {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_leafward_uptree" };
-                                let trace = json!({ "cell_id": &self.cell_id, "mask": mask, "recv_port_no": recv_port_no, "port_no": port_no, "packet": packet_ref.stringify()? });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                            }
-                        }
-                        self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone()))).context(PacketEngineError::Chain { func_name: _f, comment: S("leafcast packet to ca ") + &self.cell_id.get_name() })?;
-                    } else {
-                        // forward to neighbor
-                        {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_port_leafward" };
-                                let trace = json!({ "cell_id": &self.cell_id, "mask": mask, "recv_port_no": recv_port_no, "port_no": port_no, "packet": packet.stringify()? });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                            }
-                        }
-                        self._move_to_outbuf(OutbufType::Message, recv_port_no, port_no)?;
-                    }
-                }
-            }
-        };
-        if packet.is_snake() {
-            {
-                if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_snake" };
-                    let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "count": count, "is_snake": true, "packet": packet_ref.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
-                }
-            }
-            self.pe_to_cm.send(PeToCmPacketOld::Snake((recv_port_no, count, packet)))?; 
-        }
-        Ok(())
-    }
-    fn _move_to_outbuf(&mut self, outbuf_type: OutbufType, recv_port_no: PortNo, port_no: PortNo) -> Result<(), Error> {
-        let (_no_free_slots, outbuf) = self._get_outbuf_mut(&outbuf_type, port_no);
-        outbuf.push_back(recv_port_no);
-        assert!(outbuf.len() <= MAX_PORTS as usize); // Should be guaranteed by flow control
-        self._send_to_port(&outbuf_type, recv_port_no, port_no)?;
-        Ok(()) 
-    }
-    fn _send_to_port(&mut self, outbuf_type: &OutbufType, recv_port_no: PortNo, port_no: PortNo) -> Result<(), Error> {
-        while self._get_no_free_slots(outbuf_type, port_no) > 0 {
-            self._decr_no_free_slots(outbuf_type, port_no);
-            let (_, packet_ref) = self._get_inbuf(port_no);
-            let pe_to_port = self.pe_to_ports.get(&port_no).expect("PacketEngine: pe_to_ports must be set");
-            pe_to_port.send(PeToPortPacket::Packet((OutbufType::Message, packet_ref.clone())))?;
-            if self._decrement_inbuf_count(port_no)? == 0 {
-                let pe_to_recv_port = self.pe_to_ports.get(&recv_port_no).expect("PacketEngine: pe_to_ports must be set");
-                pe_to_recv_port.send(PeToPortPacket::Ready)?;
-            }
-        }
-        Ok(())
-    }
-    fn forward_old(&mut self, recv_port_no: PortNo, entry: RoutingTableEntry, user_mask: Mask, packet_ref: &Packet)
-            -> Result<(), Error> {
-        let _f = "forward_old";
-        let packet = packet_ref.clone();
-        {
-            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+            if let Some(event) = tracing(Level::Info, &["pe"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_forward" };
                 let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "user_mask": user_mask, 
                     "entry_mask": entry.get_mask(), "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let count = if packet.get_tree_uuid().is_control() {
             // No snake for hop-by-hop messages
-            // Send with CA flow control (currently none)
+            // The control outbuf goes ahead of messages
             let mask = user_mask.and(entry.get_mask());
             let port_nos = mask.get_port_nos();
             {
-                if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                if let Some(event) = tracing(Level::Info, &["pe"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_forward_connected_tree" };
                     let trace = json!({ "cell_id": &self.cell_id, "port": recv_port_no,
                         "user_mask": user_mask, "entry_mask": entry.get_mask(), "port_nos": port_nos,
                         "packet": packet.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             for &port_no in port_nos.iter() {
-                // Should add_to_buffer_front, but that may confuse my "when to send pong" algorithm
                 if port_no == PortNo(0) { 
-                    self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone())))?;
+                    self.deliver_to_cm(recv_port_no, packet.clone())?;
                 } else {
-                    let pe_to_port = self.pe_to_ports_old.get(&port_no).expect("PacketEngine forward pe_to_port must be defined");
-                    pe_to_port.send(packet.clone())?;  // Control message so just send
+                    self.add_to_outbuf(OutbufType::Control, port_no, &packet)?;
                 }
             }
             0
@@ -842,24 +567,24 @@ impl PacketEngine {
                 if *parent == 0 {
                     // deliver to CModel
                     {
-                        if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                        if let Some(event) = tracing(Level::Info, &["pe"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_as_parent" };
                             let trace = json!({ "cell_id": &self.cell_id, "recv_port": recv_port_no, "entry": entry, "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
-                    self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone())))?;
+                    self.deliver_to_cm(recv_port_no, packet.clone())?;
                     0
                 } else {
                     // Forward rootward
                     {
-                        if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                        if let Some(event) = tracing(Level::Info, &["pe"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_ports_rootward" };
                             let trace = json!({ "cell_id": &self.cell_id, "port": recv_port_no, "packet": packet.stringify()? });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
-                    self.send_pong_if_room(recv_port_no, parent, &packet)?;
+                    self.add_to_outbuf(PacketEngine::traffic_class(&packet), parent, &packet)?;
                     1
                 }
             } else {
@@ -872,31 +597,30 @@ impl PacketEngine {
                     if *port_no == 0 {
                         // deliver to CModel
                         {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                            if let Some(event) = tracing(Level::Info, &["pe"]) {
                                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_leafward_uptree" };
                                 let trace = json!({ "cell_id": &self.cell_id, "mask": mask, "recv_port_no": recv_port_no, "port_no": port_no, "packet": packet_ref.stringify()? });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                add_to_trace(event, trace_params, &trace, _f);
                             }
                         }
-                        self.pe_to_cm.send(PeToCmPacketOld::Packet((recv_port_no, packet.clone()))).context(PacketEngineError::Chain { func_name: _f, comment: S("leafcast packet to ca ") + &self.cell_id.get_name() })?;
+                        self.deliver_to_cm(recv_port_no, packet.clone()).context(PacketEngineError::Chain { func_name: _f, comment: S("leafcast packet to ca ") + &self.cell_id.get_name() })?;
                     } else {
                         count = count + 1;  // Only count ports other than 0
                         // forward to neighbor
                         {
-                            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                            if let Some(event) = tracing(Level::Info, &["pe"]) {
                                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_port_leafward" };
                                 let trace = json!({ "cell_id": &self.cell_id, "mask": mask, "recv_port_no": recv_port_no, "port_no": port_no, "packet": packet.stringify()? });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                add_to_trace(event, trace_params, &trace, _f);
                             }
-                            if CONFIG.debug_options.all || CONFIG.debug_options.flow_control {
-                                let msg_type = MsgType::msg_type(&packet);
-                                match packet.get_ait_state() {
-                                    AitState::Normal => println!("PacketEngine {}: port {} {} outbuf size {} msg type {} {}", self.cell_id, *port_no, _f, self.get_outbuf_size_old(port_no), msg_type, packet.get_ait_state()),
-                                    _ => ()
-                                }
+                            if let Some(event) = tracing(Level::Debug, &["flow_control"]).filter(|_| packet.get_ait_state() == AitState::Normal) {
+                                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_flow_control_forward" };
+                                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "msg_type": MsgType::msg_type(&packet),
+                                    "outbuf_size": self.get_outbuf_size(&OutbufType::Message, port_no) });
+                                add_to_trace(event, trace_params, &trace, _f);
                             }
                         }
-                        self.send_pong_if_room(recv_port_no, port_no, &packet)?;
+                        self.add_to_outbuf(PacketEngine::traffic_class(&packet), port_no, &packet)?;
                     }
                 }
                 count
@@ -904,34 +628,131 @@ impl PacketEngine {
         };
         if packet.is_snake() {
             {
-                if CONFIG.trace_options.all | CONFIG.trace_options.pe {
+                if let Some(event) = tracing(Level::Info, &["pe"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_cm_snake" };
                     let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "count": count, "is_snake": true, "packet": packet_ref.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
-            self.pe_to_cm.send(PeToCmPacketOld::Snake((recv_port_no, count, packet)))?; 
+            self.pe_to_cm.send(PeToCmPacket::Snake((recv_port_no, count, packet)))?; 
         }
         Ok(())
     }
-    // Send reply if there is room for my packet in the buffer of the out port
-    fn send_pong_if_room(&mut self, recv_port_no: PortNo, port_no: PortNo, packet: &Packet) ->
-            Result<(), Error> {
-        let _f = "send_pong_if_room";
-        let has_room = self.add_to_out_buffer_back(recv_port_no, port_no, packet.clone());
+    // Queue a packet for a port, fragmented to the link's packet size, and send what the port has
+    // credits for.  The rest wait in the queue, so nothing is dropped when the port is busy.
+    fn add_to_outbuf(&mut self, outbuf_type: OutbufType, port_no: PortNo, packet: &Packet) -> Result<(), Error> {
+        let _f = "add_to_outbuf";
+        let reroute_port_no = self.reroute[port_no.as_usize()];
+        let port_no = if reroute_port_no == PortNo(0) { port_no } else { reroute_port_no };
+        if port_no == PortNo(0) {
+            self.deliver_to_cm(port_no, packet.clone())?;
+            return Ok(());
+        }
+        let fragments = packet.fragment(self.packet_sizes[port_no.as_usize()]);
+        self.get_outbuf_mut(&outbuf_type, port_no).extend(fragments);
+        self.count_queue_depth(port_no);
         {
-            if CONFIG.trace_options.all | CONFIG.trace_options.pe {
-                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_send_pong" };
-                let trace = json!({ "cell_id": &self.cell_id, "recv_port_no": recv_port_no, "port_no": port_no, "has_room": has_room, "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+            if let Some(event) = tracing(Level::Info, &["pe"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_add_to_outbuf" };
+                let trace = json!({ "cell_id": &self.cell_id, "port_no": port_no, "outbuf": outbuf_type,
+                    "outbuf_size": self.get_outbuf_size(&outbuf_type, port_no), "no_free_slots": self.get_no_free_slots(port_no),
+                    "packet": packet.stringify()? });
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
-        if has_room {  // Send pong if packet went into first half of outbuf
-            self.send_packet_flow_control(port_no)?;
+        self.send_packet_flow_control(port_no)
+    }
+    // Send as many packets as the port has credits for, in strict priority order of the traffic
+    // classes.  The classes share the credits, so a lower class only gets one when every class
+    // ahead of it is empty, and a flood of messages can't hold the link against a Discover.
+    fn send_packet_flow_control(&mut self, port_no: PortNo) -> Result<(), Error> {
+        let _f = "send_packet_flow_control";
+        for outbuf_type in self.traffic_classes.clone().iter() {
+            while self.get_no_free_slots(port_no) > 0 {
+                let tree_weights = &self.tree_weights;
+                let packet = match self.out_buffers[port_no.as_usize()]
+                        .get_mut(outbuf_type)
+                        .expect("PacketEngine: send_packet_flow_control: OutbufType must be set")
+                        .pop_front(tree_weights) {
+                    Some(packet) => packet,
+                    None => break
+                };
+                self.decr_no_free_slots(port_no);
+                {
+                    if let Some(event) = tracing(Level::Info, &["pe_port"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "pe_to_port_packet" };
+                        let trace = json!({ "cell_id": self.cell_id, "port_no": port_no, "outbuf": outbuf_type,
+                            "no_free_slots": self.get_no_free_slots(port_no), "packet": packet.stringify()? });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                self.count().packet_out(port_no, &packet);
+                if *outbuf_type != OutbufType::Control { // Control packets go hop by hop, so they are never resent
+                    self.add_seen_packet_count(port_no);
+                    self.add_sent_packet(port_no, packet.clone());
+                }
+                self.pe_to_ports.get(&port_no)
+                    .ok_or::<Error>(PacketEngineError::Sender { cell_id: self.cell_id, func_name: _f, port_no }.into())?
+                    .send(PeToPortPacket::Packet((outbuf_type.clone(), packet)))?;
+            }
         }
+        self.count_queue_depth(port_no);
         Ok(())
     }
 }
+// Packets waiting for credits in one traffic class of a port.  Each tree has its own queue, and
+// the trees take turns, sending as many packets per turn as their weights (deficit round robin),
+// so a tree with a flood of packets only slows the others down in proportion to the weights.
+#[derive(Debug, Clone, Default)]
+pub struct OutBuffer {
+    queues: HashMap<Uuid, Buffer>,
+    turns: VecDeque<Uuid>, // Trees with packets waiting, the one sending first
+    deficits: HashMap<Uuid, usize>, // Packets left in a tree's current turn
+    len: usize,
+}
+impl OutBuffer {
+    pub fn new() -> OutBuffer { Default::default() }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+    pub fn push_back(&mut self, packet: Packet) {
+        let uuid = packet.get_tree_uuid().for_lookup();
+        if !self.queues.contains_key(&uuid) { self.turns.push_back(uuid); }
+        self.queues.entry(uuid).or_insert_with(Buffer::new).push_back(packet);
+        self.len += 1;
+    }
+    pub fn extend<I: IntoIterator<Item=Packet>>(&mut self, packets: I) {
+        for packet in packets { self.push_back(packet); }
+    }
+    // Trees not in tree_weights get 1 packet per turn
+    pub fn pop_front(&mut self, tree_weights: &TreeWeights) -> Option<Packet> {
+        let uuid = *self.turns.front()?;
+        let weight = tree_weights.get(&uuid).cloned().unwrap_or(1).max(1);
+        let deficit = self.deficits.entry(uuid).or_insert(weight);
+        *deficit -= 1;
+        let turn_over = *deficit == 0;
+        let queue = self.queues.get_mut(&uuid).expect("OutBuffer: pop_front: a tree with a turn must have a queue");
+        let packet = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&uuid);
+            self.deficits.remove(&uuid);
+            self.turns.pop_front();
+        } else if turn_over {
+            self.deficits.remove(&uuid);
+            self.turns.pop_front();
+            self.turns.push_back(uuid);
+        }
+        self.len -= 1;
+        packet
+    }
+    // Keeps the order of each tree's packets, which is all that matters on a new port
+    pub fn into_packets(self) -> Vec<Packet> {
+        let OutBuffer { mut queues, turns, .. } = self;
+        turns
+            .into_iter()
+            .flat_map(|uuid| queues.remove(&uuid).unwrap_or_default())
+            .collect()
+    }
+}
 impl fmt::Display for PacketEngine {
     fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut s = format!("Packet Engine for cell {}", self.cell_id);
@@ -965,8 +786,6 @@ pub enum PacketEngineError {
     //Buffer { func_name: &'static str, buffer_name: &'static str },
     #[fail(display = "PacketEngineError::Chain {} {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
-    #[fail(display = "PacketEngineError::InBuffer {} port {} on cell {} count is zero", func_name, port_no, cell_id)]
-    InBuffer { func_name: &'static str, cell_id: CellID, port_no: PortNo},
     #[fail(display = "PacketEngineError::Sender {}: No sender for port {} on cell {}", func_name, port_no, cell_id)]
     Sender { func_name: &'static str, cell_id: CellID, port_no: PortNo },
     #[fail(display = "PacketEngineError::Uuid {}: CellID {}: type {} entry uuid {}, packet uuid {}", func_name, cell_id, msg_type, table_uuid, packet_uuid)]
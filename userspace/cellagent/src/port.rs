@@ -12,28 +12,26 @@ use std::{
 use crate::app_message_formats::{PortToCa, PortToCaMsg, PortFromCa};
 use crate::config::CONFIG;
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
-use crate::ec_message_formats::{PortToPe, PortFromPe, PortToPeOld, PortFromPeOld};
+use crate::ec_message_formats::{PortToPe, PortFromPe, PeToPortPacket};
+use crate::link::LinkStatus;
+use crate::metrics::count_thread;
 use crate::name::{Name, CellID, PortID};
 use crate::packet::{Packet};
-use crate::utility::{ByteArray, PortNo, PortNumber, S, TraceHeader, TraceHeaderParams, TraceType,
-                     write_err};
+use crate::trace_filter::{Level, tracing, is_tracing};
+use crate::utility::{ByteArray, OutbufType, PortNo, PortNumber, S, TraceHeader, TraceHeaderParams, write_err};
+use crate::wire;
 
 #[derive(Clone, Debug)]
 pub struct DuplexPortPeChannel {
     port_from_pe: PortFromPe,
-    port_from_pe_old: PortFromPeOld,
     port_to_pe: PortToPe,
-    port_to_pe_old: PortToPeOld,
 }
 impl DuplexPortPeChannel {
-    pub fn new(port_from_pe: PortFromPe, port_from_pe_old: PortFromPeOld,
-               port_to_pe: PortToPe, port_to_pe_old: PortToPeOld) -> DuplexPortPeChannel {
-        DuplexPortPeChannel { port_from_pe, port_from_pe_old, port_to_pe, port_to_pe_old }
+    pub fn new(port_from_pe: PortFromPe, port_to_pe: PortToPe) -> DuplexPortPeChannel {
+        DuplexPortPeChannel { port_from_pe, port_to_pe }
     }
     pub fn get_port_from_pe(&self) -> &PortFromPe { &self.port_from_pe }
-    pub fn get_port_from_pe_old(&self) -> &PortFromPeOld { &self.port_from_pe_old }
     pub fn get_port_to_pe(&self) -> &PortToPe { &self.port_to_pe }
-    pub fn get_port_to_pe_old(&self) -> &PortToPeOld { &self.port_to_pe_old }
 }
 
 #[derive(Clone, Debug)]
@@ -50,7 +48,7 @@ impl DuplexPortCaChannel {
 }
 
 // TODO: There is no distinction between a broken link and a disconnected one.  We may want to revisit.
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum PortStatus {
     Connected,
     Disconnected(FailoverInfo),
@@ -63,19 +61,6 @@ impl fmt::Display for PortStatus {
         }
     }
 }
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub enum PortStatusOld {
-    Connected,
-    Disconnected,
-}
-impl fmt::Display for PortStatusOld {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            PortStatusOld::Connected    => write!(f, "Connected"),
-            PortStatusOld::Disconnected => write!(f, "Disconnected")
-        }
-    }
-}
 
 pub trait CommonPortLike: 'static {
     fn get_id(&self) -> PortID { return self.get_base_port().get_id(); }
@@ -99,6 +84,7 @@ pub trait InteriorPortLike: 'static + Clone + Sync + Send + CommonPortLike {
         let thread_name = format!("Port {} listen_link", port_clone.get_id().get_name());
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(port.get_cell_id());
             let _ = port.listen().map_err(|e| write_err("port listen link", &e));
             if CONFIG.continue_on_error { port.listen().map_err(|e| write_err("port continue listen link", &e)).ok();  }
         }).expect("thread failed");
@@ -107,28 +93,30 @@ pub trait InteriorPortLike: 'static + Clone + Sync + Send + CommonPortLike {
         let thread_name = format!("Port {} listen_pe", port_clone.get_id().get_name());
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(port.get_cell_id());
             let _ = port.listen_pe_loop().map_err(|e| write_err("port listen pe", &e));
             if CONFIG.continue_on_error { port.listen_pe_loop().map_err(|e| write_err("port continue listen pe", &e)).ok(); }
         }).expect("thread failed");
     }
 
     // THESE COULD BE PROTECTED
-    fn send_to_link(self: &mut Self, packet: &mut Packet) -> Result<(), Error>;
-    fn listen_link(&mut self, port_to_pe_old: &PortToPeOld) -> Result<(), Error>;
+    fn send_to_link(self: &mut Self, outbuf_type: &OutbufType, packet: &mut Packet) -> Result<(), Error>;
+    fn listen_link(&mut self, port_to_pe: &PortToPe) -> Result<(), Error>;
+    // My packet engine took the oldest packet I gave it, so send its credit back over the link
+    fn return_credit(&mut self) -> Result<(), Error>;
 
     // THESE COULD BE PRIVATE
     fn listen(&mut self) -> Result<(), Error> {
         let _f = "listen";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
-         let port_pe_old = self.get_duplex_port_pe_channel().clone();
-        let port_to_pe_old = port_pe_old.get_port_to_pe_old();
-        self.listen_link(&port_to_pe_old)
+        let port_to_pe = self.get_duplex_port_pe_channel().get_port_to_pe().clone();
+        self.listen_link(&port_to_pe)
     }
     fn get_duplex_port_pe_channel(&self) -> &DuplexPortPeChannel {
         self.get_base_port().get_duplex_port_pe_channel()
@@ -137,30 +125,37 @@ pub trait InteriorPortLike: 'static + Clone + Sync + Send + CommonPortLike {
     fn listen_pe_loop(&mut self) -> Result<(), Error> {
         let _f = "listen_pe_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
             //println!("Port {}: waiting for packet from pe", id);
-            let mut packet = self.get_duplex_port_pe_channel().port_from_pe_old.recv().context(PortError::Chain { func_name: _f, comment: S(self.get_id().get_name()) + " port_from_pe"})?;
+            let msg = self.get_duplex_port_pe_channel().port_from_pe.recv().context(PortError::Chain { func_name: _f, comment: S(self.get_id().get_name()) + " port_from_pe"})?;
+            let (outbuf_type, mut packet) = match msg {
+                PeToPortPacket::Packet(outbuf_type_and_packet) => outbuf_type_and_packet,
+                PeToPortPacket::Activity(_) => continue, // Nothing to do with it yet
+                PeToPortPacket::Credit => {
+                    self.return_credit()?;
+                    continue
+                }
+            };
             {
                 let ait_state = packet.get_ait_state();
-                if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                if let Some(event) = tracing(Level::Info, &["port"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_pe" };
                     let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "ait_state": ait_state, "packet":packet.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
-                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_port_like" };
                     let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
-            self.send_to_link(&mut packet)?;
+            self.send_to_link(&outbuf_type, &mut packet)?;
         }
     }
 }
@@ -168,12 +163,12 @@ pub trait InteriorPortLike: 'static + Clone + Sync + Send + CommonPortLike {
 pub trait BorderPortLike: 'static + Clone + Sync + Send + CommonPortLike {
     fn listen_noc_and_ca(&self) -> Result<JoinHandle<()>, Error> {
         let _f = "listen_noc_and_ca";
-        let status = PortToCaMsg::Status(self.get_port_no(), PortStatusOld::Connected);
+        let status = PortToCaMsg::Status(self.get_port_no(), LinkStatus::Connected);
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_pe_status" };
                 let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "status": PortStatus::Connected });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         let port_to_ca = self.get_base_port().get_duplex_port_ca_channel().port_to_ca;
@@ -199,6 +194,7 @@ pub trait BorderPortLike: 'static + Clone + Sync + Send + CommonPortLike {
         let thread_name = format!("Port {} {}", self.get_id().get_name(), _f);
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(port.get_cell_id());
             let _ = port.listen_noc_loop().map_err(|e| write_err("port", &e));
             if CONFIG.continue_on_error { let _ = port.clone().listen_noc(); }
         })?;
@@ -209,10 +205,10 @@ pub trait BorderPortLike: 'static + Clone + Sync + Send + CommonPortLike {
     fn listen_noc_loop(&mut self) -> Result<(), Error> {
         let _f = "listen_noc_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         return self.listen();
@@ -226,6 +222,7 @@ pub trait BorderPortLike: 'static + Clone + Sync + Send + CommonPortLike {
         let thread_name = format!("Port {} {}", self.get_id().get_name(), _f);
         let join_handle = thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
+            let _thread = count_thread(port.get_cell_id());
             let _ = port.listen_ca_loop().map_err(|e| write_err("port", &e));
             if CONFIG.continue_on_error { let _ = port.listen_ca(); }
         })?;
@@ -236,19 +233,19 @@ pub trait BorderPortLike: 'static + Clone + Sync + Send + CommonPortLike {
     fn listen_ca_loop(&self) -> Result<(), Error> {
         let _f = "listen_ca_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
             let mut bytes = self.get_base_port().get_duplex_port_ca_channel().port_from_ca.recv().context(PortError::Chain { func_name: _f, comment: S(self.get_id().get_name()) + " recv from ca"})?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                if let Some(event) = tracing(Level::Info, &["port"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_ca" };
-                    let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "bytes": bytes.stringify()? });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    let trace = json!({ "cell_id": self.get_cell_id(), "id": self.get_id().get_name(), "bytes": wire::to_json(&bytes)? });
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             (*self).send(&mut bytes)?;
@@ -340,7 +337,7 @@ impl PortSeed {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FailoverInfo {
     port_id: PortID,
     sent: bool,
@@ -353,7 +350,7 @@ impl FailoverInfo {
     }
     pub fn if_sent(&self) -> bool { self.sent }
     pub fn if_recd(&self) -> bool { self.sent | self.recd }
-    pub fn get_saved_packet(&self) -> Option<Packet> { self.packet_opt }
+    pub fn get_saved_packet(&self) -> Option<Packet> { self.packet_opt.clone() }
     // Call on every data packet send
     pub fn save_packet(&mut self, packet: &Packet) {
         self.sent = true;
@@ -369,7 +366,7 @@ impl FailoverInfo {
 }
 impl fmt::Display for FailoverInfo {
     fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let packet_out = match self.packet_opt {
+        let packet_out = match &self.packet_opt {
             Some(p) => p.stringify().expect("Failover Display: Stringify packet must succeed"),
             None => "None".to_string()
         };
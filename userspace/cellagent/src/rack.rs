@@ -12,7 +12,7 @@ use crossbeam::crossbeam_channel::unbounded as channel;
 
 use crate::blueprint::{Blueprint, Cell, };
 use crate::config::{CONFIG, CellQty, LinkQty};
-use crate::dal::{add_to_trace, fork_trace_header, get_cell_replay_lines, update_trace_header};
+use crate::dal::{add_to_trace, fork_trace_header, get_cell_replay_records, update_trace_header};
 use crate::link::{Link, DuplexLinkPortChannel, LinkFromPorts, LinkToPorts };
 use crate::nalcell::{NalCell};
 use crate::name::{CellID, LinkID};
@@ -21,7 +21,10 @@ use crate::replay::{process_trace_record, TraceFormat};
 use crate::simulated_border_port::{SimulatedBorderPortFactory, SimulatedBorderPort, DuplexPortNocChannel};
 use crate::simulated_interior_port::{SimulatedInteriorPortFactory, SimulatedInteriorPort, DuplexPortLinkChannel,
                                      LinkFromPort, LinkToPort, PortFromLink, PortToLink};
-use crate::utility::{CellNo, CellConfig, PortNo, Edge, S, TraceHeaderParams, TraceType};
+use crate::tenant::{Tenant, Tenants};
+use crate::tree_key::SharedTreeKeys;
+use crate::trace_filter::{Level, tracing};
+use crate::utility::{CellNo, CellConfig, PortNo, Edge, S, TraceHeaderParams};
 
 #[derive(Clone, Debug)]
 pub struct DuplexLinkEndChannel {
@@ -69,6 +72,7 @@ impl EdgeConnection {
     pub fn new(left: CellInteriorConnection, rite: CellInteriorConnection) -> EdgeConnection {
         EdgeConnection { left, rite }
     }
+    pub fn get_edge(&self) -> Edge { Edge(self.left.cell_no, self.rite.cell_no) } // Used only in tests
 }
 impl fmt::Display for EdgeConnection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -80,6 +84,8 @@ type NalCellType = NalCell::<SimulatedInteriorPortFactory, SimulatedInteriorPort
 pub struct Rack {
     cells: HashMap<CellNo, NalCellType>,
     links: HashMap<EdgeConnection, Link>,
+    tenants: Tenants, // Charged by every cell in the rack
+    tree_keys: SharedTreeKeys, // Deployed by every cell in the rack
 }
 impl Rack {
     pub fn new() -> Rack { Default::default() }
@@ -90,6 +96,7 @@ impl Rack {
         let mut edge_connection_list = Vec::<EdgeConnection>::new();
         if *num_cells < 1  { return Err(RackError::Cells{ num_cells, func_name: _f }.into()); }
         if edge_list.len() < *num_cells - 1 { return Err(RackError::Edges { nlinks: LinkQty(edge_list.len()), func_name: _f }.into() ); }
+        self.tenants = Tenant::new_tenants(num_cells).context(RackError::Chain { func_name: _f, comment: S("tenants") })?;
         let mut link_handles = Vec::new();
         let mut duplex_port_link_channel_cell_port_map = HashMap::<CellNo, HashMap::<PortNo, DuplexPortLinkChannel>>::new();
         let mut duplex_link_port_channel_cell_port_map = HashMap::<CellNo, HashMap::<PortNo, DuplexLinkPortChannel>>::new();
@@ -183,6 +190,8 @@ impl Rack {
                 border_cell.get_num_phys_ports(),
                 &HashSet::from_iter(border_ports.clone()),
                 CellConfig::Large,
+                self.tenants.clone(),
+                self.tree_keys.clone(),
                 simulated_interior_port_factory.clone(),
                 Some(simulated_border_port_factory.clone()),
             ) {
@@ -193,12 +202,12 @@ impl Rack {
                 }
             };
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.dc || CONFIG.trace_options.visualize { // Needed for visualization
+                if let Some(event) = tracing(Level::Info, &["dc", "visualize"]) { // Needed for visualization
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "border_cell_start" };
                     let cell_id = nal_cell.get_id();
                     let trace = json!({ "cell_id": cell_id, "cell_number": cell_no,
                                          "border_ports": border_ports, "location":  CONFIG.geometry.get(*cell_no)});
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             self.cells.insert(cell_no, nal_cell);
@@ -210,6 +219,8 @@ impl Rack {
                 interior_cell.get_num_phys_ports(),
                 &HashSet::new(),
                 CellConfig::Large,
+                self.tenants.clone(),
+                self.tree_keys.clone(),
                 simulated_interior_port_factory.clone(),
                 None,
             )
@@ -221,11 +232,11 @@ impl Rack {
                 }
             };
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.dc || CONFIG.trace_options.visualize { // Needed for visualization
+                if let Some(event) = tracing(Level::Info, &["dc", "visualize"]) { // Needed for visualization
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "interior_cell_start" };
                     let cell_id = nal_cell.get_id();
                     let trace = json!({ "cell_id": cell_id, "cell_number": cell_no, "location": CONFIG.geometry.get(*cell_no as usize) });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             self.cells.insert(cell_no, nal_cell);
@@ -251,10 +262,10 @@ impl Rack {
             )?;
             println!("{}", edge_connection);
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.dc {
+                if let Some(event) = tracing(Level::Info, &["dc"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "connect_link" };
                     let trace = json!({ "left_cell": left_cell_id, "rite_cell": rite_cell_id, "left_port": left_port_no, "rite_port": rite_port_no, "link_id": link.get_id() });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             let mut link_clone = link.clone();
@@ -285,6 +296,7 @@ impl Rack {
     pub fn get_cells(&self) -> &HashMap<CellNo, NalCell::<SimulatedInteriorPortFactory, SimulatedInteriorPort, SimulatedBorderPortFactory, SimulatedBorderPort>> { &self.cells }
     pub fn get_links_mut(&mut self) -> &mut HashMap<EdgeConnection, Link> { &mut self.links }
     pub fn get_links(&self) -> &HashMap<EdgeConnection, Link> { &self.links }
+    pub fn get_tenants(&self) -> &Tenants { &self.tenants }
     pub fn get_cell_ids(&self) -> HashMap<CellNo, CellID> {
         self.cells.iter().map(|cell_no_and_cell| (*cell_no_and_cell.0, cell_no_and_cell.1.get_id())).collect::<HashMap<CellNo, _>>()
     }
@@ -294,8 +306,8 @@ impl Rack {
     pub fn select_noc_border_cell(&mut self) -> Result<(CellNo, NalCell::<SimulatedInteriorPortFactory, SimulatedInteriorPort, SimulatedBorderPortFactory, SimulatedBorderPort>), Error> {
         let _f = "select_noc_border_cell";
         return if CONFIG.replay {
-            let mut trace_lines = get_cell_replay_lines("Rack").context(RackError::Chain { func_name: _f, comment: S("Rack") })?;
-            let record = trace_lines.next().transpose()?.expect(&format!("First record for rack must be there"));
+            let mut trace_records = get_cell_replay_records("Rack").context(RackError::Chain { func_name: _f, comment: S("Rack") })?;
+            let record = trace_records.next().transpose()?.expect(&format!("First record for rack must be there"));
             let trace_format = process_trace_record(record)?;
             match trace_format {
                 TraceFormat::BorderCell(cell_no,) => {
@@ -2,16 +2,16 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
+use ec_trace_reader::{TraceHeader, TraceRecord};
 use failure::{Error};
-use serde_json::Value;
 use std::{fmt, fmt::Write};
 
 // Structs to parse trace records
-use crate::name::{CellID, TreeID};
+use crate::name::{CellID, OriginatorID, TreeID};
 use crate::packet_engine::NumberOfPackets;
-use crate::port::PortStatusOld;
+use crate::link::LinkStatus;
 use crate::routing_table_entry::RoutingTableEntry;
-use crate::utility::{CellNo, ByteArray, PortNo, TraceType};
+use crate::utility::{CellNo, ByteArray, PortNo};
 use crate::uuid_ec::Uuid;
 
 #[derive(Debug)]
@@ -20,7 +20,11 @@ pub enum TraceFormat {
     CaNewFormat(CellID, TreeID, TreeID, TreeID),
     CaToCmEntryFormat(RoutingTableEntry),
     CaFromCmBytesMsg(PortNo, bool, Uuid, ByteArray),
-    CaFromCmBytesStatus(PortNo, bool, NumberOfPackets, PortStatusOld),
+    CaFromCmBytesStatus(PortNo, bool, NumberOfPackets, LinkStatus),
+    CaFromCmBytesPort(PortNo, ByteArray),
+    CaFromCmBytesUp(OriginatorID, ByteArray),
+    CaFromPortBytes(PortNo, ByteArray),
+    CaFromPortStatus(PortNo, LinkStatus),
     CaToNoc(PortNo, ByteArray),
     BorderCell(CellNo)
 }
@@ -32,63 +36,81 @@ impl fmt::Display for TraceFormat {
             TraceFormat::CaToCmEntryFormat(_) => "CaToCmEntry",
             TraceFormat::CaFromCmBytesMsg(_, _, _, _) => "CaFromCmBytesMsg",
             TraceFormat::CaFromCmBytesStatus(_, _, _, _) => "CaFromCmBytesStatus",
+            TraceFormat::CaFromCmBytesPort(_, _) => "CaFromCmBytesPort",
+            TraceFormat::CaFromCmBytesUp(_, _) => "CaFromCmBytesUp",
+            TraceFormat::CaFromPortBytes(_, _) => "CaFromPortBytes",
+            TraceFormat::CaFromPortStatus(_, _) => "CaFromPortStatus",
             TraceFormat::CaToNoc(_, _) => "CaToNoc",
             TraceFormat::BorderCell(_) => "BorderCell"
         };
         write!(f, "{}", s)
     }
 }
-pub fn process_trace_record(mut record: String) -> Result<TraceFormat, Error> {
+pub fn process_trace_record(trace: TraceRecord) -> Result<TraceFormat, Error> {
     let _f = "process_trace_record";
-    record.pop(); // Remove trailing comma
-    let trace: TraceRecord = serde_json::from_str(&record)?;
-    let format = trace.header.format;
-    let trace_format = match format.as_str() {
+    let format = trace.format();
+    let trace_format = match format {
         "ca_new" => {
-            let ca_new: CaNew = serde_json::from_value(trace.body)?;
+            let ca_new: CaNew = trace.body_as()?;
             TraceFormat::CaNewFormat(ca_new.cell_id, ca_new.my_tree_id,
                                      ca_new.control_tree_id, ca_new.connected_tree_id)
         },
         "ca_to_cm_entry" => {
-            let ca_to_cm_entry: CaToCmEntry = serde_json::from_value(trace.body)?;
+            let ca_to_cm_entry: CaToCmEntry = trace.body_as()?;
             TraceFormat::CaToCmEntryFormat(ca_to_cm_entry.entry)
         },
         "ca_from_cm_bytes" => {
-            let m2a: CaFromCmBytesMsg = match serde_json::from_value(trace.body) {
+            let m2a: CaFromCmBytesMsg = match trace.body_as() {
                 Ok(m) => m,
                 Err(e) => {
                     println!("Replay {} error {}", format, e);
-                    return Err(e.into());
+                    return Err(e);
                 }
             };
             TraceFormat::CaFromCmBytesMsg(m2a.port, m2a.is_ait, m2a.tree_uuid, m2a.bytes)
         }
         "ca_from_cm_status" => {
-            let m2a: CaFromCmBytesStatus = match serde_json::from_value(trace.body) {
+            let m2a: CaFromCmBytesStatus = match trace.body_as() {
                 Ok(m) => m,
                 Err(e) => {
                     println!("Replay {} error {}", format, e);
-                    return Err(e.into());
+                    return Err(e);
                 }
             };
             TraceFormat::CaFromCmBytesStatus(m2a.port, m2a.is_border, m2a.no_packets, m2a.status)
         }
-        "ca_to_noc_tree_name" => {
-            let a2n: CaToNoc = match serde_json::from_value(trace.body) {
+        "ca_from_cm_bytes_port" => {
+            let m2a: CaFromCmBytesPort = trace.body_as()?;
+            TraceFormat::CaFromCmBytesPort(m2a.port, m2a.bytes)
+        }
+        "ca_from_cm_bytes_up" => {
+            let m2a: CaFromCmBytesUp = trace.body_as()?;
+            TraceFormat::CaFromCmBytesUp(m2a.originator_id, m2a.bytes)
+        }
+        "ca_from_port_bytes" => {
+            let p2a: CaFromPortBytes = trace.body_as()?;
+            TraceFormat::CaFromPortBytes(p2a.port, p2a.bytes)
+        }
+        "ca_from_port_status" => {
+            let p2a: CaFromPortStatus = trace.body_as()?;
+            TraceFormat::CaFromPortStatus(p2a.port, p2a.status)
+        }
+        "ca_to_noc_tree_name" | "ca_to_noc_placement" => {
+            let a2n: CaToNoc = match trace.body_as() {
                 Ok(m) => m,
                 Err(e) => {
                     println!("Replay {} error {}", format, e);
-                    return Err(e.into());
+                    return Err(e);
                 }
             };
             TraceFormat::CaToNoc(a2n.noc_port, a2n.bytes)
         }
         "border_cell" => {
-            let bc: BorderCell = match serde_json::from_value(trace.body) {
+            let bc: BorderCell = match trace.body_as() {
                 Ok(c) => c,
                 Err(e) => {
                     println!("Replay {} error {}", format, e);
-                    return Err(e.into());
+                    return Err(e);
                 }
             };
             TraceFormat::BorderCell(bc.cell_no)
@@ -98,25 +120,6 @@ pub fn process_trace_record(mut record: String) -> Result<TraceFormat, Error> {
     Ok(trace_format)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TraceHeader {
-    starting_epoch: u64,
-    epoch: u64,
-    spawning_thread_id: u64,
-    thread_id: u64,
-    event_id: Vec<u64>,
-    trace_type: TraceType,
-    module: String,
-    line_no: u32,
-    function: String,
-    format: String,
-    repo: String,
-}
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TraceRecord {
-    header: TraceHeader,
-    body: Value
-}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TraceRecordCaNew {
     header: TraceHeader,
@@ -172,7 +175,27 @@ struct CaFromCmBytesStatus {
     port: PortNo,
     is_border: bool,
     no_packets: NumberOfPackets,
-    status: PortStatusOld
+    status: LinkStatus
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaFromCmBytesPort {
+    port: PortNo,
+    bytes: ByteArray
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaFromCmBytesUp {
+    originator_id: OriginatorID,
+    bytes: ByteArray
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaFromPortBytes {
+    port: PortNo,
+    bytes: ByteArray
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaFromPortStatus {
+    port: PortNo,
+    status: LinkStatus
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TraceRecordCaToNoc {
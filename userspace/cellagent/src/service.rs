@@ -6,18 +6,24 @@ use std::{fmt, thread};
 use std::collections::HashSet;
 
 use crate::app_message_formats::{ContainerToVm, ContainerFromVm};
-use crate::app_message::{AppMsgDirection, AppInterapplicationMsg, AppMessage};
+use crate::app_message::{AppMsgDirection, AppMsgType, AppHealthMsg, AppInterapplicationMsg, AppMessage, HealthReport};
 #[cfg(feature="delete_tree")]
 use crate::app_message::AppDeleteTreeMsg;
 use crate::config::{CONFIG};
+use crate::container::ContainerStatus;
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
-use crate::name::{CellID, ContainerID, UptreeID};  // CellID for tracing purposes
+use crate::name::{Name, CellID, ContainerID, UptreeID};  // CellID for tracing purposes
 use crate::noc::{NOC_CONTROL_TREE_NAME, NOC_LISTEN_TREE_NAME};
-use crate::uptree_spec::{AllowedTree};
-use crate::utility::{ByteArray, S, write_err, TraceHeader, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, tracing};
+use crate::tree_key::TreeKeys;
+use crate::uptree_spec::{AllowedTree, RestartPolicy};
+use crate::utility::{S, write_err, TraceHeader, TraceHeaderParams};
+use crate::wire;
 
 const NOC_MASTER: &str ="NocMaster";
 const NOC_AGENT: &str = "NocAgent";
+// Images a ContainerSpec may name; keep in sync with Service::new
+pub const SERVICE_IMAGES: [&str; 2] = [NOC_MASTER, NOC_AGENT];
 
 #[derive(Debug, Clone)]
 pub enum Service {
@@ -26,13 +32,14 @@ pub enum Service {
 }
 impl Service {
     pub fn new(cell_id: CellID, container_id: ContainerID, service_name: &str, allowed_trees: &HashSet<AllowedTree>,
-            container_to_vm: ContainerToVm) -> Result<Service, ServiceError> {
+            tree_keys: &TreeKeys, restart_policy: Option<RestartPolicy>, container_to_vm: ContainerToVm) -> Result<Service, ServiceError> {
         match service_name {
-            NOC_MASTER => Ok(Service::NocMaster { service: NocMaster::new(cell_id, container_id, NOC_MASTER, container_to_vm, allowed_trees) }),
-            NOC_AGENT => Ok(Service::NocAgent { service: NocAgent::new(cell_id, container_id, NOC_AGENT, container_to_vm, allowed_trees) }),
+            NOC_MASTER => Ok(Service::NocMaster { service: NocMaster::new(cell_id, container_id, NOC_MASTER, container_to_vm, allowed_trees, tree_keys, restart_policy) }),
+            NOC_AGENT => Ok(Service::NocAgent { service: NocAgent::new(cell_id, container_id, NOC_AGENT, container_to_vm, allowed_trees, tree_keys, restart_policy) }),
             _ => Err(ServiceError::NoSuchService { func_name: "create_service", service_name: S(service_name) })
         }
     }
+    pub fn is_image(image: &str) -> bool { SERVICE_IMAGES.contains(&image) }
     pub fn initialize(&self, up_tree_id: UptreeID, container_from_vm: ContainerFromVm) -> Result<(), Error> {
         match self {
             Service::NocMaster { service } => service.initialize(up_tree_id, container_from_vm),
@@ -54,31 +61,33 @@ pub struct NocMaster {
     container_id: ContainerID,
     name: String,
     container_to_vm: ContainerToVm,
-    allowed_trees: HashSet<AllowedTree>
+    allowed_trees: HashSet<AllowedTree>,
+    tree_keys: TreeKeys,
+    restart_policy: Option<RestartPolicy>,
+    restarts: usize,
 }
 impl NocMaster {
     pub fn new(cell_id: CellID, container_id: ContainerID, name: &str, container_to_vm: ContainerToVm,
-               allowed_trees: &HashSet<AllowedTree>) -> NocMaster {
+               allowed_trees: &HashSet<AllowedTree>, tree_keys: &TreeKeys, restart_policy: Option<RestartPolicy>) -> NocMaster {
         NocMaster { cell_id, container_id, name: S(name), container_to_vm,
-            allowed_trees: allowed_trees.to_owned() }
+            allowed_trees: allowed_trees.to_owned(), tree_keys: tree_keys.clone(), restart_policy, restarts: 0 }
     }
     pub fn get_name(&self) -> &str { &self.name }
     pub fn get_id(&self) -> &ContainerID { &self.container_id }
     pub fn initialize(&self, _up_tree_id: UptreeID, container_from_vm: ContainerFromVm) -> Result<(), Error> {
         let _f = "initialize";
         println!("Service {} running NocMaster", self.container_id);
+        self.report_status(ContainerStatus::Starting, "")?;
         self.listen_vm(container_from_vm);
         let base_tree = AllowedTree::new(NOC_CONTROL_TREE_NAME);
         let body = "Hello From Master";
-        let app_msg = AppInterapplicationMsg::new(&self.get_name(),
-            false, false, &base_tree, AppMsgDirection::Leafward, &Vec::new(), body);
-        let serialized = serde_json::to_string(&app_msg as &dyn AppMessage)?;
-        let bytes:ByteArray = ByteArray::new(&serialized);
+        let app_msg = interapplication_msg(&self.get_name(), &base_tree, AppMsgDirection::Leafward, body, &self.tree_keys)?;
+        let bytes = wire::encode(&app_msg as &dyn AppMessage)?;
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+            if let Some(event) = tracing(Level::Info, &["svc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocMaster_to_vm" };
                 let trace = json!({ "cell_id": self.cell_id, "NocMaster": self.get_name(), "container_id": self.container_id, "app_msg": app_msg });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         self.container_to_vm.send(bytes)?;
@@ -88,39 +97,63 @@ impl NocMaster {
     fn listen_vm(&self, container_from_vm: ContainerFromVm) {
         let _f = "listen_vm";
         //println!("Service {} on {}: listening to VM", self.name, self.container_id);
-        let master = self.clone();
+        let mut master = self.clone();
         let child_trace_header = fork_trace_header();
         let thread_name = format!("{} listen_vm_loop", self.get_name()); // NOC NOC
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
-            let _ = master.listen_vm_loop(&container_from_vm).map_err(|e| write_err("service", &e));
-            if CONFIG.continue_on_error { master.listen_vm(container_from_vm); }
+            // Ok means the container was undeployed
+            match master.listen_vm_loop(&container_from_vm) {
+                Ok(()) => { let _ = master.report_status(ContainerStatus::Exited, "").map_err(|e| write_err("service", &e)); },
+                Err(e) => {
+                    write_err("service", &e);
+                    let _ = master.report_status(ContainerStatus::Failed, &e.to_string()).map_err(|e| write_err("service", &e));
+                    if let Some(delay) = RestartPolicy::restart_delay_after_error(master.restart_policy, master.restarts, CONFIG.continue_on_error) {
+                        thread::sleep(delay);
+                        master.restarts = master.restarts + 1;
+                        let _ = master.report_status(ContainerStatus::Starting, "").map_err(|e| write_err("service", &e));
+                        master.listen_vm(container_from_vm);
+                    }
+                }
+            }
         }).expect("Service listen_vm thread failed");
     }
     // WORKER (ContainerFromVm)
     fn listen_vm_loop(&self, container_from_vm: &ContainerFromVm) -> Result<(), Error> {
         let _f = "listen_vm_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+            if let Some(event) = tracing(Level::Info, &["svc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.cell_id, "NocMaster": self.get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
+        self.report_status(ContainerStatus::Running, "")?;
         let mut first = true; // Stack
         loop {
             let bytes = container_from_vm.recv().context(ServiceError::Chain { func_name: _f, comment: S("NocMaster from vm")})?;
-            let serialized = bytes.stringify()?;
-            let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(ServiceError::Chain { func_name: _f, comment: S("NocMaster from vm")})?;
+            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(ServiceError::Chain { func_name: _f, comment: S("NocMaster from vm")})?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+                if let Some(event) = tracing(Level::Info, &["svc"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocMaster_from_vm" };
                     let trace = json!({ "cell_id": self.cell_id, "NocMaster": self.get_name(), "container_id": self.container_id, "app_msg": app_msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            if app_msg.get_msg_type() == AppMsgType::AppUndeployMsg {
+                println!("NocMaster on container {} undeployed", self.container_id);
+                return Ok(());
+            }
+            let body = app_msg.open(&self.tree_keys)?;
+            if let Some(health_report) = HealthReport::from_body(&body) {
+                if let Some(event) = tracing(Level::Warn, &["svc"]) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocMaster_health" };
+                    let trace = json!({ "cell_id": self.cell_id, "NocMaster": self.get_name(), "health_report": health_report });
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
+            } else {
+                println!("NocMaster on container {} got msg {}", self.container_id, body);
             }
-            let body = app_msg.get_payload();
-            println!("NocMaster on container {} got msg {}", self.container_id, body);
             if first {
                 first = false;
                 #[cfg(feature="delete_tree")]
@@ -131,13 +164,12 @@ impl NocMaster {
                     let delete_msg = AppDeleteTreeMsg::new(self.get_name(),
                                                            false, &target_tree, AppMsgDirection::Rootward);
                     //println!("Service {} sending {}", self.container_id, msg);
-                    let serialized = serde_json::to_string(&delete_msg as &dyn AppMessage)?;
-                    let bytes = ByteArray::new(&serialized);
+                    let bytes = wire::encode(&delete_msg as &dyn AppMessage)?;
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+                        if let Some(event) = tracing(Level::Info, &["svc"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocAgent_to_vm" };
                             let trace = json!({ "NocMaster": self.get_name(), "app_msg": delete_msg });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
                     self.container_to_vm.send(bytes)?;
@@ -154,6 +186,11 @@ impl NocMaster {
         }
     }
 }
+impl NocMaster {
+    fn report_status(&self, status: ContainerStatus, reason: &str) -> Result<(), Error> {
+        report_status(self.cell_id, self.get_name(), self.container_id, &self.container_to_vm, status, self.restarts, reason)
+    }
+}
 impl fmt::Display for NocMaster {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} running in {}", self.name, self.container_id)
@@ -165,17 +202,21 @@ pub struct NocAgent {
     container_id: ContainerID,
     name: String,
     container_to_vm: ContainerToVm,
-    allowed_trees: HashSet<AllowedTree>
+    allowed_trees: HashSet<AllowedTree>,
+    tree_keys: TreeKeys,
+    restart_policy: Option<RestartPolicy>,
+    restarts: usize,
 }
 impl NocAgent {
     pub fn new(cell_id: CellID, container_id: ContainerID, name: &str, container_to_vm: ContainerToVm,
-            allowed_trees: &HashSet<AllowedTree>) -> NocAgent {
+            allowed_trees: &HashSet<AllowedTree>, tree_keys: &TreeKeys, restart_policy: Option<RestartPolicy>) -> NocAgent {
         NocAgent { cell_id, container_id, name: S(name), container_to_vm,
-            allowed_trees: allowed_trees.to_owned() }
+            allowed_trees: allowed_trees.to_owned(), tree_keys: tree_keys.clone(), restart_policy, restarts: 0 }
     }
     pub fn initialize(&self, _up_tree_id: UptreeID, container_from_vm: ContainerFromVm) -> Result<(), Error> {
         let _f = "initialize";
         println!("Service {} running NocAgent", self.container_id);
+        self.report_status(ContainerStatus::Starting, "")?;
         self.listen_vm(container_from_vm);
         Ok(())  // Needed to be consistent with NocMaster initialize
     }
@@ -185,13 +226,25 @@ impl NocAgent {
     fn listen_vm(&self, container_from_vm: ContainerFromVm) {
         let _f = "listen_vm";
         //println!("Service {} on {}: listening to VM", self.name, self.container_id);
-        let agent = self.clone();
+        let mut agent = self.clone();
         let child_trace_header = fork_trace_header();
         let thread_name = format!("{} listen_vm_loop", self.get_name()); // NOC NOC
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
-            let _ = agent.listen_vm_loop(&container_from_vm).map_err(|e| write_err("service", &e));
-            if CONFIG.continue_on_error { agent.listen_vm(container_from_vm); }
+            // Ok means the container was undeployed
+            match agent.listen_vm_loop(&container_from_vm) {
+                Ok(()) => { let _ = agent.report_status(ContainerStatus::Exited, "").map_err(|e| write_err("service", &e)); },
+                Err(e) => {
+                    write_err("service", &e);
+                    let _ = agent.report_status(ContainerStatus::Failed, &e.to_string()).map_err(|e| write_err("service", &e));
+                    if let Some(delay) = RestartPolicy::restart_delay_after_error(agent.restart_policy, agent.restarts, CONFIG.continue_on_error) {
+                        thread::sleep(delay);
+                        agent.restarts = agent.restarts + 1;
+                        let _ = agent.report_status(ContainerStatus::Starting, "").map_err(|e| write_err("service", &e));
+                        agent.listen_vm(container_from_vm);
+                    }
+                }
+            }
         }).expect("Service listen_vm thread failed");
     }
 
@@ -199,49 +252,84 @@ impl NocAgent {
     fn listen_vm_loop(&self, container_from_vm: &ContainerFromVm) -> Result<(), Error> {
         let _f = "listen_vm_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+            if let Some(event) = tracing(Level::Info, &["svc"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.cell_id, "NocAgent": self.get_name(), "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
+        self.report_status(ContainerStatus::Running, "")?;
         loop {
             let bytes = container_from_vm.recv().context(ServiceError::Chain { func_name: _f, comment: S("NocAgent recv from vm") })?;
-            let serialized = bytes.stringify()?;
-            let app_msg: Box<dyn AppMessage> = serde_json::from_str(&serialized).context(ServiceError::Chain { func_name: _f, comment: S("NocAgent from vm") })?;
+            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).context(ServiceError::Chain { func_name: _f, comment: S("NocAgent from vm") })?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+                if let Some(event) = tracing(Level::Info, &["svc"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocAgent_from_vm" };
                     let trace = json!({ "cell_id": self.cell_id, "NocAgent": self.get_name(), "container_id": self.container_id, "app_msg": app_msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
-            let body = app_msg.get_payload();
+            if app_msg.get_msg_type() == AppMsgType::AppUndeployMsg {
+                println!("NocAgent on container {} undeployed", self.container_id);
+                return Ok(());
+            }
+            let body = app_msg.open(&self.tree_keys)?;
             println!("NocAgent on container {} got msg {}", self.container_id, body);
             let msg = format!("Reply from {}", self.container_id);
             let target_tree = AllowedTree::new(NOC_LISTEN_TREE_NAME);
-            let reply = AppInterapplicationMsg::new(self.get_name(),
-                false, false, &target_tree, AppMsgDirection::Rootward,
-                                                    &vec![], &msg);
+            let reply = interapplication_msg(self.get_name(), &target_tree, AppMsgDirection::Rootward, &msg, &self.tree_keys)?;
             //println!("Service {} sending {}", self.container_id, msg);
-            let serialized = serde_json::to_string(&reply as &dyn AppMessage)?;
-            let bytes = ByteArray::new(&serialized);
+            let bytes = wire::encode(&reply as &dyn AppMessage)?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.svc {
+                if let Some(event) = tracing(Level::Info, &["svc"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "NocAgent_to_vm" };
                     let trace = json!({ "cell_id": self.cell_id, "NocAgent": self.get_name(), "container_id": self.container_id, "app_msg": reply });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             self.container_to_vm.send(bytes)?;
         }
     }
 }
+impl NocAgent {
+    fn report_status(&self, status: ContainerStatus, reason: &str) -> Result<(), Error> {
+        report_status(self.cell_id, self.get_name(), self.container_id, &self.container_to_vm, status, self.restarts, reason)
+    }
+}
 impl fmt::Display for NocAgent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} running in {}", self.name, self.container_id)
     }
 }
+// Sealed, so only containers on the target tree can read it.  Containers only get keys when the
+// config has auth keys, and without them nothing is sealed.
+fn interapplication_msg(name: &str, target_tree: &AllowedTree, direction: AppMsgDirection, body: &str,
+                        tree_keys: &TreeKeys) -> Result<AppInterapplicationMsg, Error> {
+    let _f = "interapplication_msg";
+    if tree_keys.is_empty() {
+        return Ok(AppInterapplicationMsg::new(name, false, false, target_tree, direction, &vec![], body));
+    }
+    let tree_key = tree_keys
+        .get(target_tree.get_name())
+        .ok_or(ServiceError::NoTreeKey { func_name: _f, tree_name: S(target_tree.get_name()) })?;
+    AppInterapplicationMsg::new_sealed(name, false, false, target_tree, direction, &vec![], body, tree_key)
+}
+// Status goes to the VM like any other message from the container
+fn report_status(cell_id: CellID, name: &str, container_id: ContainerID, container_to_vm: &ContainerToVm,
+                 status: ContainerStatus, restarts: usize, reason: &str) -> Result<(), Error> {
+    let _f = "report_status";
+    let health_msg = AppHealthMsg::new(name, &container_id.get_name(), status, restarts, reason);
+    let bytes = wire::encode(&health_msg as &dyn AppMessage)?;
+    {
+        if let Some(event) = tracing(Level::Info, &["svc"]) {
+            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "service_status" };
+            let trace = json!({ "cell_id": cell_id, "container_id": container_id, "app_msg": health_msg });
+            add_to_trace(event, trace_params, &trace, _f);
+        }
+    }
+    container_to_vm.send(bytes).context(ServiceError::Chain { func_name: _f, comment: S(name) })?;
+    Ok(())
+}
 // Errors
 use failure::{Error, ResultExt, Fail};
 
@@ -250,6 +338,8 @@ pub enum ServiceError {
     #[fail(display = "ServiceError::Chain {} {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
     #[fail(display = "ServiceError::NoSuchService {}: No image for service named {}", func_name, service_name)]
-    NoSuchService { func_name: &'static str, service_name: String }
+    NoSuchService { func_name: &'static str, service_name: String },
+    #[fail(display = "ServiceError::NoTreeKey {}: No key to seal a payload sent on tree {}", func_name, tree_name)]
+    NoTreeKey { func_name: &'static str, tree_name: String }
 }
 
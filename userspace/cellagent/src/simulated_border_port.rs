@@ -10,13 +10,14 @@ use std::{
 };
 
 use crate::blueprint::{Blueprint};
-use crate::config::{CONFIG};
 use crate::dal::{add_to_trace};
 use crate::app_message_formats::{PortToCaMsg, PortToCa, NocToPortMsg, PortToNocMsg};
 use crate::name::{Name, PortID, CellID};
 use crate::port::{CommonPortLike, BorderPortLike, PortSeed, BasePort, BorderPortFactoryLike, DuplexPortPeOrCaChannel, DuplexPortCaChannel};
-use crate::utility::{CellNo, PortNo, PortNumber, ByteArray, S, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, tracing};
+use crate::utility::{CellNo, PortNo, PortNumber, ByteArray, S, TraceHeaderParams};
 use crate::uuid_ec::{AitState};
+use crate::wire;
 
 pub type PortToNoc = mpsc::Sender<PortToNocMsg>;
 pub type PortFromNoc = mpsc::Receiver<NocToPortMsg>;
@@ -73,10 +74,10 @@ impl BorderPortLike for SimulatedBorderPort {
     fn send(&self, bytes: &mut ByteArray) -> Result<(), Error> {
         let _f = "send_to_noc";
         {
-            if CONFIG.trace_options.all | CONFIG.trace_options.port {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_noc" };
-                let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "bytes": bytes.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "bytes": wire::to_json(&bytes)? });
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
        Ok(self.duplex_port_noc_channel.as_ref().unwrap().port_to_noc.send(bytes.clone()).context(SimulatedBorderPortError::Chain {func_name: "new",comment: S("")})?)
@@ -86,17 +87,17 @@ impl BorderPortLike for SimulatedBorderPort {
         loop {
             let msg = self.recv()?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                if let Some(event) = tracing(Level::Info, &["port"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_noc_app" };
                     let trace = json!({ "cell_id": self.base_port.get_cell_id(),"id": self.base_port.get_id().get_name(), "msg": msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                if let Some(event) = tracing(Level::Info, &["port"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_ca_app" };
                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "msg": msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             port_to_ca.send(PortToCaMsg::AppMsg(self.base_port.get_port_no(), msg)).context(SimulatedBorderPortError::Chain { func_name: "listen_noc_for_pe", comment: S(self.base_port.get_id().get_name()) + " send app msg to pe"})?;
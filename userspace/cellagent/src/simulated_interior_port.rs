@@ -4,20 +4,21 @@
  *--------------------------------------------------------------------------------------------*/
 use std::{
     fmt,
-    collections::{HashMap, },
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
 };
 
 use crossbeam::crossbeam_channel as mpsc;
 use crate::blueprint::{Blueprint, };
-use crate::config::{CONFIG};
 use crate::dal::{add_to_trace};
-use crate::ec_message_formats::{PortToPePacketOld, PortToPeOld};
+use crate::ec_message_formats::{PortToPePacket, PortToPe};
 use crate::link::{LinkStatus};
 use crate::name::{Name, CellID, PortID};
 use crate::packet::{Packet}; // Eventually use SimulatedPacket
-use crate::port::{CommonPortLike, InteriorPortLike, PortSeed, BasePort, InteriorPortFactoryLike, 
-                  PortStatusOld, DuplexPortPeOrCaChannel, DuplexPortPeChannel};
-use crate::utility::{CellNo, PortNo, PortNumber, S, TraceHeaderParams, TraceType};
+use crate::port::{self, CommonPortLike, InteriorPortLike, PortSeed, BasePort, InteriorPortFactoryLike, 
+                  PortStatus, DuplexPortPeOrCaChannel, DuplexPortPeChannel};
+use crate::trace_filter::{Level, tracing, is_tracing};
+use crate::utility::{CellNo, OutbufType, PortNo, PortNumber, S, TraceHeaderParams};
 use crate::uuid_ec::{AitState};
 
 #[derive(Clone, Debug)]
@@ -37,6 +38,7 @@ pub struct SimulatedInteriorPort {
     failover_info: FailoverInfo,
     is_connected: bool,
     duplex_port_link_channel: Option<DuplexPortLinkChannel>,
+    handed_to_pe: Arc<Mutex<VecDeque<Option<OutbufType>>>>, // Outbuf types of the packets my packet engine hasn't taken, shared with my clones
 }
 
 impl SimulatedInteriorPort {
@@ -50,24 +52,40 @@ impl SimulatedInteriorPort {
             None => Err(SimulatedInteriorPortError::ChannelClosed { func_name: _f, port_no: self.base_port.get_port_no(), cell_id: self.base_port.get_cell_id()}.into()),
         }
     }
-    fn direct_send(&mut self, packet: &Packet) -> Result<(), Error> {
+    // The outbuf type goes along so the other end knows which credit to return, None for packets that take no credit
+    fn direct_send(&mut self, outbuf_type: Option<OutbufType>, packet: &Packet) -> Result<(), Error> {
         let _f = "direct_send";
         {
-            if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-               (!packet.is_entl() || CONFIG.trace_options.entl)  {
+            if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "direct_send" };
                 let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait state": packet.get_ait_state(), "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
 	    }
         self.failover_info.save_packet(&packet);
         match &self.duplex_port_link_channel {
             Some(connected_duplex_port_link_channel) => {
-                Ok(connected_duplex_port_link_channel.port_to_link.send(*packet).context(SimulatedInteriorPortError::Chain {func_name: "new",comment: S("")})?)
+                Ok(connected_duplex_port_link_channel.port_to_link.send(PortToLinkPacket::Packet((outbuf_type, packet.clone()))).context(SimulatedInteriorPortError::Chain {func_name: "new",comment: S("")})?)
             },
             None => Err(SimulatedInteriorPortError::SendDisconnected { func_name: _f, port_no: self.base_port.get_port_no(), cell_id: self.base_port.get_cell_id()}.into()),
         }
     }
+    // The packet engine returns a credit for each packet in the order I hand them over, so remember the order
+    fn send_to_pe(&mut self, port_to_pe: &PortToPe, outbuf_type: Option<OutbufType>, packet: Packet) -> Result<(), Error> {
+        self.handed_to_pe.lock().unwrap().push_back(outbuf_type);
+        Ok(port_to_pe.send(PortToPePacket::Packet((self.base_port.get_port_no(), packet)))?)
+    }
+    // A broken link reports the packet that may not have made it across
+    fn get_port_status(&self, status: LinkStatus) -> PortStatus {
+        match status {
+            LinkStatus::Connected => PortStatus::Connected,
+            LinkStatus::Disconnected => {
+                let mut failover_info = port::FailoverInfo::new(self.base_port.get_id());
+                if let Some(packet) = self.failover_info.get_saved_packet() { failover_info.save_packet(&packet); }
+                PortStatus::Disconnected(failover_info)
+            }
+        }
+    }
 }
 impl fmt::Display for SimulatedInteriorPort {
     fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -86,14 +104,13 @@ impl CommonPortLike for SimulatedInteriorPort {
 }
 
 impl InteriorPortLike for SimulatedInteriorPort {
-    fn send_to_link(self: &mut Self, packet: &mut Packet) -> Result<(), Error> {
+    fn send_to_link(self: &mut Self, outbuf_type: &OutbufType, packet: &mut Packet) -> Result<(), Error> {
         let _f = "send_to_link";
         {
-            if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-               (!packet.is_entl() || CONFIG.trace_options.entl) {
+            if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "simulated_port_from_port" };
                 let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait state": packet.get_ait_state(), "packet": packet.stringify()? });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
 	    }
         let ait_state = packet.get_ait_state();
@@ -110,26 +127,51 @@ impl InteriorPortLike for SimulatedInteriorPort {
             AitState::SnakeD |
             AitState::Normal => ()
         }
-	    self.direct_send(packet)
+	    self.direct_send(Some(outbuf_type.clone()), packet)
+    }
+    fn return_credit(self: &mut Self) -> Result<(), Error> {
+        let _f = "return_credit";
+        let outbuf_type = match self.handed_to_pe.lock().unwrap().pop_front() {
+            Some(Some(outbuf_type)) => outbuf_type,
+            _ => return Ok(()) // The packet came with no credit
+        };
+        {
+            if let Some(event) = tracing(Level::Info, &["port"]) {
+                let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_link_credit" };
+                let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "outbuf": outbuf_type });
+                add_to_trace(event, trace_params, &trace, _f);
+            }
+        }
+        match &self.duplex_port_link_channel {
+            Some(connected_duplex_port_link_channel) => {
+                Ok(connected_duplex_port_link_channel.port_to_link.send(PortToLinkPacket::Credit(outbuf_type)).context(SimulatedInteriorPortError::Chain { func_name: _f, comment: S("") })?)
+            },
+            None => Err(SimulatedInteriorPortError::SendDisconnected { func_name: _f, port_no: self.base_port.get_port_no(), cell_id: self.base_port.get_cell_id()}.into()),
+        }
     }
-    fn listen_link(self: &mut Self, port_to_pe: &PortToPeOld) -> Result<(), Error> {
+    fn listen_link(self: &mut Self, port_to_pe: &PortToPe) -> Result<(), Error> {
         let _f = "listen_link";
         loop {
             let msg = self.recv_from_link().context(SimulatedInteriorPortError::Chain { func_name: _f, comment: S(self.base_port.get_id().get_name()) + " recv from link"})?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                if let Some(event) = tracing(Level::Info, &["port"]) {
                     match &msg {
-                        LinkToPortPacket::Packet(packet) => {
-                            if !packet.is_entl() || CONFIG.trace_options.entl {
+                        LinkToPortPacket::Packet((_, packet)) => {
+                            if !packet.is_entl() || is_tracing(Level::Info, &["entl"]) {
                                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_link_packet" };
                                 let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": packet.get_ait_state(), "packet": packet.stringify()? });
-                                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                add_to_trace(event, trace_params, &trace, _f);
                             }
                         },
                         LinkToPortPacket::Status(status) => {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_link_status" };
                             let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "status": status, "msg": msg});
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
+                        },
+                        LinkToPortPacket::Credit(outbuf_type) => {
+                            let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_from_link_credit" };
+                            let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "outbuf": outbuf_type });
+                            add_to_trace(event, trace_params, &trace, _f);
                         },
                     }
                 }
@@ -141,19 +183,20 @@ impl InteriorPortLike for SimulatedInteriorPort {
                         LinkStatus::Disconnected => self.set_disconnected()
                     };
                     {
-                        if CONFIG.trace_options.all || CONFIG.trace_options.port {
+                        if let Some(event) = tracing(Level::Info, &["port"]) {
                             let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_pe_status" };
                             let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "status": status });
-                            add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                            add_to_trace(event, trace_params, &trace, _f);
                         }
                     }
-                    let status_old = match status {
-                        LinkStatus::Connected => PortStatusOld::Connected,
-                        LinkStatus::Disconnected => PortStatusOld::Disconnected
-                    };
-                    port_to_pe.send(PortToPePacketOld::Status((self.base_port.get_port_no(), self.base_port.is_border(), status_old))).context(SimulatedInteriorPortError::Chain { func_name: _f, comment: S(self.base_port.get_id().get_name()) + " send status to pe"})?;
+                    let port_status = self.get_port_status(status);
+                    port_to_pe.send(PortToPePacket::Status((self.base_port.get_port_no(), self.base_port.is_border(), port_status))).context(SimulatedInteriorPortError::Chain { func_name: _f, comment: S(self.base_port.get_id().get_name()) + " send status to pe"})?;
+                }
+                // The other end's packet engine took one of my packets, so mine can send another
+                LinkToPortPacket::Credit(outbuf_type) => {
+                    port_to_pe.send(PortToPePacket::Increment((self.base_port.get_port_no(), outbuf_type))).context(SimulatedInteriorPortError::Chain { func_name: _f, comment: S(self.base_port.get_id().get_name()) + " increment"})?;
                 }
-                LinkToPortPacket::Packet(mut packet) => {
+                LinkToPortPacket::Packet((outbuf_type, mut packet)) => {
                     self.failover_info.clear_saved_packet();
                     let ait_state = packet.get_ait_state();
                     match ait_state {
@@ -164,93 +207,86 @@ impl InteriorPortLike for SimulatedInteriorPort {
                         AitState::SnakeD |
                         AitState::Normal => {
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_pe_packet" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": packet.get_ait_state(), "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                            port_to_pe.send(PortToPePacketOld::Packet((self.base_port.get_port_no(), packet)))?;
+                            self.send_to_pe(port_to_pe, outbuf_type, packet)?;
                         },
                         AitState::Teck => {
                             packet.next_ait_state()?;
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let ait_state = packet.get_ait_state();
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_link_tack" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                            self.direct_send(&packet)?;
+                            self.direct_send(outbuf_type, &packet)?; // Echo the outbuf type so I know it at Tuck
                         }
                         AitState::Tack => {
                             packet.next_ait_state()?;
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let ait_state = packet.get_ait_state();
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_link_tuck" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                            self.direct_send(&packet)?;
+                            self.direct_send(outbuf_type, &packet)?;
                         }
                         AitState::Tuck => {
                             packet.next_ait_state()?;
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let ait_state = packet.get_ait_state();
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_link_tyck" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                            self.direct_send(&packet)?;
+                            self.direct_send(None, &packet)?;
                             packet.make_ait();
                            {
-                            if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                            (!packet.is_entl() || CONFIG.trace_options.entl) {
+                            if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                              let ait_state = packet.get_ait_state();
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_pe_ait_packet" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                           port_to_pe.send(PortToPePacketOld::Packet((self.base_port.get_port_no(), packet)))?;                           
+                           self.send_to_pe(port_to_pe, outbuf_type, packet)?;
                         }
                         AitState::Tyck => {
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_pe_aitd_packet" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": packet.get_ait_state(), "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
                             packet.next_ait_state()?;
                             // TODO: Send AITD as acknowledgement that transfer completed correctly
                             let mut tick_packet: Packet = Default::default();
                             tick_packet.make_tick();
-                            self.direct_send(&tick_packet)?;
+                            self.direct_send(None, &tick_packet)?;
                         }
                         AitState::Tick | 
                         AitState::Tock => {
                             packet.next_ait_state()?;
                             {
-                                if (CONFIG.trace_options.all || CONFIG.trace_options.port) && 
-                                   (!packet.is_entl() || CONFIG.trace_options.entl) {
+                                if let Some(event) = tracing(Level::Info, &["port"]).filter(|_| !packet.is_entl() || is_tracing(Level::Info, &["entl"])) {
                                     let ait_state = packet.get_ait_state();
                                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "port_to_link" };
                                     let trace = json!({ "cell_id": self.base_port.get_cell_id(), "id": self.base_port.get_id().get_name(), "ait_state": ait_state, "packet": packet.stringify()? });
-                                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                                    add_to_trace(event, trace_params, &trace, _f);
                                 }
                             }
-                            self.direct_send(&packet)?;
+                            self.direct_send(None, &packet)?;
                         },
                     }
                 }
@@ -291,6 +327,7 @@ impl InteriorPortFactoryLike<SimulatedInteriorPort> for SimulatedInteriorPortFac
             is_connected: false,
             duplex_port_link_channel,
             failover_info: FailoverInfo::new(port_id),
+            handed_to_pe: Default::default(),
         })
     }
     fn get_port_seed(&self) -> &PortSeed {
@@ -308,15 +345,37 @@ pub type PortFromLink = mpsc::Receiver<LinkToPortPacket>;
 #[derive(Debug, Clone, Serialize)]
 pub enum LinkToPortPacket {
     Status(LinkStatus),
-    Packet(PACKET),
+    Credit(OutbufType),
+    Packet((Option<OutbufType>, PACKET)),
+}
+impl From<PortToLinkPacket> for LinkToPortPacket {
+    fn from(msg: PortToLinkPacket) -> LinkToPortPacket {
+        match msg {
+            PortToLinkPacket::Credit(outbuf_type) => LinkToPortPacket::Credit(outbuf_type),
+            PortToLinkPacket::Packet(outbuf_type_and_packet) => LinkToPortPacket::Packet(outbuf_type_and_packet)
+        }
+    }
 }
 pub type LinkToPort = mpsc::Sender<LinkToPortPacket>;
 
-// Port to Link
-pub type PortToLinkPacket = Packet; // SimulatedPacket
+// Port to Link; credits go back over the link, so a port never has more packets in its
+// packet engine's queue than the sender has credits for
+#[derive(Debug, Clone, Serialize)]
+pub enum PortToLinkPacket {
+    Credit(OutbufType),
+    Packet((Option<OutbufType>, PACKET)), // SimulatedPacket
+}
+impl PortToLinkPacket {
+    pub fn stringify(&self) -> Result<String, Error> {
+        Ok(match self {
+            PortToLinkPacket::Credit(outbuf_type) => format!("Credit {:?}", outbuf_type),
+            PortToLinkPacket::Packet((_, packet)) => packet.stringify()?
+        })
+    }
+}
 pub type LinkFromPort = mpsc::Receiver<PortToLinkPacket>;
 
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FailoverInfo {
     port_id: PortID,
     packet_opt: Option<Packet>
@@ -327,7 +386,7 @@ impl FailoverInfo {
     }
     pub fn if_sent(&self) -> bool { self.packet_opt.is_some() }
     pub fn if_recd(&self) -> bool { self.packet_opt.is_none() }
-    pub fn get_saved_packet(&self) -> Option<Packet> { self.packet_opt }
+    pub fn get_saved_packet(&self) -> Option<Packet> { self.packet_opt.clone() }
     // Call on every data packet send
     fn save_packet(&mut self, packet: &Packet) {
         self.packet_opt = Some(packet.clone());
@@ -339,7 +398,7 @@ impl FailoverInfo {
 }
 impl fmt::Display for FailoverInfo {
     fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let packet_out = match self.packet_opt {
+        let packet_out = match &self.packet_opt {
             Some(p) => p.stringify().expect("Failover Display: Stringify packet must succeed"),
             None => "None".to_string()
         };
@@ -2,76 +2,208 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-//use std::fmt;
-use std::collections::HashMap;
+use std::{fmt, fmt::Write,
+          collections::{HashMap, HashSet},
+          sync::{Arc, Mutex}};
 
-use crate::config::CellQty;
-use crate::name::{TenantID};
-//use utility::S;
+use crate::config::{CellQty, SEPARATOR};
+use crate::name::{Name, CellID, TenantID};
+use crate::uptree_spec::AllowedTree;
+use crate::utility::S;
 
-#[derive(Clone)]
-pub struct Tenant { 
+pub const ROOT_TENANT_NAME: &str = "Root";
+
+// One registry for all the cells of a rack, so a quota can't be spent once per cell
+pub type Tenants = Arc<Mutex<Tenant>>;
+
+// Tenant names are compound, so a sub-tenant's name starts with its parent's
+pub fn is_within(tenant_name: &str, ancestor_name: &str) -> bool {
+    tenant_name == ancestor_name || tenant_name.starts_with(&format!("{}{}", ancestor_name, SEPARATOR))
+}
+
+// The cells a tree or manifest actually reached.  The NOC's own trees and manifests are
+// tracked so their owner is known, but they don't count against the quota.
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    cell_ids: HashSet<CellID>,
+    is_charged: bool,
+}
+impl Usage {
+    fn new(is_charged: bool) -> Usage { Usage { cell_ids: HashSet::new(), is_charged } }
+    fn get_ncells(&self) -> CellQty { CellQty(self.cell_ids.len()) }
+    fn get_charged(&self) -> usize { if self.is_charged { self.cell_ids.len() } else { 0 } }
+}
+
+// A tenant owns a quota of cells.  Each cell a stacked tree or deployed manifest reaches is
+// charged against it until the tree is deleted or the manifest undeployed, and so are the
+// quotas handed to sub-tenants, so a tenant can never give away more than it has.
+#[derive(Debug, Clone, Default)]
+pub struct Tenant {
     id: TenantID,
     ncells: CellQty,
-    children: HashMap<TenantID,Box<Tenant>>,
+    trees: HashMap<AllowedTree, Usage>,
+    manifests: HashMap<String, Usage>,
+    children: HashMap<TenantID, Box<Tenant>>,
 }
-/*
 impl Tenant {
-    pub fn new(id: &'static str, n: CellQty, parent_id: Option<TenantID>) -> Result<Tenant, Error> {
+    pub fn new(id: &str, n: CellQty, parent_id: Option<TenantID>) -> Result<Tenant, Error> {
+        let _f = "new";
         let name = match parent_id {
-            Some(p) => Ok(p.add_component(id).context(TenantError::Chain { func_name: "new", comment: S("")})?),
-            None => TenantID::new(id)
-        }?;
-        Ok(Tenant { id: name, ncells: n, children: HashMap::new() })
+            Some(p) => p.add_component(id).context(TenantError::Chain { func_name: _f, comment: S(id) })?,
+            None => TenantID::new(id)?
+        };
+        Ok(Tenant { id: name, ncells: n, trees: HashMap::new(), manifests: HashMap::new(), children: HashMap::new() })
+    }
+    pub fn new_tenants(ncells: CellQty) -> Result<Tenants, Error> {
+        let _f = "new_tenants";
+        let root = Tenant::new(ROOT_TENANT_NAME, ncells, None).context(TenantError::Chain { func_name: _f, comment: S("root tenant") })?;
+        Ok(Arc::new(Mutex::new(root)))
     }
     pub fn get_id(&self) -> TenantID { self.id }
-//  pub fn get_ncells(&self) -> usize { self.ncells }
-//  pub fn get_size(&self) -> usize { self.ncells }
-//  pub fn get_children(&self) -> &HashMap<TenantID,Box<Tenant>> { &self.children }
-//  pub fn get_subtenant(&self, id: TenantID) -> Option<&Box<Tenant>> {
-//       self.children.get(&id)
-//  }
-//  pub fn get_mut_subtenant(&mut self, id: TenantID) -> Option<&mut Box<Tenant>> {
-//       self.children.get_mut(&id)
-//  }
-    pub fn create_subtenant(&mut self, id: &'static str, n: CellQty) -> Result<Tenant, Error> {
-        if *self.ncells < *n {
-            Err(TenantError::Quota { request: n, func_name: "create_subtenant", available: self.ncells }.into())
-        } else {
-            let tenant = Tenant::new(id, n, Some(self.get_id()));
-            match tenant {
-                Ok(t) => {
-                    if !self.children.contains_key(&t.get_id()) {
-                        self.children.insert(t.get_id(),Box::new(t.clone()));
-                        Ok(t)
-                    } else {
-                        Err(TenantError::DuplicateName { func_name: "create_subtenant", tenant_name: S(id) }.into())
-                    }
-                },
-                Err(_) => tenant
+    pub fn get_ncells(&self) -> CellQty { self.ncells }
+    pub fn get_children(&self) -> &HashMap<TenantID, Box<Tenant>> { &self.children }
+    pub fn owns_tree(&self, tree: &AllowedTree) -> bool { self.trees.contains_key(tree) }
+    // Cells charged to trees, manifests and sub-tenants
+    pub fn get_used(&self) -> CellQty {
+        let trees: usize = self.trees.values().map(|usage| usage.get_charged()).sum();
+        let manifests: usize = self.manifests.values().map(|usage| usage.get_charged()).sum();
+        let children: usize = self.children.values().map(|child| *child.ncells).sum();
+        CellQty(trees + manifests + children)
+    }
+    pub fn get_available(&self) -> CellQty { CellQty(self.ncells.saturating_sub(*self.get_used())) }
+    pub fn create_subtenant(&mut self, id: &str, n: CellQty) -> Result<TenantID, Error> {
+        let _f = "create_subtenant";
+        self.check_quota(_f, n)?;
+        let tenant = Tenant::new(id, n, Some(self.get_id())).context(TenantError::Chain { func_name: _f, comment: S(id) })?;
+        let tenant_id = tenant.get_id();
+        if self.children.values().any(|child| child.get_id().get_name() == tenant_id.get_name()) {
+            return Err(TenantError::DuplicateName { func_name: _f, tenant_name: tenant_id.get_name() }.into());
+        }
+        self.children.insert(tenant_id, Box::new(tenant));
+        Ok(tenant_id)
+    }
+    // Tenant names are compound, parent first, so I can find any tenant below me
+    pub fn find_mut(&mut self, tenant_name: &str) -> Option<&mut Tenant> {
+        if self.id.get_name() == tenant_name { return Some(self); }
+        self.children
+            .values_mut()
+            .find_map(|child| child.find_mut(tenant_name))
+    }
+    pub fn get_mut(&mut self, tenant_name: &str) -> Result<&mut Tenant, Error> {
+        let _f = "get_mut";
+        self.find_mut(tenant_name)
+            .ok_or(TenantError::NoTenant { func_name: _f, tenant_name: S(tenant_name) }.into())
+    }
+    // Which tenant owns a tree and how many cells it reached
+    pub fn find_tree(&self, tree: &AllowedTree) -> Option<(TenantID, CellQty)> {
+        match self.trees.get(tree) {
+            Some(usage) => Some((self.id, usage.get_ncells())),
+            None => self.children.values().find_map(|child| child.find_tree(tree))
+        }
+    }
+    fn find_tree_owner_mut(&mut self, tree: &AllowedTree) -> Option<&mut Tenant> {
+        if self.trees.contains_key(tree) { return Some(self); }
+        self.children
+            .values_mut()
+            .find_map(|child| child.find_tree_owner_mut(tree))
+    }
+    fn find_manifest_owner_mut(&mut self, manifest_id: &str) -> Option<&mut Tenant> {
+        if self.manifests.contains_key(manifest_id) { return Some(self); }
+        self.children
+            .values_mut()
+            .find_map(|child| child.find_manifest_owner_mut(manifest_id))
+    }
+    // Made where the request comes in; a tenant with no cells left can't start a tree
+    pub fn claim_tree(&mut self, tree: &AllowedTree, is_charged: bool) -> Result<(), Error> {
+        let _f = "claim_tree";
+        if self.trees.contains_key(tree) { return Ok(()); }
+        if is_charged { self.check_quota(_f, CellQty(1))?; }
+        self.trees.insert(tree.clone(), Usage::new(is_charged));
+        Ok(())
+    }
+    // Called on the root by each cell that joins a tree.  Trees nobody claimed, such as the base
+    // tree, are free.
+    pub fn charge_tree(&mut self, tree: &AllowedTree, cell_id: CellID) -> Result<(), Error> {
+        let _f = "charge_tree";
+        match self.find_tree_owner_mut(tree) {
+            Some(owner) => owner.charge(_f, |tenant| tenant.trees.get_mut(tree), cell_id),
+            None => Ok(())
+        }
+    }
+    // A manifest deployed again under the same ID replaces its earlier charge
+    pub fn claim_manifest(&mut self, manifest_id: &str, is_charged: bool) -> Result<(), Error> {
+        let _f = "claim_manifest";
+        let previous = self.manifests.remove(manifest_id);
+        if is_charged {
+            if let Err(e) = self.check_quota(_f, CellQty(1)) {
+                if let Some(previous) = previous { self.manifests.insert(S(manifest_id), previous); }
+                return Err(e.into());
             }
         }
+        self.manifests.insert(S(manifest_id), Usage::new(is_charged));
+        Ok(())
+    }
+    // Called on the root by each cell the manifest is deployed on
+    pub fn charge_manifest(&mut self, manifest_id: &str, cell_id: CellID) -> Result<(), Error> {
+        let _f = "charge_manifest";
+        match self.find_manifest_owner_mut(manifest_id) {
+            Some(owner) => owner.charge(_f, |tenant| tenant.manifests.get_mut(manifest_id), cell_id),
+            None => Ok(())
+        }
+    }
+    // Undeploy doesn't say which tenant deployed the manifest, so look for it below me, too
+    pub fn release_manifest(&mut self, manifest_id: &str) -> Option<CellQty> {
+        match self.manifests.remove(manifest_id) {
+            Some(usage) => Some(usage.get_ncells()),
+            None => self.children.values_mut().find_map(|child| child.release_manifest(manifest_id))
+        }
+    }
+    // Nor does a tree delete say which tenant stacked the tree
+    pub fn release_tree(&mut self, tree: &AllowedTree) -> Option<CellQty> {
+        match self.trees.remove(tree) {
+            Some(usage) => Some(usage.get_ncells()),
+            None => self.children.values_mut().find_map(|child| child.release_tree(tree))
+        }
+    }
+    fn charge(&mut self, func_name: &'static str, usage: impl Fn(&mut Tenant) -> Option<&mut Usage>,
+              cell_id: CellID) -> Result<(), Error> {
+        let (is_charged, is_counted) = match usage(self) {
+            Some(usage) => (usage.is_charged, usage.cell_ids.contains(&cell_id)),
+            None => return Ok(())
+        };
+        if is_counted { return Ok(()); }
+        if is_charged { self.check_quota(func_name, CellQty(1))?; }
+        if let Some(usage) = usage(self) { usage.cell_ids.insert(cell_id); }
+        Ok(())
+    }
+    fn check_quota(&self, func_name: &'static str, n: CellQty) -> Result<(), TenantError> {
+        let available = self.get_available();
+        if *available < *n {
+            Err(TenantError::Quota { func_name, tenant: self.id.get_name(), request: n, available })
+        } else {
+            Ok(())
+        }
     }
 }
-
-impl fmt::Debug for Tenant { 
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut s = "Tenant: ".to_string();
-        s = s + &format!("{:?} {} cells", self.id, *self.ncells);
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("Tenant {}: {} cells, {} available", self.id, self.ncells, self.get_available());
+        for (tree, usage) in &self.trees { write!(s, "\n  Tree {} {} cells", tree, usage.get_ncells())?; }
+        for (manifest_id, usage) in &self.manifests { write!(s, "\n  Manifest {} {} cells", manifest_id, usage.get_ncells())?; }
+        for child in self.children.values() { write!(s, "\n  Sub-tenant {} {} cells", child.id, child.ncells)?; }
         write!(f, "{}", s)
     }
 }
-*/
 // Errors
-/*
-use config::CellQty;
+use failure::{Error, ResultExt};
 #[derive(Debug, Fail)]
 pub enum TenantError {
     #[fail(display = "TenantError::Chain {} {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
     #[fail(display = "TenantError::DuplicateName {}: A tenant named '{}' already exists.", func_name, tenant_name)]
     DuplicateName { func_name: &'static str, tenant_name: String },
-    #[fail(display = "TenantError::Quota {}: You asked for {:?} cells, but only {:?} are available", func_name, request, available)]
-    Quota { func_name: &'static str, request: CellQty, available: CellQty }
+    #[fail(display = "TenantError::NoTenant {}: No tenant named '{}'", func_name, tenant_name)]
+    NoTenant { func_name: &'static str, tenant_name: String },
+    #[fail(display = "TenantError::Quota {}: Tenant {} asked for {} cells, but only {} are available", func_name, tenant, request, available)]
+    Quota { func_name: &'static str, tenant: String, request: CellQty, available: CellQty }
 }
-*/
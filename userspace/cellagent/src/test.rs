@@ -9,25 +9,37 @@
 #![allow(unused_variables)]
 #![allow(unused_imports)]
 #[cfg(test)]
+#[macro_use] extern crate crossbeam;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde_json;
 #[macro_use] extern crate lazy_static;
 pub mod app_message;
 pub mod app_message_formats;
+pub mod auth;
 pub mod blueprint;
+pub mod cell_replay;
 pub mod cellagent;
 pub mod cmodel;
 pub mod config;
 pub mod container;
+pub mod counters;
 pub mod dal;
+#[cfg(any(feature = "simulator"))]
 pub mod datacenter;
 pub mod dumpstack;
-pub mod ec_message;
-pub mod ec_message_formats;
+#[cfg(feature = "cell")]
+pub mod ecnl;
 pub mod errors;
 pub mod gvm_equation;
+#[cfg(any(feature = "simulator"))]
 pub mod link;
+pub mod ec_message;
+pub mod ec_message_formats;
+#[cfg(feature = "cell")]
+pub mod ecnl_port;
+pub mod metrics;
+#[cfg(any(feature = "simulator", feature = "cell"))]
 pub mod nalcell;
 pub mod name;
 pub mod noc;
@@ -35,25 +47,64 @@ pub mod packet;
 pub mod packet_engine;
 pub mod port;
 pub mod port_tree;
+#[cfg(any(feature = "simulator"))]
 pub mod rack;
+pub mod replay;
 pub mod routing_table;
 pub mod routing_table_entry;
 pub mod service;
+pub mod simulated_border_port;
+#[cfg(any(feature = "simulator"))]
+pub mod simulated_interior_port;
+pub mod snake;
 pub mod tenant;
+pub mod trace_filter;
+pub mod trace_sink;
 pub mod traph;
 pub mod traph_element;
 pub mod tree;
+pub mod tree_key;
+pub mod uptree;
 pub mod uptree_spec;
 pub mod utility;
 pub mod uuid_ec;
 pub mod vm;
+pub mod wire;
+
+use std::{collections::{HashMap, HashSet}, io::BufRead, sync::{Arc, Mutex}, thread, time::Duration};
 
-use std::{collections::{HashMap}};
+use crossbeam::crossbeam_channel::unbounded as channel;
+use ec_trace_reader::{TraceFileHeader, TraceReader};
 
+use crate::app_message::{AppMessage, AppMsgDirection, AppMsgType, AppHealthMsg, AppHealthMsgPayload, AppInterapplicationMsg, AppManifestMsg,
+                         HealthReport, SenderMsgSeqNo};
+use crate::auth::{AuthKey, Authenticator};
 use crate::blueprint::{Blueprint};
-use crate::config::{CONFIG, CellQty, PortQty};
+use crate::cell_replay::{CellOutput, CellReplay, unmatched};
+use crate::cellagent::{CellAgent};
+use crate::container::{Container, ContainerStatus};
+use crate::config::{CONFIG, BASE_TREE_NAME, CONNECTED_PORTS_TREE_NAME, CONTROL_TREE_NAME, PACKET_MAX, CellQty, PathLength, PortQty};
+use crate::dal::{get_cell_replay_file_name};
 use crate::datacenter::{Datacenter};
-use crate::utility::{CellNo, Edge, PortNo, TraceHeader, is2e};
+use crate::counters::PacketEngineCounters;
+use crate::ec_message::{DiscoverMsg, Message, MsgType};
+use crate::ec_message_formats::{CmToPePacket, PeToPortPacket, PortToPePacket};
+use crate::link::{LinkStatus};
+use crate::metrics::{CellMetrics, MetricsSource};
+use crate::name::{Name, CellID, ContainerID, OriginatorID, TreeID, UptreeID};
+use crate::noc::{NOC_AGENT_DEPLOY_TREE_NAME, NOC_AGENT_MANIFEST};
+use crate::packet::{Packet, Packetizer};
+use crate::packet_engine::{NumberOfPackets, PacketEngine, NUM_SLOTS};
+use crate::routing_table_entry::{RoutingTableEntry};
+use crate::tenant::{ROOT_TENANT_NAME, Tenant, is_within};
+use crate::trace_filter::{Level, TraceEvent, TraceFilter};
+use crate::trace_sink::{FileSink, RingBufferSink, Rotation, SocketAddress, SocketSink, TraceSink};
+use crate::tree_key::{SharedTreeKeys, TreeKey, TreeKeys};
+use crate::uptree::{UpTree};
+use crate::uptree_spec::{AllowedTree, ContainerSpec, DeployMode, Manifest, RestartPolicy, UpTreeSpec, VmSpec};
+use crate::utility::{ByteArray, CellConfig, CellNo, CellType, Edge, Mask, OutbufType, Path, Placement, PlacementReport, PortNo, PortNumber, TraceHeader, S, is2e};
+use crate::uuid_ec::{Uuid};
+use crate::wire::{WireFormat, WIRE_VERSION};
 
 trait Test {
     fn test(&mut self);
@@ -175,12 +226,12 @@ struct DatacenterGraph {
 impl DatacenterGraph {
     fn new_three_cells() -> DatacenterGraph {
         let mut border_cell_ports = HashMap::new();
-        border_cell_ports.insert(CellNo(0), vec![PortNo(2)]);
+        border_cell_ports.insert(CellNo(0), vec![PortNo(3)]);
         match Datacenter::construct(
             match Blueprint::new(
                 TRIANGLE_GRAPH_SPEC.num_cells,
                 &TRIANGLE_GRAPH_SPEC.edges,
-                PortQty(3),
+                PortQty(4),
                 &HashMap::new(),
                 &border_cell_ports,
             ) {
@@ -197,12 +248,12 @@ impl DatacenterGraph {
     }
     fn new_four_cells() -> DatacenterGraph {
         let mut border_cell_ports = HashMap::new();
-        border_cell_ports.insert(CellNo(0), vec![PortNo(3)]);
+        border_cell_ports.insert(CellNo(0), vec![PortNo(4)]);
         match Datacenter::construct(
             match Blueprint::new(
                 TWO_BY_TWO_WITH_DIAGONALS_GRAPH_SPEC.num_cells,
                 &TWO_BY_TWO_WITH_DIAGONALS_GRAPH_SPEC.edges,
-                PortQty(4),
+                PortQty(5),
                 &HashMap::new(),
                 &border_cell_ports,
             ) {
@@ -272,14 +323,12 @@ impl Test for DatacenterGraph {
         assert_eq!(rack.get_cell_ids().len(), *self.graph_spec.num_cells);
         // Check that each numbered cell exists in datacenter
         for i in 0..*self.graph_spec.num_cells {
-            &rack.get_cells()[&CellNo(i)];
-            ()
+            assert!(rack.get_cells().contains_key(&CellNo(i)));
         }
         assert_eq!(rack.get_links().len(), self.graph_spec.edges.len());
         // Check that each edge exists as link in datacenter
         for edge in &self.graph_spec.edges {
-            &rack.get_links()[&edge];
-            ()
+            assert!(rack.get_links().keys().any(|edge_connection| edge_connection.get_edge() == *edge));
         }
     }
 }
@@ -306,9 +355,9 @@ struct DatacenterPortsSpec<'a> {
 lazy_static! {
     static ref TRIANGLE_PORTS_SPEC: DatacenterPortsSpec<'static> = {
         let mut cell_port_exceptions = HashMap::new();
-        cell_port_exceptions.insert(CellNo(0), PortQty(3));
+        cell_port_exceptions.insert(CellNo(0), PortQty(4));
         DatacenterPortsSpec {
-            default_num_phys_ports_per_cell: PortQty(2),
+            default_num_phys_ports_per_cell: PortQty(3),
             cell_port_exceptions: cell_port_exceptions,
             graph_spec: &TRIANGLE_GRAPH_SPEC,
         }
@@ -318,9 +367,9 @@ lazy_static! {
 lazy_static! {
     static ref FIVE_BY_TWO_PORTS_SPEC: DatacenterPortsSpec<'static> = {
         let mut cell_port_exceptions = HashMap::new();
-        cell_port_exceptions.insert(CellNo(4), PortQty(2));
+        cell_port_exceptions.insert(CellNo(4), PortQty(3));
         DatacenterPortsSpec {
-            default_num_phys_ports_per_cell: PortQty(4), // This seems like it should be OK as 3, but test_default_port_border fails
+            default_num_phys_ports_per_cell: PortQty(5), // Port 0, three links, and a border port on cell 7
             cell_port_exceptions: cell_port_exceptions,
             graph_spec: &FIVE_BY_TWO_GRAPH_SPEC,
         }
@@ -421,10 +470,10 @@ impl<'a> Test for DatacenterPorts<'a> {
         for (_cell_no, cell) in self.dc.get_rack().get_cells() {
             match self.ports_spec.cell_port_exceptions.get(&CellNo(cell.get_name().trim_start_matches("C:").parse().unwrap())) {
                 Some(num_phys_ports) => {
-                    assert_eq!(cell.get_num_ports(), PortQty(**num_phys_ports+1));
+                    assert_eq!(cell.get_num_ports(), *num_phys_ports);
                 }
                 None => {
-                    assert_eq!(cell.get_num_ports(), PortQty(*self.ports_spec.default_num_phys_ports_per_cell+1));
+                    assert_eq!(cell.get_num_ports(), self.ports_spec.default_num_phys_ports_per_cell);
                 }
             }
         }
@@ -587,6 +636,1210 @@ impl<'a, 'b> Drop for DatacenterBorder<'a, 'b> {
 test_result!(test_default_port_border, DatacenterBorder::new_default_port_border());
 test_result!(test_exception_port_border, DatacenterBorder::new_exception_port_border());
 
+struct UpTreeRouting {
+    up_tree: UpTree<usize>,
+}
+
+impl UpTreeRouting {
+    // 0 is the root with children 1 and 2; 3 and 4 are children of 2
+    fn new_five_members() -> UpTreeRouting {
+        let spec = UpTreeSpec::new("test1", vec![0, 0, 0, 2, 2]).expect("UpTreeSpec construction failure");
+        let id = UptreeID::new("test1").expect("UptreeID construction failure");
+        match UpTree::new(id, &spec, &[10, 11, 12, 13, 14]) {
+            Ok(up_tree) => UpTreeRouting { up_tree },
+            Err(err) => panic!("UpTree construction failure: {}", err)
+        }
+    }
+    fn new_too_few_members() -> UpTreeRouting {
+        let spec = UpTreeSpec::new("test2", vec![1, 1, 0, 1]).expect("UpTreeSpec construction failure");
+        let id = UptreeID::new("test2").expect("UptreeID construction failure");
+        match UpTree::new(id, &spec, &[10, 11]) {
+            Ok(up_tree) => UpTreeRouting { up_tree },
+            Err(err) => panic!("UpTree construction failure: {}", err)
+        }
+    }
+}
+
+impl Test for UpTreeRouting {
+    fn test(&mut self) {
+        assert_eq!(self.up_tree.get_root(), 10);
+        assert_eq!(self.up_tree.next_hops(10, AppMsgDirection::Rootward).unwrap(), Vec::<usize>::new());
+        assert_eq!(self.up_tree.next_hops(10, AppMsgDirection::Leafward).unwrap(), vec![11, 12]);
+        assert_eq!(self.up_tree.next_hops(13, AppMsgDirection::Rootward).unwrap(), vec![12]);
+        assert_eq!(self.up_tree.next_hops(12, AppMsgDirection::Leafward).unwrap(), vec![13, 14]);
+        assert!(self.up_tree.next_hops(15, AppMsgDirection::Leafward).is_err());
+    }
+}
+
+test_result!(test_up_tree_routing, UpTreeRouting::new_five_members());
+test_error!(test_up_tree_too_few_members, UpTreeRouting::new_too_few_members(), "UpTreeError::Members");
+
+struct ManifestLoading {
+    json: String,
+    expected_errors: usize,
+}
+
+impl ManifestLoading {
+    fn new(schema_version: &str, container_image: &str, container_tree: &str, vm_tree: &str, up_tree: &str) -> ManifestLoading {
+        ManifestLoading::with_containers(schema_version, container_image, container_tree, vm_tree, up_tree, 1)
+    }
+    // Every container is the same but for its id; the VM's up tree has one member
+    fn with_containers(schema_version: &str, container_image: &str, container_tree: &str, vm_tree: &str, up_tree: &str,
+                       n_containers: usize) -> ManifestLoading {
+        let containers = (1..=n_containers)
+            .map(|n| format!(r#"{{"id": "c{}", "image": "{}", "params": [], "allowed_trees": [{{"name": "{}"}}]}}"#,
+                             n, container_image, container_tree))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let json = format!(r#"{{"schema_version": "{}", "manifest": {{
+            "id": "test", "deployment_tree": {{"name": "NocMasterAgent"}}, "cell_config": "Large",
+            "allowed_trees": [{{"name": "NocMasterAgent"}}],
+            "vms": [{{"id": "vm1", "image": "Ubuntu", "required_config": "Large",
+                      "allowed_trees": [{{"name": "{}"}}],
+                      "containers": [{}],
+                      "trees": [{{"id": "vm_tree", "parent_list": [0]}}]}}],
+            "trees": [{{"id": "manifest_tree", "parent_list": {}}}]}}}}"#,
+            schema_version, vm_tree, containers, up_tree);
+        ManifestLoading { json, expected_errors: 0 }
+    }
+    fn new_valid() -> ManifestLoading {
+        ManifestLoading::new(crate::config::SCHEMA_VERSION, "NocAgent", "NocMasterAgent", "NocMasterAgent", "[0]")
+    }
+    // Unknown image, container tree not in the VM, VM tree not in the manifest, and two VMs needed for one
+    fn new_four_errors() -> ManifestLoading {
+        ManifestLoading { expected_errors: 4,
+            ..ManifestLoading::new(crate::config::SCHEMA_VERSION, "Unknown", "Other", "Another", "[0, 0]") }
+    }
+    // Up trees must have as many members as there are VMs or containers, not just enough
+    fn new_undersized() -> ManifestLoading {
+        ManifestLoading { expected_errors: 1,
+            ..ManifestLoading::with_containers(crate::config::SCHEMA_VERSION, "NocAgent", "NocMasterAgent", "NocMasterAgent", "[0]", 2) }
+    }
+    // A redeploy names a new version of the same image
+    fn new_versioned_image() -> ManifestLoading {
+        ManifestLoading::new(crate::config::SCHEMA_VERSION, "NocAgent:2", "NocMasterAgent", "NocMasterAgent", "[0]")
+    }
+    fn new_wrong_version() -> ManifestLoading {
+        ManifestLoading::new("0.0", "NocAgent", "NocMasterAgent", "NocMasterAgent", "[0]")
+    }
+    // The one the NOC deploys on every cell
+    fn new_noc_agent() -> ManifestLoading {
+        let json = std::fs::read_to_string(NOC_AGENT_MANIFEST).expect("NOC agent manifest must be there");
+        ManifestLoading { json, expected_errors: 0 }
+    }
+}
+
+impl Test for ManifestLoading {
+    fn test(&mut self) {
+        match Manifest::from_json(&self.json) {
+            Ok(manifest) => {
+                assert_eq!(self.expected_errors, 0);
+                let reloaded = Manifest::from_json(&manifest.to_json().unwrap()).unwrap();
+                assert_eq!(reloaded.get_id(), manifest.get_id());
+            },
+            Err(err) => {
+                assert!(self.expected_errors > 0, "{}", err);
+                assert!(format!("{}", err).contains(&format!("{} problems", self.expected_errors)), "{}", err);
+            }
+        }
+    }
+}
+
+test_result!(test_manifest_valid, ManifestLoading::new_valid());
+test_result!(test_manifest_collects_errors, ManifestLoading::new_four_errors());
+test_result!(test_manifest_undersized_tree, ManifestLoading::new_undersized());
+test_result!(test_manifest_versioned_image, ManifestLoading::new_versioned_image());
+test_error!(test_manifest_wrong_version, ManifestLoading::new_wrong_version(), "UpTreeSpecError::Version");
+test_result!(test_manifest_noc_agent, ManifestLoading::new_noc_agent());
+
+struct RestartBackoff {
+    policy: RestartPolicy,
+    expected_ms: Vec<Option<u64>>, // Delay before restart 1, 2, ...
+}
+
+impl RestartBackoff {
+    fn new_on_failure() -> RestartBackoff {
+        RestartBackoff { policy: RestartPolicy::OnFailure { max_retries: 3, backoff_ms: 100 },
+            expected_ms: vec![Some(100), Some(200), Some(400), None] }
+    }
+    fn new_always() -> RestartBackoff {
+        RestartBackoff { policy: RestartPolicy::Always { backoff_ms: 50 },
+            expected_ms: vec![Some(50), Some(50), Some(50), Some(50)] }
+    }
+}
+
+impl Test for RestartBackoff {
+    fn test(&mut self) {
+        for (restarts, expected) in self.expected_ms.iter().enumerate() {
+            let delay = self.policy.restart_delay(restarts).map(|delay| delay.as_millis() as u64);
+            assert_eq!(delay, *expected, "restart {}", restarts + 1);
+        }
+    }
+}
+
+test_result!(test_restart_on_failure_backoff, RestartBackoff::new_on_failure());
+test_result!(test_restart_always, RestartBackoff::new_always());
+
+// What happens after a container fails, with continue_on_error set
+struct RestartAfterError {
+    policy: Option<RestartPolicy>,
+    expected_ms: Option<u64>,
+}
+
+impl Test for RestartAfterError {
+    fn test(&mut self) {
+        let delay = RestartPolicy::restart_delay_after_error(self.policy, 0, true).map(|delay| delay.as_millis() as u64);
+        assert_eq!(delay, self.expected_ms);
+    }
+}
+
+test_result!(test_restart_never_after_error, RestartAfterError { policy: Some(RestartPolicy::Never), expected_ms: None });
+test_result!(test_restart_no_policy_after_error, RestartAfterError { policy: None, expected_ms: Some(0) });
+
+struct FailedContainer {}
+
+impl Test for FailedContainer {
+    // A container that fails is restarted by its policy and reports each step to its VM, and the
+    // cell agent passes the failure on to the NOC master as a report it can read back
+    fn test(&mut self) {
+        let cell_id = CellID::new("C:92").unwrap();
+        let (container_to_vm, vm_from_container) = channel();
+        let (vm_to_container, container_from_vm) = channel();
+        let policy = RestartPolicy::OnFailure { max_retries: 1, backoff_ms: 0 };
+        let container = Container::new(cell_id, ContainerID::new("Failing").unwrap(), "NocMaster", None, &HashSet::new(),
+                                       &TreeKeys::new(), Some(policy), container_to_vm).unwrap();
+        container.initialize(UptreeID::new("Failing").unwrap(), container_from_vm).unwrap();
+        let mut health = || loop {
+            let bytes = vm_from_container.recv_timeout(Duration::from_secs(5)).expect("No health report");
+            let app_msg: Box<dyn AppMessage> = wire::decode(&bytes).unwrap();
+            if app_msg.get_msg_type() == AppMsgType::AppHealthMsg {
+                let health_msg: AppHealthMsg = serde_json::from_value(app_msg.value()).unwrap();
+                let report = health_msg.get_payload().clone();
+                return report;
+            }
+        };
+        let statuses = |reports: &[AppHealthMsgPayload]| reports.iter().map(|report| (report.get_status(), report.get_restarts())).collect::<Vec<_>>();
+        let started = vec![health(), health()];
+        assert_eq!(statuses(&started), vec![(ContainerStatus::Starting, 0), (ContainerStatus::Running, 0)]);
+        vm_to_container.send(ByteArray::new("Not a message")).unwrap();
+        let restarted = vec![health(), health(), health()];
+        assert_eq!(statuses(&restarted), vec![(ContainerStatus::Failed, 0), (ContainerStatus::Starting, 1), (ContainerStatus::Running, 1)]);
+        assert!(!restarted[0].get_reason().is_empty());
+        let body = HealthReport::new(cell_id, &restarted[0]).to_body().unwrap();
+        let health_report = HealthReport::from_body(&body).expect("Not a health report");
+        assert_eq!(health_report.get_cell_id(), cell_id);
+        assert_eq!(health_report.get_report().get_status(), ContainerStatus::Failed);
+        assert!(HealthReport::from_body("Hello From Master").is_none());
+    }
+}
+
+test_result!(test_failed_container, FailedContainer {});
+
+struct TenantQuota {
+    tenant: Tenant,
+    subtenant_cells: CellQty,
+    tree_cells: CellQty,
+}
+
+impl TenantQuota {
+    fn new(subtenant_cells: usize, tree_cells: usize) -> TenantQuota {
+        let tenant = Tenant::new(ROOT_TENANT_NAME, CellQty(10), None).expect("Tenant construction failure");
+        TenantQuota { tenant, subtenant_cells: CellQty(subtenant_cells), tree_cells: CellQty(tree_cells) }
+    }
+    fn new_within_quota() -> TenantQuota { TenantQuota::new(6, 4) }
+    fn new_over_quota() -> TenantQuota { TenantQuota::new(6, 7) }
+}
+
+impl Test for TenantQuota {
+    // Cells given to the sub-tenant come out of the root's quota, and the sub-tenant is limited to its own
+    fn test(&mut self) {
+        let sub_id = self.tenant.create_subtenant("Sub", self.subtenant_cells).unwrap();
+        assert!(is_within(&sub_id.get_name(), ROOT_TENANT_NAME));
+        assert!(!is_within(&sub_id.get_name(), &format!("{}+Su", ROOT_TENANT_NAME)));
+        assert_eq!(*self.tenant.get_available(), 10 - *self.subtenant_cells);
+        assert!(self.tenant.create_subtenant("Sub", CellQty(1)).is_err());
+        let sub = self.tenant.find_mut(&sub_id.get_name()).expect("Sub-tenant not found");
+        sub.claim_tree(&AllowedTree::new("SubTree"), true).unwrap();
+        // Each cell on the tree charges once, no matter how often it asks
+        for i in 0..*self.tree_cells {
+            let cell_id = CellID::new(&format!("C:{}", i)).unwrap();
+            self.tenant.charge_tree(&AllowedTree::new("SubTree"), cell_id).unwrap();
+            self.tenant.charge_tree(&AllowedTree::new("SubTree"), cell_id).unwrap();
+        }
+        assert_eq!(self.tenant.find_tree(&AllowedTree::new("SubTree")), Some((sub_id, self.tree_cells)));
+    }
+}
+
+test_result!(test_tenant_within_quota, TenantQuota::new_within_quota());
+test_error!(test_tenant_over_quota, TenantQuota::new_over_quota(), "TenantError::Quota");
+
+struct TreeRelease {}
+
+impl Test for TreeRelease {
+    // Deleting a stacked tree gives the cells it reached back to the tenant that stacked it
+    fn test(&mut self) {
+        let mut tenant = Tenant::new(ROOT_TENANT_NAME, CellQty(10), None).expect("Tenant construction failure");
+        let sub_id = tenant.create_subtenant("Sub", CellQty(4)).unwrap();
+        let tree = AllowedTree::new("SubTree");
+        tenant.find_mut(&sub_id.get_name()).expect("Sub-tenant not found").claim_tree(&tree, true).unwrap();
+        for i in 0..4 { tenant.charge_tree(&tree, CellID::new(&format!("C:{}", i)).unwrap()).unwrap(); }
+        let available = |tenant: &mut Tenant| *tenant.find_mut(&sub_id.get_name()).expect("Sub-tenant not found").get_available();
+        assert_eq!(available(&mut tenant), 0);
+        assert!(tenant.charge_tree(&tree, CellID::new("C:4").unwrap()).is_err());
+        assert_eq!(tenant.release_tree(&tree), Some(CellQty(4)));
+        assert_eq!(available(&mut tenant), 4);
+        assert_eq!(tenant.find_tree(&tree), None);
+        assert_eq!(tenant.release_tree(&tree), None);
+    }
+}
+
+test_result!(test_tree_release, TreeRelease {});
+
+struct IsolationViolation {
+    tenant: Option<&'static str>, // Tenant the deploy is for, the sender's own if None
+    tree_name: &'static str, // Tree to deploy on
+    expected: &'static str,
+}
+
+impl IsolationViolation {
+    fn new_other_tenant() -> IsolationViolation {
+        IsolationViolation { tenant: Some("Elsewhere"), tree_name: BASE_TREE_NAME, expected: "CellAgentError::TenantNotAllowed" }
+    }
+    fn new_other_tree() -> IsolationViolation {
+        IsolationViolation { tenant: None, tree_name: "NotGiven", expected: "CellAgentError::TreeNotAllowed" }
+    }
+}
+
+impl Test for IsolationViolation {
+    // A border cell refuses a deploy for a tenant outside the sender's or on a tree the sender wasn't given
+    fn test(&mut self) {
+        let cell_id = CellID::new("C:91").unwrap();
+        let border_port_nos = [PortNo(2)].iter().cloned().collect::<HashSet<_>>();
+        let (cm_to_ca, _ca_from_cm) = channel();
+        let (_port_to_pe, pe_from_ports) = channel();
+        let (ca_to_cm, cm_from_ca) = channel();
+        let (pe_to_cm, cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let (ca_to_port, _port_from_ca) = channel();
+        let ca_to_ports = [(PortNo(2), ca_to_port)].iter().cloned().collect::<HashMap<_, _>>();
+        let (mut cell_agent, _cm_join_handle) = CellAgent::new(cell_id, None, CellType::Border, CellConfig::Large, PortQty(4),
+                ca_to_ports, cm_to_ca, pe_from_ports, HashMap::new(), &border_port_nos,
+                ca_to_cm, cm_from_ca, pe_to_cm, cm_from_pe, cm_to_pe, pe_from_cm,
+                Tenant::new_tenants(CONFIG.num_cells).unwrap(), SharedTreeKeys::default()).unwrap();
+        let manifest = Manifest::from_file(NOC_AGENT_MANIFEST).unwrap();
+        let deploy_msg = AppManifestMsg::new("Noc", false, false, &AllowedTree::new(self.tree_name),
+                                             &manifest, manifest.get_allowed_trees());
+        let deploy_msg = match self.tenant {
+            Some(tenant) => deploy_msg.with_tenant(tenant),
+            None => deploy_msg
+        };
+        let originator_id = OriginatorID::new(cell_id, "BorderPort+2").unwrap();
+        let e = cell_agent.app_manifest(&deploy_msg, originator_id).unwrap_err();
+        assert!(e.find_root_cause().to_string().starts_with(self.expected), "{}", e);
+    }
+}
+
+test_result!(test_deploy_other_tenant, IsolationViolation::new_other_tenant());
+test_result!(test_deploy_other_tree, IsolationViolation::new_other_tree());
+
+struct MessageAuthentication {
+    expected: &'static str, // Error from the second cell, empty if it takes every message
+}
+
+impl MessageAuthentication {
+    // A cell's config has its own secret key and only the public keys of the others
+    fn authenticator(cell_no: usize) -> Authenticator {
+        let auth_keys = (0..3)
+            .map(|i| {
+                let auth_key = AuthKey::from_secret(&format!("{:064x}", i + 1)).unwrap();
+                (CellNo(i), if i == cell_no { auth_key } else { auth_key.without_secret() })
+            })
+            .collect::<HashMap<_, _>>();
+        Authenticator::from_keys(CellID::new(&format!("C:{}", cell_no)).unwrap(), &auth_keys).unwrap()
+    }
+    fn discover(cell_no: usize) -> DiscoverMsg {
+        let cell_id = CellID::new(&format!("C:{}", cell_no)).unwrap();
+        DiscoverMsg::new(cell_id, OriginatorID::new(cell_id, "Test").unwrap(),
+                         TreeID::new("C:0").unwrap().to_port_tree_id_0(), PathLength(CellQty(0)), Path::new0())
+    }
+    fn verify(receiver: &mut Authenticator, bytes: &ByteArray) -> Result<(), failure::Error> {
+        let msg = MsgType::msg_from_bytes(bytes)?;
+        receiver.verify(msg.get_header(), bytes)
+    }
+}
+
+impl Test for MessageAuthentication {
+    fn test(&mut self) {
+        let msg = MessageAuthentication::discover(0);
+        let bytes = MessageAuthentication::authenticator(0).sign(&msg).unwrap();
+        let mut receiver = MessageAuthentication::authenticator(1);
+        MessageAuthentication::verify(&mut receiver, &bytes).unwrap();
+        // Cell 2 forwards the message it got from cell 0 with the same sequence number, twice
+        let forwarder = MessageAuthentication::authenticator(2);
+        let forwarded = msg.update(CellID::new("C:2").unwrap());
+        for _ in 0..2 {
+            MessageAuthentication::verify(&mut receiver, &forwarder.sign(&forwarded).unwrap()).unwrap();
+        }
+        let result = match self.expected {
+            "AuthError::Replayed" => MessageAuthentication::verify(&mut receiver, &bytes),
+            // Cell 0 sends more than a window's worth of messages before the first one is replayed
+            "AuthError::Replayed outside window" => {
+                let sender = MessageAuthentication::authenticator(0);
+                let bytes = sender.sign(&msg).unwrap();
+                MessageAuthentication::verify(&mut receiver, &bytes).unwrap();
+                for _ in 0..1025 {
+                    MessageAuthentication::verify(&mut receiver, &sender.sign(&msg).unwrap()).unwrap();
+                }
+                MessageAuthentication::verify(&mut receiver, &bytes)
+            },
+            // Cell 2 signs a message claiming to be from cell 0
+            "AuthError::Forged" => MessageAuthentication::verify(&mut receiver, &forwarder.sign(&MessageAuthentication::discover(0)).unwrap()),
+            _ => Ok(())
+        };
+        if let Err(e) = result {
+            assert!(self.expected.starts_with(e.to_string().split(' ').next().unwrap_or_default()), "{}", e);
+            panic!("{}", e);
+        }
+    }
+}
+
+test_result!(test_auth_forwarded, MessageAuthentication { expected: "" });
+test_error!(test_auth_replayed, MessageAuthentication { expected: "AuthError::Replayed" }, "AuthError::Replayed");
+test_error!(test_auth_replayed_outside_window, MessageAuthentication { expected: "AuthError::Replayed outside window" }, "AuthError::Replayed");
+test_error!(test_auth_forged, MessageAuthentication { expected: "AuthError::Forged" }, "AuthError::Forged");
+
+struct NocBootstrap {
+    datacenter_graph: DatacenterGraph,
+}
+
+impl NocBootstrap {
+    fn new_ten_cells() -> NocBootstrap {
+        NocBootstrap { datacenter_graph: DatacenterGraph::new_ten_cells() }
+    }
+}
+
+impl Test for NocBootstrap {
+    // The NOC stacks several trees over every cell before it deploys its agents, so charging
+    // them would use up the root's quota before the last one reached all the cells
+    fn test(&mut self) {
+        let agent_deploy = AllowedTree::new(NOC_AGENT_DEPLOY_TREE_NAME);
+        let num_cells = self.datacenter_graph.graph_spec.num_cells;
+        let tenants = self.datacenter_graph.dc.get_rack().get_tenants();
+        let mut ncells = CellQty(0);
+        for _ in 0..600 {
+            ncells = tenants.lock().unwrap().find_tree(&agent_deploy).map_or(CellQty(0), |(_, ncells)| ncells);
+            if ncells == num_cells { break; }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(ncells, num_cells);
+        let root = tenants.lock().unwrap();
+        assert_eq!(root.find_tree(&agent_deploy).map(|(owner, _)| owner.get_name()), Some(S(ROOT_TENANT_NAME)));
+        assert_eq!(root.get_available(), num_cells);
+    }
+}
+
+test_result!(test_noc_bootstrap_with_quotas, NocBootstrap::new_ten_cells());
+
+// Each case starts with the NOC agent deployed on every cell, then undeploys it (no mode) or
+// redeploys it with the given image
+struct ManifestLifecycle {
+    datacenter_graph: DatacenterGraph,
+    mode: Option<DeployMode>,
+    image: &'static str,
+}
+
+impl ManifestLifecycle {
+    fn new(mode: Option<DeployMode>, image: &'static str) -> ManifestLifecycle {
+        ManifestLifecycle { datacenter_graph: DatacenterGraph::new_ten_cells(), mode, image }
+    }
+}
+// Placement reports for manifests deployed on the NOC agent's deploy tree
+impl DatacenterGraph {
+    fn reports(&self, manifest_id: &str) -> Vec<PlacementReport> {
+        self.dc.get_noc().get_placements()
+            .into_iter()
+            .filter(|report| report.get_manifest_id() == manifest_id)
+            .collect()
+    }
+    // What each cell reported for the manifest's VMs after the first skip reports, in order
+    fn placements(&self, manifest_id: &str, skip: usize) -> HashMap<CellID, Vec<(Placement, usize)>> {
+        let mut placements = HashMap::new();
+        for report in self.reports(manifest_id).into_iter().skip(skip) {
+            for (_, placement) in report.get_vms() {
+                placements.entry(report.get_cell_id()).or_insert_with(Vec::new).push((*placement, report.get_free_slots()));
+            }
+        }
+        placements
+    }
+    // The border cell at the root of the agent's deploy tree doesn't run an agent
+    fn wait_for(&self, manifest_id: &str, skip: usize, placement: Placement) -> HashMap<CellID, Vec<(Placement, usize)>> {
+        let num_cells = *self.graph_spec.num_cells - 1;
+        let mut placements = HashMap::new();
+        for _ in 0..600 {
+            placements = self.placements(manifest_id, skip);
+            let ncells = placements.values().filter(|cell| cell.iter().any(|(p, _)| *p == placement)).count();
+            if ncells == num_cells { break; }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(placements.len(), num_cells, "{:?}", placements);
+        placements
+    }
+}
+
+impl Test for ManifestLifecycle {
+    fn test(&mut self) {
+        let deployed = self.datacenter_graph.wait_for("NocAgent", 0, Placement::Placed);
+        let skip = self.datacenter_graph.reports("NocAgent").len();
+        let dc = &self.datacenter_graph.dc;
+        let json = std::fs::read_to_string(NOC_AGENT_MANIFEST).unwrap()
+            .replace(r#""image": "NocAgent""#, &format!(r#""image": "{}""#, self.image));
+        let manifest = Manifest::from_json(&json).unwrap();
+        match self.mode {
+            None => {
+                dc.undeploy(manifest.get_id(), manifest.get_deployment_tree()).unwrap();
+                // Every cell gets back the agent's slot
+                for (cell_id, placements) in self.datacenter_graph.wait_for("NocAgent", skip, Placement::Undeployed) {
+                    let (_, free_slots) = placements[0];
+                    assert_eq!(free_slots, deployed[&cell_id][0].1 + 1, "{}", cell_id);
+                }
+            },
+            Some(mode) => {
+                dc.redeploy(&manifest, mode).unwrap();
+                if self.image == "NocAgent" {
+                    // Same image, so nothing stops or starts
+                    for (cell_id, placements) in self.datacenter_graph.wait_for("NocAgent", skip, Placement::Unchanged) {
+                        assert!(placements.iter().all(|(p, _)| *p == Placement::Unchanged), "{} {:?}", cell_id, placements);
+                    }
+                } else {
+                    self.datacenter_graph.wait_for("NocAgent", skip, Placement::Placed);
+                    for (cell_id, placements) in self.datacenter_graph.wait_for("NocAgent", skip, Placement::Undeployed) {
+                        let order = placements.iter().map(|(p, _)| *p).collect::<Vec<_>>();
+                        let expected = match mode {
+                            DeployMode::Replace => vec![Placement::Undeployed, Placement::Placed],
+                            DeployMode::Rolling => vec![Placement::Placed, Placement::Undeployed],
+                        };
+                        assert_eq!(order, expected, "{}", cell_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+test_result!(test_undeploy, ManifestLifecycle::new(None, "NocAgent"));
+test_result!(test_redeploy_same_image, ManifestLifecycle::new(Some(DeployMode::Replace), "NocAgent"));
+test_result!(test_redeploy_new_version, ManifestLifecycle::new(Some(DeployMode::Replace), "NocAgent:2"));
+test_result!(test_rolling_upgrade, ManifestLifecycle::new(Some(DeployMode::Rolling), "NocAgent:2"));
+
+struct PlacementDeferral {
+    datacenter_graph: DatacenterGraph,
+}
+
+impl PlacementDeferral {
+    // One VM of agent containers, on the agent's deploy tree
+    fn manifest(id: &str, ncontainers: usize) -> Manifest {
+        let agent = Manifest::from_file(NOC_AGENT_MANIFEST).unwrap();
+        let allowed_trees = agent.get_allowed_trees().clone();
+        let up_tree = UpTreeSpec::new(id, vec![0]).unwrap();
+        let containers = (0..ncontainers)
+            .map(|i| ContainerSpec::new(&format!("{}{}", id, i), "NocAgent", vec![], &allowed_trees).unwrap())
+            .collect::<Vec<_>>();
+        let vm_spec = VmSpec::new("vm1", "Ubuntu", CellConfig::Large, &allowed_trees,
+                                  containers.iter().collect(), vec![&up_tree]).unwrap();
+        Manifest::new(id, CellConfig::Large, agent.get_deployment_tree(), &allowed_trees,
+                      vec![&vm_spec], vec![&up_tree]).unwrap()
+    }
+}
+impl Test for PlacementDeferral {
+    // A VM too big for the cell is refused, one that only needs slots in use waits for them, and
+    // starts as soon as an undeploy gives them back
+    fn test(&mut self) {
+        let graph = &self.datacenter_graph;
+        let agent = graph.wait_for("NocAgent", 0, Placement::Placed);
+        let free_slots = agent.values().next().unwrap()[0].1;
+        graph.dc.deploy(&PlacementDeferral::manifest("Filler", free_slots)).unwrap();
+        for (cell_id, placements) in graph.wait_for("Filler", 0, Placement::Placed) {
+            assert_eq!(placements, vec![(Placement::Placed, 0)], "{}", cell_id);
+        }
+        graph.dc.deploy(&PlacementDeferral::manifest("Giant", CellConfig::Large.get_slots() + 1)).unwrap();
+        graph.wait_for("Giant", 0, Placement::Refused);
+        graph.dc.deploy(&PlacementDeferral::manifest("Waiter", 1)).unwrap();
+        for (cell_id, placements) in graph.wait_for("Waiter", 0, Placement::Deferred) {
+            assert_eq!(placements, vec![(Placement::Deferred, 0)], "{}", cell_id);
+        }
+        let (filler_skip, waiter_skip) = (graph.reports("Filler").len(), graph.reports("Waiter").len());
+        let filler = PlacementDeferral::manifest("Filler", free_slots);
+        graph.dc.undeploy(filler.get_id(), filler.get_deployment_tree()).unwrap();
+        graph.wait_for("Filler", filler_skip, Placement::Undeployed);
+        for (cell_id, placements) in graph.wait_for("Waiter", waiter_skip, Placement::Placed) {
+            assert_eq!(placements, vec![(Placement::Placed, free_slots - 1)], "{}", cell_id);
+        }
+    }
+}
+
+test_result!(test_placement_deferral, PlacementDeferral { datacenter_graph: DatacenterGraph::new_ten_cells() });
+
+struct PayloadSealing {
+    tree_key: TreeKey, // The sender's key for tree Sealed
+    tree_keys: TreeKeys, // The receiver's keys
+    is_sealed: bool,
+    expected: &'static str, // Error from opening the payload, empty if it opens
+}
+
+impl PayloadSealing {
+    fn new(tree_name: &str, is_same_key: bool, is_sealed: bool, expected: &'static str) -> PayloadSealing {
+        let tree_key = TreeKey::new();
+        let mut tree_keys = TreeKeys::new();
+        tree_keys.insert(S(tree_name), if is_same_key { tree_key.clone() } else { TreeKey::new() });
+        PayloadSealing { tree_key, tree_keys, is_sealed, expected }
+    }
+    fn new_same_tree() -> PayloadSealing { PayloadSealing::new("Sealed", true, true, "") }
+    fn new_other_tree() -> PayloadSealing { PayloadSealing::new("Other", true, true, "AppMessageError::NoTreeKey") }
+    fn new_wrong_key() -> PayloadSealing { PayloadSealing::new("Sealed", false, true, "TreeKeyError::Open") }
+    fn new_unsealed() -> PayloadSealing { PayloadSealing::new("Sealed", true, false, "AppMessageError::Unsealed") }
+}
+
+impl Test for PayloadSealing {
+    // Only a container with the key of the target tree can read the body
+    fn test(&mut self) {
+        let tree = AllowedTree::new("Sealed");
+        let msg = if self.is_sealed {
+            AppInterapplicationMsg::new_sealed("Test", false, false, &tree, AppMsgDirection::Leafward,
+                                               &vec![], "Secret", &self.tree_key).unwrap()
+        } else {
+            AppInterapplicationMsg::new("Test", false, false, &tree, AppMsgDirection::Leafward, &vec![], "Secret")
+        };
+        assert!(!self.is_sealed || !format!("{}", msg).contains("Secret"));
+        match msg.open(&self.tree_keys) {
+            Ok(body) => assert_eq!(body, "Secret"),
+            Err(e) => {
+                assert!(self.expected.starts_with(e.to_string().split(' ').next().unwrap_or_default()), "{}", e);
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+test_result!(test_payload_sealed, PayloadSealing::new_same_tree());
+test_error!(test_payload_no_key, PayloadSealing::new_other_tree(), "AppMessageError::NoTreeKey");
+test_error!(test_payload_wrong_key, PayloadSealing::new_wrong_key(), "TreeKeyError::Open");
+test_error!(test_payload_unsealed, PayloadSealing::new_unsealed(), "AppMessageError::Unsealed");
+
+struct TreeKeyWrapping {
+    is_impostor: bool, // Opened by a cell claiming to be C:1 without C:1's secret key
+}
+
+impl Test for TreeKeyWrapping {
+    // Cell 0 deploys a manifest with its tree keys wrapped for each cell, and only cell 1 can open cell 1's
+    fn test(&mut self) {
+        let mut tree_keys = TreeKeys::new();
+        tree_keys.insert(S("Sealed"), TreeKey::new());
+        let wrapped = MessageAuthentication::authenticator(0).wrap_tree_keys(&tree_keys).unwrap();
+        assert_eq!(wrapped.get_cell_names().len(), 3);
+        let tree = AllowedTree::new("Sealed");
+        let msg = AppInterapplicationMsg::new_sealed("Test", false, false, &tree, AppMsgDirection::Leafward,
+                                                     &vec![], "Secret", &tree_keys["Sealed"]).unwrap();
+        let opener = if self.is_impostor {
+            let mut auth_keys = HashMap::new();
+            auth_keys.insert(CellNo(0), AuthKey::from_secret(&format!("{:064x}", 1)).unwrap().without_secret());
+            auth_keys.insert(CellNo(1), AuthKey::from_secret(&format!("{:064x}", 99)).unwrap());
+            Authenticator::from_keys(CellID::new("C:1").unwrap(), &auth_keys).unwrap()
+        } else {
+            MessageAuthentication::authenticator(1)
+        };
+        match opener.open_tree_keys(&wrapped) {
+            Ok(opened) => assert_eq!(msg.open(&opened).unwrap(), "Secret"),
+            Err(e) => {
+                assert!(e.to_string().starts_with("TreeKeyError::Open"), "{}", e);
+                panic!("{}", e);
+            }
+        }
+    }
+}
+
+test_result!(test_tree_keys_wrapped, TreeKeyWrapping { is_impostor: false });
+test_error!(test_tree_keys_impostor, TreeKeyWrapping { is_impostor: true }, "TreeKeyError::Open");
+
+struct WireEncoding {
+    version: u8,
+}
+
+impl WireEncoding {
+    fn new_current() -> WireEncoding { WireEncoding { version: WIRE_VERSION } }
+    fn new_unknown_version() -> WireEncoding { WireEncoding { version: WIRE_VERSION + 1 } }
+}
+
+impl Test for WireEncoding {
+    // Binary is smaller than JSON, and either form decodes to the same message
+    fn test(&mut self) {
+        let tree = AllowedTree::new("Wire");
+        let msg = AppInterapplicationMsg::new("Test", false, false, &tree, AppMsgDirection::Leafward, &vec![], "Hello");
+        let json = wire::encode_as(WireFormat::Json, &msg as &dyn AppMessage).unwrap();
+        let mut binary = wire::encode_as(WireFormat::Binary, &msg as &dyn AppMessage).unwrap().get_bytes().clone();
+        binary[1] = self.version;
+        let binary = ByteArray::new_from_bytes(&binary);
+        assert!(binary.len() < json.len());
+        assert_eq!(wire::format_of(&binary).unwrap(), WireFormat::Binary);
+        let decoded: Box<dyn AppMessage> = wire::decode(&binary).unwrap();
+        assert_eq!(decoded.get_msg_type(), AppMsgType::AppInterapplicationMsg);
+        let from_json: serde_json::Value = serde_json::from_str(&wire::to_json(&json).unwrap()).unwrap();
+        let from_binary: serde_json::Value = serde_json::from_str(&wire::to_json(&binary).unwrap()).unwrap();
+        assert_eq!(from_json, from_binary);
+        // Uuids are byte strings in the binary form, but traces still show them as in JSON
+        let discover = MessageAuthentication::discover(1);
+        let json = wire::encode_as(WireFormat::Json, &discover as &dyn Message).unwrap();
+        let binary = wire::encode_as(WireFormat::Binary, &discover as &dyn Message).unwrap();
+        assert_eq!(wire::to_json(&binary).unwrap(), wire::to_json(&json).unwrap());
+    }
+}
+
+test_result!(test_wire_binary, WireEncoding::new_current());
+test_error!(test_wire_unknown_version, WireEncoding::new_unknown_version(), "WireError::Version");
+
+struct PacketFragments {
+    packet_sizes: Vec<usize>, // Of the links along the path
+}
+
+impl PacketFragments {
+    fn new(packet_sizes: Vec<usize>) -> PacketFragments { PacketFragments { packet_sizes } }
+}
+
+impl Test for PacketFragments {
+    // A message crossing links with different packet sizes arrives intact and is acked once per packet
+    fn test(&mut self) {
+        let msg = ByteArray::new(&"Fragment me ".repeat(100));
+        let mut uuid = Uuid::new();
+        uuid.make_snake();
+        let packets = Packetizer::packetize(&uuid, SenderMsgSeqNo(0), &msg, PACKET_MAX).unwrap();
+        let snakes = packets.iter().filter(|packet| packet.is_snake()).count();
+        let received = self.packet_sizes.iter().fold(packets, |packets, &packet_size| {
+            packets.iter().flat_map(|packet| packet.fragment(packet_size)).collect()
+        });
+        assert!(received.iter().all(|packet| packet.get_frame_len() <= *self.packet_sizes.iter().min().unwrap()));
+        assert_eq!(received.iter().filter(|packet| packet.is_snake()).count(), snakes);
+        assert_eq!(received.iter().filter(|packet| packet.is_last_packet()).count(), 1);
+        assert_eq!(Packetizer::unpacketize(&received).unwrap().get_bytes(), msg.get_bytes());
+    }
+}
+
+test_result!(test_packet_fragments, PacketFragments::new(vec![9000, 256, 1500, 100]));
+
+struct FlowControlStress {
+    num_ports: u8,
+    num_packets: usize, // Sent on each tree
+}
+
+impl FlowControlStress {
+    fn new(num_ports: u8, num_packets: usize) -> FlowControlStress { FlowControlStress { num_ports, num_packets } }
+    fn tree(&self, name: &str, parent: u8, children: &[u8]) -> (Packet, RoutingTableEntry) {
+        let no_ports = PortQty(self.num_ports + 1); // Port 0 too
+        let port_tree_id = TreeID::new(name).unwrap().to_port_tree_id_0();
+        let children = children.iter().map(|&port_no| PortNumber::new(PortNo(port_no), no_ports).unwrap()).collect::<HashSet<_>>();
+        let parent = PortNumber::new(PortNo(parent), no_ports).unwrap();
+        let entry = RoutingTableEntry::new(port_tree_id, true, parent, Mask::make(&children), true);
+        let packets = Packetizer::packetize(&port_tree_id.get_uuid(), SenderMsgSeqNo(0), &ByteArray::new(name), PACKET_MAX).unwrap();
+        (packets[0].clone(), entry)
+    }
+}
+
+impl Test for FlowControlStress {
+    // Every port is slow and gets more packets than it has credits for, from the cell agent and
+    // from a neighbor at the same time.  All of them must get through.
+    fn test(&mut self) {
+        let (pe_to_cm, _cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let (port_to_pe, pe_from_ports) = channel();
+        let port_nos = (1..=self.num_ports).collect::<Vec<_>>();
+        let mut pe_to_ports = HashMap::new();
+        let mut ports_from_pe = HashMap::new();
+        for &port_no in &port_nos {
+            let (pe_to_port, port_from_pe) = channel();
+            pe_to_ports.insert(PortNo(port_no), pe_to_port);
+            ports_from_pe.insert(port_no, port_from_pe);
+        }
+        let cell_id = CellID::new("C:0").unwrap();
+        let packet_engine = PacketEngine::new(cell_id, TreeID::new("C:0").unwrap(), pe_to_cm, pe_to_ports, &HashSet::new());
+        packet_engine.start(pe_from_cm, pe_from_ports);
+        // One tree from the cell agent to every port, one from port 1 to the others
+        let (from_cm, cm_entry) = self.tree("FromCm", 0, &port_nos);
+        let (from_port, port_entry) = self.tree("FromPort", 1, &port_nos[1..]);
+        cm_to_pe.send(CmToPePacket::Entry(cm_entry)).unwrap();
+        cm_to_pe.send(CmToPePacket::Entry(port_entry)).unwrap();
+        // Wait until the packet engine has the entries, since packets from ports can get ahead of them
+        let port1 = Mask::new(PortNumber::new(PortNo(1), PortQty(self.num_ports + 1)).unwrap());
+        cm_to_pe.send(CmToPePacket::Packet((port1, from_cm.clone()))).unwrap();
+        match ports_from_pe[&1].recv_timeout(Duration::from_secs(5)) {
+            Ok(PeToPortPacket::Packet((outbuf_type, _))) => port_to_pe.send(PortToPePacket::Increment((PortNo(1), outbuf_type))).unwrap(),
+            _ => panic!("No packet from the packet engine")
+        }
+        let ports = port_nos.iter().map(|&port_no| {
+            let port_from_pe = ports_from_pe.remove(&port_no).unwrap();
+            let port_to_pe = port_to_pe.clone();
+            let expected = if port_no == 1 { self.num_packets } else { 2*self.num_packets };
+            thread::spawn(move || -> (usize, usize) {
+                let (mut received, mut most_held) = (0, 0);
+                let mut held = Vec::new();
+                while received < expected {
+                    match port_from_pe.recv_timeout(Duration::from_secs(30)) {
+                        Ok(PeToPortPacket::Packet((outbuf_type, _))) => { received += 1; held.push(outbuf_type); },
+                        Ok(_) => (), // Activity, or credits for the packets from port 1
+                        Err(_) => break // Deadlock or lost packets
+                    }
+                    most_held = std::cmp::max(most_held, held.len());
+                    // Slow link: return credits in batches
+                    if held.len() == NUM_SLOTS || received == expected {
+                        thread::sleep(Duration::from_millis(1));
+                        for outbuf_type in held.drain(..) {
+                            port_to_pe.send(PortToPePacket::Increment((PortNo(port_no), outbuf_type))).unwrap();
+                        }
+                    }
+                }
+                (received, most_held)
+            })
+        }).collect::<Vec<_>>();
+        for _ in 0..self.num_packets {
+            cm_to_pe.send(CmToPePacket::Packet((Mask::all_but_zero(PortQty(self.num_ports + 1)), from_cm.clone()))).unwrap();
+            port_to_pe.send(PortToPePacket::Packet((PortNo(1), from_port.clone()))).unwrap();
+        }
+        for (port_no, port) in port_nos.iter().zip(ports) {
+            let (received, most_held) = port.join().unwrap();
+            let expected = if *port_no == 1 { self.num_packets } else { 2*self.num_packets };
+            assert_eq!(received, expected, "port {}", port_no);
+            assert!(most_held <= NUM_SLOTS, "port {} held {}", port_no, most_held);
+        }
+    }
+}
+
+test_result!(test_flow_control_stress, FlowControlStress::new(8, 200));
+
+struct TrafficClasses {
+    weight: usize, // Of the heavy tree; the light one gets 1
+    num_packets: usize, // Flooded on each tree
+}
+
+impl TrafficClasses {
+    fn new(weight: usize, num_packets: usize) -> TrafficClasses { TrafficClasses { weight, num_packets } }
+    fn tree(name: &str, is_control: bool) -> (Packet, RoutingTableEntry) {
+        let no_ports = PortQty(1);
+        let port_tree_id = TreeID::new(name).unwrap().to_port_tree_id_0();
+        let parent = PortNumber::new(PortNo(0), no_ports).unwrap();
+        let child = PortNumber::new(PortNo(1), no_ports).unwrap();
+        let entry = RoutingTableEntry::new(port_tree_id, true, parent, Mask::new(child), true);
+        let mut uuid = port_tree_id.get_uuid();
+        if is_control { uuid.make_control(); }
+        let packets = Packetizer::packetize(&uuid, SenderMsgSeqNo(0), &ByteArray::new(name), PACKET_MAX).unwrap();
+        (packets[0].clone(), entry)
+    }
+}
+
+impl Test for TrafficClasses {
+    // A Discover gets past a flood of application packets, and the flooding trees share the
+    // port in proportion to their weights
+    fn test(&mut self) {
+        let (pe_to_cm, _cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let (port_to_pe, pe_from_ports) = channel();
+        let (pe_to_port, port_from_pe) = channel();
+        let pe_to_ports = vec![(PortNo(1), pe_to_port)].into_iter().collect();
+        let cell_id = CellID::new("C:0").unwrap();
+        let packet_engine = PacketEngine::new(cell_id, TreeID::new("C:0").unwrap(), pe_to_cm, pe_to_ports, &HashSet::new());
+        packet_engine.start(pe_from_cm, pe_from_ports);
+        let (heavy, heavy_entry) = TrafficClasses::tree("Heavy", false);
+        let (light, light_entry) = TrafficClasses::tree("Light", false);
+        let (discover, control_entry) = TrafficClasses::tree("Control", true);
+        let heavy_uuid = heavy.get_tree_uuid().for_lookup();
+        for entry in vec![heavy_entry, light_entry, control_entry] {
+            cm_to_pe.send(CmToPePacket::Entry(entry)).unwrap();
+        }
+        cm_to_pe.send(CmToPePacket::TreeWeight((heavy_uuid, self.weight))).unwrap();
+        // The port returns no credits until the flood is queued
+        let port1 = Mask::new(PortNumber::new(PortNo(1), PortQty(1)).unwrap());
+        for _ in 0..self.num_packets {
+            cm_to_pe.send(CmToPePacket::Packet((port1, heavy.clone()))).unwrap();
+            cm_to_pe.send(CmToPePacket::Packet((port1, light.clone()))).unwrap();
+        }
+        cm_to_pe.send(CmToPePacket::Packet((port1, discover))).unwrap();
+        let recv = || loop {
+            match port_from_pe.recv_timeout(Duration::from_secs(5)) {
+                Ok(PeToPortPacket::Packet((outbuf_type, packet))) => return (outbuf_type, packet.get_tree_uuid().for_lookup()),
+                Ok(_) => (),
+                Err(_) => panic!("No packet from the packet engine")
+            }
+        };
+        // The classes share the credits, so the Discover waits for the first one to come back
+        let first = (0..NUM_SLOTS).map(|_| recv()).collect::<Vec<_>>();
+        assert!(first.iter().all(|(outbuf_type, _)| *outbuf_type == OutbufType::Message));
+        thread::sleep(Duration::from_millis(100)); // Until the Discover is queued
+        port_to_pe.send(PortToPePacket::Increment((PortNo(1), OutbufType::Message))).unwrap();
+        assert_eq!(recv().0, OutbufType::Control);
+        let rounds = 10;
+        let mut no_heavy = 0;
+        for _ in 0..rounds*(self.weight + 1) {
+            port_to_pe.send(PortToPePacket::Increment((PortNo(1), OutbufType::Message))).unwrap();
+            let (outbuf_type, uuid) = recv();
+            assert_eq!(outbuf_type, OutbufType::Message);
+            if uuid == heavy_uuid { no_heavy += 1; }
+        }
+        assert_eq!(no_heavy, rounds*self.weight);
+    }
+}
+
+test_result!(test_traffic_classes, TrafficClasses::new(3, 100));
+
+struct CongestedLink {
+    num_control: usize, // Queued behind the flood
+    num_packets: usize, // Flooded on the link
+}
+
+impl Test for CongestedLink {
+    // The port never holds more packets than it has credits for, whatever their class, and each
+    // credit it returns goes to a control packet until there are none left
+    fn test(&mut self) {
+        let (pe_to_cm, _cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let (port_to_pe, pe_from_ports) = channel();
+        let (pe_to_port, port_from_pe) = channel();
+        let pe_to_ports = vec![(PortNo(1), pe_to_port)].into_iter().collect();
+        let cell_id = CellID::new("C:0").unwrap();
+        let packet_engine = PacketEngine::new(cell_id, TreeID::new("C:0").unwrap(), pe_to_cm, pe_to_ports, &HashSet::new());
+        packet_engine.start(pe_from_cm, pe_from_ports);
+        let (flood, flood_entry) = TrafficClasses::tree("Flood", false);
+        let (discover, control_entry) = TrafficClasses::tree("Control", true);
+        cm_to_pe.send(CmToPePacket::Entry(flood_entry)).unwrap();
+        cm_to_pe.send(CmToPePacket::Entry(control_entry)).unwrap();
+        let port1 = Mask::new(PortNumber::new(PortNo(1), PortQty(1)).unwrap());
+        for _ in 0..self.num_packets {
+            cm_to_pe.send(CmToPePacket::Packet((port1, flood.clone()))).unwrap();
+        }
+        let recv = |timeout| loop {
+            match port_from_pe.recv_timeout(timeout) {
+                Ok(PeToPortPacket::Packet((outbuf_type, _))) => return Some(outbuf_type),
+                Ok(_) => (),
+                Err(_) => return None
+            }
+        };
+        for _ in 0..NUM_SLOTS {
+            assert_eq!(recv(Duration::from_secs(5)), Some(OutbufType::Message));
+        }
+        for _ in 0..self.num_control {
+            cm_to_pe.send(CmToPePacket::Packet((port1, discover.clone()))).unwrap();
+        }
+        assert_eq!(recv(Duration::from_millis(100)), None, "Sent without a credit");
+        for i in 0..self.num_control + NUM_SLOTS {
+            port_to_pe.send(PortToPePacket::Increment((PortNo(1), OutbufType::Message))).unwrap();
+            let expected = if i < self.num_control { OutbufType::Control } else { OutbufType::Message };
+            assert_eq!(recv(Duration::from_secs(5)), Some(expected), "credit {}", i);
+        }
+        assert_eq!(recv(Duration::from_millis(100)), None, "Sent without a credit");
+    }
+}
+
+test_result!(test_congested_link, CongestedLink { num_control: 3, num_packets: 100 });
+
+struct PacketCounters {}
+
+impl Test for PacketCounters {
+    // A packet from the cell agent goes out port 1, and the tree's reply comes back to the cell agent
+    fn test(&mut self) {
+        let (pe_to_cm, cm_from_pe) = channel();
+        let (cm_to_pe, pe_from_cm) = channel();
+        let (port_to_pe, pe_from_ports) = channel();
+        let (pe_to_port, port_from_pe) = channel();
+        let pe_to_ports = vec![(PortNo(1), pe_to_port)].into_iter().collect();
+        let cell_id = CellID::new("C:0").unwrap();
+        let packet_engine = PacketEngine::new(cell_id, TreeID::new("C:0").unwrap(), pe_to_cm, pe_to_ports, &HashSet::new());
+        packet_engine.start(pe_from_cm, pe_from_ports);
+        let (packet, entry) = TrafficClasses::tree("Counted", false);
+        let uuid = packet.get_tree_uuid().for_lookup();
+        cm_to_pe.send(CmToPePacket::Entry(entry)).unwrap();
+        cm_to_pe.send(CmToPePacket::Packet((entry.get_mask(), packet.clone()))).unwrap();
+        match port_from_pe.recv_timeout(Duration::from_secs(30)) {
+            Ok(PeToPortPacket::Packet(_)) => port_to_pe.send(PortToPePacket::Packet((PortNo(1), packet.clone()))).unwrap(),
+            _ => panic!("No packet from the packet engine")
+        }
+        cm_from_pe.recv_timeout(Duration::from_secs(30)).expect("No packet for the cell agent");
+        let counters = packet_engine.get_counters();
+        let port = counters.get_port(PortNo(1)).unwrap();
+        assert_eq!((port.get_packets_out(), port.get_packets_in()), (1, 1));
+        assert_eq!(port.get_bytes_out(), packet.get_frame_len());
+        assert_eq!(port.get_drops(), 0);
+        let tree = counters.get_tree(&uuid).unwrap();
+        assert_eq!((tree.get_forwarded(), tree.get_to_cm()), (1, 1));
+    }
+}
+
+test_result!(test_packet_counters, PacketCounters {});
+
+struct MetricsText {}
+
+impl Test for MetricsText {
+    // One family per metric, with a sample for each cell
+    fn test(&mut self) {
+        let cell_id = CellID::new("C:0").unwrap();
+        let cell_metrics = Arc::new(Mutex::new(CellMetrics::default()));
+        cell_metrics.lock().unwrap().msg_sent(MsgType::Discover);
+        cell_metrics.lock().unwrap().failover_started();
+        let counters = Arc::new(Mutex::new(PacketEngineCounters::new(2)));
+        let (packet, _) = TrafficClasses::tree("Metered", false);
+        counters.lock().unwrap().packet_out(PortNo(1), &packet);
+        let text = metrics::render(&[MetricsSource::new(cell_id, cell_metrics, counters)]).unwrap();
+        assert!(text.contains("ec_messages_sent_total{cell=\"C:0\",msg_type=\"Discover\"} 1"));
+        assert!(text.contains("ec_failovers_total{cell=\"C:0\",outcome=\"started\"} 1"));
+        assert!(text.contains("ec_port_packets_total{cell=\"C:0\",port=\"1\",direction=\"out\"} 1"));
+        assert_eq!(text.lines().filter(|line| line.starts_with("# TYPE")).count(), 14);
+    }
+}
+
+test_result!(test_metrics_text, MetricsText {});
+
+// A connection that reads the request and keeps what the server writes back
+struct HttpExchange {
+    request: std::io::Cursor<Vec<u8>>,
+    response: Vec<u8>,
+}
+
+impl std::io::Read for HttpExchange {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.request.read(buf) }
+}
+
+impl std::io::Write for HttpExchange {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.response.write(buf) }
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+struct MetricsHttp {
+    request: String,
+    is_local: bool, // Whether the client is on this machine
+    expected: &'static str, // Status line of the response
+}
+
+impl MetricsHttp {
+    fn new(method: &str, path: &str, body_len: usize, is_local: bool, expected: &'static str) -> MetricsHttp {
+        let request = format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, path, body_len, " ".repeat(body_len));
+        MetricsHttp { request, is_local, expected }
+    }
+}
+
+impl Test for MetricsHttp {
+    // Only the methods a path takes get through, only local clients change the trace filters, and
+    // an oversized body is turned away unread
+    fn test(&mut self) {
+        let mut exchange = HttpExchange { request: std::io::Cursor::new(self.request.clone().into_bytes()), response: Vec::new() };
+        metrics::respond(&mut exchange, self.is_local, &[]).unwrap();
+        let response = String::from_utf8(exchange.response).unwrap();
+        assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", self.expected)), "{}", response);
+        assert_eq!(response.contains("Allow: "), self.expected.starts_with("405"));
+    }
+}
+
+test_result!(test_metrics_get, MetricsHttp::new("GET", "/metrics", 0, false, "200 OK"));
+test_result!(test_metrics_post, MetricsHttp::new("POST", "/metrics", 0, true, "405 Method Not Allowed"));
+test_result!(test_trace_filters_delete, MetricsHttp::new("DELETE", "/trace_filters", 0, true, "405 Method Not Allowed"));
+test_result!(test_trace_filters_too_big, MetricsHttp::new("PUT", "/trace_filters", 1 << 20, true, "413 Payload Too Large"));
+test_result!(test_trace_filters_remote_get, MetricsHttp::new("GET", "/trace_filters", 0, false, "200 OK"));
+test_result!(test_trace_filters_remote_put, MetricsHttp::new("PUT", "/trace_filters", 0, false, "403 Forbidden"));
+
+struct TraceRotation {}
+
+impl Test for TraceRotation {
+    // Records land in the file for all cells and the cell's own file, which rotate when full
+    // and start again with the file header
+    fn test(&mut self) {
+        let dir_name = format!("{}/trace_rotation/", std::env::temp_dir().display());
+        let _ = std::fs::remove_dir_all(&dir_name);
+        std::fs::create_dir_all(&dir_name).unwrap();
+        let mut sink = FileSink::new(&dir_name, "trace", "{\"schema_version\":\"test\"}", Some(Rotation { max_bytes: 100, max_files: 2 }));
+        let line = "x".repeat(48);
+        for _ in 0..7 { sink.write_record("C:0", "test", &line).unwrap(); }
+        sink.flush().unwrap();
+        let cell_file_name = format!("{}trace-C-0.json", dir_name);
+        assert_eq!(std::fs::read_to_string(&cell_file_name).unwrap().lines().count(), 2);
+        assert_eq!(std::fs::read_to_string(format!("{}.1", cell_file_name)).unwrap().lines().count(), 3);
+        assert_eq!(std::fs::read_to_string(format!("{}.2", cell_file_name)).unwrap().lines().count(), 3);
+        assert!(!std::path::Path::new(&format!("{}.3", cell_file_name)).exists());
+        assert!(std::path::Path::new(&format!("{}/trace.json.2", dir_name)).exists());
+    }
+}
+
+test_result!(test_trace_rotation, TraceRotation {});
+
+struct RingBufferDump {}
+
+impl Test for RingBufferDump {
+    // The ring buffer keeps the latest records, and writing it out gives a trace file of them
+    fn test(&mut self) {
+        let file_name = format!("{}/ring_buffer.json", std::env::temp_dir().display());
+        let mut sink = RingBufferSink::new(3);
+        for n in 0..5 { sink.write_record("C:0", "test", &format!("{{\"n\":{}}}", n)).unwrap(); }
+        assert_eq!(trace_sink::write_ring_buffer(&file_name, "{\"schema_version\":\"test\"}").unwrap(), 3);
+        let lines = std::fs::read_to_string(&file_name).unwrap().lines().map(S).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["{\"schema_version\":\"test\"}", "{\"n\":2}", "{\"n\":3}", "{\"n\":4}"]);
+    }
+}
+
+test_result!(test_ring_buffer_dump, RingBufferDump {});
+
+struct SocketReconnect {}
+
+impl Test for SocketReconnect {
+    // A socket sink drops records while its listener is away and connects again once it's back
+    fn test(&mut self) {
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut sink = SocketSink::new(SocketAddress::Tcp(address.to_string()), "{\"schema_version\":\"test\"}");
+        assert!(sink.write_record("C:0", "test", "{\"n\":0}").is_err());
+        let listener = std::net::TcpListener::bind(address).unwrap();
+        assert!(sink.write_record("C:0", "test", "{\"n\":1}").is_ok());
+        thread::sleep(trace_sink::RECONNECT_DELAY);
+        sink.write_record("C:0", "test", "{\"n\":2}").unwrap();
+        sink.flush().unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let lines = std::io::BufReader::new(stream).lines().take(2).map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["{\"schema_version\":\"test\"}", "{\"n\":2}"]);
+    }
+}
+
+test_result!(test_socket_reconnect, SocketReconnect {});
+
+#[derive(Debug, Deserialize)]
+struct BorderCellBody { cell_no: CellNo }
+
+struct TraceReading {}
+
+impl Test for TraceReading {
+    // What the file sink writes, the reader gives back as typed records
+    fn test(&mut self) {
+        let dir_name = format!("{}/trace_reading/", std::env::temp_dir().display());
+        let _ = std::fs::remove_dir_all(&dir_name);
+        std::fs::create_dir_all(&dir_name).unwrap();
+        let file_header = TraceFileHeader::new("0123456789abcdef", 42);
+        let mut sink = FileSink::new(&dir_name, "trace", &serde_json::to_string(&file_header).unwrap(), None);
+        for (format, cell_no) in &[("border_cell", 3), ("ca_new", 4), ("border_cell", 5)] {
+            let mut record = json!({ "header": TraceHeader::new(), "body": { "cell_no": cell_no } });
+            record["header"]["format"] = json!(format);
+            sink.write_record("Rack", format, &serde_json::to_string(&record).unwrap()).unwrap();
+        }
+        sink.flush().unwrap();
+        let reader = TraceReader::open(format!("{}trace-Rack.json", dir_name)).unwrap();
+        assert_eq!(reader.get_file_header(), Some(&file_header));
+        let cell_nos = reader
+            .bodies::<BorderCellBody>("border_cell")
+            .map(|body| body.unwrap().cell_no)
+            .collect::<Vec<_>>();
+        assert_eq!(cell_nos, vec![CellNo(3), CellNo(5)]);
+        assert!(TraceReader::new("{\"header\": {}},\n".as_bytes()).is_err());
+    }
+}
+
+test_result!(test_trace_reading, TraceReading {});
+
+struct TraceFilters {}
+
+impl Test for TraceFilters {
+    // A filter takes records at its level or above, from its module or one under it, that match
+    // the cell, tree, and message type in the record body, even when the body has only the tree's UUID
+    fn test(&mut self) {
+        let filter = "debug module=pe cell=C:3 tree=Base".parse::<TraceFilter>().unwrap();
+        assert_eq!(filter.to_string(), "Debug module=pe cell=C:3 tree=Base");
+        let body = json!({ "cell_id": { "name": "C:3" }, "port_tree_id": { "name": "C:3+Base" } });
+        assert!(filter.matches(&TraceEvent::new(Level::Debug, &["pe_cm"]), &body));
+        assert!(filter.matches(&TraceEvent::new(Level::Info, &["ca", "pe"]), &body));
+        assert!(!filter.matches(&TraceEvent::new(Level::Debug, &["peer"]), &body));
+        assert!(!filter.matches(&TraceEvent::new(Level::Debug, &["ca"]), &body));
+        let other_cell = json!({ "cell_id": { "name": "C:4" }, "port_tree_id": { "name": "C:4+Base" } });
+        assert!(!filter.matches(&TraceEvent::new(Level::Debug, &["pe"]), &other_cell));
+        // A routing table entry has only the UUID of its tree
+        let tree_id = TreeID::new("C:3+Base").unwrap();
+        let entry = json!({ "cell_id": { "name": "C:3" }, "entry": { "tree_uuid": tree_id.get_uuid() } });
+        assert!(filter.matches(&TraceEvent::new(Level::Debug, &["pe"]), &entry));
+        let other_tree = json!({ "cell_id": { "name": "C:3" }, "entry": { "tree_uuid": TreeID::new("C:3+Other").unwrap().get_uuid() } });
+        assert!(!filter.matches(&TraceEvent::new(Level::Debug, &["pe"]), &other_tree));
+        let msg_filter = "warn msg_type=Discover".parse::<TraceFilter>().unwrap();
+        let msg = json!({ "msg": { "header": { "msg_type": "Discover" } } });
+        assert!(msg_filter.matches(&TraceEvent::new(Level::Error, &["ca"]), &msg));
+        assert!(!msg_filter.matches(&TraceEvent::new(Level::Info, &["ca"]), &msg));
+        assert!("verbose module=pe".parse::<TraceFilter>().is_err());
+        assert!("info module".parse::<TraceFilter>().is_err());
+    }
+}
+
+test_result!(test_trace_filters, TraceFilters {});
+
+struct CellReplayOutputs {}
+
+impl Test for CellReplayOutputs {
+    // Outputs match if they differ only in what changes from one run to the next
+    fn test(&mut self) {
+        let msg = |seq_no: u64, signature: &str, hops: u64| {
+            CellOutput::Msg(json!({ "header": { "sender_msg_seq_no": seq_no, "signature": signature, "msg_type": "Discover" },
+                                    "payload": { "tree_id": { "name": "C:0", "uuid": Uuid::new() }, "hops": hops } }))
+        };
+        let recorded = vec![msg(1, "ab", 1), msg(2, "cd", 2), msg(3, "ef", 2)];
+        let replayed = vec![msg(7, "12", 2), msg(8, "34", 1), msg(9, "56", 3)];
+        assert_eq!(unmatched(&recorded, &replayed[..2]).len(), 1);
+        let missing = unmatched(&recorded, &replayed);
+        let extra = unmatched(&replayed, &recorded);
+        assert_eq!((missing.len(), extra.len()), (1, 1));
+        assert!(extra[0].to_string().starts_with("Msg \"Discover\""));
+    }
+}
+
+test_result!(test_cell_replay_outputs, CellReplayOutputs {});
+
+struct CellReplayRun {}
+
+impl CellReplayRun {
+    fn write_trace(cell_name: &str, records: &[(&str, serde_json::Value)]) {
+        let file_name = get_cell_replay_file_name(cell_name);
+        std::fs::create_dir_all(std::path::Path::new(&file_name).parent().unwrap()).unwrap();
+        let mut lines = vec![serde_json::to_string(&TraceFileHeader::new(&CONFIG.config_hash, 0)).unwrap()];
+        for (format, body) in records {
+            let mut record = json!({ "header": TraceHeader::new(), "body": body });
+            record["header"]["format"] = json!(format);
+            lines.push(serde_json::to_string(&record).unwrap());
+        }
+        std::fs::write(&file_name, lines.join("\n")).unwrap();
+    }
+}
+impl Test for CellReplayRun {
+    // A cell agent replayed from its trace gets its inputs in the recorded order; run it once to
+    // record what it sends, and the second run sends the same
+    fn test(&mut self) {
+        let cell_name = "C:90";
+        let cell_id = CellID::new(cell_name).unwrap();
+        let my_tree_id = TreeID::new(cell_name).unwrap();
+        let status = |port: u8, status: LinkStatus| {
+            json!({ "cell_id": cell_id, "port": PortNo(port), "is_border": false, "no_packets": NumberOfPackets::new(), "status": status })
+        };
+        let mut records = vec![
+            ("ca_new", json!({ "cell_id": cell_id, "my_tree_id": my_tree_id,
+                               "control_tree_id": my_tree_id.add_component(CONTROL_TREE_NAME).unwrap(),
+                               "connected_tree_id": my_tree_id.add_component(CONNECTED_PORTS_TREE_NAME).unwrap(),
+                               "cell_config": CellConfig::Large, "cell_type": CellType::Interior,
+                               "no_ports": PortQty(4), "border_port_nos": Vec::<PortNo>::new() })),
+            ("ca_from_cm_status", status(1, LinkStatus::Connected)),
+            ("ca_from_cm_status", status(2, LinkStatus::Connected)),
+            ("ca_from_cm_status", status(1, LinkStatus::Disconnected)),
+        ];
+        CellReplayRun::write_trace(cell_name, &records);
+        let first = CellReplay::run(cell_name).unwrap();
+        assert!(first.get_recorded().is_empty());
+        assert!(!first.is_match());
+        for output in first.get_replayed() {
+            match output {
+                CellOutput::Entry(entry) => records.push(("ca_to_cm_entry", json!({ "cell_id": cell_id, "entry": entry }))),
+                CellOutput::Msg(msg) => records.push(("ca_to_cm_bytes", json!({ "cell_id": cell_id, "msg": msg }))),
+                CellOutput::Noc(_, _) => panic!("Interior cell sent to the NOC")
+            }
+        }
+        let hellos = first.get_replayed().iter().filter(|output| output.to_string().starts_with("Msg \"Hello\"")).count();
+        assert_eq!(hellos, 2);
+        CellReplayRun::write_trace(cell_name, &records);
+        let second = CellReplay::run(cell_name).unwrap();
+        assert!(second.is_match(), "{}", second);
+    }
+}
+
+test_result!(test_cell_replay_run, CellReplayRun {});
 
 // Errors
 use failure::{Error};
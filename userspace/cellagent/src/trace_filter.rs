@@ -0,0 +1,254 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Which trace records get written.  Each record has a level and the modules it belongs to,
+// e.g., "ca", "pe_cm", "discover", or "replay".  A filter on a module covers everything under
+// it, so "pe" covers "pe_cm" and "pe_port".
+use std::{fmt,
+          str::FromStr,
+          sync::{RwLock, atomic::{AtomicUsize, Ordering}}};
+
+use ec_trace_reader::tree_names::TreeNames;
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+use crate::config::CONFIG;
+use crate::utility::{S, TraceType};
+use crate::uuid_ec::Uuid;
+
+const MAX_TREE_NAMES: usize = 10_000; // Learned from records; the trees made here are always remembered
+
+lazy_static! {
+    static ref TRACE_FILTERS: RwLock<Vec<ActiveFilter>> = RwLock::new(active_filters(&CONFIG.trace_filters));
+    // Most verbose level of any filter, so most calls to tracing() don't need the lock
+    static ref MAX_LEVEL: AtomicUsize = AtomicUsize::new(max_level(&CONFIG.trace_filters));
+    // Many records name a tree only by its UUID, so remember the name of every tree made here
+    static ref TREE_NAMES: RwLock<TreeNames> = RwLock::new(TreeNames::default());
+}
+pub fn learn_tree_name(name: &str, uuid: &Uuid) {
+    if let (Ok(uuid), Ok(mut tree_names)) = (serde_json::to_value(uuid), TREE_NAMES.write()) {
+        tree_names.learn_name(name, &uuid);
+    }
+}
+fn max_level(trace_filters: &[TraceFilter]) -> usize {
+    trace_filters.iter().map(|filter| filter.level as usize + 1).max().unwrap_or(0)
+}
+fn active_filters(trace_filters: &[TraceFilter]) -> Vec<ActiveFilter> {
+    trace_filters.iter().map(ActiveFilter::new).collect()
+}
+pub fn get_trace_filters() -> Vec<TraceFilter> {
+    TRACE_FILTERS.read().map(|trace_filters| trace_filters.iter().map(|active| active.filter.clone()).collect()).unwrap_or_default()
+}
+// Takes effect for the next record on every thread
+pub fn set_trace_filters(trace_filters: Vec<TraceFilter>) {
+    if let Ok(mut filters) = TRACE_FILTERS.write() {
+        MAX_LEVEL.store(max_level(&trace_filters), Ordering::SeqCst);
+        *filters = active_filters(&trace_filters);
+    }
+}
+// Some if a filter wants records at this level from one of these modules, for any cell, tree,
+// or message type; add_to_trace() checks the rest against the record body
+pub fn tracing(level: Level, modules: &'static [&'static str]) -> Option<TraceEvent> {
+    if level as usize >= MAX_LEVEL.load(Ordering::SeqCst) { return None; }
+    let trace_filters = TRACE_FILTERS.read().ok()?;
+    if trace_filters.iter().any(|filter| filter.allows(level, modules)) {
+        Some(TraceEvent::new(level, modules))
+    } else {
+        None
+    }
+}
+pub fn is_tracing(level: Level, modules: &'static [&'static str]) -> bool { tracing(level, modules).is_some() }
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+impl Level {
+    pub fn get_trace_type(self) -> TraceType {
+        match self {
+            Level::Error => TraceType::Error,
+            Level::Warn  => TraceType::Warn,
+            Level::Info  => TraceType::Trace,
+            Level::Debug => TraceType::Debug,
+        }
+    }
+}
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Level::Error => "Error",
+            Level::Warn  => "Warn",
+            Level::Info  => "Info",
+            Level::Debug => "Debug",
+        };
+        write!(f, "{}", s)
+    }
+}
+impl FromStr for Level {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Level, Error> {
+        let _f = "from_str";
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn"  => Ok(Level::Warn),
+            "info"  => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(TraceFilterError::Level { func_name: _f, level: S(s) }.into())
+        }
+    }
+}
+#[derive(Debug, Copy, Clone)]
+pub struct TraceEvent {
+    level: Level,
+    modules: &'static [&'static str],
+    filtered: bool,
+}
+impl TraceEvent {
+    pub fn new(level: Level, modules: &'static [&'static str]) -> TraceEvent { TraceEvent { level, modules, filtered: true } }
+    // For the few records the visualizer and replay can't do without
+    pub fn unfiltered(level: Level) -> TraceEvent { TraceEvent { level, modules: &[], filtered: false } }
+    pub fn get_level(&self) -> Level { self.level }
+    pub fn get_trace_type(&self) -> TraceType { self.level.get_trace_type() }
+    pub fn accepts(&self, body: &Value) -> bool {
+        if !self.filtered { return true; }
+        TRACE_FILTERS
+            .read()
+            .map(|trace_filters| {
+                // Trees made on other cells come with their names in some records and not others
+                if trace_filters.iter().any(|filter| filter.filter.tree.is_some()) { learn_tree_names(body); }
+                trace_filters.iter().any(|filter| filter.matches(self, body))
+            })
+            .unwrap_or(false)
+    }
+}
+// Most records name no tree that isn't already known, so those only need the read lock
+fn learn_tree_names(body: &Value) {
+    let is_new = TREE_NAMES.read().map(|tree_names| tree_names.len() < MAX_TREE_NAMES && !tree_names.knows(body)).unwrap_or(false);
+    if is_new {
+        if let Ok(mut tree_names) = TREE_NAMES.write() { tree_names.learn(body); }
+    }
+}
+// Records at level or above that match every field that is present
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceFilter {
+    pub level: Level,
+    #[serde(default)]
+    pub module: Option<String>,
+    #[serde(default)]
+    pub cell: Option<String>, // Cell name, e.g., "C:3"
+    #[serde(default)]
+    pub tree: Option<String>, // Tree name, e.g., "C:3+Base", or any part of it, e.g., "Base", also for a record with only the tree's UUID
+    #[serde(default)]
+    pub msg_type: Option<String>, // e.g., "Discover"
+}
+impl TraceFilter {
+    pub fn new(level: Level) -> TraceFilter { TraceFilter { level, module: None, cell: None, tree: None, msg_type: None } }
+    pub fn matches(&self, event: &TraceEvent, body: &Value) -> bool { ActiveFilter::new(self).matches(event, body) }
+}
+// A filter as it is used, with the key for the modules under its module, e.g., "pe_" for "pe",
+// made once when the filters are set rather than for every record
+#[derive(Debug, Clone)]
+struct ActiveFilter {
+    filter: TraceFilter,
+    module_key: Option<String>
+}
+impl ActiveFilter {
+    fn new(filter: &TraceFilter) -> ActiveFilter {
+        ActiveFilter { filter: filter.clone(), module_key: filter.module.as_ref().map(|module| format!("{}_", module)) }
+    }
+    fn allows(&self, level: Level, modules: &[&str]) -> bool {
+        level <= self.filter.level &&
+            self.filter.module.as_ref().zip(self.module_key.as_ref()).map_or(true, |(module, module_key)| {
+                modules.iter().any(|name| *name == module.as_str() || name.starts_with(module_key.as_str()))
+            })
+    }
+    fn matches(&self, event: &TraceEvent, body: &Value) -> bool {
+        let filter = &self.filter;
+        self.allows(event.level, event.modules) &&
+            filter.cell.as_ref().map_or(true, |cell| {
+                body["cell_id"]["name"].as_str() == Some(cell.as_str())
+            }) &&
+            filter.tree.as_ref().map_or(true, |tree| {
+                let tree_names = match TREE_NAMES.read() { Ok(tree_names) => tree_names, Err(_) => return false };
+                let mut names = Vec::new();
+                tree_names_in(body, &tree_names, &mut names);
+                names.iter().any(|name| name == tree || name.split('+').any(|part| part == tree))
+            }) &&
+            filter.msg_type.as_ref().map_or(true, |msg_type| {
+                let found = body["msg"]["header"]["msg_type"].as_str().or_else(|| body["msg_type"].as_str());
+                found == Some(msg_type.as_str())
+            })
+    }
+}
+// The names of the trees a record is about, from a tree or port tree ID or, if all it has is the
+// UUID, the name of the tree with that UUID, e.g., the tree_uuid of a routing table entry
+fn tree_names_in(value: &Value, tree_names: &TreeNames, names: &mut Vec<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                if key.ends_with("tree_id") || key.ends_with("tree_uuid") {
+                    let name = value["name"].as_str().or_else(|| tree_names.get(&value["uuid"]).or_else(|| tree_names.get(value)));
+                    if let Some(name) = name { names.push(S(name)); }
+                } else {
+                    tree_names_in(value, tree_names, names);
+                }
+            }
+        },
+        Value::Array(values) => for value in values { tree_names_in(value, tree_names, names); },
+        _ => ()
+    }
+}
+// e.g., "debug cell=C:3 tree=Base"
+impl FromStr for TraceFilter {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<TraceFilter, Error> {
+        let _f = "from_str";
+        let mut words = s.split_whitespace();
+        let level = words
+            .next()
+            .ok_or::<Error>(TraceFilterError::Empty { func_name: _f }.into())?
+            .parse::<Level>()?;
+        let mut filter = TraceFilter::new(level);
+        for word in words {
+            let mut key_value = word.splitn(2, '=');
+            let (key, value) = match (key_value.next(), key_value.next()) {
+                (Some(key), Some(value)) => (key, S(value)),
+                _ => return Err(TraceFilterError::Field { func_name: _f, field: S(word) }.into())
+            };
+            match key {
+                "module"   => filter.module = Some(value),
+                "cell"     => filter.cell = Some(value),
+                "tree"     => filter.tree = Some(value),
+                "msg_type" => filter.msg_type = Some(value),
+                _ => return Err(TraceFilterError::Field { func_name: _f, field: S(word) }.into())
+            }
+        }
+        Ok(filter)
+    }
+}
+impl fmt::Display for TraceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("{}", self.level);
+        for (key, value) in &[("module", &self.module), ("cell", &self.cell), ("tree", &self.tree), ("msg_type", &self.msg_type)] {
+            if let Some(value) = value { s = s + &format!(" {}={}", key, value); }
+        }
+        write!(f, "{}", s)
+    }
+}
+
+// Errors
+use failure::{Error};
+
+#[derive(Debug, Fail)]
+pub enum TraceFilterError {
+    #[fail(display = "TraceFilterError::Empty {}: A filter starts with a level", func_name)]
+    Empty { func_name: &'static str },
+    #[fail(display = "TraceFilterError::Field {}: {} isn't module=, cell=, tree=, or msg_type=", func_name, field)]
+    Field { func_name: &'static str, field: String },
+    #[fail(display = "TraceFilterError::Level {}: {} isn't Error, Warn, Info, or Debug", func_name, level)]
+    Level { func_name: &'static str, level: String },
+}
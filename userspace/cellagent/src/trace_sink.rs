@@ -0,0 +1,342 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// The DAL formats each trace record once and hands it to every sink in the config.
+// Files and sockets get JSON Lines, starting with the file header.
+use std::{collections::{HashMap, VecDeque},
+          fs::{self, File, OpenOptions},
+          io::{BufWriter, Write},
+          net::TcpStream,
+          path::Path,
+          sync::Mutex,
+          thread,
+          time::{Duration, Instant}};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(feature="webserver")]
+use {
+    actix_web::client::{ClientBuilder},
+    futures::executor::block_on,
+    std::collections::HashSet
+};
+
+use crossbeam::crossbeam_channel as mpsc;
+use crossbeam::crossbeam_channel::unbounded as channel;
+use ec_trace_reader::TraceFileHeader;
+use lazy_static::lazy_static;
+
+use crate::utility::{S, write_err};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1); // Longest a record waits in a file buffer
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1); // Before a socket sink first tries its listener again
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60); // The delay doubles with each failed try up to this
+
+lazy_static! {
+    static ref RING_BUFFER: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+}
+// The records the ring buffer sink holds, oldest first
+pub fn get_ring_buffer() -> Vec<String> {
+    RING_BUFFER.lock().map(|ring_buffer| ring_buffer.iter().cloned().collect()).unwrap_or_default()
+}
+// Writes what the ring buffer sink holds after the file header, and returns how many records it wrote
+pub fn write_ring_buffer(file_name: &str, file_header: &str) -> Result<usize, Error> {
+    let _f = "write_ring_buffer";
+    let records = get_ring_buffer();
+    let mut file = BufWriter::new(File::create(file_name).context(TraceSinkError::Chain { func_name: _f, comment: S(file_name) })?);
+    file.write_all(file_header.as_bytes())?;
+    file.write_all(b"\n")?;
+    for record in &records {
+        file.write_all(record.as_bytes())?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    Ok(records.len())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TraceSinkConfig {
+    File, // One file for all cells and one for each cell; replay reads the cell files
+    RotatingFile { max_bytes: u64, max_files: usize }, // Keeps max_files old files of each name
+    RingBuffer { capacity: usize },
+    Tcp { address: String },
+    Unix { path: String },
+    Http { url: String }, // Needs the webserver feature
+}
+
+pub trait TraceSink: Send {
+    // line is one JSON trace record with no newline, cell_name is "Other" for records not about a cell
+    fn write_record(&mut self, cell_name: &str, format: &str, line: &str) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error> { Ok(()) }
+}
+pub fn make_trace_sink(config: &TraceSinkConfig, dir_name: &str, file_name: &str, file_header: &TraceFileHeader)
+        -> Result<Box<dyn TraceSink>, Error> {
+    let _f = "make_trace_sink";
+    let file_header = serde_json::to_string(file_header)?;
+    Ok(match config {
+        TraceSinkConfig::File => Box::new(FileSink::new(dir_name, file_name, &file_header, None)),
+        TraceSinkConfig::RotatingFile { max_bytes, max_files } =>
+            Box::new(FileSink::new(dir_name, file_name, &file_header, Some(Rotation { max_bytes: *max_bytes, max_files: *max_files }))),
+        TraceSinkConfig::RingBuffer { capacity } => Box::new(RingBufferSink::new(*capacity)),
+        TraceSinkConfig::Tcp { address } => Box::new(SocketSink::new(SocketAddress::Tcp(address.clone()), &file_header)),
+        #[cfg(unix)]
+        TraceSinkConfig::Unix { path } => Box::new(SocketSink::new(SocketAddress::Unix(path.clone()), &file_header)),
+        #[cfg(not(unix))]
+        TraceSinkConfig::Unix { .. } => return Err(TraceSinkError::Unsupported { func_name: _f, sink: S("Unix"), comment: S("needs a Unix system") }.into()),
+        #[cfg(feature="webserver")]
+        TraceSinkConfig::Http { url } => Box::new(HttpSink::new(url)),
+        #[cfg(not(feature="webserver"))]
+        TraceSinkConfig::Http { .. } => return Err(TraceSinkError::Unsupported { func_name: _f, sink: S("Http"), comment: S("needs the webserver feature") }.into()),
+    })
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+// Records are buffered; the file is opened once and kept open
+struct TraceFile {
+    name: String,
+    writer: BufWriter<File>,
+    size: u64,
+}
+impl TraceFile {
+    fn open(name: &str, file_header: &str) -> Result<TraceFile, Error> {
+        let _f = "open";
+        let file = OpenOptions::new().create(true).append(true).open(name).context(TraceSinkError::Chain { func_name: _f, comment: S(name) })?;
+        let size = file.metadata()?.len();
+        let mut trace_file = TraceFile { name: S(name), writer: BufWriter::new(file), size };
+        if size == 0 { trace_file.write_line(file_header)?; }
+        Ok(trace_file)
+    }
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+    fn write_record(&mut self, line: &str, file_header: &str, rotation: Option<Rotation>) -> Result<(), Error> {
+        if let Some(rotation) = rotation {
+            if self.size >= rotation.max_bytes { self.rotate(rotation.max_files, file_header)?; }
+        }
+        self.write_line(line)
+    }
+    // name becomes name.1, name.1 becomes name.2, and so on; the oldest is overwritten
+    fn rotate(&mut self, max_files: usize, file_header: &str) -> Result<(), Error> {
+        let _f = "rotate";
+        self.writer.flush()?;
+        for n in (1..max_files).rev() {
+            let from = format!("{}.{}", self.name, n);
+            if Path::new(&from).exists() { fs::rename(&from, format!("{}.{}", self.name, n + 1))?; }
+        }
+        if max_files > 0 { fs::rename(&self.name, format!("{}.1", self.name)).context(TraceSinkError::Chain { func_name: _f, comment: self.name.clone() })?; }
+        self.writer = BufWriter::new(File::create(&self.name)?);
+        self.size = 0;
+        self.write_line(file_header)
+    }
+}
+pub struct FileSink {
+    dir_name: String,
+    file_name: String,
+    file_header: String,
+    rotation: Option<Rotation>,
+    files: HashMap<String, TraceFile>, // By cell name, plus the file for all cells
+    last_flush: Instant,
+}
+impl FileSink {
+    pub fn new(dir_name: &str, file_name: &str, file_header: &str, rotation: Option<Rotation>) -> FileSink {
+        FileSink { dir_name: S(dir_name), file_name: S(file_name), file_header: S(file_header), rotation,
+                   files: HashMap::new(), last_flush: Instant::now() }
+    }
+    fn write_to(&mut self, key: &str, name: String, line: &str) -> Result<(), Error> {
+        if !self.files.contains_key(key) { self.files.insert(S(key), TraceFile::open(&name, &self.file_header)?); }
+        let file = self.files.get_mut(key).expect("FileSink: file must be open");
+        file.write_record(line, &self.file_header, self.rotation)
+    }
+}
+impl TraceSink for FileSink {
+    fn write_record(&mut self, cell_name: &str, _format: &str, line: &str) -> Result<(), Error> {
+        // Mac Finder replaces ":" with "/" which is obviously bad for filenames in the shell
+        let cell_file_name = format!("{}{}-{}.json", self.dir_name, self.file_name, str::replace(cell_name, ":", "-"));
+        self.write_to(cell_name, cell_file_name, line)?;
+        let output_file_name = format!("{}/{}.json", self.dir_name, self.file_name);
+        self.write_to("", output_file_name, line)?;
+        if self.last_flush.elapsed() > FLUSH_INTERVAL { self.flush()?; }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        for file in self.files.values_mut() { file.writer.flush()?; }
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+// Keeps the latest records in memory for get_ring_buffer()
+pub struct RingBufferSink {
+    capacity: usize,
+}
+impl RingBufferSink {
+    pub fn new(capacity: usize) -> RingBufferSink { RingBufferSink { capacity } }
+}
+impl TraceSink for RingBufferSink {
+    fn write_record(&mut self, _cell_name: &str, _format: &str, line: &str) -> Result<(), Error> {
+        let mut ring_buffer = RING_BUFFER.lock().map_err(|_| TraceSinkError::Poisoned { func_name: "write_record", sink: S("RingBuffer") })?;
+        if ring_buffer.len() == self.capacity { ring_buffer.pop_front(); }
+        if self.capacity > 0 { ring_buffer.push_back(S(line)); }
+        Ok(())
+    }
+}
+#[derive(Debug, Clone)]
+pub enum SocketAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(String),
+}
+// One record per line to a local listener, written on a thread of its own so a slow listener
+// doesn't slow down the trace calls.  If the listener isn't there, the sink drops records until
+// it's time to try again, so a trace server started after the cells still gets the rest.
+pub struct SocketSink {
+    address: SocketAddress,
+    file_header: String, // Sent first on each connection
+    writer: Option<mpsc::Sender<SocketLine>>,
+    retry_at: Option<Instant>, // Records are dropped until then
+    retry_delay: Option<Duration>, // Set while the listener is away
+}
+enum SocketLine {
+    Record(String),
+    Flush(mpsc::Sender<()>),
+}
+impl SocketSink {
+    pub fn new(address: SocketAddress, file_header: &str) -> SocketSink {
+        SocketSink { address, file_header: S(file_header), writer: None, retry_at: None, retry_delay: None }
+    }
+    // Only the first failure is reported, not each failed try after it
+    fn disconnected(&mut self, e: Error) -> Result<(), Error> {
+        let reported = self.retry_delay.is_none();
+        let retry_delay = self.retry_delay.map_or(RECONNECT_DELAY, |delay| (delay*2).min(MAX_RECONNECT_DELAY));
+        self.writer = None;
+        self.retry_delay = Some(retry_delay);
+        self.retry_at = Some(Instant::now() + retry_delay);
+        if reported { Err(e) } else { Ok(()) }
+    }
+    fn connect(&self) -> Result<BufWriter<Box<dyn Write + Send>>, Error> {
+        let _f = "connect";
+        let stream: Box<dyn Write + Send> = match &self.address {
+            SocketAddress::Tcp(address) => Box::new(TcpStream::connect(address).context(TraceSinkError::Chain { func_name: _f, comment: S(address) })?),
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => Box::new(UnixStream::connect(path).context(TraceSinkError::Chain { func_name: _f, comment: S(path) })?),
+        };
+        let mut connection = BufWriter::new(stream);
+        connection.write_all(self.file_header.as_bytes())?;
+        connection.write_all(b"\n")?;
+        Ok(connection)
+    }
+    // Flushes whenever it catches up; returns when the connection fails or the sink is dropped
+    fn write_lines(mut connection: BufWriter<Box<dyn Write + Send>>, lines: mpsc::Receiver<SocketLine>) -> Result<(), Error> {
+        for line in lines.iter() {
+            match line {
+                SocketLine::Record(line) => {
+                    connection.write_all(line.as_bytes())?;
+                    connection.write_all(b"\n")?;
+                    if lines.is_empty() { connection.flush()?; }
+                },
+                SocketLine::Flush(done) => {
+                    connection.flush()?;
+                    let _ = done.send(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+impl TraceSink for SocketSink {
+    fn write_record(&mut self, _cell_name: &str, _format: &str, line: &str) -> Result<(), Error> {
+        let _f = "write_record";
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at { return Ok(()); }
+            self.retry_at = None;
+        }
+        if self.writer.is_none() {
+            let connection = match self.connect() {
+                Ok(connection) => connection,
+                Err(e) => return self.disconnected(e)
+            };
+            let (writer, lines) = channel();
+            thread::Builder::new().name(S("TraceSocketSink")).spawn(move || {
+                let _ = SocketSink::write_lines(connection, lines).map_err(|e| write_err("SocketSink", &e));
+            })?;
+            self.writer = Some(writer);
+            self.retry_delay = None;
+        }
+        let writer = self.writer.as_ref().expect("SocketSink: writer must be set");
+        if writer.send(SocketLine::Record(S(line))).is_err() {
+            return self.disconnected(TraceSinkError::Closed { func_name: _f, sink: S("Socket") }.into());
+        }
+        Ok(())
+    }
+    // Waits a little while for the writer to catch up
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(writer) = self.writer.as_ref() {
+            let (done, flushed) = channel();
+            if writer.send(SocketLine::Flush(done)).is_ok() { let _ = flushed.recv_timeout(FLUSH_INTERVAL); }
+        }
+        Ok(())
+    }
+}
+// Posts each record to <url>/<format>; formats the server doesn't handle aren't sent again
+#[cfg(feature="webserver")]
+pub struct HttpSink {
+    url: String,
+    skip: HashSet<String>,
+}
+#[cfg(feature="webserver")]
+impl HttpSink {
+    pub fn new(url: &str) -> HttpSink { HttpSink { url: S(url), skip: HashSet::new() } }
+}
+#[cfg(feature="webserver")]
+impl TraceSink for HttpSink {
+    fn write_record(&mut self, _cell_name: &str, format: &str, line: &str) -> Result<(), Error> {
+        if self.skip.contains(format) { return Ok(()); }
+        let server_url = format!("{}/{}", self.url, format);
+        let body = S(line);
+        let skip = block_on(
+            async {
+                let client = ClientBuilder::new().disable_timeout().finish();
+                match client
+                        .post(&server_url)
+                        .header("Content-Type", "application/json")
+                        .send_body(body)
+                        .await {
+                    Ok(response) => {
+                        if !response.status().is_success() {
+                            if response.status() != 404 {
+                                println!("Error {}: {:?}", server_url, response);
+                            }
+                            true
+                        } else {
+                            false
+                        }
+                    },
+                    Err(_) => false
+                }
+            }
+        );
+        if skip { self.skip.insert(S(format)); }
+        Ok(())
+    }
+}
+
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum TraceSinkError {
+    #[fail(display = "TraceSinkError::Chain {}: {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "TraceSinkError::Closed {}: The {} sink stopped writing", func_name, sink)]
+    Closed { func_name: &'static str, sink: String },
+    #[fail(display = "TraceSinkError::Poisoned {}: Lock for the {} sink is poisoned", func_name, sink)]
+    Poisoned { func_name: &'static str, sink: String },
+    #[fail(display = "TraceSinkError::Unsupported {}: The {} sink {}", func_name, sink, comment)]
+    Unsupported { func_name: &'static str, sink: String, comment: String },
+}
@@ -0,0 +1,127 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{fmt, collections::HashMap,
+          sync::{Arc, Mutex}};
+
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, generic_array::GenericArray};
+
+use crate::name::CellID;
+use crate::utility::{ByteArray, S, from_hex, to_hex};
+
+pub type TreeKeys = HashMap<String, TreeKey>; // Keyed by tree name; must be String for serialization
+// One set of keys for all the cells of a rack, so a tree has the same key no matter which cell
+// the manifest naming it was sent to
+pub type SharedTreeKeys = Arc<Mutex<TreeKeys>>;
+
+// Containers that share a tree share its key, so they can exchange interapplication payloads
+// that the cell agents forwarding them, and the traces they write, can't read.  The cell agent
+// the manifest is sent to deploys the keys with the manifest, wrapped for each cell.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TreeKey(Vec<u8>);
+impl TreeKey {
+    pub fn new() -> TreeKey { TreeKey(rand::random::<[u8; 32]>().to_vec()) }
+    pub fn from_bytes(bytes: &[u8; 32]) -> TreeKey { TreeKey(bytes.to_vec()) }
+    pub fn seal(&self, plaintext: &str) -> Result<(ByteArray, Vec<u8>), Error> { self.seal_bytes(plaintext.as_bytes()) }
+    pub fn open(&self, ciphertext: &ByteArray, nonce: &[u8]) -> Result<String, Error> {
+        let _f = "open";
+        let plaintext = self.open_bytes(ciphertext.get_bytes(), nonce)?;
+        let plaintext = String::from_utf8(plaintext).map_err(|_| TreeKeyError::Open { func_name: _f })?;
+        Ok(plaintext)
+    }
+    fn seal_bytes(&self, plaintext: &[u8]) -> Result<(ByteArray, Vec<u8>), Error> {
+        let _f = "seal_bytes";
+        let nonce = rand::random::<[u8; 12]>().to_vec();
+        let ciphertext = self.cipher()
+            .encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .map_err(|_| TreeKeyError::Seal { func_name: _f })?;
+        Ok((ByteArray::new_from_bytes(&ciphertext), nonce))
+    }
+    fn open_bytes(&self, ciphertext: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Error> {
+        let _f = "open_bytes";
+        if nonce.len() != 12 { return Err(TreeKeyError::Open { func_name: _f }.into()); }
+        let plaintext = self.cipher()
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(|_| TreeKeyError::Open { func_name: _f })?;
+        Ok(plaintext)
+    }
+    // Nonce first, then the ciphertext
+    fn seal_hex(&self, plaintext: &[u8]) -> Result<String, Error> {
+        let (ciphertext, nonce) = self.seal_bytes(plaintext)?;
+        Ok(to_hex(&nonce) + &to_hex(&ciphertext.get_bytes()))
+    }
+    fn open_hex(&self, sealed: &str) -> Result<Vec<u8>, Error> {
+        let _f = "open_hex";
+        let sealed = from_hex(sealed).ok_or(TreeKeyError::Open { func_name: _f })?;
+        if sealed.len() < 12 { return Err(TreeKeyError::Open { func_name: _f }.into()); }
+        self.open_bytes(&sealed[12..], &sealed[..12])
+    }
+    fn cipher(&self) -> ChaCha20Poly1305 { ChaCha20Poly1305::new(GenericArray::clone_from_slice(&self.0)) }
+}
+impl Default for TreeKey {
+    fn default() -> TreeKey { TreeKey::new() }
+}
+// Keep keys out of debug output
+impl fmt::Debug for TreeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "TreeKey(..)") }
+}
+// The tree keys of a manifest, sealed with a key made just for them.  That key is wrapped for
+// each cell with a key only that cell and the one that wrapped it can make, so the cells
+// forwarding the manifest can't read the tree keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedTreeKeys {
+    wrapped_by: CellID,
+    tree_keys: String, // Hex, sealed with the sealing key
+    sealing_keys: HashMap<String, String>, // Hex, keyed by cell name
+}
+impl WrappedTreeKeys {
+    pub fn wrap(wrapped_by: CellID, tree_keys: &TreeKeys, wrapping_keys: &HashMap<String, TreeKey>) -> Result<WrappedTreeKeys, Error> {
+        let _f = "wrap";
+        let hex_keys = tree_keys
+            .iter()
+            .map(|(tree_name, tree_key)| (tree_name, to_hex(&tree_key.0)))
+            .collect::<HashMap<_, _>>();
+        let plaintext = serde_json::to_string(&hex_keys).context(TreeKeyError::Chain { func_name: _f, comment: S(wrapped_by) })?;
+        let sealing_key = TreeKey::new();
+        let sealed_tree_keys = sealing_key.seal_hex(plaintext.as_bytes()).context(TreeKeyError::Chain { func_name: _f, comment: S(wrapped_by) })?;
+        let mut sealing_keys = HashMap::new();
+        for (cell_name, wrapping_key) in wrapping_keys {
+            let sealed = wrapping_key.seal_hex(&sealing_key.0).context(TreeKeyError::Chain { func_name: _f, comment: S(cell_name) })?;
+            sealing_keys.insert(cell_name.clone(), sealed);
+        }
+        Ok(WrappedTreeKeys { wrapped_by, tree_keys: sealed_tree_keys, sealing_keys })
+    }
+    pub fn get_wrapped_by(&self) -> CellID { self.wrapped_by }
+    pub fn get_cell_names(&self) -> Vec<&String> { self.sealing_keys.keys().collect() }
+    pub fn open(&self, cell_name: &str, wrapping_key: &TreeKey) -> Result<TreeKeys, Error> {
+        let _f = "open";
+        let sealed = self.sealing_keys
+            .get(cell_name)
+            .ok_or(TreeKeyError::NotWrapped { func_name: _f, cell_name: S(cell_name) })?;
+        let sealing_key = TreeKey(wrapping_key.open_hex(sealed)?);
+        let plaintext = sealing_key.open_hex(&self.tree_keys)?;
+        let hex_keys: HashMap<String, String> = serde_json::from_slice(&plaintext).context(TreeKeyError::Chain { func_name: _f, comment: S(cell_name) })?;
+        hex_keys
+            .into_iter()
+            .map(|(tree_name, hex_key)| {
+                let bytes = from_hex(&hex_key).ok_or(TreeKeyError::Open { func_name: _f })?;
+                Ok((tree_name, TreeKey(bytes)))
+            })
+            .collect()
+    }
+}
+// Errors
+use failure::{Error, ResultExt};
+#[derive(Debug, Fail)]
+pub enum TreeKeyError {
+    #[fail(display = "TreeKeyError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "TreeKeyError::NotWrapped {}: No tree keys wrapped for cell {}", func_name, cell_name)]
+    NotWrapped { func_name: &'static str, cell_name: String },
+    #[fail(display = "TreeKeyError::Open {}: Payload can't be decrypted with this key", func_name)]
+    Open { func_name: &'static str },
+    #[fail(display = "TreeKeyError::Seal {}: Payload can't be encrypted", func_name)]
+    Seal { func_name: &'static str },
+}
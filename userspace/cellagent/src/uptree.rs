@@ -0,0 +1,82 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{fmt, fmt::Write};
+
+use crate::app_message::AppMsgDirection;
+use crate::name::UptreeID;
+use crate::uptree_spec::{AllowedTree, UpTreeSpec};
+use crate::utility::S;
+
+// An up-tree connects the members of a deployment, containers within a VM or VMs within a cell,
+// following the parent list of an UpTreeSpec.  Member i of the tree is members[i].  It never
+// reaches past its cell, so VMs of one manifest on different cells are on different up-trees.
+#[derive(Debug, Clone)]
+pub struct UpTree<T> {
+    id: UptreeID,
+    tree_name: AllowedTree,
+    spec: UpTreeSpec,
+    members: Vec<T>,
+}
+impl<T: Copy + Eq + fmt::Display> UpTree<T> {
+    pub fn new(id: UptreeID, spec: &UpTreeSpec, member_refs: &[T]) -> Result<UpTree<T>, Error> {
+        let _f = "new";
+        let tree_size = spec.get_tree_size();
+        if tree_size > member_refs.len() {
+            return Err(UpTreeError::Members { func_name: _f, id: S(spec.get_id()), needed: tree_size, available: member_refs.len() }.into());
+        }
+        let members = member_refs[..tree_size].to_vec();
+        Ok(UpTree { id, tree_name: AllowedTree::new(spec.get_id()), spec: spec.clone(), members })
+    }
+    pub fn get_id(&self) -> UptreeID { self.id }
+    pub fn get_tree_name(&self) -> &AllowedTree { &self.tree_name }
+    pub fn get_members(&self) -> &Vec<T> { &self.members }
+    pub fn contains(&self, member: T) -> bool { self.members.contains(&member) }
+    pub fn get_root(&self) -> T { self.members[self.spec.get_root()] }
+    fn index_of(&self, member: T) -> Result<usize, Error> {
+        let _f = "index_of";
+        self.members
+            .iter()
+            .position(|&m| m == member)
+            .ok_or(UpTreeError::NotMember { func_name: _f, id: self.id, member: S(member) }.into())
+    }
+    pub fn get_parent(&self, member: T) -> Result<Option<T>, Error> {
+        let index = self.index_of(member)?;
+        Ok(self.spec.get_parent(index).map(|parent| self.members[parent]))
+    }
+    pub fn get_children(&self, member: T) -> Result<Vec<T>, Error> {
+        let index = self.index_of(member)?;
+        Ok(self.spec.get_children(index)
+            .into_iter()
+            .map(|child| self.members[child])
+            .collect())
+    }
+    // Rootward messages go to my parent, leafward messages to my children
+    pub fn next_hops(&self, member: T, direction: AppMsgDirection) -> Result<Vec<T>, Error> {
+        Ok(match direction {
+            AppMsgDirection::Rootward => self.get_parent(member)?.into_iter().collect(),
+            AppMsgDirection::Leafward => self.get_children(member)?
+        })
+    }
+}
+impl<T: fmt::Display> fmt::Display for UpTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("UpTree {} {}", self.id, self.spec);
+        for (i, member) in self.members.iter().enumerate() {
+            write!(s, "\n  {}: {}", i, member)?;
+        }
+        write!(f, "{}", s)
+    }
+}
+// Errors
+use failure::{Error, Fail};
+#[derive(Debug, Fail)]
+pub enum UpTreeError {
+//    #[fail(display = "UpTreeError::Chain {} {}", func_name, comment)]
+//    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "UpTreeError::Members {}: Up tree {} needs {} members, but only {} are deployed", func_name, id, needed, available)]
+    Members { func_name: &'static str, id: String, needed: usize, available: usize },
+    #[fail(display = "UpTreeError::NotMember {}: {} is not a member of up tree {}", func_name, member, id)]
+    NotMember { func_name: &'static str, id: UptreeID, member: String },
+}
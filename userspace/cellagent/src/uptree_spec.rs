@@ -3,10 +3,22 @@
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 use std::{fmt, fmt::Write,
-          collections::HashSet};
+          collections::HashSet,
+          fs::File,
+          io::Read,
+          time::Duration};
 
+use crate::config::SCHEMA_VERSION;
+use crate::service::Service;
 use crate::utility::{CellConfig, S};
 
+// The on-disk form of a manifest, tagged with the schema version it was written for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestFile {
+    schema_version: String,
+    manifest: Manifest
+}
+
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub struct Manifest {
     id: String,
@@ -36,8 +48,57 @@ impl Manifest {
     }
     pub fn get_id(&self) -> &String { &self.id }
     pub fn get_deployment_tree(&self) -> &AllowedTree { &self.deployment_tree }
+    pub fn get_cell_config(&self) -> CellConfig { self.cell_config }
     pub fn get_allowed_trees(&self) -> &Vec<AllowedTree> { &self.allowed_trees }
     pub fn get_vms(&self) -> &Vec<VmSpec> { &self.vms }
+    pub fn get_trees(&self) -> &Vec<UpTreeSpec> { &self.trees }
+    pub fn from_file(file_name: &str) -> Result<Manifest, Error> {
+        let _f = "from_file";
+        let mut file = File::open(file_name).context(UptreeSpecError::Chain { func_name: _f, comment: S(file_name) })?;
+        let mut json = String::new();
+        file.read_to_string(&mut json).context(UptreeSpecError::Chain { func_name: _f, comment: S(file_name) })?;
+        Manifest::from_json(&json)
+    }
+    // Deserializing skips the checks in the constructors, so every manifest read from JSON is validated
+    pub fn from_json(json: &str) -> Result<Manifest, Error> {
+        let _f = "from_json";
+        let manifest_file: ManifestFile = serde_json::from_str(json).context(UptreeSpecError::Chain { func_name: _f, comment: S("parse") })?;
+        if manifest_file.schema_version != SCHEMA_VERSION {
+            return Err(UptreeSpecError::Version { func_name: _f, found: manifest_file.schema_version, expected: SCHEMA_VERSION }.into());
+        }
+        let manifest = manifest_file.manifest;
+        let errors = manifest.validate();
+        if !errors.is_empty() {
+            return Err(UptreeSpecError::Invalid { func_name: _f, id: manifest.id, errors: ManifestErrors(errors) }.into());
+        }
+        Ok(manifest)
+    }
+    pub fn to_json(&self) -> Result<String, Error> {
+        let manifest_file = ManifestFile { schema_version: S(SCHEMA_VERSION), manifest: self.clone() };
+        Ok(serde_json::to_string_pretty(&manifest_file)?)
+    }
+    // Report every problem rather than stopping at the first one
+    pub fn validate(&self) -> Vec<UptreeSpecError> {
+        let _f = "validate";
+        let mut errors = Vec::new();
+        for vm in &self.vms {
+            for tree in vm.get_allowed_trees() {
+                if !self.allowed_trees.contains(tree) {
+                    errors.push(UptreeSpecError::Allowed { func_name: _f, vm_id: vm.get_id().clone(), tree: tree.clone() });
+                }
+            }
+            errors.append(&mut vm.validate());
+        }
+        for tree in &self.trees {
+            if let Err(e) = UpTreeSpec::validate_parent_list(tree.get_id(), tree.get_parent_list()) {
+                errors.push(e);
+            }
+            if tree.get_tree_size() != self.vms.len() {
+                errors.push(UptreeSpecError::Vms { func_name: _f, id: tree.get_id().clone(), n_vms: self.vms.len() });
+            }
+        }
+        errors
+    }
 }
 impl fmt::Display for Manifest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -83,8 +144,34 @@ impl VmSpec {
                 allowed_trees: allowed_trees.clone(), containers, trees })
     }
     pub fn get_id(&self) -> &String { &self.id }
+    pub fn get_image(&self) -> &String { &self.image }
+    pub fn get_required_config(&self) -> CellConfig { self.required_config }
     pub fn get_allowed_trees(&self) -> &Vec<AllowedTree> { &self.allowed_trees }
     pub fn get_containers(&self) -> &Vec<ContainerSpec> { &self.containers }
+    pub fn get_trees(&self) -> &Vec<UpTreeSpec> { &self.trees }
+    fn validate(&self) -> Vec<UptreeSpecError> {
+        let _f = "VmSpec::validate";
+        let mut errors = Vec::new();
+        for container in &self.containers {
+            for tree in container.get_allowed_trees() {
+                if !self.allowed_trees.contains(tree) {
+                    errors.push(UptreeSpecError::Allowed { func_name: _f, vm_id: container.get_id(), tree: tree.clone() });
+                }
+            }
+            if !Service::is_image(container.get_image_name()) {
+                errors.push(UptreeSpecError::Image { func_name: _f, container_id: container.get_id(), image: container.get_image() });
+            }
+        }
+        for tree in &self.trees {
+            if let Err(e) = UpTreeSpec::validate_parent_list(tree.get_id(), tree.get_parent_list()) {
+                errors.push(e);
+            }
+            if tree.get_tree_size() != self.containers.len() {
+                errors.push(UptreeSpecError::Containers { func_name: _f, n_containers: self.containers.len() });
+            }
+        }
+        errors
+    }
 }
 impl fmt::Display for VmSpec {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -101,7 +188,9 @@ pub struct ContainerSpec {
     id: String,
     image: String,
     params: Vec<String>,
-    allowed_trees: Vec<AllowedTree>
+    allowed_trees: Vec<AllowedTree>,
+    #[serde(default)]
+    restart_policy: Option<RestartPolicy> // None leaves it to continue_on_error
 }
 impl ContainerSpec {
     pub fn new(id: &str, image: &str, param_refs: Vec<&str>, allowed_refs: &[AllowedTree]) -> Result<ContainerSpec, Error> {
@@ -109,22 +198,84 @@ impl ContainerSpec {
         for p in param_refs { params.push(S(p)); }
         let mut allowed_trees = Vec::new();
         for a in allowed_refs { allowed_trees.push(a.clone()); }
-        Ok(ContainerSpec { id: S(id), image: S(image), params, allowed_trees: allowed_trees.clone() })
+        Ok(ContainerSpec { id: S(id), image: S(image), params, allowed_trees: allowed_trees.clone(),
+            restart_policy: None })
+    }
+    pub fn with_restart_policy(&self, restart_policy: RestartPolicy) -> ContainerSpec {
+        ContainerSpec { restart_policy: Some(restart_policy), ..self.clone() }
     }
     pub fn get_id(&self) -> String { self.id.clone() }
     pub fn get_image(&self) -> String { self.image.clone() }
+    // Images may be tagged with a version, as in NocAgent:2, so a redeploy can name a new one
+    pub fn get_image_name(&self) -> &str { self.image.splitn(2, ':').next().unwrap_or("") }
+    pub fn get_image_version(&self) -> Option<&str> { self.image.splitn(2, ':').nth(1) }
     pub fn get_allowed_trees(&self) -> &Vec<AllowedTree> { &self.allowed_trees }
+    pub fn get_restart_policy(&self) -> Option<RestartPolicy> { self.restart_policy }
 }
 impl fmt::Display for ContainerSpec {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut s = format!(" Service {}({})", self.id, self.image);
         if self.params.is_empty() { write!(s, " No parameters")?; }
         else                      { write!(s, " Parameters: {:?}", self.params)?; }
+        if let Some(restart_policy) = self.restart_policy { write!(s, " Restart {}", restart_policy)?; }
         write!(s, "\n        Allowed Trees")?;
         for a in &self.allowed_trees { write!(s, "\n          {}", a)?; }
         write!(f, "{}", s)
     }
 }
+// What a container's VM does when the container fails.  An undeployed container is never restarted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure { max_retries: usize, backoff_ms: u64 }, // Backoff doubles with each retry
+    Always { backoff_ms: u64 }
+}
+impl RestartPolicy {
+    // How long to wait before restart number restarts + 1, or None to leave the container down
+    pub fn restart_delay(&self, restarts: usize) -> Option<Duration> {
+        match *self {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnFailure { max_retries, backoff_ms } => {
+                if restarts < max_retries { Some(Duration::from_millis(backoff_ms << restarts.min(16))) }
+                else                      { None }
+            },
+            RestartPolicy::Always { backoff_ms } => Some(Duration::from_millis(backoff_ms))
+        }
+    }
+    // After a container fails.  One with no policy of its own is restarted right away if
+    // continue_on_error is set; Never always leaves it down.
+    pub fn restart_delay_after_error(restart_policy: Option<RestartPolicy>, restarts: usize, continue_on_error: bool) -> Option<Duration> {
+        match restart_policy {
+            Some(restart_policy) => restart_policy.restart_delay(restarts),
+            None if continue_on_error => Some(Duration::from_millis(0)),
+            None => None
+        }
+    }
+}
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RestartPolicy::Never => write!(f, "Never"),
+            RestartPolicy::OnFailure { max_retries, backoff_ms } => write!(f, "OnFailure({} retries, {} ms backoff)", max_retries, backoff_ms),
+            RestartPolicy::Always { backoff_ms } => write!(f, "Always({} ms backoff)", backoff_ms)
+        }
+    }
+}
+// How a redeployed manifest replaces the one already running
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum DeployMode {
+    Replace, // Every cell stops the old version, then starts the new one
+    Rolling  // One tree hop at a time, starting the new version before stopping the old one
+}
+impl fmt::Display for DeployMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            DeployMode::Replace => "Replace",
+            DeployMode::Rolling => "Rolling"
+        };
+        write!(f, "{}", s)
+    }
+}
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub struct UpTreeSpec {
     id: String,
@@ -132,32 +283,62 @@ pub struct UpTreeSpec {
 }
 impl UpTreeSpec {
     pub fn new(id: &str, parent_list: Vec<usize>) -> Result<UpTreeSpec, Error> {
-        // Validate parent_list
+        UpTreeSpec::validate_parent_list(id, &parent_list)?;
+        Ok(UpTreeSpec { id: S(id), parent_list })
+    }
+    fn validate_parent_list(id: &str, parent_list: &[usize]) -> Result<(), UptreeSpecError> {
+        let _f = "UptreeSpec::validate_parent_list";
+        let parent_list = parent_list.to_vec();
         if parent_list.len() > 1 {
             let mut count = 0;
             let mut root = 0;
             for (i, &item) in parent_list.iter().enumerate() { if i == item { root = i; count = count + 1; } };
-            if count != 1 { return Err(UptreeSpecError::Tree { func_name: "UptreeSpec::new", id: S(id), parent_list, reason: "More than one root" }.into()); }
+            if count != 1 { return Err(UptreeSpecError::Tree { func_name: _f, id: S(id), parent_list, reason: "More than one root" }); }
             for p in parent_list.clone() {
                 //let mut reached_root = true;
                 let mut r = p;
                 let mut visited = HashSet::new();
                 while r != root {
-                    if visited.contains(&r) { return Err(UptreeSpecError::Tree { func_name: "UptreeSpec::new", id: S(id), parent_list, reason: "Cycle" }.into()); }
+                    if visited.contains(&r) { return Err(UptreeSpecError::Tree { func_name: _f, id: S(id), parent_list, reason: "Cycle" }); }
                     visited.insert(r);
                     match parent_list.clone().get(r) {
                         Some(p) => {
                             r = *p;
                             //if r == root { reached_root = true; } else { reached_root = false; }
                         },
-                        None => return Err(UptreeSpecError::Tree{ func_name: "UptreeSpec::new", id: S(id), parent_list, reason: "Index out of range" }.into())
+                        None => return Err(UptreeSpecError::Tree{ func_name: _f, id: S(id), parent_list, reason: "Index out of range" })
                     }
                 }
             }
         }
-        Ok(UpTreeSpec { id: S(id), parent_list })
+        Ok(())
+    }
+    pub fn get_id(&self) -> &String { &self.id }
+    pub fn get_parent_list(&self) -> &Vec<usize> { &self.parent_list }
+    pub fn get_tree_size(&self) -> usize { self.parent_list.len() }
+    // The root is the only element that is its own parent; a one element list is its own root
+    pub fn get_root(&self) -> usize {
+        self.parent_list
+            .iter()
+            .enumerate()
+            .find(|(i, &parent)| *i == parent)
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+    pub fn get_parent(&self, index: usize) -> Option<usize> {
+        self.parent_list
+            .get(index)
+            .cloned()
+            .filter(|&parent| parent != index)
+    }
+    pub fn get_children(&self, index: usize) -> Vec<usize> {
+        self.parent_list
+            .iter()
+            .enumerate()
+            .filter(|(i, &parent)| parent == index && *i != index)
+            .map(|(i, _)| i)
+            .collect()
     }
-    fn get_tree_size(&self) -> usize { self.parent_list.len() }
 }
 impl fmt::Display for UpTreeSpec {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -181,15 +362,33 @@ impl fmt::Display for AllowedTree {
         write!(f, "{}", s)
     }
 }
-use failure::{Error, Fail};
+// Lets a list of problems found by Manifest::validate be carried in a single error
+#[derive(Debug)]
+pub struct ManifestErrors(pub Vec<UptreeSpecError>);
+impl fmt::Display for ManifestErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("{} problems", self.0.len());
+        for e in &self.0 { write!(s, "\n  {}", e)?; }
+        write!(f, "{}", s)
+    }
+}
+use failure::{Error, Fail, ResultExt};
 #[derive(Debug, Fail)]
 pub enum UptreeSpecError {
-//  #[fail(display = "UptreeSpecError::Chain {} {}", func_name, comment)]
-//  Chain { func_name: &'static str, comment: String },
+    #[fail(display = "UptreeSpecError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
     #[fail(display = "UpTreeSpecError::Allowed {}: tree {} is not in the allowed set for vm {}", func_name, tree, vm_id)]
     Allowed { func_name: &'static str, vm_id: String, tree: AllowedTree },
-    #[fail(display = "UpTreeSpecError::Containers {}: {} containers isn't enough for the specified trees", func_name, n_containers)]
+    #[fail(display = "UpTreeSpecError::Containers {}: {} containers don't match the size of the specified trees", func_name, n_containers)]
     Containers { func_name: &'static str, n_containers: usize },
+    #[fail(display = "UpTreeSpecError::Image {}: No image named {} for container {}", func_name, image, container_id)]
+    Image { func_name: &'static str, container_id: String, image: String },
+    #[fail(display = "UpTreeSpecError::Invalid {}: Manifest {} has {}", func_name, id, errors)]
+    Invalid { func_name: &'static str, id: String, errors: ManifestErrors },
     #[fail(display = "UpTreeSpecError::Tree {}: {} for parent list {:?} because {}", func_name, id, parent_list, reason)]
-    Tree { func_name: &'static str, id: String, parent_list: Vec<usize>, reason: &'static str }
+    Tree { func_name: &'static str, id: String, parent_list: Vec<usize>, reason: &'static str },
+    #[fail(display = "UpTreeSpecError::Version {}: Manifest schema version {} does not match {}", func_name, found, expected)]
+    Version { func_name: &'static str, found: String, expected: &'static str },
+    #[fail(display = "UpTreeSpecError::Vms {}: {} VMs don't match the size of up tree {}", func_name, n_vms, id)]
+    Vms { func_name: &'static str, id: String, n_vms: usize }
 }
@@ -18,7 +18,7 @@ use lazy_static::lazy_static;
 use time;
 
 use crate::config::{CellQty, MaskValue, PortQty, CONFIG, MASK_MAX, PAYLOAD_DEFAULT_ELEMENT, REPO};
-use crate::name::TreeID;
+use crate::name::{CellID, TreeID};
 use crate::uuid_ec::Uuid;
 
 pub const BASE_TENANT_MASK: Mask = Mask {
@@ -403,8 +403,10 @@ impl fmt::Display for TraceHeader {
 }
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum TraceType {
-    Trace,
+    Trace, // Level::Info
     Debug,
+    Warn,
+    Error,
 }
 impl fmt::Display for TraceType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -414,16 +416,30 @@ impl fmt::Display for TraceType {
             match *self {
                 TraceType::Trace => "Trace",
                 TraceType::Debug => "Debug",
+                TraceType::Warn  => "Warn",
+                TraceType::Error => "Error",
             }
         )
     }
 }
-#[derive(Debug, Copy, Clone, Hash, Serialize, Deserialize)]
+// Ordered so a cell can host any VM whose required config is no larger than its own
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum CellConfig {
     Small,
     Medium,
     Large,
 }
+impl CellConfig {
+    // Number of containers a cell of this size can host at once
+    pub fn get_slots(&self) -> usize {
+        match *self {
+            CellConfig::Small  => 4,
+            CellConfig::Medium => 16,
+            CellConfig::Large  => 64,
+        }
+    }
+    pub fn can_host(&self, required: CellConfig) -> bool { *self >= required }
+}
 impl Default for CellConfig {
     fn default() -> CellConfig {
         CellConfig::Large
@@ -439,6 +455,54 @@ impl fmt::Display for CellConfig {
         write!(f, "{}", s)
     }
 }
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Placement {
+    Placed,
+    Deferred, // Fits the cell, but not the slots currently free
+    Refused,  // Cell is too small for the VM
+    Undeployed, // Stopped and its slots released
+    Unchanged, // Already running the images a redeploy asks for, so left alone
+}
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Placement::Placed   => "Placed",
+            Placement::Deferred => "Deferred",
+            Placement::Refused  => "Refused",
+            Placement::Undeployed => "Undeployed",
+            Placement::Unchanged => "Unchanged",
+        };
+        write!(f, "{}", s)
+    }
+}
+// What one cell did with the VMs of a manifest, along with the capacity it advertises
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementReport {
+    cell_id: CellID,
+    manifest_id: String,
+    cell_config: CellConfig,
+    free_slots: usize,
+    vms: Vec<(String, Placement)>,
+}
+impl PlacementReport {
+    pub fn new(cell_id: CellID, manifest_id: &str, cell_config: CellConfig, free_slots: usize) -> PlacementReport {
+        PlacementReport { cell_id, manifest_id: S(manifest_id), cell_config, free_slots, vms: Vec::new() }
+    }
+    pub fn get_cell_id(&self) -> CellID { self.cell_id }
+    pub fn get_manifest_id(&self) -> &String { &self.manifest_id }
+    pub fn get_vms(&self) -> &Vec<(String, Placement)> { &self.vms }
+    pub fn get_free_slots(&self) -> usize { self.free_slots }
+    pub fn add_vm(&mut self, vm_id: &str, placement: Placement) { self.vms.push((S(vm_id), placement)); }
+    pub fn set_free_slots(&mut self, free_slots: usize) { self.free_slots = free_slots; }
+}
+impl fmt::Display for PlacementReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("Manifest {} on cell {} ({}, {} free slots)", self.manifest_id,
+                            self.cell_id, self.cell_config, self.free_slots);
+        for (vm_id, placement) in &self.vms { s = s + &format!("\n  {}: {}", vm_id, placement); }
+        write!(f, "{}", s)
+    }
+}
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, Default)]
 pub struct CellInfo {
     // Any data the cell agent wants to expose to applications
@@ -559,6 +623,7 @@ impl fmt::Display for ActivityData {
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum OutbufType {
     Control,
+    Ait,
     Message,
     HeadOfLine(HolSelector),
 }
@@ -566,6 +631,7 @@ impl fmt::Display for OutbufType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             OutbufType::Control => "Control".to_owned(),
+            OutbufType::Ait => "Ait".to_owned(),
             OutbufType::Message => "Message".to_owned(),
             OutbufType::HeadOfLine(selector) => selector.to_string(),
         };
@@ -751,6 +817,17 @@ pub fn _dbg_get_thread_id() -> u64 {
 pub fn S<T: fmt::Display>(s: T) -> String {
     s.to_string()
 }
+// Keys and signatures are carried as hex, which takes less room than JSON arrays of numbers
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 { return None; }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
 // Errors
 use failure::{Error, Fail, ResultExt};
 #[derive(Debug, Fail)]
@@ -150,6 +150,9 @@ impl Uuid {
         self.set_code(new_code);
         self.get_ait_state()
     }
+    pub fn clear_snake(&mut self) {
+        if self.is_snake() { self.make_snake(); } // make_snake toggles the flag
+    }
     pub fn make_snaked(&mut self) -> AitState {
         self.set_code(SNAKED);
         AitState::SnakeD
@@ -2,7 +2,7 @@
  *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
  *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::{collections::{HashSet},
+use std::{collections::{HashMap, HashSet},
           //sync::mpsc::channel,
           thread};
 use crossbeam::crossbeam_channel::unbounded as channel;
@@ -11,11 +11,15 @@ use crate::app_message_formats::{VmToCa, VmFromCa,
                                  VmToContainer, ContainerFromVm,
                                  ContainerToVm, VmFromContainer};
 use crate::config::{CONFIG};
-use crate::container::{Container};
+use crate::container::{Container, ContainerStatus};
 use crate::dal::{add_to_trace, fork_trace_header, update_trace_header};
 use crate::name::{Name, CellID, ContainerID, UptreeID, VmID}; // CellID for tracing purposes
-use crate::uptree_spec::{AllowedTree, ContainerSpec};
-use crate::utility::{S, write_err, TraceHeader, TraceHeaderParams, TraceType};
+use crate::trace_filter::{Level, tracing};
+use crate::tree_key::TreeKeys;
+use crate::uptree::UpTree;
+use crate::uptree_spec::{AllowedTree, ContainerSpec, UpTreeSpec};
+use crate::utility::{S, write_err, TraceHeader, TraceHeaderParams};
+use crate::wire;
 
 #[derive(Debug, Clone)]
 pub struct VirtualMachine {
@@ -23,29 +27,74 @@ pub struct VirtualMachine {
     id: VmID,
     vm_to_ca: VmToCa,
     allowed_trees: Vec<AllowedTree>,
-    vm_to_containers: Vec<VmToContainer>,
+    vm_to_containers: HashMap<ContainerID, VmToContainer>,
+    up_trees: HashMap<AllowedTree, UpTree<ContainerID>>,
 }
 impl VirtualMachine {
     pub fn new(cell_id: CellID, id: VmID, vm_to_ca: VmToCa, allowed_trees_ref: &[AllowedTree]) -> VirtualMachine {
         //println!("Create VM {}", id);
         VirtualMachine { cell_id, id, vm_to_ca, allowed_trees: allowed_trees_ref.to_owned(),
-            vm_to_containers: Vec::new() }
+            vm_to_containers: HashMap::new(), up_trees: HashMap::new() }
     }
     pub fn initialize(&mut self, up_tree_name: &str, vm_from_ca: VmFromCa, allowed_trees: &HashSet<AllowedTree>,
-            container_specs: &[ContainerSpec]) -> Result<(), Error> {
+            container_specs: &[ContainerSpec], up_tree_specs: &[UpTreeSpec], tree_keys: &TreeKeys) -> Result<(), Error> {
+        let _f = "initialize";
         //println!("VM {} initializing", self.id);
-        let up_tree_id = UptreeID::new(up_tree_name).context(VmError::Chain { func_name: "initialize", comment: S(self.id.get_name()) + " up tree id"})?;
-        for container_spec in container_specs {
+        let vm_up_tree_id = UptreeID::new(up_tree_name).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name()) + " up tree id"})?;
+        let mut container_ids = Vec::new();
+        for (index, _) in container_specs.iter().enumerate() {
+            let name = format!("Container:{}+{}", self.id, index);
+            let container_id = ContainerID::new(&name).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name())})?;
+            container_ids.push(container_id);
+        }
+        // Container i in the VmSpec is member i of each of the VM's up trees
+        for up_tree_spec in up_tree_specs {
+            let up_tree_id = UptreeID::new(up_tree_spec.get_id()).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name()) + " up tree id"})?;
+            let up_tree = UpTree::new(up_tree_id, up_tree_spec, &container_ids).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name())})?;
+            {
+                if let Some(event) = tracing(Level::Info, &["vm"]) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_up_tree" };
+                    let trace = json!({ "cell_id": self.cell_id, "id": self.id, "up_tree_id": up_tree_id,
+                        "parent_list": up_tree_spec.get_parent_list(), "members": up_tree.get_members() });
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            self.up_trees.insert(up_tree.get_tree_name().clone(), up_tree);
+        }
+        let mut vm_from_containers = Vec::new();
+        for (container_spec, &container_id) in container_specs.iter().zip(container_ids.iter()) {
             let (vm_to_container, container_from_vm): (VmToContainer, ContainerFromVm) = channel();
             let (container_to_vm, vm_from_container): (ContainerToVm, VmFromContainer) = channel();
-            let name = format!("Container:{}+{}", self.id, container_specs.len() + 1);
-            let container_id = ContainerID::new(&name).context(VmError::Chain { func_name: "initialize", comment: S(self.id.get_name())})?;
-            let service_name = container_spec.get_image();
-            let container = Container::new(self.cell_id, container_id, service_name.as_str(), allowed_trees,
-                 container_to_vm).context(VmError::Chain { func_name: "initialize", comment: S("")})?;
-            container.initialize(up_tree_id, container_from_vm).context(VmError::Chain { func_name: "initialize", comment: S(self.id.get_name())})?;
-            self.vm_to_containers.push(vm_to_container);
-            // Next line must be inside loop or vm_to_container goes out of scope in listen_container
+            // A container may name the up trees it belongs to
+            let mut container_allowed_trees = allowed_trees.clone();
+            let mut container_up_tree_id = vm_up_tree_id;
+            for up_tree in self.up_trees.values().filter(|up_tree| up_tree.contains(container_id)) {
+                if container_up_tree_id == vm_up_tree_id { container_up_tree_id = up_tree.get_id(); }
+                container_allowed_trees.insert(up_tree.get_tree_name().clone());
+            }
+            // A container only gets the keys of trees it may name
+            let container_tree_keys: TreeKeys = tree_keys
+                .iter()
+                .filter(|(tree_name, _)| container_allowed_trees.contains(&AllowedTree::new(tree_name)))
+                .map(|(tree_name, tree_key)| (tree_name.clone(), tree_key.clone()))
+                .collect();
+            let service_name = container_spec.get_image_name();
+            let container = Container::new(self.cell_id, container_id, service_name, container_spec.get_image_version(), &container_allowed_trees,
+                 &container_tree_keys, container_spec.get_restart_policy(), container_to_vm).context(VmError::Chain { func_name: _f, comment: S("")})?;
+            {
+                if let Some(event) = tracing(Level::Info, &["vm"]) {
+                    let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_container" };
+                    let trace = json!({ "cell_id": self.cell_id, "id": self.id, "container_id": container_id,
+                        "image": service_name, "version": container.get_image_version() });
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            container.initialize(container_up_tree_id, container_from_vm).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name())})?;
+            self.vm_to_containers.insert(container_id, vm_to_container);
+            vm_from_containers.push((container_id, vm_from_container));
+        }
+        // Start listening only after all containers exist so each listener can route on the up trees
+        for (container_id, vm_from_container) in vm_from_containers {
             self.listen_container(container_id, vm_from_container, self.vm_to_ca.clone());
         }
         //println!("VM {}: {} containers", self.id, self.vm_to_containers.len());
@@ -53,6 +102,14 @@ impl VirtualMachine {
         Ok(())
     }
     pub fn _get_id(&self) -> &VmID { &self.id }
+    // Containers report their own status; this covers the VM's threads
+    fn report_failure(&self, error: &Error) -> Result<(), Error> {
+        let _f = "report_failure";
+        let health_msg = AppHealthMsg::new(&self.id.get_name(), &self.id.get_name(), ContainerStatus::Failed, 0, &error.to_string());
+        let bytes = wire::encode(&health_msg as &dyn AppMessage)?;
+        self.vm_to_ca.send(bytes).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name()) + " send to ca"})?;
+        Ok(())
+    }
 
     // SPAWN THREAD (listen_ca_loop)
     fn listen_ca(&self, vm_from_ca: VmFromCa) {
@@ -63,8 +120,12 @@ impl VirtualMachine {
         let thread_name = format!("VirtualMachine {} listen_ca_loop", self.id);
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
-            let _ = vm.listen_ca_loop(&vm_from_ca).map_err(|e| write_err("vm", &e));
-            if CONFIG.continue_on_error { vm.listen_ca(vm_from_ca); }
+            // Ok means the VM was undeployed
+            if let Err(e) = vm.listen_ca_loop(&vm_from_ca) {
+                write_err("vm", &e);
+                let _ = vm.report_failure(&e).map_err(|e| write_err("vm", &e));
+                if CONFIG.continue_on_error { vm.listen_ca(vm_from_ca); }
+            }
         }).expect("VM listen_ca thread failed");
     }
 
@@ -78,8 +139,12 @@ impl VirtualMachine {
         let thread_name = format!("VirtualMachine {} listen_container_loop", self.id);
         thread::Builder::new().name(thread_name).spawn( move || {
             update_trace_header(child_trace_header);
-            let _ = vm.listen_container_loop(container_id, &vm_from_container, &vm_to_ca).map_err(|e| write_err("vm", &e));
-            if CONFIG.continue_on_error { vm.listen_container(container_id, vm_from_container, vm_to_ca); }
+            // Ok means the container has exited
+            if let Err(e) = vm.listen_container_loop(container_id, &vm_from_container, &vm_to_ca) {
+                write_err("vm", &e);
+                let _ = vm.report_failure(&e).map_err(|e| write_err("vm", &e));
+                if CONFIG.continue_on_error { vm.listen_container(container_id, vm_from_container, vm_to_ca); }
+            }
         }).expect("VM listen_container thread failed");
     }
 
@@ -87,54 +152,82 @@ impl VirtualMachine {
     fn listen_ca_loop(&self, vm_from_ca: &VmFromCa) -> Result<(), Error> {
         let _f = "listen_ca_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.vm {
+            if let Some(event) = tracing(Level::Info, &["vm"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.cell_id, "id": self.id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
             let bytes = vm_from_ca.recv().context("listen_ca_loop").context(VmError::Chain { func_name: "listen_ca_loop", comment: S(self.id.get_name()) })?;
+            let msg: Box<dyn AppMessage> = wire::decode(&bytes)?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.vm {
-                    let msg: Box<dyn AppMessage> = serde_json::from_str(&bytes.stringify()?)?;
+                if let Some(event) = tracing(Level::Info, &["vm"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_from_ca" };
                     let trace = json!({ "cell_id": self.cell_id, "id": self.id, "msg": msg.to_string() });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_to_container" };
                     let trace = json!({ "cell_id": self.cell_id, "id": self.id, "msg": msg.to_string() });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             //println!("VM {} send to {} containers msg from ca: {}", self.id,  self.vm_to_containers.len(), msg);
-            for vm_to_container in &self.vm_to_containers {
+            for vm_to_container in self.vm_to_containers.values() {
                 vm_to_container.send(bytes.clone()).context(VmError::Chain { func_name: "listen_ca_loop", comment: S("send to container") })?;
             }
+            // Containers stop when they see the undeploy, so the VM can stop too
+            if msg.get_msg_type() == AppMsgType::AppUndeployMsg { return Ok(()); }
         }
     }
 
     // WORKER (VmFromContainer)
-    fn listen_container_loop(&self, _: ContainerID, vm_from_container: &VmFromContainer, vm_to_ca: &VmToCa)
+    fn listen_container_loop(&self, container_id: ContainerID, vm_from_container: &VmFromContainer, vm_to_ca: &VmToCa)
             -> Result<(), Error> {
         let _f = "listen_container_loop";
         {
-            if CONFIG.trace_options.all || CONFIG.trace_options.vm {
+            if let Some(event) = tracing(Level::Info, &["vm"]) {
                 let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "worker" };
                 let trace = json!({ "cell_id": self.cell_id, "id": self.id, "thread_name": thread::current().name(), "thread_id": TraceHeader::parse(thread::current().id()) });
-                add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                add_to_trace(event, trace_params, &trace, _f);
             }
         }
         loop {
-            let bytes = vm_from_container.recv().context("listen_container_loop").context(VmError::Chain { func_name: "listen_container_loop", comment: S(self.id.get_name()) + " recv from container"})?;
+            // The channel closes when the container exits
+            let bytes = match vm_from_container.recv() {
+                Ok(bytes) => bytes,
+                Err(_) => return Ok(())
+            };
+            let msg: Box<dyn AppMessage> = wire::decode(&bytes).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name()) + " from container"})?;
             {
-                if CONFIG.trace_options.all || CONFIG.trace_options.vm {
-                    let msg: Box<dyn AppMessage> = serde_json::from_str(&bytes.stringify()?)?;
+                if let Some(event) = tracing(Level::Info, &["vm"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_from_container" };
                     let trace = json!({ "cell_id": self.cell_id, "id": self.id, "msg": msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
+                }
+            }
+            // Messages on one of my up trees stay inside the VM
+            if let Some(up_tree) = self.up_trees.get(msg.get_target_tree_name()) {
+                let next_hops = up_tree.next_hops(container_id, msg.get_direction()).context(VmError::Chain { func_name: _f, comment: S(self.id.get_name()) })?;
+                {
+                    if let Some(event) = tracing(Level::Info, &["vm"]) {
+                        let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_to_container_up_tree" };
+                        let trace = json!({ "cell_id": self.cell_id, "id": self.id, "up_tree_id": up_tree.get_id(), "next_hops": next_hops, "msg": msg });
+                        add_to_trace(event, trace_params, &trace, _f);
+                    }
+                }
+                for next_hop in next_hops {
+                    let vm_to_container = self.vm_to_containers
+                        .get(&next_hop)
+                        .ok_or::<Error>(VmError::Container { func_name: _f, vm_id: self.id, container_id: next_hop }.into())?;
+                    vm_to_container.send(bytes.clone()).context(VmError::Chain { func_name: _f, comment: S("send to container on up tree") })?;
+                }
+                continue;
+            }
+            {
+                if let Some(event) = tracing(Level::Info, &["vm"]) {
                     let trace_params = &TraceHeaderParams { module: file!(), line_no: line!(), function: _f, format: "vm_to_ca" };
                     let trace = json!({ "cell_id": self.cell_id, "id": self.id, "msg": msg });
-                    add_to_trace(TraceType::Trace, trace_params, &trace, _f);
+                    add_to_trace(event, trace_params, &trace, _f);
                 }
             }
             //println!("VM {} got from container {} msg {} {} {} {}", self.id, container_id, msg.0, msg.1, msg.2, msg.3);
@@ -144,12 +237,14 @@ impl VirtualMachine {
 }
 // Errors
 use failure::{Error, ResultExt};
-use crate::app_message::AppMessage;
+use crate::app_message::{AppMessage, AppMsgType, AppHealthMsg};
 
 #[derive(Debug, Fail)]
 pub enum VmError {
     #[fail(display = "VmError::Chain {} {}", func_name, comment)]
     Chain { func_name: &'static str, comment: String },
+    #[fail(display = "VmError::Container {}: No container {} in VM {}", func_name, container_id, vm_id)]
+    Container { func_name: &'static str, vm_id: VmID, container_id: ContainerID },
 //    #[fail(display = "VmError::AllowedTree {}: {} is not an allowed tree for VM {}", func_name, tree, vm_id)]
 //    AllowedTree { func_name: &'static str, tree: AllowedTree, vm_id: VmID }
 }
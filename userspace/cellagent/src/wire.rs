@@ -0,0 +1,101 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::fmt;
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+
+use crate::app_message::AppMessage;
+use crate::config::CONFIG;
+use crate::ec_message::Message;
+use crate::utility::{ByteArray, S};
+
+// Binary messages start with a marker byte, which can't start a JSON message, followed by the
+// version of the encoding.  Receivers decode either form, so cells configured differently can
+// still talk to each other, and traces always get the JSON form.
+const BINARY_MARKER: u8 = 0xEC;
+pub const WIRE_VERSION: u8 = 1; // Version 1 is CBOR
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    Json,
+    Binary,
+}
+impl WireFormat {
+    // Set per fabric in the config file, JSON if not
+    pub fn configured() -> WireFormat { CONFIG.wire_format.unwrap_or(WireFormat::Json) }
+}
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            WireFormat::Json   => "Json",
+            WireFormat::Binary => "Binary",
+        };
+        write!(f, "{}", s)
+    }
+}
+// Use with msg as &dyn Message or &dyn AppMessage so the type tag goes on the wire
+pub fn encode<T>(value: &T) -> Result<ByteArray, Error>
+        where T: Serialize + ?Sized {
+    encode_as(WireFormat::configured(), value)
+}
+pub fn encode_as<T>(format: WireFormat, value: &T) -> Result<ByteArray, Error>
+        where T: Serialize + ?Sized {
+    let _f = "encode_as";
+    Ok(match format {
+        WireFormat::Json => {
+            let serialized = serde_json::to_string(value).context(WireError::Chain { func_name: _f, comment: S("json") })?;
+            ByteArray::new(&serialized)
+        },
+        WireFormat::Binary => {
+            let mut bytes = vec![BINARY_MARKER, WIRE_VERSION];
+            // to_writer needs a sized value, and trait objects aren't
+            let mut serializer = serde_cbor::Serializer::new(serde_cbor::ser::IoWrite::new(&mut bytes));
+            value.serialize(&mut serializer).context(WireError::Chain { func_name: _f, comment: S("binary") })?;
+            ByteArray::new_from_bytes(&bytes)
+        }
+    })
+}
+pub fn decode<T>(bytes: &ByteArray) -> Result<T, Error>
+        where T: DeserializeOwned {
+    let _f = "decode";
+    Ok(match format_of(bytes)? {
+        WireFormat::Json => serde_json::from_str(&bytes.stringify()?).context(WireError::Chain { func_name: _f, comment: S("json") })?,
+        WireFormat::Binary => serde_cbor::from_slice(&bytes.get_bytes()[2..]).context(WireError::Chain { func_name: _f, comment: S("binary") })?
+    })
+}
+// The JSON form of a message in either encoding, for trace records
+pub fn to_json(bytes: &ByteArray) -> Result<String, Error> {
+    let _f = "to_json";
+    Ok(match format_of(bytes)? {
+        WireFormat::Json => bytes.stringify()?,
+        // Uuids are byte strings in the binary form, which JSON has no type for, so rebuild
+        // the message to get the JSON it would have been sent as
+        WireFormat::Binary => {
+            let json = match decode::<Box<dyn Message>>(bytes) {
+                Ok(msg) => serde_json::to_string(&msg),
+                Err(_) => serde_json::to_string(&decode::<Box<dyn AppMessage>>(bytes)?)
+            };
+            json.context(WireError::Chain { func_name: _f, comment: S("") })?
+        }
+    })
+}
+pub fn format_of(bytes: &ByteArray) -> Result<WireFormat, Error> {
+    let _f = "format_of";
+    match bytes.get_bytes().get(0..2) {
+        Some(&[BINARY_MARKER, WIRE_VERSION]) => Ok(WireFormat::Binary),
+        Some(&[BINARY_MARKER, version]) => Err(WireError::Version { func_name: _f, version }.into()),
+        _ => Ok(WireFormat::Json)
+    }
+}
+// Errors
+use failure::{Error, ResultExt};
+#[derive(Debug, Fail)]
+pub enum WireError {
+    #[fail(display = "WireError::Chain {} {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "WireError::Version {}: Binary encoding version {} is not supported", func_name, version)]
+    Version { func_name: &'static str, version: u8 },
+}
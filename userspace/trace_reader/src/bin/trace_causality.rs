@@ -0,0 +1,87 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// trace_causality <trace file> [--explain <record>] [--critical-path] [--dot <file>] [--chrome <file>]
+//   --explain        The messages that led to a record, given by its number or as format[@cell],
+//                    e.g., ca_to_cm_entry@C:3 for the last routing table entry on C:3
+//   --critical-path  The chain of events the last ca_discover_done record waited for
+//   --dot            Write the graph, or just the records printed, in Graphviz format
+//   --chrome         Write the graph in Chrome trace-event format
+#[macro_use] extern crate failure;
+
+use std::{env, fs};
+
+use ec_trace_reader::TraceReader;
+use ec_trace_reader::causality::{CausalGraph};
+
+fn main() -> Result<(), Error> {
+    let _f = "main";
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let file_name = args.first().ok_or::<Error>(CausalityMainError::Usage { func_name: _f }.into())?;
+    let graph = CausalGraph::read(TraceReader::open(file_name)?)?;
+    println!("{} records, {} edges", graph.records().len(), graph.edges().len());
+    let mut printed = None;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().ok_or::<Error>(CausalityMainError::Value { func_name: _f, option: option.clone() }.into());
+        match option.as_str() {
+            "--explain" => {
+                let index = find_record(&graph, value()?)?;
+                let chain = graph.message_chain(index);
+                print_records(&graph, &format!("Messages leading to record {}", index), &chain);
+                printed = Some(chain);
+            },
+            "--critical-path" => {
+                let path = graph.critical_path()?;
+                print_records(&graph, "Critical path of discovery", &path);
+                printed = Some(path);
+            },
+            "--dot" => {
+                let file_name = value()?;
+                fs::write(file_name, graph.to_dot(printed.as_deref()))?;
+            },
+            "--chrome" => {
+                let file_name = value()?;
+                fs::write(file_name, serde_json::to_string(&graph.to_chrome_trace())?)?;
+            },
+            _ => return Err(CausalityMainError::Usage { func_name: _f }.into())
+        }
+    }
+    Ok(())
+}
+// A record number, or the last record with this format, on this cell if format@cell
+fn find_record(graph: &CausalGraph, selector: &str) -> Result<usize, Error> {
+    let _f = "find_record";
+    if let Ok(index) = selector.parse::<usize>() {
+        if index < graph.records().len() { return Ok(index); }
+    }
+    let mut parts = selector.splitn(2, '@');
+    let format = parts.next().unwrap_or("");
+    graph.find(format, parts.next())
+        .last()
+        .cloned()
+        .ok_or::<Error>(CausalityMainError::Record { func_name: _f, selector: selector.to_owned() }.into())
+}
+fn print_records(graph: &CausalGraph, title: &str, indices: &[usize]) {
+    println!("{}", title);
+    let records = graph.records();
+    for &index in indices {
+        let header = records[index].header();
+        println!("{:>8} {:>12}µs {:>6} {:<30} {:?}", index, header.epoch().saturating_sub(header.starting_epoch()),
+                 records[index].cell_name(), header.format(), header.event_id());
+    }
+}
+
+// Errors
+use failure::{Error};
+
+#[derive(Debug, Fail)]
+pub enum CausalityMainError {
+    #[fail(display = "CausalityMainError::Record {}: No record {}", func_name, selector)]
+    Record { func_name: &'static str, selector: String },
+    #[fail(display = "CausalityMainError::Usage {}: trace_causality <trace file> [--explain <record>] [--critical-path] [--dot <file>] [--chrome <file>]", func_name)]
+    Usage { func_name: &'static str },
+    #[fail(display = "CausalityMainError::Value {}: {} needs a value", func_name, option)]
+    Value { func_name: &'static str, option: String },
+}
@@ -0,0 +1,45 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// trace_diff <trace file> <trace file>
+// Compares two runs from their traces for all cells; exits with 1 if the final trees or
+// routing tables differ and with 2 if it can't compare them
+#[macro_use] extern crate failure;
+
+use std::{env, process};
+
+use ec_trace_reader::TraceReader;
+use ec_trace_reader::diff::{RunSummary, TraceDiff};
+
+const DIFFERS: i32 = 1;
+const FAILED: i32 = 2;
+
+fn main() {
+    match diff() {
+        Ok(diff) => if diff.fabric_differs() { process::exit(DIFFERS); },
+        Err(e) => {
+            eprintln!("trace_diff: {}", e);
+            process::exit(FAILED);
+        }
+    }
+}
+fn diff() -> Result<TraceDiff, Error> {
+    let _f = "diff";
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() != 2 { return Err(DiffMainError::Usage { func_name: _f }.into()); }
+    let left = RunSummary::read(TraceReader::open(&args[0])?)?;
+    let right = RunSummary::read(TraceReader::open(&args[1])?)?;
+    let diff = TraceDiff::new(&left, &right);
+    println!("{}", diff);
+    Ok(diff)
+}
+
+// Errors
+use failure::{Error};
+
+#[derive(Debug, Fail)]
+pub enum DiffMainError {
+    #[fail(display = "DiffMainError::Usage {}: trace_diff <trace file> <trace file>", func_name)]
+    Usage { func_name: &'static str },
+}
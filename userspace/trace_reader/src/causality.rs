@@ -0,0 +1,282 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// The happened-before graph of a trace.  Event ids give three kinds of edges:
+//   Program: a thread's records in the order of the last element of their event ids
+//   Fork:    fork_trace() gives a new thread its parent's event id with a 0 pushed on, so the
+//            child's first record follows the parent's last record before the fork
+//   Message: a message's header names the sending cell and its sequence number, so the
+//            record of the send comes before the first record about it on each receiving cell
+use std::{collections::{HashMap, HashSet},
+          fmt,
+          io::BufRead};
+
+use serde_json::{json, Value};
+
+use crate::{TraceReader, TraceRecord};
+
+pub const DISCOVER_DONE: &str = "ca_discover_done";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EdgeKind {
+    Program,
+    Fork,
+    Message,
+}
+impl fmt::Display for EdgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            EdgeKind::Program => "Program",
+            EdgeKind::Fork    => "Fork",
+            EdgeKind::Message => "Message",
+        };
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edge {
+    pub from: usize, // Index of the record in the trace, not counting the file header
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+// A thread's records share everything but the last element of their event ids.  Threads that
+// were never forked all have one-element event ids, so the thread id tells them apart.
+type Lineage = (u64, Vec<u64>);
+
+pub struct CausalGraph {
+    records: Vec<TraceRecord>,
+    edges: Vec<Edge>,
+    causes: Vec<Vec<usize>>, // Indices into edges by the record they lead to
+}
+impl CausalGraph {
+    pub fn read<R: BufRead>(reader: TraceReader<R>) -> Result<CausalGraph, Error> {
+        let _f = "read";
+        let records = reader
+            .collect::<Result<Vec<_>, Error>>()
+            .context(CausalityError::Chain { func_name: _f, comment: "reading trace".to_owned() })?;
+        Ok(CausalGraph::new(records))
+    }
+    pub fn new(records: Vec<TraceRecord>) -> CausalGraph {
+        let mut graph = CausalGraph { causes: vec![Vec::new(); records.len()], records, edges: Vec::new() };
+        let mut lineages: HashMap<Lineage, Vec<usize>> = HashMap::new();
+        for (index, record) in graph.records.iter().enumerate() {
+            lineages.entry(lineage(record, record.header().thread_id())).or_default().push(index);
+        }
+        for indices in lineages.values_mut() {
+            indices.sort_by_key(|&index| graph.records[index].header().event_id().last().cloned().unwrap_or(0));
+            for pair in indices.windows(2) { graph.add_edge(pair[0], pair[1], EdgeKind::Program); }
+        }
+        for indices in lineages.values() {
+            let first = indices[0];
+            if let Some(parent) = graph.fork_cause(&lineages, first) { graph.add_edge(parent, first, EdgeKind::Fork); }
+        }
+        graph.add_message_edges();
+        graph
+    }
+    pub fn records(&self) -> &[TraceRecord] { &self.records }
+    pub fn edges(&self) -> &[Edge] { &self.edges }
+    pub fn causes(&self, index: usize) -> impl Iterator<Item=&Edge> {
+        self.causes[index].iter().map(move |&edge| &self.edges[edge])
+    }
+    // Records with this format, on this cell if there is one, in trace order
+    pub fn find(&self, format: &str, cell_name: Option<&str>) -> Vec<usize> {
+        self.records
+            .iter()
+            .enumerate()
+            .filter(|(_, record)| record.format() == format)
+            .filter(|(_, record)| cell_name.is_none_or(|name| record.cell_name() == name))
+            .map(|(index, _)| index)
+            .collect()
+    }
+    // The messages that led to this record, e.g., a ca_to_cm_entry or ca_process_failover_msg
+    // record: back along the thread to the message it was handling, to the record of that
+    // message's send, and so on back to the start.  Returns the sends and receives, oldest
+    // first, followed by the record.
+    pub fn message_chain(&self, index: usize) -> Vec<usize> {
+        let path = self.trace_back(index, |graph, edges| {
+            edges.iter()
+                .max_by_key(|edge| (edge.kind == EdgeKind::Message, graph.epoch(edge.from)))
+                .cloned()
+        });
+        let mut chain = Vec::new();
+        for pair in path.windows(2) {
+            if self.is_message_edge(pair[0], pair[1]) { chain.push(pair[0]); chain.push(pair[1]); }
+        }
+        chain.push(index);
+        chain.dedup();
+        chain
+    }
+    // What discovery waited for: back from the last ca_discover_done record, each step to the
+    // cause that happened last
+    pub fn critical_path(&self) -> Result<Vec<usize>, Error> {
+        let _f = "critical_path";
+        let last = self.find(DISCOVER_DONE, None)
+            .into_iter()
+            .max_by_key(|&index| self.epoch(index))
+            .ok_or::<Error>(CausalityError::NotFound { func_name: _f, format: DISCOVER_DONE.to_owned() }.into())?;
+        Ok(self.trace_back(last, |graph, edges| {
+            edges.iter()
+                .max_by_key(|edge| (graph.epoch(edge.from), edge.kind == EdgeKind::Message))
+                .cloned()
+        }))
+    }
+    // Graphviz; only these records and the edges between them if there are some
+    pub fn to_dot(&self, subset: Option<&[usize]>) -> String {
+        let included: HashSet<usize> = match subset {
+            Some(indices) => indices.iter().cloned().collect(),
+            None => (0..self.records.len()).collect()
+        };
+        let mut by_cell: HashMap<&str, Vec<usize>> = HashMap::new();
+        for index in 0..self.records.len() {
+            if included.contains(&index) { by_cell.entry(self.records[index].cell_name()).or_default().push(index); }
+        }
+        let mut cell_names = by_cell.keys().cloned().collect::<Vec<_>>();
+        cell_names.sort();
+        let mut dot = String::from("digraph trace {\n    rankdir=TB;\n    node [shape=box, fontsize=10];\n");
+        for (n, cell_name) in cell_names.iter().enumerate() {
+            dot += &format!("    subgraph cluster_{} {{\n        label=\"{}\";\n", n, cell_name);
+            for &index in &by_cell[cell_name] {
+                let header = self.records[index].header();
+                dot += &format!("        r{} [label=\"{} {}\\n{:?} {}\"];\n",
+                                     index, index, header.format(), header.event_id(), header.thread_id());
+            }
+            dot += "    }\n";
+        }
+        for edge in self.edges.iter().filter(|edge| included.contains(&edge.from) && included.contains(&edge.to)) {
+            let style = match edge.kind {
+                EdgeKind::Program => "solid",
+                EdgeKind::Fork    => "dashed",
+                EdgeKind::Message => "bold, color=blue",
+            };
+            dot += &format!("    r{} -> r{} [style={}];\n", edge.from, edge.to, style);
+        }
+        dot + "}\n"
+    }
+    // Chrome trace-event format for chrome://tracing or Perfetto: a process for each cell, its
+    // threads, a 1µs slice for each record, and a flow arrow for each fork and message
+    pub fn to_chrome_trace(&self) -> Value {
+        let mut cell_names = self.records.iter().map(TraceRecord::cell_name).collect::<Vec<_>>();
+        cell_names.sort();
+        cell_names.dedup();
+        let pids: HashMap<&str, usize> = cell_names.iter().enumerate().map(|(pid, name)| (*name, pid)).collect();
+        let mut events = cell_names
+            .iter()
+            .map(|name| json!({ "ph": "M", "name": "process_name", "pid": pids[name], "args": { "name": name } }))
+            .collect::<Vec<_>>();
+        let pid_tid = |index: usize| {
+            let record = &self.records[index];
+            (pids[record.cell_name()], record.header().thread_id())
+        };
+        for (index, record) in self.records.iter().enumerate() {
+            let header = record.header();
+            let (pid, tid) = pid_tid(index);
+            events.push(json!({ "ph": "X", "name": header.format(), "cat": header.trace_type(), "ts": self.time(index), "dur": 1,
+                                "pid": pid, "tid": tid,
+                                "args": { "record": index, "event_id": header.event_id(), "function": header.function(), "line_no": header.line_no() } }));
+        }
+        for (id, edge) in self.edges.iter().enumerate().filter(|(_, edge)| edge.kind != EdgeKind::Program) {
+            let name = edge.kind.to_string();
+            let (from_pid, from_tid) = pid_tid(edge.from);
+            let (to_pid, to_tid) = pid_tid(edge.to);
+            events.push(json!({ "ph": "s", "name": name, "cat": "causality", "id": id, "ts": self.time(edge.from), "pid": from_pid, "tid": from_tid }));
+            events.push(json!({ "ph": "f", "bp": "e", "name": name, "cat": "causality", "id": id, "ts": self.time(edge.to), "pid": to_pid, "tid": to_tid }));
+        }
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, kind: EdgeKind) {
+        self.causes[to].push(self.edges.len());
+        self.edges.push(Edge { from, to, kind });
+    }
+    fn epoch(&self, index: usize) -> u64 { self.records[index].header().epoch() }
+    // Microseconds since the run started
+    fn time(&self, index: usize) -> u64 {
+        let header = self.records[index].header();
+        header.epoch().saturating_sub(header.starting_epoch())
+    }
+    // The parent's last record before it forked the thread of this record, which is the
+    // first one of its thread; if the parent hadn't written one, whatever its thread followed
+    fn fork_cause(&self, lineages: &HashMap<Lineage, Vec<usize>>, first: usize) -> Option<usize> {
+        let header = self.records[first].header();
+        let event_id = header.event_id();
+        if event_id.len() < 2 { return None; }
+        let forked_at = event_id[event_id.len() - 2];
+        let parent_id = &event_id[..event_id.len() - 2];
+        let parent = lineage_of(header.spawning_thread_id(), parent_id);
+        let candidates = lineages.get(&parent)?;
+        candidates
+            .iter()
+            .rev()
+            .find(|&&index| self.records[index].header().event_id().last().cloned().unwrap_or(0) < forked_at)
+            .cloned()
+            .or_else(|| self.fork_cause(lineages, candidates[0]))
+    }
+    fn add_message_edges(&mut self) {
+        let mut sends: HashMap<(String, u64), usize> = HashMap::new();
+        let mut receives: Vec<((String, u64), usize)> = Vec::new();
+        let mut seen: HashSet<((String, u64), String)> = HashSet::new();
+        for (index, record) in self.records.iter().enumerate() {
+            let key = match get_msg_key(record) { Some(key) => key, None => continue };
+            let cell_name = record.cell_name();
+            if key.0 == cell_name {
+                let epoch = record.header().epoch();
+                let send = sends.entry(key).or_insert(index);
+                if self.records[*send].header().epoch() > epoch { *send = index; }
+            } else if seen.insert((key.clone(), cell_name.to_owned())) {
+                receives.push((key, index));
+            }
+        }
+        for (key, index) in receives {
+            if let Some(&send) = sends.get(&key) { self.add_edge(send, index, EdgeKind::Message); }
+        }
+    }
+    fn is_message_edge(&self, from: usize, to: usize) -> bool {
+        self.causes(to).any(|edge| edge.from == from && edge.kind == EdgeKind::Message)
+    }
+    // The path, oldest first, from a record with no causes to this one, taking the edge
+    // choose() picks at each step
+    fn trace_back<F>(&self, index: usize, choose: F) -> Vec<usize>
+            where F: Fn(&CausalGraph, &[Edge]) -> Option<Edge> {
+        let mut path = vec![index];
+        let mut visited = HashSet::new();
+        visited.insert(index);
+        let mut current = index;
+        loop {
+            let edges = self.causes(current).cloned().collect::<Vec<_>>();
+            match choose(self, &edges) {
+                Some(edge) if visited.insert(edge.from) => { path.push(edge.from); current = edge.from; },
+                _ => break
+            }
+        }
+        path.reverse();
+        path
+    }
+}
+fn lineage(record: &TraceRecord, thread_id: u64) -> Lineage {
+    let event_id = record.header().event_id();
+    lineage_of(thread_id, &event_id[..event_id.len().saturating_sub(1)])
+}
+fn lineage_of(thread_id: u64, prefix: &[u64]) -> Lineage {
+    // Only threads that were never forked need the thread id
+    let thread_id = if prefix.is_empty() { thread_id } else { 0 };
+    (thread_id, prefix.to_vec())
+}
+// The sending cell and sequence number of the message a record is about
+fn get_msg_key(record: &TraceRecord) -> Option<(String, u64)> {
+    let header = &record.body()["msg"]["header"];
+    let sending_cell = header["sending_cell_id"]["name"].as_str()?;
+    let seq_no = header["sender_msg_seq_no"].as_u64()?;
+    Some((sending_cell.to_owned(), seq_no))
+}
+
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum CausalityError {
+    #[fail(display = "CausalityError::Chain {}: {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "CausalityError::NotFound {}: No {} record in the trace", func_name, format)]
+    NotFound { func_name: &'static str, format: String },
+}
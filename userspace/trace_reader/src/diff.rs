@@ -0,0 +1,189 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Compares two runs by what they did rather than record by record, since thread scheduling
+// changes the order of records from one run to the next.  Each trace is summarized by cell:
+// the final routing table, the messages sent of each type, when discovery finished, and how
+// failovers came out.  Tree UUIDs are made up at run time, so trees are matched by name.
+use std::{collections::{BTreeMap, BTreeSet},
+          fmt,
+          io::BufRead};
+
+use serde_json::Value;
+
+use crate::TraceReader;
+use crate::tree_names::TreeNames;
+
+#[derive(Debug, Clone, Default)]
+pub struct CellSummary {
+    pub routing_table: BTreeMap<String, Value>, // Last entry for each tree, without the UUID
+    pub msgs_sent: BTreeMap<String, usize>, // By message type
+    pub failovers: BTreeMap<String, usize>, // Success, Failure, and Reroute
+    pub discover_done: Option<u64>, // Microseconds from the start of the run
+}
+impl CellSummary {
+    // The trees with an entry in use
+    pub fn trees(&self) -> BTreeSet<&String> {
+        self.routing_table
+            .iter()
+            .filter(|(_, entry)| entry["inuse"].as_bool().unwrap_or(false))
+            .map(|(tree, _)| tree)
+            .collect()
+    }
+}
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    pub cells: BTreeMap<String, CellSummary>,
+    pub discovery_time: Option<u64>, // Of the last ca_discover_done record
+    pub last_entry_time: Option<u64>, // Of the last routing table change
+}
+impl RunSummary {
+    // Reads the trace for all cells
+    pub fn read<R: BufRead>(reader: TraceReader<R>) -> Result<RunSummary, Error> {
+        let _f = "read";
+        let records = reader
+            .collect::<Result<Vec<_>, Error>>()
+            .context(DiffError::Chain { func_name: _f, comment: "reading trace".to_owned() })?;
+        let mut tree_names = TreeNames::default();
+        for record in &records { tree_names.learn(record.body()); }
+        let mut summary = RunSummary::default();
+        for record in &records {
+            let time = record.header().epoch().saturating_sub(record.header().starting_epoch());
+            let body = record.body();
+            let cell = summary.cells.entry(record.cell_name().to_owned()).or_insert_with(CellSummary::default);
+            match record.format() {
+                "ca_to_cm_entry" => {
+                    let entry = &body["entry"];
+                    let tree = tree_names.name(&entry["tree_uuid"]);
+                    let mut entry = entry.clone();
+                    if let Some(fields) = entry.as_object_mut() { fields.remove("tree_uuid"); }
+                    cell.routing_table.insert(tree, entry);
+                    summary.last_entry_time = summary.last_entry_time.max(Some(time));
+                },
+                "ca_to_cm_bytes" => {
+                    let msg_type = body["msg"]["header"]["msg_type"].as_str().unwrap_or("Unknown");
+                    *cell.msgs_sent.entry(msg_type.to_owned()).or_insert(0) += 1;
+                },
+                "ca_process_failover_d_msg" => {
+                    let response = body["msg"]["payload"]["response"].as_str().unwrap_or("Unknown");
+                    *cell.failovers.entry(response.to_owned()).or_insert(0) += 1;
+                },
+                "ca_to_cm_reroute" => *cell.failovers.entry("Reroute".to_owned()).or_insert(0) += 1,
+                "ca_discover_done" => {
+                    cell.discover_done = Some(time);
+                    summary.discovery_time = summary.discovery_time.max(Some(time));
+                },
+                _ => ()
+            }
+        }
+        summary.cells.retain(|_, cell| !(cell.routing_table.is_empty() && cell.msgs_sent.is_empty() &&
+                                         cell.failovers.is_empty() && cell.discover_done.is_none()));
+        Ok(summary)
+    }
+}
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiffKind {
+    Tree,
+    RoutingTable,
+    MsgCount,
+    Failover,
+    Convergence,
+}
+impl DiffKind {
+    // Differences in where the fabric ended up, rather than how it got there
+    pub fn is_fabric_state(self) -> bool { self == DiffKind::Tree || self == DiffKind::RoutingTable }
+}
+impl fmt::Display for DiffKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            DiffKind::Tree         => "Tree",
+            DiffKind::RoutingTable => "RoutingTable",
+            DiffKind::MsgCount     => "MsgCount",
+            DiffKind::Failover     => "Failover",
+            DiffKind::Convergence  => "Convergence",
+        };
+        write!(f, "{}", s)
+    }
+}
+#[derive(Debug, Clone)]
+pub struct Difference {
+    pub kind: DiffKind,
+    pub cell: String, // "All" for the whole run
+    pub key: String, // Tree, message type, or failover outcome
+    pub left: String,
+    pub right: String,
+}
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<12} {:<6} {:<24} {} | {}", self.kind.to_string(), self.cell, self.key, self.left, self.right)
+    }
+}
+#[derive(Debug, Clone, Default)]
+pub struct TraceDiff {
+    differences: Vec<Difference>,
+}
+impl TraceDiff {
+    pub fn new(left: &RunSummary, right: &RunSummary) -> TraceDiff {
+        let mut diff = TraceDiff::default();
+        let cell_names = left.cells.keys().chain(right.cells.keys()).collect::<BTreeSet<_>>();
+        let empty = CellSummary::default();
+        for cell_name in cell_names {
+            let l = left.cells.get(cell_name).unwrap_or(&empty);
+            let r = right.cells.get(cell_name).unwrap_or(&empty);
+            let (l_trees, r_trees) = (l.trees(), r.trees());
+            for tree in l_trees.symmetric_difference(&r_trees) {
+                diff.add(DiffKind::Tree, cell_name, tree, present(l_trees.contains(tree)), present(r_trees.contains(tree)));
+            }
+            for tree in l_trees.intersection(&r_trees) {
+                let (l_entry, r_entry) = (&l.routing_table[*tree], &r.routing_table[*tree]);
+                if l_entry != r_entry { diff.add(DiffKind::RoutingTable, cell_name, tree, l_entry.to_string(), r_entry.to_string()); }
+            }
+            diff.add_counts(DiffKind::MsgCount, cell_name, &l.msgs_sent, &r.msgs_sent);
+            diff.add_counts(DiffKind::Failover, cell_name, &l.failovers, &r.failovers);
+            if l.discover_done.is_some() != r.discover_done.is_some() {
+                diff.add(DiffKind::Convergence, cell_name, "discover_done", time(l.discover_done), time(r.discover_done));
+            }
+        }
+        if left.discovery_time != right.discovery_time {
+            diff.add(DiffKind::Convergence, "All", "discovery time", time(left.discovery_time), time(right.discovery_time));
+        }
+        if left.last_entry_time != right.last_entry_time {
+            diff.add(DiffKind::Convergence, "All", "last routing change", time(left.last_entry_time), time(right.last_entry_time));
+        }
+        diff.differences.sort_by(|a, b| (a.kind, &a.cell, &a.key).cmp(&(b.kind, &b.cell, &b.key)));
+        diff
+    }
+    pub fn get_differences(&self) -> &Vec<Difference> { &self.differences }
+    // The runs ended with different trees or routing tables
+    pub fn fabric_differs(&self) -> bool { self.differences.iter().any(|difference| difference.kind.is_fabric_state()) }
+
+    fn add(&mut self, kind: DiffKind, cell: &str, key: &str, left: String, right: String) {
+        self.differences.push(Difference { kind, cell: cell.to_owned(), key: key.to_owned(), left, right });
+    }
+    fn add_counts(&mut self, kind: DiffKind, cell: &str, left: &BTreeMap<String, usize>, right: &BTreeMap<String, usize>) {
+        for key in left.keys().chain(right.keys()).collect::<BTreeSet<_>>() {
+            let (l, r) = (left.get(key).cloned().unwrap_or(0), right.get(key).cloned().unwrap_or(0));
+            if l != r { self.add(kind, cell, key, l.to_string(), r.to_string()); }
+        }
+    }
+}
+impl fmt::Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("{} differences, final fabric state {}", self.differences.len(),
+                            if self.fabric_differs() { "differs" } else { "is the same" });
+        for difference in &self.differences { s = s + &format!("\n{}", difference); }
+        write!(f, "{}", s)
+    }
+}
+fn present(is_present: bool) -> String { if is_present { "present".to_owned() } else { "absent".to_owned() } }
+fn time(time: Option<u64>) -> String { time.map_or("none".to_owned(), |t| format!("{}µs", t)) }
+
+// Errors
+use failure::{Error, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum DiffError {
+    #[fail(display = "DiffError::Chain {}: {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+}
@@ -0,0 +1,153 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+#![warn(bare_trait_objects)]
+#![deny(unused_must_use)]
+#![warn(rust_2018_idioms)]
+#[macro_use] extern crate failure;
+#[macro_use] extern crate serde_derive;
+
+pub mod causality;
+pub mod diff;
+pub mod testing;
+pub mod tree_names;
+
+// Trace files are JSON Lines: a file header record followed by one trace record per line.
+// The cell agent's replay and the trace analyzer both read them with this crate.
+use std::{fs::File,
+          io::{BufRead, BufReader, Lines},
+          path::Path};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+pub const TRACE_SCHEMA_VERSION: &str = "1.0";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceFileHeader {
+    pub schema_version: String,
+    pub config_hash: String, // Runs with the same hash used the same config file
+    pub start_time: u64, // Microseconds since the Unix epoch, the starting_epoch of every record
+}
+impl TraceFileHeader {
+    pub fn new(config_hash: &str, start_time: u64) -> TraceFileHeader {
+        TraceFileHeader { schema_version: TRACE_SCHEMA_VERSION.to_owned(), config_hash: config_hash.to_owned(), start_time }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceHeader {
+    starting_epoch: u64,
+    epoch: u64,
+    spawning_thread_id: u64,
+    thread_id: u64,
+    event_id: Vec<u64>,
+    trace_type: String,
+    module: String,
+    line_no: u32,
+    function: String,
+    format: String,
+    repo: String,
+}
+impl TraceHeader {
+    pub fn starting_epoch(&self) -> u64 { self.starting_epoch }
+    pub fn epoch(&self) -> u64 { self.epoch }
+    pub fn spawning_thread_id(&self) -> u64 { self.spawning_thread_id }
+    pub fn thread_id(&self) -> u64 { self.thread_id }
+    pub fn event_id(&self) -> &Vec<u64> { &self.event_id }
+    pub fn trace_type(&self) -> &str { &self.trace_type }
+    pub fn module(&self) -> &str { &self.module }
+    pub fn line_no(&self) -> u32 { self.line_no }
+    pub fn function(&self) -> &str { &self.function }
+    pub fn format(&self) -> &str { &self.format }
+    pub fn repo(&self) -> &str { &self.repo }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    header: TraceHeader,
+    body: Value
+}
+impl TraceRecord {
+    pub fn header(&self) -> &TraceHeader { &self.header }
+    pub fn body(&self) -> &Value { &self.body }
+    pub fn format(&self) -> &str { self.header.format() }
+    // The same name the file sink uses for the cell files
+    pub fn cell_name(&self) -> &str { self.body["cell_id"]["name"].as_str().unwrap_or("Other") }
+    // The body as the struct for this record's format
+    pub fn body_as<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let _f = "body_as";
+        Ok(serde_json::from_value(self.body.clone())
+            .context(TraceReaderError::Body { func_name: _f, format: self.format().to_owned() })?)
+    }
+}
+pub struct TraceReader<R: BufRead> {
+    file_header: Option<TraceFileHeader>, // None for an empty file
+    lines: Lines<R>,
+    line_no: usize,
+}
+impl TraceReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TraceReader<BufReader<File>>, Error> {
+        let _f = "open";
+        let file_name = path.as_ref().display().to_string();
+        let file = File::open(path).context(TraceReaderError::Chain { func_name: _f, comment: file_name })?;
+        TraceReader::new(BufReader::new(file))
+    }
+}
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(reader: R) -> Result<TraceReader<R>, Error> {
+        let _f = "new";
+        let mut lines = reader.lines();
+        let file_header = match lines.next().transpose()? {
+            None => None,
+            Some(line) => {
+                let file_header: TraceFileHeader = serde_json::from_str(&line)
+                    .context(TraceReaderError::FileHeader { func_name: _f })?;
+                if file_header.schema_version != TRACE_SCHEMA_VERSION {
+                    return Err(TraceReaderError::Version { func_name: _f, found: file_header.schema_version, expected: TRACE_SCHEMA_VERSION }.into());
+                }
+                Some(file_header)
+            }
+        };
+        Ok(TraceReader { file_header, lines, line_no: 1 })
+    }
+    pub fn get_file_header(&self) -> Option<&TraceFileHeader> { self.file_header.as_ref() }
+    // The bodies of the records with this format
+    pub fn bodies<T: DeserializeOwned>(self, format: &'static str) -> impl Iterator<Item=Result<T, Error>> {
+        self.filter(move |record| record.as_ref().map(|r| r.format() == format).unwrap_or(true))
+            .map(|record| record.and_then(|r| r.body_as()))
+    }
+}
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, Error>;
+    fn next(&mut self) -> Option<Result<TraceRecord, Error>> {
+        let _f = "next";
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into()))
+            };
+            self.line_no += 1;
+            if line.trim().is_empty() { continue; }
+            let line_no = self.line_no;
+            return Some(serde_json::from_str(&line)
+                .map_err(|e| e.context(TraceReaderError::Record { func_name: _f, line_no }).into()));
+        }
+    }
+}
+
+// Errors
+use failure::{Error, Fail, ResultExt};
+
+#[derive(Debug, Fail)]
+pub enum TraceReaderError {
+    #[fail(display = "TraceReaderError::Chain {}: {}", func_name, comment)]
+    Chain { func_name: &'static str, comment: String },
+    #[fail(display = "TraceReaderError::Body {}: Body doesn't match format {}", func_name, format)]
+    Body { func_name: &'static str, format: String },
+    #[fail(display = "TraceReaderError::FileHeader {}: First line isn't a trace file header", func_name)]
+    FileHeader { func_name: &'static str },
+    #[fail(display = "TraceReaderError::Record {}: Line {} isn't a trace record", func_name, line_no)]
+    Record { func_name: &'static str, line_no: usize },
+    #[fail(display = "TraceReaderError::Version {}: Found schema version {}, expected {}", func_name, found, expected)]
+    Version { func_name: &'static str, found: String, expected: &'static str },
+}
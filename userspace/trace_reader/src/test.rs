@@ -0,0 +1,108 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+#[macro_use] extern crate serde_json;
+
+use ec_trace_reader::{test_result, TraceFileHeader, TraceReader};
+use ec_trace_reader::causality::{CausalGraph, EdgeKind};
+use ec_trace_reader::diff::{DiffKind, RunSummary, TraceDiff};
+use ec_trace_reader::testing::Test;
+use ec_trace_reader::tree_names::TreeNames;
+
+struct Causality {}
+
+impl Test for Causality {
+    // The main thread forks a thread for each cell; C:0 sends a message that leads to a
+    // routing table entry on C:1 and then to C:1 finishing discovery
+    fn test(&mut self) {
+        let record = |thread_id: u64, spawning_thread_id: u64, event_id: Vec<u64>, epoch: u64, format: &str, body: serde_json::Value| {
+            json!({ "header": { "starting_epoch": 100, "epoch": epoch, "spawning_thread_id": spawning_thread_id, "thread_id": thread_id,
+                                "event_id": event_id, "trace_type": "Trace", "module": "test.rs", "line_no": 0, "function": "test",
+                                "format": format, "repo": "CellAgent" },
+                    "body": body }).to_string()
+        };
+        let msg = json!({ "header": { "sending_cell_id": { "name": "C:0" }, "sender_msg_seq_no": 7, "msg_type": "Discover" } });
+        let lines = vec![
+            serde_json::to_string(&TraceFileHeader::new("0123456789abcdef", 100)).unwrap(),
+            record(1, 1, vec![1], 110, "border_cell", json!({ "cell_no": 0 })),
+            record(2, 1, vec![2, 1], 120, "ca_new", json!({ "cell_id": { "name": "C:0" } })),
+            record(3, 1, vec![3, 1], 125, "ca_new", json!({ "cell_id": { "name": "C:1" } })),
+            record(2, 1, vec![2, 2], 130, "ca_send_msg", json!({ "cell_id": { "name": "C:0" }, "msg": msg })),
+            record(3, 1, vec![3, 2], 140, "ca_got_msg", json!({ "cell_id": { "name": "C:1" }, "msg": msg })),
+            record(3, 1, vec![3, 3], 150, "ca_to_cm_entry", json!({ "cell_id": { "name": "C:1" } })),
+            record(3, 1, vec![3, 4], 160, "ca_discover_done", json!({ "cell_id": { "name": "C:1" } })),
+        ];
+        let graph = CausalGraph::read(TraceReader::new(lines.join("\n").as_bytes()).unwrap()).unwrap();
+        assert_eq!(graph.edges().iter().filter(|edge| edge.kind == EdgeKind::Fork).count(), 2);
+        assert_eq!(graph.edges().iter().filter(|edge| edge.kind == EdgeKind::Message).map(|edge| (edge.from, edge.to)).collect::<Vec<_>>(), vec![(3, 4)]);
+        let entry = graph.find("ca_to_cm_entry", Some("C:1"))[0];
+        assert_eq!(graph.message_chain(entry), vec![3, 4, 5]);
+        assert_eq!(graph.critical_path().unwrap(), vec![0, 1, 3, 4, 5, 6]);
+        assert!(graph.to_dot(None).contains("r3 -> r4 [style=bold, color=blue]"));
+        assert_eq!(graph.to_chrome_trace()["traceEvents"].as_array().unwrap().len(), 3 + 7 + 2 * 3);
+    }
+}
+
+test_result!(test_causality, Causality {});
+
+struct TraceDiffs {}
+
+impl Test for TraceDiffs {
+    // Runs are compared by tree name, since tree UUIDs differ from run to run
+    fn test(&mut self) {
+        let run = |uuids: (&str, &str), parent: u8, discovers: usize, done: u64| {
+            let record = |epoch: u64, format: &str, body: serde_json::Value| {
+                json!({ "header": { "starting_epoch": 0, "epoch": epoch, "spawning_thread_id": 1, "thread_id": 1,
+                                    "event_id": [epoch], "trace_type": "Trace", "module": "test.rs", "line_no": 0, "function": "test",
+                                    "format": format, "repo": "CellAgent" },
+                        "body": body }).to_string()
+            };
+            let cell_id = json!({ "name": "C:0", "uuid": { "uuid": "0000ffff-0000-4000-8000-000000000000" } });
+            let my_tree_uuid = json!({ "uuid": format!("0000{}-0000-4000-8000-000000000000", uuids.0) });
+            let port_tree_uuid = json!({ "uuid": format!("0001{}-0000-4000-8000-000000000000", uuids.1) });
+            let mut lines = vec![
+                serde_json::to_string(&TraceFileHeader::new("0123456789abcdef", 0)).unwrap(),
+                record(1, "ca_new", json!({ "cell_id": cell_id, "my_tree_id": { "name": "C:0", "uuid": my_tree_uuid } })),
+                record(2, "ca_process_discover_msg", json!({ "cell_id": cell_id, "new_port_tree_id": { "name": "C:1", "uuid": port_tree_uuid } })),
+                record(3, "ca_to_cm_entry", json!({ "cell_id": cell_id, "entry": { "tree_uuid": my_tree_uuid, "inuse": true, "parent": 0 } })),
+                record(4, "ca_to_cm_entry", json!({ "cell_id": cell_id, "entry": { "tree_uuid": port_tree_uuid, "inuse": true, "parent": parent } })),
+            ];
+            for _ in 0..discovers {
+                lines.push(record(5, "ca_to_cm_bytes", json!({ "cell_id": cell_id, "msg": { "header": { "msg_type": "Discover" } } })));
+            }
+            lines.push(record(done, "ca_discover_done", json!({ "cell_id": cell_id })));
+            RunSummary::read(TraceReader::new(lines.join("\n").as_bytes()).unwrap()).unwrap()
+        };
+        let left = run(("aaaa", "bbbb"), 1, 2, 50);
+        assert!(left.cells["C:0"].trees().contains(&"C:1 port 1".to_owned()));
+        let same = TraceDiff::new(&left, &run(("cccc", "dddd"), 1, 2, 50));
+        assert!(same.get_differences().is_empty());
+        let diff = TraceDiff::new(&left, &run(("cccc", "dddd"), 2, 3, 60));
+        assert!(diff.fabric_differs());
+        let kinds = diff.get_differences().iter().map(|difference| difference.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![DiffKind::RoutingTable, DiffKind::MsgCount, DiffKind::Convergence]);
+    }
+}
+
+test_result!(test_trace_diffs, TraceDiffs {});
+
+struct TreeNamesKnown {}
+
+impl Test for TreeNamesKnown {
+    // A record is known once every ID in it, however deep, has been learned
+    fn test(&mut self) {
+        let id = |name: &str, key: &str| json!({ "name": name, "uuid": { "uuid": format!("0000{}-0000-4000-8000-000000000000", key) } });
+        let record = json!({ "cell_id": id("C:0", "0001"), "msg": { "tree_ids": [id("C:0+Base", "0002")] } });
+        let mut tree_names = TreeNames::default();
+        assert!(!tree_names.knows(&record));
+        tree_names.learn_name("C:0", &id("C:0", "0001")["uuid"]);
+        assert!(!tree_names.knows(&record));
+        tree_names.learn(&record);
+        assert!(tree_names.knows(&record));
+        assert_eq!(tree_names.len(), 2);
+        assert!(tree_names.knows(&json!({ "tree_uuid": { "uuid": "00000003-0000-4000-8000-000000000000" } }))); // Nothing to learn without a name
+    }
+}
+
+test_result!(test_tree_names_known, TreeNamesKnown {});
@@ -0,0 +1,20 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// What the tests of the trace tools share; each test is a struct that knows how to run itself,
+// e.g., test_result!(test_trace_diffs, TraceDiffs {});
+pub trait Test {
+    fn test(&mut self);
+}
+
+#[macro_export]
+macro_rules! test_result {
+    ($name:ident, $testable:expr) => {
+        #[test]
+        fn $name() {
+            let mut testable = $testable;
+            $crate::testing::Test::test(&mut testable);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright © 2016-present Earth Computing Corporation. All rights reserved.
+ *  Licensed under the MIT License. See LICENSE.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+// Routing table entries name their tree only by UUID, which is made up at run time.  The names
+// come from the tree and cell IDs elsewhere in the trace, {"name": .., "uuid": {"uuid": ..}}.
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Default)]
+pub struct TreeNames {
+    names: HashMap<String, String>, // By UUID without the first two bytes
+}
+impl TreeNames {
+    // Every ID in value, however deep
+    pub fn learn(&mut self, value: &Value) {
+        match value {
+            Value::Object(fields) => {
+                if let (Some(name), Some((key, _))) = (fields.get("name").and_then(Value::as_str), fields.get("uuid").and_then(uuid_key)) {
+                    self.names.entry(key).or_insert_with(|| name.to_owned());
+                }
+                for value in fields.values() { self.learn(value); }
+            },
+            Value::Array(values) => for value in values { self.learn(value); },
+            _ => ()
+        }
+    }
+    // Whether learn() would find nothing new in value
+    pub fn knows(&self, value: &Value) -> bool {
+        match value {
+            Value::Object(fields) => {
+                let known = match (fields.get("name").and_then(Value::as_str), fields.get("uuid").and_then(uuid_key)) {
+                    (Some(_), Some((key, _))) => self.names.contains_key(&key),
+                    _ => true
+                };
+                known && fields.values().all(|value| self.knows(value))
+            },
+            Value::Array(values) => values.iter().all(|value| self.knows(value)),
+            _ => true
+        }
+    }
+    pub fn len(&self) -> usize { self.names.len() }
+    pub fn is_empty(&self) -> bool { self.names.is_empty() }
+    pub fn learn_name(&mut self, name: &str, uuid: &Value) {
+        if let Some((key, _)) = uuid_key(uuid) { self.names.entry(key).or_insert_with(|| name.to_owned()); }
+    }
+    // The name of the tree, or of the tree a port tree belongs to
+    pub fn get(&self, uuid: &Value) -> Option<&str> {
+        let (key, _) = uuid_key(uuid)?;
+        self.names.get(&key).map(String::as_str)
+    }
+    // "Tree <uuid>" if the name hasn't been seen
+    pub fn name(&self, uuid: &Value) -> String {
+        match uuid_key(uuid) {
+            Some((key, port_no)) => {
+                let name = self.names.get(&key).cloned().unwrap_or_else(|| format!("Tree {}", key));
+                if port_no == 0 { name } else { format!("{} port {}", name, port_no) }
+            },
+            None => "Unknown".to_owned()
+        }
+    }
+}
+// The first two bytes of a UUID are flags and the root port of a port tree; the rest is the
+// same for a tree and its port trees
+fn uuid_key(uuid: &Value) -> Option<(String, u8)> {
+    let uuid = uuid["uuid"].as_str()?;
+    let port_no = u8::from_str_radix(uuid.get(2..4)?, 16).ok()?;
+    Some((uuid.get(4..)?.to_owned(), port_no))
+}